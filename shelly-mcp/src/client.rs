@@ -0,0 +1,173 @@
+//! A typed client for driving a shelly-mcp server from Rust, without hand-rolling the
+//! JSON-RPC framing
+
+use std::fmt;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use rmcp::model::{CallToolRequestParam, CallToolResult};
+use rmcp::service::RunningService;
+use rmcp::transport::{IntoTransport, TokioChildProcess};
+use rmcp::{RoleClient, ServiceExt};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use shelly::process_manager::{ProcessId, ProcessStatus, ProcessUpdate};
+use shelly::ExecutionResult;
+use tokio::process::Command;
+
+use crate::{
+    CancelMatchingArgs, CancelProcessArgs, ErrorKind, ExecuteCliArgs, JoinProcessArgs,
+    ProcessStatusArgs,
+};
+
+/// A tool call the server rejected. Returned inside the `anyhow::Error` of a failed
+/// call, so callers can `downcast_ref` it to branch on `kind`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ToolError {
+    pub kind: ErrorKind,
+    pub message: String,
+}
+
+impl fmt::Display for ToolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for ToolError {}
+
+/// A session with a shelly-mcp server
+pub struct ShellyClient {
+    service: RunningService<RoleClient, ()>,
+}
+
+impl ShellyClient {
+    /// Start the server with `command`, e.g. the `shelly-mcp` binary, and talk to it over
+    /// its stdin and stdout
+    pub async fn spawn(command: Command) -> Result<Self> {
+        let transport = TokioChildProcess::new(command).context("Failed to start MCP server")?;
+        Self::connect(transport).await
+    }
+
+    /// Talk to a server over any rmcp transport, e.g. one end of `tokio::io::duplex`
+    pub async fn connect<T, E, A>(transport: T) -> Result<Self>
+    where
+        T: IntoTransport<RoleClient, E, A>,
+        E: std::error::Error + Send + Sync + 'static,
+    {
+        let service = ()
+            .serve(transport)
+            .await
+            .context("Failed to initialize MCP session")?;
+        Ok(Self { service })
+    }
+
+    /// Run a command, waiting up to `args.wait_ms` for it to finish
+    pub async fn execute_cli(&self, args: ExecuteCliArgs) -> Result<ExecutionResult> {
+        self.call("execute_cli", args).await
+    }
+
+    /// Wait up to `timeout` for the process to finish. `cursor` is the one from the
+    /// previous update, to get only what was added since.
+    pub async fn join_process(
+        &self,
+        process_id: &ProcessId,
+        timeout: Duration,
+        cursor: Option<usize>,
+    ) -> Result<ProcessUpdate> {
+        let args = JoinProcessArgs {
+            process_id: process_id.0.clone(),
+            timeout_ms: timeout.as_millis() as u64,
+            cursor,
+        };
+        self.call("join_process", args).await
+    }
+
+    pub async fn cancel_process(&self, process_id: &ProcessId) -> Result<()> {
+        let args = CancelProcessArgs {
+            process_id: process_id.0.clone(),
+        };
+        self.call_tool("cancel_process", args).await?;
+        Ok(())
+    }
+
+    /// Cancel the running processes whose command matches `args.pattern`, returning the
+    /// ones cancelled
+    pub async fn cancel_matching(&self, args: CancelMatchingArgs) -> Result<Vec<ProcessId>> {
+        #[derive(Deserialize)]
+        struct Cancelled {
+            cancelled: Vec<ProcessId>,
+        }
+        let result: Cancelled = self.call("cancel_matching", args).await?;
+        Ok(result.cancelled)
+    }
+
+    pub async fn process_status(&self, process_id: &ProcessId) -> Result<ProcessStatus> {
+        let args = ProcessStatusArgs {
+            process_id: process_id.0.clone(),
+            include_tail: None,
+        };
+        self.call("process_status", args).await
+    }
+
+    /// End the session, which also stops a server started with `spawn`
+    pub async fn close(self) -> Result<()> {
+        self.service.cancel().await?;
+        Ok(())
+    }
+
+    /// Call a tool and deserialize its structured content
+    async fn call<T: DeserializeOwned>(
+        &self,
+        name: &'static str,
+        args: impl Serialize,
+    ) -> Result<T> {
+        let structured = self
+            .call_tool(name, args)
+            .await?
+            .structured_content
+            .with_context(|| format!("{name} returned no structured content"))?;
+        serde_json::from_value(structured).with_context(|| format!("Unexpected {name} result"))
+    }
+
+    /// Call a tool, turning a failed result into a `ToolError`
+    async fn call_tool(
+        &self,
+        name: &'static str,
+        args: impl Serialize,
+    ) -> Result<CallToolResult> {
+        let serde_json::Value::Object(arguments) = serde_json::to_value(args)? else {
+            anyhow::bail!("{name} arguments must be an object");
+        };
+        let request = CallToolRequestParam {
+            name: name.into(),
+            arguments: Some(arguments),
+        };
+        let mut result = self
+            .service
+            .call_tool(request)
+            .await
+            .with_context(|| format!("{name} call failed"))?;
+        if result.is_error != Some(true) {
+            return Ok(result);
+        }
+        let error = result
+            .structured_content
+            .as_mut()
+            .map(|structured| structured["error"].take())
+            .and_then(|error| serde_json::from_value::<ToolError>(error).ok());
+        match error {
+            Some(error) => Err(anyhow::Error::new(error).context(format!("{name} failed"))),
+            None => {
+                let text = result
+                    .content
+                    .iter()
+                    .filter_map(|content| content.as_text())
+                    .map(|text| text.text.as_str())
+                    .collect::<Vec<_>>()
+                    .join("\n");
+                anyhow::bail!("{name} failed: {text}")
+            }
+        }
+    }
+}
@@ -1,14 +1,62 @@
+use std::path::PathBuf;
 use std::sync::Arc;
 use std::{collections::HashMap, time::Duration};
 
 use rmcp::{
     handler::server::{tool::ToolRouter, wrapper::Parameters},
-    model::{CallToolResult, Content, ServerCapabilities, ServerInfo},
-    tool, tool_handler, tool_router, ErrorData, ServerHandler,
+    model::{
+        AnnotateAble, CallToolResult, Content, ListResourceTemplatesResult, ListResourcesResult,
+        PaginatedRequestParam, ProgressNotificationParam, RawResource, RawResourceTemplate,
+        ReadResourceRequestParam, ReadResourceResult, ResourceContents, ServerCapabilities,
+        ServerInfo,
+    },
+    service::RequestContext,
+    tool, tool_handler, tool_router, ErrorData, RoleServer, ServerHandler,
 };
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
-use shelly::process_manager::{ProcessId, ProcessManager};
+use shelly::output::StreamFilter;
+use shelly::process_manager::{ProcessId, ProcessManager, ProcessUpdate};
+use shelly::streaming_executor::{EnvPolicy, ResourceLimits};
+use tokio::sync::mpsc;
+
+pub mod client;
+pub mod log_file;
+
+pub use client::{ShellyClient, ToolError};
+
+/// What went wrong in a failed tool call. Returned as `error.kind` in the structured
+/// content, so clients can branch on it instead of parsing the message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorKind {
+    ProcessNotFound,
+    /// The process has already exited, so it can't be cancelled or sent input
+    AlreadyCompleted,
+    /// The command couldn't be started
+    SpawnFailed,
+    /// A handler ran out of time
+    Timeout,
+    /// A handler couldn't be found, loaded or prepared
+    HandlerError,
+    /// The arguments don't make sense, e.g. an invalid regex
+    InvalidArguments,
+    /// A file couldn't be read or created
+    Io,
+}
+
+/// A failed tool result carrying `kind` next to the human-readable `message`
+fn tool_error(kind: ErrorKind, message: impl Into<String>) -> CallToolResult {
+    let message = message.into();
+    CallToolResult {
+        content: vec![Content::text(message.clone())],
+        structured_content: Some(serde_json::json!({
+            "error": { "kind": kind, "message": message },
+        })),
+        is_error: Some(true),
+        meta: None,
+    }
+}
 
 #[derive(Clone)]
 pub struct ShellyMcp {
@@ -17,26 +65,137 @@ pub struct ShellyMcp {
 }
 
 #[derive(Debug, Serialize, Deserialize, JsonSchema)]
-struct ExecuteCliArgs {
+pub struct ExecuteCliArgs {
     /// Command to execute
-    command: String,
+    pub command: String,
     /// Command arguments
     #[serde(default)]
-    args: Vec<String>,
+    pub args: Vec<String>,
 
     /// Working directory (required - must be provided)
-    working_dir: String,
+    pub working_dir: String,
 
     /// Environment variables (optional)
     #[serde(default)]
-    env: HashMap<String, String>,
+    pub env: HashMap<String, String>,
 
-    /// Timeout in milliseconds
-    #[serde(default = "default_timeout")]
-    timeout_ms: u64,
+    /// Inherit the server's environment before applying `env`. Set to false for a clean
+    /// environment containing only `env`.
+    #[serde(default = "default_true")]
+    pub inherit_env: bool,
+
+    /// Variables to drop from the inherited environment. Defaults to ["RUST_LOG"]
+    #[serde(default)]
+    pub env_remove: Option<Vec<String>>,
+
+    /// Run under a pseudo-terminal, for commands that behave differently or refuse to
+    /// run when output is piped. stderr is merged into stdout.
+    #[serde(default)]
+    pub pty: bool,
+
+    /// Don't run the command; report what it would run as after the handler's rewrites,
+    /// including the environment variables it sets
+    #[serde(default)]
+    pub dry_run: bool,
+
+    /// Environment variables whose values are masked as *** in the output; `*` matches
+    /// anything. Defaults to names containing TOKEN, SECRET, PASSWORD, API_KEY or ACCESS_KEY
+    #[serde(default)]
+    pub redact_env: Option<Vec<String>>,
+
+    /// Roughly how many tokens the summary may take; longer summaries are cut. Defaults
+    /// to SHELLY_MAX_SUMMARY_TOKENS, or 500
+    #[serde(default)]
+    pub max_summary_tokens: Option<usize>,
+
+    /// Run the command through the user's shell so pipes, `&&`, globs and `$VAR` work.
+    /// `command` and `args` are joined with spaces and handed to `$SHELL -c` as is
+    #[serde(default)]
+    pub shell: bool,
+
+    /// Collapse runs of identical lines into `... (repeated N times)` when no handler
+    /// summarizes the output, e.g. with disable_enhancements
+    #[serde(default)]
+    pub dedupe: bool,
+
+    /// Kill the command once it has used this many seconds of CPU time. Unix only;
+    /// unlimited by default
+    #[serde(default)]
+    pub max_cpu_secs: Option<u64>,
+
+    /// Limit each process of the command to this many MiB of address space. Unix only;
+    /// unlimited by default
+    #[serde(default)]
+    pub max_memory_mb: Option<u64>,
+
+    /// How long to wait for the command before returning with is_running: true, in
+    /// milliseconds. The command keeps running; follow it with join_process.
+    /// `timeout_ms` is accepted as an older name for this.
+    #[serde(default = "default_timeout", alias = "timeout_ms")]
+    pub wait_ms: u64,
+
+    /// Hard cap on how long the command may run, in milliseconds, after which it is
+    /// cancelled even if it was left running in the background. Unlimited by default.
+    #[serde(default)]
+    pub max_runtime_ms: Option<u64>,
+
+    /// Kill the command if it prints nothing for this many milliseconds, e.g. when it
+    /// is stuck waiting on a lock, and report it as failed. Unlimited by default.
+    #[serde(default)]
+    pub idle_timeout_ms: Option<u64>,
+
+    /// Run the command through this handler, by name (e.g. "cargo") or path, instead of
+    /// the one picked by the command's name. The handler must still match the command.
+    #[serde(default)]
+    pub handler: Option<String>,
+
+    /// Text written to the command's stdin, which is then closed, like `cmd < file`.
+    /// Can't be combined with pty.
+    #[serde(default)]
+    pub stdin: Option<String>,
 
     /// Run the _exact_ command specified by the user
-    disable_enhancements: bool,
+    pub disable_enhancements: bool,
+
+    /// Handler settings (optional). Valid keys and types come from the matching
+    /// handler's settings schema
+    #[serde(default)]
+    pub settings: HashMap<String, serde_json::Value>,
+
+    /// Cancel the command when wait_ms elapses instead of leaving it running, as if
+    /// max_runtime_ms were wait_ms
+    #[serde(default)]
+    pub kill_on_timeout: bool,
+}
+
+impl ExecuteCliArgs {
+    /// Run `command` in `working_dir` with every other argument at its default
+    pub fn new(command: impl Into<String>, working_dir: impl Into<String>) -> Self {
+        Self {
+            command: command.into(),
+            args: Vec::new(),
+            working_dir: working_dir.into(),
+            env: HashMap::new(),
+            inherit_env: true,
+            env_remove: None,
+            pty: false,
+            dry_run: false,
+            redact_env: None,
+            max_summary_tokens: None,
+            shell: false,
+            dedupe: false,
+            max_cpu_secs: None,
+            max_memory_mb: None,
+            wait_ms: default_timeout(),
+            max_runtime_ms: None,
+            idle_timeout_ms: None,
+            handler: None,
+            stdin: None,
+            disable_enhancements: false,
+            settings: HashMap::new(),
+            kill_on_timeout: false,
+        }
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize, JsonSchema)]
@@ -46,6 +205,20 @@ struct JoinProcessArgs {
     /// Timeout in milliseconds for updates
     #[serde(default = "default_join_timeout")]
     timeout_ms: u64,
+    /// `cursor` from the previous join. Returns everything added since then, so a
+    /// retried join never loses output. Omit to continue from the last join that
+    /// didn't pass one.
+    #[serde(default)]
+    cursor: Option<usize>,
+}
+
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+struct JoinAnyProcessArgs {
+    /// Process IDs to wait on
+    process_ids: Vec<String>,
+    /// Timeout in milliseconds to wait for one of them to finish
+    #[serde(default = "default_join_timeout")]
+    timeout_ms: u64,
 }
 
 #[derive(Debug, Serialize, Deserialize, JsonSchema)]
@@ -54,10 +227,203 @@ struct CancelProcessArgs {
     process_id: String,
 }
 
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+pub struct CancelMatchingArgs {
+    /// Text to look for in the commands of running processes, e.g. "npm run dev"
+    pub pattern: String,
+    /// Treat pattern as a regular expression rather than plain text
+    #[serde(default)]
+    pub regex: bool,
+    /// Required to cancel more than one process at once
+    #[serde(default)]
+    pub confirm: bool,
+}
+
 #[derive(Debug, Serialize, Deserialize, JsonSchema)]
 struct ProcessStatusArgs {
     /// Process ID to check status
     process_id: String,
+    /// Also return the last N lines of raw output (stdout and stderr combined) as
+    /// `tail`, for a quick look without a separate tail_process call
+    #[serde(default)]
+    include_tail: Option<usize>,
+}
+
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+struct ListProcessesArgs {
+    /// Only include processes that are still running
+    #[serde(default)]
+    running_only: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+struct TailProcessArgs {
+    /// Process ID to tail
+    process_id: String,
+    /// Number of lines to return from the end of the output
+    #[serde(default = "default_tail_lines")]
+    lines: usize,
+    /// Which output to tail: stdout, stderr or both combined
+    #[serde(default)]
+    stream: Stream,
+}
+
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+struct SendInputArgs {
+    /// Process ID to send input to
+    process_id: String,
+    /// Text to write to the process's stdin
+    data: String,
+    /// Append a newline after the data
+    #[serde(default = "default_true")]
+    newline: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+struct SearchOutputArgs {
+    /// Process whose output file to search
+    #[serde(default)]
+    process_id: Option<String>,
+    /// Output file to search, as returned by execute_cli (alternative to process_id)
+    #[serde(default)]
+    output_file: Option<String>,
+    /// Regular expression to match lines against, e.g. `error\[`
+    pattern: String,
+    /// Lines of context to include before and after each match
+    #[serde(default = "default_context_lines")]
+    context_lines: usize,
+    /// Maximum number of matches to return
+    #[serde(default = "default_max_matches")]
+    max_matches: usize,
+    /// Which output to search: stdout, stderr or both combined
+    #[serde(default)]
+    stream: Stream,
+}
+
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+struct ReadOutputArgs {
+    /// Process whose output file to read
+    #[serde(default)]
+    process_id: Option<String>,
+    /// Output file to read, as returned by execute_cli (alternative to process_id)
+    #[serde(default)]
+    output_file: Option<String>,
+    /// Where to start, in `unit`s from the start of the file. Pass the previous
+    /// response's next_offset to continue.
+    #[serde(default)]
+    offset: u64,
+    /// How much to read, in `unit`s
+    #[serde(default = "default_read_length")]
+    length: u64,
+    /// Whether offset and length count lines or bytes
+    #[serde(default)]
+    unit: ReadUnit,
+}
+
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+enum ReadUnit {
+    Bytes,
+    #[default]
+    Lines,
+}
+
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+enum Stream {
+    Stdout,
+    Stderr,
+    #[default]
+    Both,
+}
+
+impl From<Stream> for shelly::output::StreamFilter {
+    fn from(stream: Stream) -> Self {
+        match stream {
+            Stream::Stdout => Self::Stdout,
+            Stream::Stderr => Self::Stderr,
+            Stream::Both => Self::Both,
+        }
+    }
+}
+
+impl From<ReadUnit> for shelly::output::ReadUnit {
+    fn from(unit: ReadUnit) -> Self {
+        match unit {
+            ReadUnit::Bytes => Self::Bytes,
+            ReadUnit::Lines => Self::Lines,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+struct ShellyMetricsArgs {
+    /// Return the counters in the Prometheus text exposition format
+    #[serde(default)]
+    prometheus: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+struct ShellyInfoArgs {
+    /// Directory whose project handlers to list. Defaults to the server's current
+    /// directory.
+    #[serde(default)]
+    working_dir: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+struct DescribeHandlerArgs {
+    /// Full command line, e.g. "cargo build --release"
+    command: String,
+    /// Handler settings to preview with (optional)
+    #[serde(default)]
+    settings: HashMap<String, serde_json::Value>,
+    /// Directory the command would run in; handlers may read files there. Defaults to
+    /// the server's current directory.
+    #[serde(default)]
+    working_dir: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+struct ReplayOutputArgs {
+    /// Process whose captured output to replay
+    #[serde(default)]
+    process_id: Option<String>,
+    /// Output file to replay, as returned by execute_cli (alternative to process_id)
+    #[serde(default)]
+    output_file: Option<String>,
+    /// Handler to run: an installed handler's name, e.g. "cargo", or a path to a
+    /// handler file
+    handler: String,
+    /// Command line the handler sees. Defaults to the command recorded with the output.
+    #[serde(default)]
+    command: Option<String>,
+    /// Handler settings (optional)
+    #[serde(default)]
+    settings: HashMap<String, serde_json::Value>,
+    /// Directory handlers are looked up from and may read files in. Defaults to the
+    /// server's current directory.
+    #[serde(default)]
+    working_dir: Option<String>,
+}
+
+/// URI prefix of the resource holding a process's output file
+const OUTPUT_URI_PREFIX: &str = "shelly://output/";
+
+fn output_uri(process_id: &ProcessId) -> String {
+    format!("{OUTPUT_URI_PREFIX}{}", process_id.0)
+}
+
+/// Text content for an execute_cli result, listing any actions the handler offers so
+/// the agent sees them without digging through the structured result
+fn describe_actions(actions: &[shelly::ProcessAction]) -> String {
+    let mut text = "command executed".to_string();
+    for action in actions {
+        if let shelly::ProcessAction::Custom { name, description } = action {
+            text.push_str(&format!("\nhandler action {name}: {description}"));
+        }
+    }
+    text
 }
 
 impl ShellyMcp {
@@ -67,6 +433,105 @@ impl ShellyMcp {
             process_manager: Arc::new(ProcessManager::new()),
         }
     }
+
+    /// A server that records its processes under the output directory, so process ids
+    /// handed out before a restart can still be looked up
+    pub fn persistent() -> anyhow::Result<Self> {
+        let registry_dir = shelly::output::output_dir(None)?.join("processes");
+        Ok(Self {
+            tool_router: Self::tool_router(),
+            process_manager: Arc::new(ProcessManager::with_registry(registry_dir)?),
+        })
+    }
+
+    /// One resource per tracked process that has an output file
+    async fn output_resources(&self) -> Vec<RawResource> {
+        let processes = self.process_manager.list_processes(false).await;
+        processes
+            .into_iter()
+            .filter_map(|process| {
+                let output_file = process.output_file?;
+                let size = std::fs::metadata(&output_file).ok()?.len();
+                let mut resource = RawResource::new(output_uri(&process.id), process.command);
+                resource.description = Some(format!("Output of a {:?} process", process.state));
+                resource.mime_type = Some("text/plain".to_string());
+                resource.size = u32::try_from(size).ok();
+                Some(resource)
+            })
+            .collect()
+    }
+
+    /// The contents of the output file behind a `shelly://output/{process_id}` URI
+    async fn read_output_resource(&self, uri: &str) -> Result<ReadResourceResult, ErrorData> {
+        let not_found = || ErrorData::resource_not_found(format!("No such resource: {uri}"), None);
+        let process_id = uri.strip_prefix(OUTPUT_URI_PREFIX).ok_or_else(not_found)?;
+        let output_file = self
+            .process_manager
+            .get_process_status(&ProcessId(process_id.to_string()))
+            .await
+            .and_then(|info| info.output_file)
+            .ok_or_else(not_found)?;
+        let text = tokio::fs::read_to_string(&output_file).await.map_err(|e| {
+            ErrorData::internal_error(
+                format!("Failed to read {}: {e}", output_file.display()),
+                None,
+            )
+        })?;
+        Ok(ReadResourceResult {
+            contents: vec![ResourceContents::TextResourceContents {
+                uri: uri.to_string(),
+                mime_type: Some("text/plain".to_string()),
+                text,
+                meta: None,
+            }],
+        })
+    }
+}
+
+impl ShellyMcp {
+    /// The output file named directly, or the one belonging to `process_id`
+    async fn resolve_output_file(
+        &self,
+        process_id: Option<String>,
+        output_file: Option<String>,
+    ) -> Result<PathBuf, CallToolResult> {
+        match (process_id, output_file) {
+            (_, Some(output_file)) => Ok(PathBuf::from(output_file)),
+            (Some(process_id), None) => self
+                .process_manager
+                .get_process_status_summary(&ProcessId(process_id))
+                .await
+                .ok_or_else(|| tool_error(ErrorKind::ProcessNotFound, "Process not found"))?
+                .output_file
+                .ok_or_else(|| tool_error(ErrorKind::Io, "Process has no output file")),
+            (None, None) => Err(tool_error(
+                ErrorKind::InvalidArguments,
+                "Either process_id or output_file is required",
+            )),
+        }
+    }
+
+    /// Why `action` couldn't be applied to a process that isn't running: it's unknown,
+    /// or it has already exited
+    async fn not_running_error(&self, process_id: &ProcessId, action: &str) -> CallToolResult {
+        match self.process_manager.get_process_status_summary(process_id).await {
+            Some(status) if status.state.is_finished() => tool_error(
+                ErrorKind::AlreadyCompleted,
+                format!("Can't {action} process {}: it has already exited", process_id.0),
+            ),
+            Some(_) => tool_error(
+                ErrorKind::InvalidArguments,
+                format!("Can't {action} process {} right now", process_id.0),
+            ),
+            None => tool_error(ErrorKind::ProcessNotFound, "Process not found"),
+        }
+    }
+}
+
+impl Default for ShellyMcp {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 #[tool_router]
@@ -74,47 +539,117 @@ impl ShellyMcp {
     /// Execute a CLI command with smart filtering.
     #[tool(
         name = "execute_cli",
-        description = "Execute a CLI command. Shelly will remove noise from the command, both by filtering, and by using the best flags to show the most important information. Always provide working_dir."
+        description = "Execute a CLI command. Shelly will remove noise from the command, both by filtering, and by using the best flags to show the most important information. Always provide working_dir. wait_ms is how long this call waits before returning with is_running: true while the command carries on (follow it with join_process); max_runtime_ms is a hard cap after which the command is cancelled. Handlers can be tuned via `settings`; valid keys come from the handler's declared settings schema, and values of the wrong type are rejected."
     )]
     async fn execute_cli(
         &self,
         params: Parameters<ExecuteCliArgs>,
+        context: RequestContext<RoleServer>,
+    ) -> Result<CallToolResult, ErrorData> {
+        // Clients that pass a progress token get the summary pushed to them as it grows,
+        // instead of having to wait for the timeout
+        let progress = context.meta.get_progress_token().map(|progress_token| {
+            let (progress_tx, mut progress_rx) = mpsc::unbounded_channel::<ProcessUpdate>();
+            let peer = context.peer.clone();
+            tokio::spawn(async move {
+                while let Some(update) = progress_rx.recv().await {
+                    let notification = ProgressNotificationParam {
+                        progress_token: progress_token.clone(),
+                        progress: update.cursor as f64,
+                        total: None,
+                        message: Some(update.incremental_summary),
+                    };
+                    if let Err(e) = peer.notify_progress(notification).await {
+                        tracing::warn!("failed to send progress notification: {e}");
+                        return;
+                    }
+                }
+            });
+            progress_tx
+        });
+        self.run_command(params, progress).await
+    }
+
+    /// `execute_cli`, sending summary updates to `progress` while waiting out the timeout
+    async fn run_command(
+        &self,
+        params: Parameters<ExecuteCliArgs>,
+        progress: Option<mpsc::UnboundedSender<ProcessUpdate>>,
     ) -> Result<CallToolResult, ErrorData> {
         let params = params.0;
 
         let request = shelly::ExecuteRequest {
             cmd: params.command,
             args: params.args,
-            settings: HashMap::new(),
+            settings: params.settings,
             exact: params.disable_enhancements,
             working_dir: params.working_dir.into(),
             env: params.env,
+            env_policy: EnvPolicy {
+                inherit: params.inherit_env,
+                remove: params
+                    .env_remove
+                    .unwrap_or_else(|| EnvPolicy::default().remove),
+            },
+            kill_on_timeout: params.kill_on_timeout,
+            max_runtime_ms: params.max_runtime_ms,
+            idle_timeout_ms: params.idle_timeout_ms,
+            handler: params.handler,
+            output_dir: None,
+            ansi: shelly::output::AnsiMode::Strip,
+            pty: params.pty,
+            dry_run: params.dry_run,
+            redact_env: params.redact_env,
+            max_summary_tokens: params.max_summary_tokens,
+            shell: params.shell,
+            dedupe: params.dedupe,
+            limits: ResourceLimits {
+                cpu_secs: params.max_cpu_secs,
+                memory_mb: params.max_memory_mb,
+            },
+            stdin: params.stdin,
         };
 
         // Use streaming version with timeout
-        let timeout_duration = tokio::time::Duration::from_millis(params.timeout_ms);
-        let result = shelly::execute_command_streaming(
+        let timeout_duration = tokio::time::Duration::from_millis(params.wait_ms);
+        let result = shelly::execute_command_streaming_with_progress(
             request,
             self.process_manager.clone(),
             timeout_duration,
+            progress,
         )
         .await;
 
         Ok(match result {
-            Ok(result) => CallToolResult {
-                content: vec![Content::text("command executed")],
-                structured_content: Some(serde_json::to_value(&result).unwrap()),
-                is_error: None,
-                meta: None,
-            },
-            Err(err) => CallToolResult::error(vec![Content::text(err.to_string())]),
+            Ok(result) => {
+                let mut structured = serde_json::to_value(&result).unwrap();
+                if let Some(process_id) = &result.process_id {
+                    structured["output_resource"] = output_uri(process_id).into();
+                }
+                CallToolResult {
+                    content: vec![Content::text(describe_actions(&result.available_actions))],
+                    structured_content: Some(structured),
+                    is_error: None,
+                    meta: None,
+                }
+            }
+            Err(err) => {
+                let kind = if err.chain().any(|e| e.is::<shelly::runtime::HandlerTimeout>()) {
+                    ErrorKind::Timeout
+                } else if err.downcast_ref::<shelly::runtime::HandlerError>().is_some() {
+                    ErrorKind::HandlerError
+                } else {
+                    ErrorKind::SpawnFailed
+                };
+                tool_error(kind, format!("{err:#}"))
+            }
         })
     }
 
     /// Join a running process to continue receiving updates
     #[tool(
         name = "join_process",
-        description = "Join a running process to continue receiving updates with timeout"
+        description = "Join a running process to continue receiving updates with timeout. Each update includes a cursor; pass it to the next join to get only newer output, and repeat a join with the same cursor to safely retry. timed_out is true when the join gave up waiting while the process was still running, false once it has finished."
     )]
     async fn join_process(
         &self,
@@ -128,20 +663,67 @@ impl ShellyMcp {
         // Get incremental updates since last join
         let update = self
             .process_manager
-            .join_process(&process_id, Duration::from_millis(params.timeout_ms))
+            .join_process(
+                &process_id,
+                Duration::from_millis(params.timeout_ms),
+                params.cursor,
+            )
             .await;
         tracing::info!("Got updates for process");
         if let Some(update) = update {
             Ok(CallToolResult {
-                content: vec![Content::text(format!("Process joined"))],
+                content: vec![Content::text("Process joined")],
                 structured_content: Some(serde_json::to_value(&update).unwrap()),
                 is_error: None,
                 meta: None,
             })
         } else {
-            Ok(CallToolResult::error(vec![Content::text(
-                "Process not found",
-            )]))
+            Ok(tool_error(ErrorKind::ProcessNotFound, "Process not found"))
+        }
+    }
+
+    /// Wait for whichever of several processes finishes first
+    #[tool(
+        name = "join_any_process",
+        description = "Wait until any one of several processes finishes and return its ID and update. Use this instead of polling join_process on each of several parallel commands. A process that has already finished is returned immediately; if none finish within the timeout, process_id is null."
+    )]
+    async fn join_any_process(
+        &self,
+        params: Parameters<JoinAnyProcessArgs>,
+    ) -> Result<CallToolResult, ErrorData> {
+        let params = params.0;
+        let process_ids: Vec<ProcessId> = params.process_ids.into_iter().map(ProcessId).collect();
+        let no_process_ids = process_ids.is_empty();
+
+        match self
+            .process_manager
+            .join_any(&process_ids, Duration::from_millis(params.timeout_ms))
+            .await
+        {
+            Ok(Some((process_id, update))) => {
+                let mut structured = serde_json::to_value(&update).unwrap();
+                structured["process_id"] = serde_json::to_value(&process_id).unwrap();
+                Ok(CallToolResult {
+                    content: vec![Content::text(format!("Process {} finished", process_id.0))],
+                    structured_content: Some(structured),
+                    is_error: None,
+                    meta: None,
+                })
+            }
+            Ok(None) => Ok(CallToolResult {
+                content: vec![Content::text("No process finished within the timeout")],
+                structured_content: Some(serde_json::json!({ "process_id": null })),
+                is_error: None,
+                meta: None,
+            }),
+            Err(e) => {
+                let kind = if no_process_ids {
+                    ErrorKind::InvalidArguments
+                } else {
+                    ErrorKind::ProcessNotFound
+                };
+                Ok(tool_error(kind, format!("{e:#}")))
+            }
         }
     }
 
@@ -164,16 +746,47 @@ impl ShellyMcp {
                 meta: None,
             })
         } else {
-            Ok(CallToolResult::error(vec![Content::text(
-                "Failed to cancel process or process not found",
-            )]))
+            Ok(self.not_running_error(&process_id, "cancel").await)
         }
     }
 
+    /// Cancel the running processes whose command matches a pattern
+    #[tool(
+        name = "cancel_matching",
+        description = "Cancel running processes by their command when the process_id is lost, e.g. pattern \"npm run dev\". Matches commands containing pattern, or matching it as a regex if regex is true. Returns the IDs of the processes cancelled. If more than one process matches, nothing is cancelled and the matches are listed unless confirm is true."
+    )]
+    async fn cancel_matching(
+        &self,
+        params: Parameters<CancelMatchingArgs>,
+    ) -> Result<CallToolResult, ErrorData> {
+        let params = params.0;
+        let cancelled = match self
+            .process_manager
+            .cancel_matching(&params.pattern, params.regex, params.confirm)
+            .await
+        {
+            Ok(cancelled) => cancelled,
+            Err(e) => return Ok(tool_error(ErrorKind::InvalidArguments, format!("{e:#}"))),
+        };
+
+        let text = if cancelled.is_empty() {
+            "No running process matches".to_string()
+        } else {
+            let ids: Vec<&str> = cancelled.iter().map(|id| id.0.as_str()).collect();
+            format!("Cancelled {}", ids.join(", "))
+        };
+        Ok(CallToolResult {
+            content: vec![Content::text(text)],
+            structured_content: Some(serde_json::json!({ "cancelled": cancelled })),
+            is_error: None,
+            meta: None,
+        })
+    }
+
     /// Get status of a process
     #[tool(
         name = "process_status",
-        description = "Get the current status of a process"
+        description = "Get the current status of a process. Raw output is left out unless include_tail asks for its last lines."
     )]
     async fn process_status(
         &self,
@@ -183,17 +796,420 @@ impl ShellyMcp {
         let process_id = ProcessId(params.process_id);
 
         if let Some(status) = self.process_manager.get_process_status_summary(&process_id).await {
+            let mut structured = serde_json::to_value(&status).unwrap();
+            if let Some(lines) = params.include_tail {
+                let tail = self
+                    .process_manager
+                    .tail_output(&process_id, lines, StreamFilter::Both)
+                    .await;
+                structured["tail"] = tail.unwrap_or_default().into();
+            }
             Ok(CallToolResult {
                 content: vec![Content::text("Process status retrieved")],
-                structured_content: Some(serde_json::to_value(&status).unwrap()),
+                structured_content: Some(structured),
+                is_error: None,
+                meta: None,
+            })
+        } else {
+            Ok(tool_error(ErrorKind::ProcessNotFound, "Process not found"))
+        }
+    }
+
+    /// List tracked processes
+    #[tool(
+        name = "list_processes",
+        description = "List the processes shelly is tracking, with their IDs, commands and states"
+    )]
+    async fn list_processes(
+        &self,
+        params: Parameters<ListProcessesArgs>,
+    ) -> Result<CallToolResult, ErrorData> {
+        let params = params.0;
+
+        let processes = self
+            .process_manager
+            .list_processes(params.running_only)
+            .await;
+
+        let running = self.process_manager.running_count();
+        let max_concurrent = self.process_manager.max_concurrent;
+        Ok(CallToolResult {
+            content: vec![Content::text(format!(
+                "{} process(es), {running} of at most {max_concurrent} running",
+                processes.len()
+            ))],
+            structured_content: Some(serde_json::json!({
+                "processes": processes,
+                "running": running,
+                "max_concurrent": max_concurrent,
+            })),
+            is_error: None,
+            meta: None,
+        })
+    }
+
+    /// Report the server's version, output directory, handlers and process counts
+    #[tool(
+        name = "shelly_info",
+        description = "Check that shelly is up and how it is set up: its version, where output files go, the handlers it would use (by command and source), whether SHELLY_DISABLE_HANDLERS has turned them all off, and how many processes it is tracking and running. A good first call when an integration misbehaves."
+    )]
+    async fn shelly_info(
+        &self,
+        params: Parameters<ShellyInfoArgs>,
+    ) -> Result<CallToolResult, ErrorData> {
+        let working_dir = match params.0.working_dir {
+            Some(dir) => PathBuf::from(dir),
+            None => std::env::current_dir().unwrap_or_default(),
+        };
+        let version = env!("CARGO_PKG_VERSION");
+        let output_dir = match shelly::output::output_dir(None) {
+            Ok(dir) => dir,
+            Err(err) => {
+                return Ok(tool_error(ErrorKind::Io, format!("{err:#}")));
+            }
+        };
+        let handlers = match shelly::handler::list_handlers(&working_dir) {
+            Ok(handlers) => handlers,
+            Err(err) => {
+                return Ok(tool_error(
+                    ErrorKind::HandlerError,
+                    format!("Failed to list handlers: {err:#}"),
+                ));
+            }
+        };
+        let tracked = self.process_manager.list_processes(false).await.len();
+        let running = self.process_manager.running_count();
+
+        Ok(CallToolResult {
+            content: vec![Content::text(format!(
+                "shelly {version}, {} handler(s), {tracked} process(es) tracked, {running} running",
+                handlers.len()
+            ))],
+            structured_content: Some(serde_json::json!({
+                "version": version,
+                "output_dir": output_dir,
+                "handlers": handlers
+                    .iter()
+                    .map(|handler| serde_json::json!({
+                        "command": handler.command,
+                        "source": handler.source.to_string(),
+                        "path": handler.path,
+                    }))
+                    .collect::<Vec<_>>(),
+                "tracked": tracked,
+                "running": running,
+                "max_concurrent": self.process_manager.max_concurrent,
+                "handlers_disabled": shelly::handlers_disabled(),
+            })),
+            is_error: None,
+            meta: None,
+        })
+    }
+
+    /// Report how the commands run by this server turned out
+    #[tool(
+        name = "shelly_metrics",
+        description = "Counters for the commands this server has run since it started: how many started, succeeded, failed, were cancelled or timed out, their average duration and the 95th percentile summary size. Set prometheus to get them in the Prometheus text format instead."
+    )]
+    async fn shelly_metrics(
+        &self,
+        params: Parameters<ShellyMetricsArgs>,
+    ) -> Result<CallToolResult, ErrorData> {
+        let metrics = self.process_manager.metrics.snapshot();
+        let text = if params.0.prometheus {
+            metrics.to_prometheus()
+        } else {
+            format!(
+                "{} started, {} succeeded, {} failed, {} cancelled, {} timed out",
+                metrics.started,
+                metrics.succeeded,
+                metrics.failed,
+                metrics.cancelled,
+                metrics.timed_out
+            )
+        };
+        Ok(CallToolResult {
+            content: vec![Content::text(text)],
+            structured_content: Some(serde_json::to_value(&metrics).unwrap()),
+            is_error: None,
+            meta: None,
+        })
+    }
+
+    /// Return the raw tail of a process's output
+    #[tool(
+        name = "tail_process",
+        description = "Get the last N lines of a process's raw, unfiltered output, stdout and stderr combined unless stream picks one (e.g. stream: \"stderr\" for just the errors of a build). Works for running and completed processes."
+    )]
+    async fn tail_process(
+        &self,
+        params: Parameters<TailProcessArgs>,
+    ) -> Result<CallToolResult, ErrorData> {
+        let params = params.0;
+        let process_id = ProcessId(params.process_id);
+
+        if let Some(output) = self
+            .process_manager
+            .tail_output(&process_id, params.lines, params.stream.into())
+            .await
+        {
+            Ok(CallToolResult {
+                content: vec![Content::text("Process output retrieved")],
+                structured_content: Some(serde_json::json!({
+                    "process_id": process_id,
+                    "lines": params.lines,
+                    "output": output,
+                })),
                 is_error: None,
                 meta: None,
             })
         } else {
-            Ok(CallToolResult::error(vec![Content::text(
-                "Process not found",
-            )]))
+            Ok(tool_error(ErrorKind::ProcessNotFound, "Process not found"))
+        }
+    }
+
+    /// Read a window of a process's full output file
+    #[tool(
+        name = "read_output",
+        description = "Read part of a command's full output file, by lines (default) or bytes. Returns the content, the file's total size, whether more remains and the next_offset to continue from, so large logs can be paged through instead of read at once. Takes a process_id or an output_file path."
+    )]
+    async fn read_output(
+        &self,
+        params: Parameters<ReadOutputArgs>,
+    ) -> Result<CallToolResult, ErrorData> {
+        let params = params.0;
+        let output_file = match self
+            .resolve_output_file(params.process_id, params.output_file)
+            .await
+        {
+            Ok(output_file) => output_file,
+            Err(e) => return Ok(e),
+        };
+
+        let unit = params.unit.into();
+        match shelly::output::read_output(&output_file, params.offset, params.length, unit) {
+            Ok(slice) => Ok(CallToolResult {
+                content: vec![Content::text(slice.content.clone())],
+                structured_content: Some(serde_json::to_value(&slice).unwrap()),
+                is_error: None,
+                meta: None,
+            }),
+            Err(e) => Ok(tool_error(ErrorKind::Io, format!("{e:#}"))),
+        }
+    }
+
+    /// Search a process's full output file
+    #[tool(
+        name = "search_output",
+        description = "Search a command's full output file with a regex, returning matching lines with line numbers and surrounding context (grep -n -C style). Use this instead of reading a large output file, e.g. pattern `error\\[` over a long build log. Set stream to stdout or stderr to search only that stream. Takes a process_id or an output_file path."
+    )]
+    async fn search_output(
+        &self,
+        params: Parameters<SearchOutputArgs>,
+    ) -> Result<CallToolResult, ErrorData> {
+        let params = params.0;
+        let output_file = match self
+            .resolve_output_file(params.process_id, params.output_file)
+            .await
+        {
+            Ok(output_file) => output_file,
+            Err(e) => return Ok(e),
+        };
+
+        let result = match shelly::output::search_output(
+            &output_file,
+            &params.pattern,
+            params.context_lines,
+            params.max_matches,
+            params.stream.into(),
+        ) {
+            Ok(result) => result,
+            Err(e) => {
+                let kind = if e.downcast_ref::<std::io::Error>().is_some() {
+                    ErrorKind::Io
+                } else {
+                    ErrorKind::InvalidArguments
+                };
+                return Ok(tool_error(kind, format!("{e:#}")));
+            }
+        };
+
+        // Same layout as `grep -n -C`: `N:` for matches, `N-` for context and `--`
+        // between groups that aren't adjacent
+        let mut rendered = String::new();
+        let mut previous = None;
+        for line in &result.lines {
+            if previous.is_some_and(|n| n + 1 != line.line_number) {
+                rendered.push_str("--\n");
+            }
+            let separator = if line.is_match { ':' } else { '-' };
+            rendered.push_str(&format!("{}{separator}{}\n", line.line_number, line.text));
+            previous = Some(line.line_number);
         }
+        let shown = result.lines.iter().filter(|line| line.is_match).count();
+        if shown < result.total_matches {
+            rendered.push_str(&format!(
+                "[{} more match(es) not shown; raise max_matches or narrow the pattern]\n",
+                result.total_matches - shown
+            ));
+        }
+
+        Ok(CallToolResult {
+            content: vec![Content::text(if rendered.is_empty() {
+                "No matches".to_string()
+            } else {
+                rendered
+            })],
+            structured_content: Some(serde_json::json!({
+                "output_file": output_file,
+                "total_matches": result.total_matches,
+                "lines": result.lines,
+            })),
+            is_error: None,
+            meta: None,
+        })
+    }
+
+    /// Describe the handlers that would intercept a command
+    #[tool(
+        name = "describe_handler",
+        description = "Check whether shelly has a handler for a command before running it. Returns the matching handlers, the settings they accept (usable in execute_cli's settings), and the command as the handlers would rewrite it. Nothing is executed."
+    )]
+    async fn describe_handler(
+        &self,
+        params: Parameters<DescribeHandlerArgs>,
+    ) -> Result<CallToolResult, ErrorData> {
+        let params = params.0;
+        let working_dir = match params.working_dir {
+            Some(dir) => PathBuf::from(dir),
+            None => std::env::current_dir().unwrap_or_default(),
+        };
+
+        Ok(
+            match shelly::describe_handler(&params.command, &params.settings, &working_dir).await {
+                Ok(Some(description)) => {
+                    let mut structured = serde_json::to_value(&description).unwrap();
+                    structured["handled"] = true.into();
+                    CallToolResult {
+                        content: vec![Content::text(format!(
+                            "{} handler(s) match",
+                            description.handlers.len()
+                        ))],
+                        structured_content: Some(structured),
+                        is_error: None,
+                        meta: None,
+                    }
+                }
+                Ok(None) => CallToolResult {
+                    content: vec![Content::text(
+                        "No handler matches this command; it will run unmodified",
+                    )],
+                    structured_content: Some(serde_json::json!({ "handled": false })),
+                    is_error: None,
+                    meta: None,
+                },
+                Err(err) => tool_error(
+                    ErrorKind::HandlerError,
+                    format!("Failed to describe handler: {err:#}"),
+                ),
+            },
+        )
+    }
+
+    /// Re-run a handler over output that was already captured
+    #[tool(
+        name = "replay_output",
+        description = "Run a handler's summarize/finalize over a command's already-captured output and return the new summary, without executing anything. Use it to try out handler changes on a real run instead of re-running a slow command. Takes a process_id or an output_file path, and the handler's name (e.g. \"cargo\") or file path. A finished command's output goes through finalize(), a running one's through summarize()."
+    )]
+    async fn replay_output(
+        &self,
+        params: Parameters<ReplayOutputArgs>,
+    ) -> Result<CallToolResult, ErrorData> {
+        let params = params.0;
+        // A running process's output file doesn't record its command yet
+        let command = match (params.command, &params.process_id) {
+            (Some(command), _) => Some(command),
+            (None, Some(process_id)) => match self
+                .process_manager
+                .get_process_status_summary(&ProcessId(process_id.clone()))
+                .await
+            {
+                Some(status) => Some(status.command),
+                None => return Ok(tool_error(ErrorKind::ProcessNotFound, "Process not found")),
+            },
+            (None, None) => None,
+        };
+        let output_file = match self
+            .resolve_output_file(params.process_id, params.output_file)
+            .await
+        {
+            Ok(output_file) => output_file,
+            Err(e) => return Ok(e),
+        };
+        let working_dir = match params.working_dir {
+            Some(dir) => PathBuf::from(dir),
+            None => std::env::current_dir().unwrap_or_default(),
+        };
+
+        match shelly::replay_output(
+            &output_file,
+            &params.handler,
+            command.as_deref(),
+            &params.settings,
+            &working_dir,
+        )
+        .await
+        {
+            Ok(replay) => Ok(CallToolResult {
+                content: vec![Content::text(
+                    replay.result.summary.clone().unwrap_or_default(),
+                )],
+                structured_content: Some(serde_json::to_value(&replay).unwrap()),
+                is_error: None,
+                meta: None,
+            }),
+            Err(e) => Ok(tool_error(
+                ErrorKind::HandlerError,
+                format!("Failed to replay output: {e:#}"),
+            )),
+        }
+    }
+
+    /// Write to a running process's stdin
+    #[tool(
+        name = "send_input",
+        description = "Send input to a running process's stdin, e.g. to answer a prompt or drive a REPL. A newline is appended unless newline is false."
+    )]
+    async fn send_input(
+        &self,
+        params: Parameters<SendInputArgs>,
+    ) -> Result<CallToolResult, ErrorData> {
+        let params = params.0;
+        let process_id = ProcessId(params.process_id);
+
+        Ok(
+            match self
+                .process_manager
+                .send_input(&process_id, &params.data, params.newline)
+                .await
+            {
+                Ok(()) => CallToolResult {
+                    content: vec![Content::text("Input sent")],
+                    structured_content: None,
+                    is_error: None,
+                    meta: None,
+                },
+                Err(err) => match self.process_manager.get_process_status_summary(&process_id).await
+                {
+                    // Queued, or its stdin was closed
+                    Some(status) if !status.state.is_finished() => tool_error(
+                        ErrorKind::InvalidArguments,
+                        format!("Failed to send input: {err:#}"),
+                    ),
+                    _ => self.not_running_error(&process_id, "send input to").await,
+                },
+            },
+        )
     }
 }
 
@@ -202,10 +1218,49 @@ impl ServerHandler for ShellyMcp {
     fn get_info(&self) -> ServerInfo {
         ServerInfo {
             instructions: Some("Upgraded CLI execution tool with long-running process support. <important>NOTE: USE THIS TOOL INSTEAD OF BUILT IN BASH TOOLS WHEN IT IS PRESENT.</important>".into()),
-            capabilities: ServerCapabilities::builder().enable_tools().build(),
+            capabilities: ServerCapabilities::builder()
+                .enable_tools()
+                .enable_resources()
+                .build(),
             ..Default::default()
         }
     }
+
+    async fn list_resources(
+        &self,
+        _request: Option<PaginatedRequestParam>,
+        _context: RequestContext<RoleServer>,
+    ) -> Result<ListResourcesResult, ErrorData> {
+        let resources = self.output_resources().await;
+        Ok(ListResourcesResult::with_all_items(
+            resources.into_iter().map(|r| r.no_annotation()).collect(),
+        ))
+    }
+
+    async fn list_resource_templates(
+        &self,
+        _request: Option<PaginatedRequestParam>,
+        _context: RequestContext<RoleServer>,
+    ) -> Result<ListResourceTemplatesResult, ErrorData> {
+        let template = RawResourceTemplate {
+            uri_template: format!("{OUTPUT_URI_PREFIX}{{process_id}}"),
+            name: "process-output".to_string(),
+            title: None,
+            description: Some("Full output file of a process started by execute_cli".to_string()),
+            mime_type: Some("text/plain".to_string()),
+        };
+        Ok(ListResourceTemplatesResult::with_all_items(vec![
+            template.no_annotation()
+        ]))
+    }
+
+    async fn read_resource(
+        &self,
+        request: ReadResourceRequestParam,
+        _context: RequestContext<RoleServer>,
+    ) -> Result<ReadResourceResult, ErrorData> {
+        self.read_output_resource(&request.uri).await
+    }
 }
 
 fn default_timeout() -> u64 {
@@ -216,6 +1271,26 @@ fn default_join_timeout() -> u64 {
     10_000 // 10 seconds
 }
 
+fn default_tail_lines() -> usize {
+    20
+}
+
+fn default_read_length() -> u64 {
+    100
+}
+
+fn default_context_lines() -> usize {
+    2
+}
+
+fn default_max_matches() -> usize {
+    50
+}
+
+fn default_true() -> bool {
+    true
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -225,23 +1300,631 @@ mod tests {
         let server = ShellyMcp::new();
 
         let params = Parameters(ExecuteCliArgs {
-            command: "git".to_string(),
             args: vec![
                 "commit".to_string(),
                 "-m".to_string(),
                 "test message".to_string(),
             ],
-            working_dir: "/tmp".to_string(),
-            env: HashMap::new(),
-            timeout_ms: 5000,
+            wait_ms: 5000,
             disable_enhancements: true,
+            ..ExecuteCliArgs::new("git", "/tmp")
         });
 
         // This should not panic and should properly combine the command
-        let result = server.execute_cli(params).await;
+        let result = server.run_command(params, None).await;
 
         // We expect this to fail (since we're not in a git repo), but it should
         // fail with a git error, not a command parsing error
         assert!(result.is_ok()); // The MCP call itself should succeed
     }
+
+    #[test]
+    fn test_execute_cli_schema_exposes_settings() {
+        let schema = serde_json::to_value(schemars::schema_for!(ExecuteCliArgs)).unwrap();
+        let settings = &schema["properties"]["settings"];
+        assert_eq!(settings["type"], "object");
+        assert!(settings["description"]
+            .as_str()
+            .unwrap()
+            .contains("settings schema"));
+        assert!(!schema["required"]
+            .as_array()
+            .unwrap()
+            .contains(&serde_json::json!("settings")));
+    }
+
+    #[tokio::test]
+    async fn test_describe_handler_reports_no_handler() {
+        let server = ShellyMcp::new();
+
+        let params = Parameters(DescribeHandlerArgs {
+            command: "definitely-unhandled-tool --flag".to_string(),
+            settings: HashMap::new(),
+            working_dir: None,
+        });
+        let result = server.describe_handler(params).await.unwrap();
+
+        assert_ne!(result.is_error, Some(true));
+        assert_eq!(
+            result.structured_content,
+            Some(serde_json::json!({ "handled": false }))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_replay_output_uses_process_command() {
+        let server = ShellyMcp::new();
+        let dir = std::env::temp_dir().join(format!("shelly-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir(&dir).unwrap();
+        std::fs::write(
+            dir.join("bash.json"),
+            r#"{ "matches": { "command": "bash" }, "drop": ["^noise"] }"#,
+        )
+        .unwrap();
+
+        let mut args = ExecuteCliArgs::new("bash", dir.to_str().unwrap());
+        args.args = vec!["-c".to_string(), "echo noise; echo signal".to_string()];
+        args.wait_ms = 5000;
+        args.disable_enhancements = true;
+        let result = server.run_command(Parameters(args), None).await.unwrap();
+        let process_id = result.structured_content.unwrap()["process_id"]
+            .as_str()
+            .unwrap()
+            .to_string();
+
+        // The handler sees the process's own command, without it being run again
+        let result = server
+            .replay_output(Parameters(ReplayOutputArgs {
+                process_id: Some(process_id),
+                output_file: None,
+                handler: "bash.json".to_string(),
+                command: None,
+                settings: HashMap::new(),
+                working_dir: Some(dir.to_str().unwrap().to_string()),
+            }))
+            .await
+            .unwrap();
+        let _ = std::fs::remove_dir_all(&dir);
+
+        assert_ne!(result.is_error, Some(true), "{result:?}");
+        let replay = result.structured_content.unwrap();
+        assert_eq!(replay["exit_code"], 0);
+        assert_eq!(replay["summary"], "signal\n");
+    }
+
+    #[tokio::test]
+    async fn test_shelly_info_reports_version_and_handlers() {
+        let server = ShellyMcp::new();
+
+        let result = server
+            .shelly_info(Parameters(ShellyInfoArgs { working_dir: None }))
+            .await
+            .unwrap();
+
+        assert_ne!(result.is_error, Some(true));
+        let info = result.structured_content.unwrap();
+        assert_eq!(info["version"], env!("CARGO_PKG_VERSION"));
+        assert!(info["output_dir"].is_string(), "{info}");
+        let handlers = info["handlers"].as_array().unwrap();
+        assert!(
+            handlers
+                .iter()
+                .any(|handler| handler["command"] == "cargo" && handler["source"] == "built-in"),
+            "{info}"
+        );
+        assert_eq!(info["tracked"], 0);
+        assert_eq!(info["running"], 0);
+        assert_eq!(info["handlers_disabled"], false);
+    }
+
+    #[tokio::test]
+    async fn test_list_processes_filters_running() {
+        let server = ShellyMcp::new();
+
+        for (command, args) in [("echo", vec!["hi"]), ("sleep", vec!["5"])] {
+            let params = Parameters(ExecuteCliArgs {
+                args: args.into_iter().map(String::from).collect(),
+                wait_ms: 500,
+                disable_enhancements: true,
+                ..ExecuteCliArgs::new(command, "/tmp")
+            });
+            server.run_command(params, None).await.unwrap();
+        }
+
+        let all = server
+            .list_processes(Parameters(ListProcessesArgs {
+                running_only: false,
+            }))
+            .await
+            .unwrap();
+        let all = &all.structured_content.unwrap()["processes"];
+        assert_eq!(all.as_array().unwrap().len(), 2);
+        assert!(all[0]["raw_stdout"].is_null(), "raw output should be redacted");
+
+        let running = server
+            .list_processes(Parameters(ListProcessesArgs { running_only: true }))
+            .await
+            .unwrap();
+        let running = &running.structured_content.unwrap();
+        assert_eq!(running["processes"].as_array().unwrap().len(), 1);
+        assert_eq!(running["processes"][0]["command"], "sleep 5");
+        assert_eq!(running["running"], 1);
+    }
+
+    #[tokio::test]
+    async fn test_tail_process_returns_last_lines() {
+        let server = ShellyMcp::new();
+
+        let result = server
+            .run_command(
+                Parameters(ExecuteCliArgs {
+                    args: vec!["1".to_string(), "10".to_string()],
+                    wait_ms: 5000,
+                    disable_enhancements: true,
+                    ..ExecuteCliArgs::new("seq", "/tmp")
+                }),
+                None,
+            )
+            .await
+            .unwrap();
+        let process_id = result.structured_content.unwrap()["process_id"]
+            .as_str()
+            .unwrap()
+            .to_string();
+
+        let tail = |stream| {
+            server.tail_process(Parameters(TailProcessArgs {
+                process_id: process_id.clone(),
+                lines: 3,
+                stream,
+            }))
+        };
+        let both = tail(Stream::Both).await.unwrap();
+        assert_eq!(both.structured_content.unwrap()["output"], "8\n9\n10\n");
+        let stderr = tail(Stream::Stderr).await.unwrap();
+        assert_eq!(stderr.structured_content.unwrap()["output"], "");
+
+        // process_status only includes the tail when asked to
+        for (include_tail, expected) in [(None, None), (Some(2), Some("9\n10\n"))] {
+            let status = server
+                .process_status(Parameters(ProcessStatusArgs {
+                    process_id: process_id.clone(),
+                    include_tail,
+                }))
+                .await
+                .unwrap()
+                .structured_content
+                .unwrap();
+            assert_eq!(status["tail"].as_str(), expected);
+            assert!(status["raw_stdout"].is_null());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_search_output_returns_matches_with_context() {
+        let server = ShellyMcp::new();
+
+        let result = server
+            .run_command(
+                Parameters(ExecuteCliArgs {
+                    args: vec!["1".to_string(), "100".to_string()],
+                    wait_ms: 5000,
+                    disable_enhancements: true,
+                    ..ExecuteCliArgs::new("seq", "/tmp")
+                }),
+                None,
+            )
+            .await
+            .unwrap();
+        let process_id = result.structured_content.unwrap()["process_id"]
+            .as_str()
+            .unwrap()
+            .to_string();
+
+        let search = server
+            .search_output(Parameters(SearchOutputArgs {
+                process_id: Some(process_id),
+                output_file: None,
+                pattern: r"\] (50|52|90)$".to_string(),
+                context_lines: 1,
+                max_matches: 2,
+                stream: Stream::Both,
+            }))
+            .await
+            .unwrap();
+        // Line numbers are offset by the header: exit code, command and working dir
+        let text = search.content[0].as_text().unwrap().text.clone();
+        assert_eq!(
+            text,
+            "53-[out] 49\n54:[out] 50\n55-[out] 51\n56:[out] 52\n57-[out] 53\n\
+             [1 more match(es) not shown; raise max_matches or narrow the pattern]\n"
+        );
+        assert_eq!(search.structured_content.unwrap()["total_matches"], 3);
+
+        let missing = server
+            .search_output(Parameters(SearchOutputArgs {
+                process_id: None,
+                output_file: None,
+                pattern: "x".to_string(),
+                context_lines: 0,
+                max_matches: 1,
+                stream: Stream::Both,
+            }))
+            .await
+            .unwrap();
+        assert_eq!(missing.is_error, Some(true));
+    }
+
+    #[tokio::test]
+    async fn test_read_output_pages_through_lines() {
+        let server = ShellyMcp::new();
+
+        let result = server
+            .run_command(
+                Parameters(ExecuteCliArgs {
+                    args: vec!["1".to_string(), "4".to_string()],
+                    wait_ms: 5000,
+                    disable_enhancements: true,
+                    ..ExecuteCliArgs::new("seq", "/tmp")
+                }),
+                None,
+            )
+            .await
+            .unwrap();
+        let process_id = result.structured_content.unwrap()["process_id"]
+            .as_str()
+            .unwrap()
+            .to_string();
+
+        let read = |offset| {
+            server.read_output(Parameters(ReadOutputArgs {
+                process_id: Some(process_id.clone()),
+                output_file: None,
+                offset,
+                length: 3,
+                unit: ReadUnit::Lines,
+            }))
+        };
+        // Skip the header: exit code, command and working dir
+        let first = read(4).await.unwrap().structured_content.unwrap();
+        assert_eq!(first["content"], "[out] 1\n[out] 2\n[out] 3\n");
+        assert_eq!(first["next_offset"], 7);
+        assert_eq!(first["has_more"], true);
+
+        let second = read(7).await.unwrap().structured_content.unwrap();
+        assert_eq!(second["content"], "[out] 4\n");
+        assert_eq!(second["has_more"], false);
+    }
+
+    #[tokio::test]
+    async fn test_join_any_process_returns_finished_process() {
+        let server = ShellyMcp::new();
+
+        let mut ids = Vec::new();
+        for (command, args) in [("sleep", vec!["5"]), ("echo", vec!["done"])] {
+            let result = server
+                .run_command(
+                    Parameters(ExecuteCliArgs {
+                        args: args.into_iter().map(String::from).collect(),
+                        wait_ms: 200,
+                        disable_enhancements: true,
+                        ..ExecuteCliArgs::new(command, "/tmp")
+                    }),
+                    None,
+                )
+                .await
+                .unwrap();
+            let structured = result.structured_content.unwrap();
+            ids.push(structured["process_id"].as_str().unwrap().to_string());
+        }
+
+        // `echo` already finished while execute_cli was waiting on it
+        let joined = server
+            .join_any_process(Parameters(JoinAnyProcessArgs {
+                process_ids: ids.clone(),
+                timeout_ms: 5000,
+            }))
+            .await
+            .unwrap();
+        let joined = joined.structured_content.unwrap();
+        assert_eq!(joined["process_id"], ids[1]);
+        assert_eq!(joined["status"]["Completed"]["exit_code"], 0);
+
+        let timed_out = server
+            .join_any_process(Parameters(JoinAnyProcessArgs {
+                process_ids: vec![ids[0].clone()],
+                timeout_ms: 100,
+            }))
+            .await
+            .unwrap();
+        assert!(timed_out.structured_content.unwrap()["process_id"].is_null());
+
+        let unknown = server
+            .join_any_process(Parameters(JoinAnyProcessArgs {
+                process_ids: vec!["nope".to_string()],
+                timeout_ms: 100,
+            }))
+            .await
+            .unwrap();
+        assert_eq!(unknown.is_error, Some(true));
+    }
+
+    #[tokio::test]
+    async fn test_send_input_reaches_process() {
+        let server = ShellyMcp::new();
+
+        let result = server
+            .run_command(
+                Parameters(ExecuteCliArgs {
+                    args: vec!["-n".to_string(), "1".to_string()],
+                    wait_ms: 200,
+                    disable_enhancements: true,
+                    ..ExecuteCliArgs::new("head", "/tmp")
+                }),
+                None,
+            )
+            .await
+            .unwrap();
+        let process_id = result.structured_content.unwrap()["process_id"]
+            .as_str()
+            .unwrap()
+            .to_string();
+
+        let sent = server
+            .send_input(Parameters(SendInputArgs {
+                process_id: process_id.clone(),
+                data: "hello from stdin".to_string(),
+                newline: true,
+            }))
+            .await
+            .unwrap();
+        assert_ne!(sent.is_error, Some(true));
+
+        let joined = server
+            .join_process(Parameters(JoinProcessArgs {
+                process_id: process_id.clone(),
+                timeout_ms: 5000,
+                cursor: None,
+            }))
+            .await
+            .unwrap();
+        let update = joined.structured_content.unwrap();
+        assert!(update["incremental_summary"]
+            .as_str()
+            .unwrap()
+            .contains("hello from stdin"));
+
+        // The process has exited, so further input is rejected
+        let rejected = server
+            .send_input(Parameters(SendInputArgs {
+                process_id,
+                data: "too late".to_string(),
+                newline: true,
+            }))
+            .await
+            .unwrap();
+        assert_eq!(rejected.is_error, Some(true));
+        assert_eq!(
+            rejected.structured_content.unwrap()["error"]["kind"],
+            "already_completed"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_errors_carry_a_kind() {
+        let server = ShellyMcp::new();
+        let kind = |result: CallToolResult| {
+            assert_eq!(result.is_error, Some(true));
+            result.structured_content.unwrap()["error"]["kind"].clone()
+        };
+
+        let status = server
+            .process_status(Parameters(ProcessStatusArgs {
+                process_id: "missing".to_string(),
+                include_tail: None,
+            }))
+            .await
+            .unwrap();
+        assert_eq!(kind(status), "process_not_found");
+
+        let cancel = server
+            .cancel_process(Parameters(CancelProcessArgs {
+                process_id: "missing".to_string(),
+            }))
+            .await
+            .unwrap();
+        assert_eq!(kind(cancel), "process_not_found");
+
+        let output_file = std::env::temp_dir().join(format!("shelly-{}.txt", uuid::Uuid::new_v4()));
+        std::fs::write(&output_file, "line\n").unwrap();
+        let missing_file = output_file.with_extension("missing");
+        for (output_file, pattern, expected) in [
+            (Some(output_file.display().to_string()), "(", "invalid_arguments"),
+            (Some(missing_file.display().to_string()), "x", "io"),
+            (None, "x", "invalid_arguments"),
+        ] {
+            let search = server
+                .search_output(Parameters(SearchOutputArgs {
+                    process_id: None,
+                    output_file,
+                    pattern: pattern.to_string(),
+                    context_lines: 0,
+                    max_matches: 10,
+                    stream: Stream::Both,
+                }))
+                .await
+                .unwrap();
+            assert_eq!(kind(search), expected, "pattern {pattern}");
+        }
+        std::fs::remove_file(output_file).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_execute_cli_streams_progress() {
+        let server = ShellyMcp::new();
+        let (progress_tx, mut progress_rx) = mpsc::unbounded_channel();
+
+        let result = server
+            .run_command(
+                Parameters(ExecuteCliArgs {
+                    args: vec!["-c".to_string(), "echo one; sleep 1; echo two".to_string()],
+                    wait_ms: 5000,
+                    disable_enhancements: true,
+                    ..ExecuteCliArgs::new("bash", "/tmp")
+                }),
+                Some(progress_tx),
+            )
+            .await
+            .unwrap();
+        assert_eq!(result.structured_content.unwrap()["exit_code"], 0);
+
+        // "one" is summarized while the command is still sleeping
+        let first = progress_rx.recv().await.unwrap();
+        assert_eq!(first.incremental_summary, "one\n");
+        assert_eq!(first.cursor, 4);
+    }
+
+    #[tokio::test]
+    async fn test_output_is_readable_as_resource() {
+        let server = ShellyMcp::new();
+
+        let result = server
+            .run_command(
+                Parameters(ExecuteCliArgs {
+                    args: vec!["1".to_string(), "3".to_string()],
+                    wait_ms: 5000,
+                    disable_enhancements: true,
+                    ..ExecuteCliArgs::new("seq", "/tmp")
+                }),
+                None,
+            )
+            .await
+            .unwrap();
+        let uri = result.structured_content.unwrap()["output_resource"]
+            .as_str()
+            .unwrap()
+            .to_string();
+        assert!(uri.starts_with("shelly://output/"), "{uri}");
+
+        let resources = server.output_resources().await;
+        assert_eq!(resources.len(), 1);
+        assert_eq!(resources[0].uri, uri);
+        assert_eq!(resources[0].name, "seq 1 3");
+        assert!(resources[0].size.unwrap() > 0);
+
+        let read = server.read_output_resource(&uri).await.unwrap();
+        let ResourceContents::TextResourceContents { text, .. } = &read.contents[0] else {
+            panic!("expected text contents");
+        };
+        assert!(text.ends_with("[out] 1\n[out] 2\n[out] 3\n"), "{text}");
+
+        let missing = server.read_output_resource("shelly://output/nope").await;
+        assert!(missing.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_cancel_matching_needs_confirm_for_several() {
+        let server = ShellyMcp::new();
+        for _ in 0..2 {
+            let mut args = ExecuteCliArgs::new("sleep", "/tmp");
+            args.args = vec!["30".to_string()];
+            args.wait_ms = 0;
+            args.disable_enhancements = true;
+            server.run_command(Parameters(args), None).await.unwrap();
+        }
+        let cancel = |confirm| {
+            server.cancel_matching(Parameters(CancelMatchingArgs {
+                pattern: "sleep 30".to_string(),
+                regex: false,
+                confirm,
+            }))
+        };
+
+        let refused = cancel(false).await.unwrap();
+        assert_eq!(refused.is_error, Some(true));
+        assert_eq!(
+            refused.structured_content.unwrap()["error"]["kind"],
+            "invalid_arguments"
+        );
+
+        let result = cancel(true).await.unwrap();
+        assert_ne!(result.is_error, Some(true));
+        let cancelled = &result.structured_content.unwrap()["cancelled"];
+        assert_eq!(cancelled.as_array().unwrap().len(), 2, "{cancelled}");
+        assert!(server.process_manager.list_processes(true).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_shelly_metrics_counts_outcomes() {
+        let server = ShellyMcp::new();
+        for (script, max_runtime_ms) in [("true", None), ("exit 3", None), ("sleep 30", Some(200))]
+        {
+            let mut args = ExecuteCliArgs::new("bash", "/tmp");
+            args.args = vec!["-c".to_string(), script.to_string()];
+            args.wait_ms = 5000;
+            args.max_runtime_ms = max_runtime_ms;
+            args.disable_enhancements = true;
+            server.run_command(Parameters(args), None).await.unwrap();
+        }
+        let metrics = |prometheus| {
+            server.shelly_metrics(Parameters(ShellyMetricsArgs { prometheus }))
+        };
+
+        let result = metrics(false).await.unwrap();
+        let counts = result.structured_content.unwrap();
+        assert_eq!(counts["started"], 3, "{counts}");
+        assert_eq!(counts["finished"], 3, "{counts}");
+        assert_eq!(counts["succeeded"], 1, "{counts}");
+        assert_eq!(counts["failed"], 1, "{counts}");
+        assert_eq!(counts["timed_out"], 1, "{counts}");
+        assert_eq!(counts["cancelled"], 0, "{counts}");
+        assert!(counts["avg_duration_ms"].is_u64(), "{counts}");
+
+        let result = metrics(true).await.unwrap();
+        let text = result.content[0].as_text().unwrap().text.clone();
+        assert!(text.contains("# TYPE shelly_commands_started_total counter\n"), "{text}");
+        assert!(text.contains("shelly_commands_finished_total{outcome=\"failed\"} 1\n"), "{text}");
+    }
+
+    #[tokio::test]
+    async fn test_client_returns_typed_results() {
+        use rmcp::ServiceExt;
+
+        let (server_io, client_io) = tokio::io::duplex(64 * 1024);
+        tokio::spawn(async move {
+            let server = ShellyMcp::new().serve(server_io).await.unwrap();
+            server.waiting().await.unwrap();
+        });
+        let client = ShellyClient::connect(client_io).await.unwrap();
+
+        let mut args = ExecuteCliArgs::new("bash", "/tmp");
+        args.args = vec!["-c".to_string(), "echo started; sleep 30".to_string()];
+        args.wait_ms = 500;
+        args.disable_enhancements = true;
+        let result = client.execute_cli(args).await.unwrap();
+        assert!(result.is_running);
+        let process_id = result.process_id.unwrap();
+
+        let update = client
+            .join_process(&process_id, Duration::from_millis(50), None)
+            .await
+            .unwrap();
+        assert!(update.timed_out);
+
+        client.cancel_process(&process_id).await.unwrap();
+        let update = client
+            .join_process(&process_id, Duration::from_secs(10), None)
+            .await
+            .unwrap();
+        assert!(!update.timed_out);
+        assert_eq!(update.status, shelly::process_manager::ProcessState::Cancelled);
+        let status = client.process_status(&process_id).await.unwrap();
+        assert!(status.finished_at.is_some());
+
+        let err = client.cancel_process(&process_id).await.unwrap_err();
+        let tool_error = err.downcast_ref::<ToolError>().unwrap();
+        assert_eq!(tool_error.kind, ErrorKind::AlreadyCompleted);
+
+        client.close().await.unwrap();
+    }
 }
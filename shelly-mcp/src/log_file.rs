@@ -0,0 +1,142 @@
+//! The server's log file, rolled over by size so long sessions don't grow it forever
+
+use std::ffi::OsString;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+/// Environment variable that overrides how large the log grows before it is rolled
+/// over, in bytes
+pub const LOG_MAX_BYTES_ENV: &str = "SHELLY_LOG_MAX_BYTES";
+
+/// Environment variable that overrides how many rolled-over logs are kept
+pub const LOG_FILES_ENV: &str = "SHELLY_LOG_FILES";
+
+/// When the log rolls over and how much of it is kept
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LogRotation {
+    /// The log is rolled over before it would grow past this
+    pub max_bytes: u64,
+    /// Rolled-over logs kept next to the log as `<log>.1` (newest) to `<log>.N`
+    pub keep: usize,
+}
+
+impl Default for LogRotation {
+    fn default() -> Self {
+        Self {
+            max_bytes: 10 * 1024 * 1024, // 10 MiB
+            keep: 3,
+        }
+    }
+}
+
+impl LogRotation {
+    /// The defaults, overridden by `SHELLY_LOG_MAX_BYTES` and `SHELLY_LOG_FILES` where
+    /// they are set to a number
+    pub fn from_env() -> Self {
+        let env = |name| std::env::var(name).ok().and_then(|v| v.parse::<u64>().ok());
+        let default = Self::default();
+        Self {
+            max_bytes: env(LOG_MAX_BYTES_ENV).unwrap_or(default.max_bytes),
+            keep: env(LOG_FILES_ENV).map_or(default.keep, |keep| keep as usize),
+        }
+    }
+}
+
+/// A log file that is appended to until the next write would take it past
+/// `max_bytes`, then renamed to `<log>.1`, shifting older ones along and dropping the
+/// oldest
+pub struct RotatingLog {
+    path: PathBuf,
+    file: File,
+    written: u64,
+    rotation: LogRotation,
+}
+
+impl RotatingLog {
+    pub fn open(path: &Path, rotation: LogRotation) -> io::Result<Self> {
+        let file = fs::OpenOptions::new().create(true).append(true).open(path)?;
+        let written = file.metadata()?.len();
+        Ok(Self {
+            path: path.to_path_buf(),
+            file,
+            written,
+            rotation,
+        })
+    }
+
+    /// Where the `n`th most recent rolled-over log goes
+    fn rotated(&self, n: usize) -> PathBuf {
+        let mut path = OsString::from(&self.path);
+        path.push(format!(".{n}"));
+        PathBuf::from(path)
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        for n in (1..self.rotation.keep).rev() {
+            match fs::rename(self.rotated(n), self.rotated(n + 1)) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                _ => {}
+            }
+        }
+        if self.rotation.keep > 0 {
+            fs::rename(&self.path, self.rotated(1))?;
+        }
+        self.file = fs::OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(&self.path)?;
+        self.written = 0;
+        Ok(())
+    }
+}
+
+impl Write for RotatingLog {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // A single line larger than the limit still gets written, to a log of its own
+        if self.written > 0 && self.written + buf.len() as u64 > self.rotation.max_bytes {
+            self.rotate()?;
+        }
+        let written = self.file.write(buf)?;
+        self.written += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_log_file_rolls_over_by_size() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("server.log");
+        let rotation = LogRotation {
+            max_bytes: 10,
+            keep: 2,
+        };
+
+        let mut log = RotatingLog::open(&path, rotation).unwrap();
+        for line in ["one\n", "two\n", "three\n", "four\n", "five\n"] {
+            log.write_all(line.as_bytes()).unwrap();
+        }
+        let read = |name: &str| fs::read_to_string(dir.path().join(name)).unwrap_or_default();
+        assert_eq!(read("server.log"), "four\nfive\n");
+        assert_eq!(read("server.log.1"), "three\n");
+        assert_eq!(read("server.log.2"), "one\ntwo\n");
+
+        // Reopening picks up the size of what is already there, and the oldest log is
+        // dropped once there are more than `keep`
+        let mut log = RotatingLog::open(&path, rotation).unwrap();
+        log.write_all(b"six\n").unwrap();
+        assert_eq!(read("server.log"), "six\n");
+        assert_eq!(read("server.log.1"), "four\nfive\n");
+        assert_eq!(read("server.log.2"), "three\n");
+        assert!(!dir.path().join("server.log.3").exists());
+    }
+}
@@ -1,29 +1,67 @@
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use anyhow::Context;
 use rmcp::{transport::stdio, ServiceExt};
+use shelly_mcp::log_file::{LogRotation, RotatingLog};
 use shelly_mcp::ShellyMcp;
 use tracing_subscriber::EnvFilter;
 
+/// Environment variable naming the file the server logs to
+const LOG_FILE_ENV: &str = "SHELLY_LOG_FILE";
+
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     run_mcp_server().await
 }
 
+/// `SHELLY_LOG_FILE`, or `logs/shelly-mcp.log` in the output directory, where the
+/// output file cleanup, which leaves directories alone, won't remove it
+fn log_file_path() -> anyhow::Result<PathBuf> {
+    match std::env::var_os(LOG_FILE_ENV) {
+        Some(path) if !path.is_empty() => Ok(PathBuf::from(path)),
+        _ => Ok(shelly::output::output_dir(None)?
+            .join("logs")
+            .join("shelly-mcp.log")),
+    }
+}
+
+fn open_log_file() -> anyhow::Result<RotatingLog> {
+    let path = log_file_path()?;
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)
+            .with_context(|| format!("Failed to create log directory {}", parent.display()))?;
+    }
+    RotatingLog::open(&path, LogRotation::from_env())
+        .with_context(|| format!("Failed to open log file {}", path.display()))
+}
+
 async fn run_mcp_server() -> anyhow::Result<()> {
-    // Initialize logging
-    let log_file = std::fs::OpenOptions::new()
-        .create(true)
-        .append(true)
-        .open("shelly-mcp.log")?;
-
-    tracing_subscriber::fmt()
-        .with_writer(log_file)
+    // Initialize logging. stdout carries the protocol, so stderr is the only fallback
+    // for a log file that can't be opened, e.g. in a read-only launch directory.
+    let logging = tracing_subscriber::fmt()
         .with_ansi(false)
-        .with_env_filter(EnvFilter::from_default_env())
-        .init();
+        .with_env_filter(EnvFilter::from_default_env());
+    match open_log_file() {
+        Ok(log_file) => logging.with_writer(Mutex::new(log_file)).init(),
+        Err(e) => {
+            logging.with_writer(std::io::stderr).init();
+            tracing::warn!("{e:#}; logging to stderr instead");
+        }
+    }
 
     tracing::info!("Starting Shellephant MCP server");
 
     // Create and run the MCP server
-    let server = ShellyMcp::new()
+    // An unusable registry or output directory only costs ids surviving a restart
+    let server = match ShellyMcp::persistent() {
+        Ok(server) => server,
+        Err(e) => {
+            tracing::warn!("{e:#}; process ids won't survive a restart");
+            ShellyMcp::new()
+        }
+    };
+    let server = server
         .serve(stdio())
         .await
         .inspect_err(|e| tracing::error!("serving error: {e:?}"))?;
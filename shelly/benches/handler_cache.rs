@@ -0,0 +1,57 @@
+//! Per-command latency of a tight loop of quick commands that match a script handler,
+//! with a fresh `ProcessManager` per command (handlers loaded every time) and with one
+//! shared manager (handlers reused from its `HandlerCache`).
+//!
+//! `cargo bench -p shelly --bench handler_cache`
+
+use shelly::process_manager::ProcessManager;
+use shelly::{execute_command_streaming, ExecuteRequest};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+const RUNS: usize = 50;
+
+fn request() -> ExecuteRequest {
+    ExecuteRequest {
+        cmd: "git".to_string(),
+        args: vec!["--version".to_string()],
+        working_dir: std::env::current_dir().unwrap(),
+        ..Default::default()
+    }
+}
+
+/// Run the command `RUNS` times, taking a manager from `manager` for each
+async fn time_runs(mut manager: impl FnMut() -> Arc<ProcessManager>) -> Vec<Duration> {
+    let timeout = Duration::from_secs(30);
+    let mut times = Vec::with_capacity(RUNS);
+    for _ in 0..RUNS {
+        let manager = manager();
+        let start = Instant::now();
+        execute_command_streaming(request(), manager, timeout)
+            .await
+            .unwrap();
+        times.push(start.elapsed());
+    }
+    times.sort();
+    times
+}
+
+fn report(name: &str, times: &[Duration]) {
+    let mean = times.iter().sum::<Duration>() / times.len() as u32;
+    println!(
+        "{name:<10} mean {mean:>10.2?}  median {:>10.2?}  min {:>10.2?}",
+        times[times.len() / 2],
+        times[0]
+    );
+}
+
+#[tokio::main]
+async fn main() {
+    let uncached = time_runs(|| Arc::new(ProcessManager::new())).await;
+    let shared = Arc::new(ProcessManager::new());
+    let cached = time_runs(|| shared.clone()).await;
+
+    println!("{RUNS} runs of `git --version` through the git handler");
+    report("uncached", &uncached);
+    report("cached", &cached);
+}
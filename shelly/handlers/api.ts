@@ -21,31 +21,47 @@ export interface HandlerFactory {
    * @param cmd - The command name
    * @param args - The command arguments
    * @param settings - User-provided settings for this handler
+   * @param context - Where the command runs, and read access to its files
    * @returns A new handler instance
    */
-  create(cmd: string, args: string[], settings: Record<string, any>): Handler;
+  create(
+    cmd: string,
+    args: string[],
+    settings: Record<string, any>,
+    context: HandlerContext,
+  ): Handler;
 
   /**
    * Describe the settings this handler accepts.
    * Used for documentation and validation.
    */
   settings(): SettingsSchema;
+
+  /**
+   * Position of this handler when several handlers match the same command.
+   * Handlers run in ascending priority: each one is created with the command
+   * the previous one prepared, its summary is piped into the next handler as
+   * stdout, and later handlers' env vars win. Defaults to 0.
+   */
+  priority?(): number;
 }
 
 export interface Handler {
   /**
    * Prepare the command for execution.
-   * Can modify the command and set environment variables.
+   * Can modify the command and set environment variables, or reject the
+   * command so that it is never run.
    * 
    * Note: This is skipped when exact: true is set.
    * 
-   * @returns Modified command and environment variables
+   * @returns Modified command and environment variables, or a rejection
    */
   prepare(): PrepareResult;
 
   /**
    * Process incremental output chunks.
-   * Called repeatedly as output arrives, and once more when complete.
+   * Called repeatedly as output arrives. If the handler has no finalize(),
+   * it is called once more when complete, with the exit code.
    * 
    * @param stdoutChunk - New stdout data (may be empty)
    * @param stderrChunk - New stderr data (may be empty)
@@ -53,15 +69,97 @@ export interface Handler {
    * @returns Summary to emit, or null to keep buffering
    */
   summarize(stdoutChunk: string, stderrChunk: string, exitCode: number | null): SummaryResult;
+
+  /**
+   * Produce the final summary once the command has exited.
+   * Called exactly once; its summary replaces everything summarize() emitted.
+   * 
+   * @param exitCode - The command's exit code
+   * @param stdout - The command's complete stdout
+   * @param stderr - The command's complete stderr
+   * @returns The authoritative summary of the run
+   */
+  finalize?(exitCode: number, stdout: string, stderr: string): SummaryResult;
+}
+
+/**
+ * Read-only view of the command's surroundings. This is the only access a
+ * handler has to the machine: it cannot write files, spawn processes or use
+ * the network.
+ */
+export interface HandlerContext {
+  /** Directory the command runs in */
+  readonly workingDir: string;
+  /** Environment variables the request sets for the command */
+  readonly env: Readonly<Record<string, string>>;
+  /**
+   * Roughly how many tokens (about 4 characters each) the summary should
+   * take. A longer final summary is cut to fit.
+   */
+  readonly maxSummaryTokens: number;
+  /**
+   * Read a UTF-8 file. Relative paths are resolved against workingDir, and
+   * only files inside it (after following symlinks) can be read, up to 1 MiB.
+   * Throws if the file is missing or not allowed.
+   */
+  readFile(path: string): string;
+  /**
+   * Make an HTTP request and wait for the response. Only present when the
+   * runtime grants network access. Error statuses are returned, not thrown.
+   */
+  fetch?(url: string, options?: FetchOptions): FetchResponse;
+  /**
+   * Create or replace a UTF-8 file. Only present when the runtime grants
+   * file writes. Paths resolve like readFile and must stay inside workingDir.
+   */
+  writeFile?(path: string, contents: string): void;
+}
+
+export interface FetchOptions {
+  /** Defaults to GET */
+  method?: string;
+  headers?: Record<string, string>;
+  body?: string;
 }
 
-export interface PrepareResult {
+export interface FetchResponse {
+  status: number;
+  body: string;
+}
+
+export type PrepareResult = PreparedCommand | CommandChanges | RejectedCommand;
+
+export interface PreparedCommand {
   /** The command to execute */
   cmd: string;
   /** The command arguments */
   args: string[];
   /** Environment variables to set */
   env: Record<string, string>;
+  /** Names the output file instead of the start of the command, e.g. "cargo-build-release" */
+  outputLabel?: string;
+}
+
+/**
+ * Changes to the command the handler was created with, for handlers that only add or
+ * drop a few flags. Arguments are removed first, then prepended and appended.
+ */
+export interface CommandChanges {
+  /** Arguments to add before the original ones */
+  prependArgs?: string[];
+  /** Arguments to add after the original ones */
+  appendArgs?: string[];
+  /** Arguments to drop wherever they appear, e.g. ["--verbose"] */
+  removeArgs?: string[];
+  /** Environment variables to set */
+  env?: Record<string, string>;
+  /** Names the output file instead of the start of the command */
+  outputLabel?: string;
+}
+
+export interface RejectedCommand {
+  /** Refuse to run the command; the reason is returned to the caller instead */
+  reject: { reason: string };
 }
 
 export interface SummaryResult {
@@ -75,6 +173,48 @@ export interface SummaryResult {
    * Optional truncation metadata to help agents understand what was filtered.
    */
   truncation?: TruncationInfo;
+
+  /**
+   * Extra actions to offer the agent, e.g. restarting a dev server.
+   * A non-empty list replaces the actions from earlier summaries.
+   */
+  actions?: HandlerAction[];
+
+  /**
+   * What the command's exit codes mean, for commands whose non-zero exits
+   * aren't always failures, e.g. `{ 1: { label: "no matches", isError: false } }`
+   * for grep. Codes not listed keep the default: non-zero is an error.
+   */
+  exitCodes?: Record<number, ExitCodeMeaning>;
+
+  /**
+   * The summary split up by kind, so clients can jump straight to the errors.
+   * With a null summary, the sections are rendered into one instead of buffering.
+   */
+  sections?: SummarySections;
+}
+
+export interface SummarySections {
+  errors?: string[];
+  warnings?: string[];
+  /** e.g. "42 passed, 1 failed" */
+  stats?: string[];
+  /** The last lines of output */
+  tail?: string[];
+}
+
+export interface ExitCodeMeaning {
+  /** Short explanation shown to the agent, e.g. "differences found" */
+  label: string;
+  /** Whether the command should be treated as failed */
+  isError: boolean;
+}
+
+export interface HandlerAction {
+  /** Short identifier, e.g. "restart" */
+  name: string;
+  /** What the action does and how the agent can trigger it */
+  description: string;
 }
 
 export interface TruncationInfo {
@@ -29,7 +29,13 @@ class CargoHandler implements Handler {
       env.RUST_LOG = rustLog;
     }
 
-    return { cmd: this.cmd, args: modifiedArgs, env };
+    // e.g. cargo-build-release, so runs of different subcommands are easy to tell apart
+    const subcommand = this.args.find((arg) => !arg.startsWith("-") && !arg.startsWith("+"));
+    const label = [this.cmd, subcommand, this.args.includes("--release") ? "release" : undefined]
+      .filter((part) => part !== undefined)
+      .join("-");
+
+    return { cmd: this.cmd, args: modifiedArgs, env, outputLabel: label };
   }
 
   summarize(stdoutChunk: string, stderrChunk: string, exitCode: number | null): SummaryResult {
@@ -0,0 +1,207 @@
+import type { HandlerFactory, Handler, PrepareResult, SummaryResult, SettingsSchema } from "./api.ts";
+
+// Long `git status` output: section header -> label in the summary
+const STATUS_SECTIONS: Record<string, string> = {
+  "Changes to be committed:": "Staged",
+  "Changes not staged for commit:": "Unstaged",
+  "Untracked files:": "Untracked",
+  "Unmerged paths:": "Unmerged",
+};
+
+const PULL_NOISE = /^(remote: |Receiving objects|Resolving deltas|Unpacking objects| \* branch |From )/;
+const DIFFSTAT_FILE = /^ \S.* \| +(\d+|Bin )/;
+
+/** Name of the git subcommand, skipping global options like `-C <dir>` */
+function subcommand(args: string[]): string | null {
+  for (let i = 0; i < args.length; i++) {
+    if (args[i] === "-C" || args[i] === "-c") {
+      i++;
+    } else if (!args[i].startsWith("-")) {
+      return args[i];
+    }
+  }
+  return null;
+}
+
+class GitHandler implements Handler {
+  private cmd: string;
+  private args: string[];
+  private settings: Record<string, any>;
+  private stdout = "";
+  private stderr = "";
+
+  constructor(cmd: string, args: string[], settings: Record<string, any>) {
+    this.cmd = cmd;
+    this.args = args;
+    this.settings = settings;
+  }
+
+  prepare(): PrepareResult {
+    return { cmd: this.cmd, args: this.args, env: {} };
+  }
+
+  summarize(stdoutChunk: string, stderrChunk: string, exitCode: number | null): SummaryResult {
+    this.stdout += stdoutChunk;
+    this.stderr += stderrChunk;
+
+    if (exitCode === null) {
+      return { summary: null };
+    }
+
+    if (exitCode === 0) {
+      switch (subcommand(this.args)) {
+        case "status":
+          return this.summarizeStatus();
+        case "pull":
+          return this.summarizePull();
+        case "diff":
+          return this.summarizeDiff();
+      }
+    }
+
+    return { summary: this.stdout + this.stderr };
+  }
+
+  private summarizeStatus(): SummaryResult {
+    const header: string[] = [];
+    const sections = new Map<string, string[]>();
+    let current: string[] | null = null;
+
+    for (const line of this.stdout.split("\n")) {
+      const label = STATUS_SECTIONS[line];
+      if (label) {
+        current = sections.get(label) ?? [];
+        sections.set(label, current);
+      } else if (line.startsWith("\t") && current) {
+        current.push(line.trim());
+      } else if (line.startsWith("On branch ") || line.startsWith("HEAD detached")) {
+        header.push(line);
+      } else if (line.startsWith("Your branch is ahead") || line.startsWith("Your branch is behind")) {
+        header.push(line.replace(/\.$/, ""));
+      } else if (line.startsWith("Your branch and ") && line.includes("have diverged")) {
+        header.push(line.replace(/,$/, ""));
+      } else if (line.startsWith("and have ")) {
+        header.push(line.replace(/,? respectively\.$/, ""));
+      } else if (line.startsWith("nothing to commit") || line.startsWith("nothing added to commit")) {
+        if (sections.size === 0) {
+          header.push("Working tree clean");
+        }
+      }
+      // Everything else is `(use "git ..." to ...)` advice or "up to date" boilerplate
+    }
+
+    const maxFiles = this.settings.max_files ?? 20;
+    const summary = [...header];
+    for (const [label, files] of sections) {
+      summary.push(`${label} (${files.length}):`);
+      summary.push(...files.slice(0, maxFiles).map((file) => `  ${file}`));
+      if (files.length > maxFiles) {
+        summary.push(`  ... and ${files.length - maxFiles} more`);
+      }
+    }
+
+    return { summary: summary.join("\n") };
+  }
+
+  private summarizePull(): SummaryResult {
+    const kept: string[] = [];
+    let files = 0;
+    for (const raw of (this.stdout + this.stderr).split("\n")) {
+      const line = raw.split("\r").pop()!.trimEnd();
+      if (line === "" || PULL_NOISE.test(line) || /^ {3}[0-9a-f]+\.\.[0-9a-f]+ /.test(line)) {
+        continue;
+      }
+      if (DIFFSTAT_FILE.test(line)) {
+        files++;
+        continue;
+      }
+      if (/^ (create|delete) mode /.test(line)) {
+        continue;
+      }
+      kept.push(line.trim());
+    }
+
+    const truncation = files > 0 ? {
+      truncated: true,
+      reason: "filtered_noise" as const,
+      description: `Omitted per-file stats for ${files} file(s) - run git diff --stat ORIG_HEAD for details`,
+    } : undefined;
+
+    return { summary: kept.join("\n"), truncation };
+  }
+
+  /** Pass the diff through, minus hunks that only change trailing whitespace */
+  private summarizeDiff(): SummaryResult {
+    const output: string[] = [];
+    let fileHeader: string[] = [];
+    let hunk: string[] = [];
+    let dropped = 0;
+
+    const flushHunk = () => {
+      if (hunk.length === 0) {
+        return;
+      }
+      const removed = hunk.filter((l) => l.startsWith("-")).map((l) => l.slice(1).trimEnd());
+      const added = hunk.filter((l) => l.startsWith("+")).map((l) => l.slice(1).trimEnd());
+      if (removed.length > 0 && removed.join("\n") === added.join("\n")) {
+        dropped++;
+      } else {
+        output.push(...fileHeader, ...hunk);
+        fileHeader = [];
+      }
+      hunk = [];
+    };
+
+    const flushFile = () => {
+      flushHunk();
+      // Headers without hunks (renames, mode changes, binary files) are kept as-is
+      if (!fileHeader.some((l) => l.startsWith("--- "))) {
+        output.push(...fileHeader);
+      }
+      fileHeader = [];
+    };
+
+    for (const line of this.stdout.replace(/\n$/, "").split("\n")) {
+      if (line.startsWith("diff --git ")) {
+        flushFile();
+        fileHeader = [line];
+      } else if (line.startsWith("@@")) {
+        flushHunk();
+        hunk = [line];
+      } else if (hunk.length > 0) {
+        hunk.push(line);
+      } else {
+        fileHeader.push(line);
+      }
+    }
+    flushFile();
+
+    const truncation = dropped > 0 ? {
+      truncated: true,
+      reason: "filtered_noise" as const,
+      description: `Dropped ${dropped} hunk(s) that only change trailing whitespace`,
+    } : undefined;
+
+    return { summary: output.join("\n"), truncation };
+  }
+}
+
+export const gitHandler: HandlerFactory = {
+  matches(cmd: string, args: string[]): boolean {
+    return cmd === "git";
+  },
+
+  create(cmd: string, args: string[], settings: Record<string, any>): Handler {
+    return new GitHandler(cmd, args, settings);
+  },
+
+  settings(): SettingsSchema {
+    return {
+      max_files: {
+        type: "number",
+        default: 20,
+        description: "Files listed per git status section before collapsing the rest",
+      },
+    };
+  },
+};
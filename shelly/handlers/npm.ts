@@ -0,0 +1,160 @@
+import type { HandlerFactory, Handler, PrepareResult, SummaryResult, SettingsSchema } from "./api.ts";
+
+const SPINNER_CHARS = /[⠋⠙⠹⠸⠼⠴⠦⠧⠇⠏⸨⸩]/;
+const PROGRESS_BAR = /^\s*[\[(][#=.\-> ]{5,}[\])]/;
+const ERROR_LINE = /^(npm ERR!|npm error|error |\s*ERR_PNPM_|Exit status \d+$)/;
+const WARNING_LINE = /^(npm WARN|npm warn|warning |WARN )/;
+
+// npm 8: "npm ERR!   in workspace: <pkg>@<version>" / npm 10: "npm error workspace <pkg>@<version>"
+const NPM_WORKSPACE = /^npm (?:ERR!|error)\s+(?:in )?workspace:? (@?[^@\s]+)/;
+// pnpm -r: "ERR_PNPM_RECURSIVE_RUN_FIRST_FAIL  <pkg>@<version> <script>: ..."
+const PNPM_FIRST_FAIL = /ERR_PNPM_RECURSIVE_RUN_FIRST_FAIL\s+(@?[^@\s]+)@/;
+
+class NpmHandler implements Handler {
+  private cmd: string;
+  private args: string[];
+  private settings: Record<string, any>;
+  private stdout = "";
+  private stderr = "";
+
+  constructor(cmd: string, args: string[], settings: Record<string, any>) {
+    this.cmd = cmd;
+    this.args = args;
+    this.settings = settings;
+  }
+
+  prepare(): PrepareResult {
+    const quiet = this.settings.quiet ?? true;
+    const flags: string[] = [];
+
+    if (quiet) {
+      const script = ["run", "run-script", "test", "start"].includes(this.args[0]);
+      if (this.cmd === "npm") {
+        flags.push("--no-progress");
+        // Drops the "> pkg@1.0.0 test" banner; the script's own output is kept
+        if (script) {
+          flags.push("--silent");
+        }
+      } else if (this.cmd === "yarn") {
+        flags.push("--silent", "--no-progress");
+      } else if (this.cmd === "pnpm" && script) {
+        flags.push("--silent");
+      }
+    }
+
+    const modifiedArgs = [...this.args];
+    for (const flag of flags.reverse()) {
+      if (!modifiedArgs.includes(flag)) {
+        modifiedArgs.unshift(flag);
+      }
+    }
+
+    return { cmd: this.cmd, args: modifiedArgs, env: {} };
+  }
+
+  summarize(stdoutChunk: string, stderrChunk: string, exitCode: number | null): SummaryResult {
+    this.stdout += stdoutChunk;
+    this.stderr += stderrChunk;
+
+    if (exitCode === null) {
+      return { summary: null };
+    }
+
+    const showWarnings = this.settings.show_warnings ?? false;
+    let collapsed = 0;
+    let filteredWarnings = 0;
+    const lines: string[] = [];
+    for (const raw of (this.stdout + this.stderr).split("\n")) {
+      // Progress output redraws the line with \r; only the last frame matters
+      const line = raw.split("\r").pop()!.trimEnd();
+      if (SPINNER_CHARS.test(line) || PROGRESS_BAR.test(line)) {
+        collapsed++;
+        continue;
+      }
+      if (WARNING_LINE.test(line) && !showWarnings) {
+        filteredWarnings++;
+        continue;
+      }
+      // Keep paragraph breaks, but never more than one in a row
+      if (line.trim() !== "" || (lines.length > 0 && lines[lines.length - 1] !== "")) {
+        lines.push(line);
+      }
+    }
+    if (lines[lines.length - 1] === "") {
+      lines.pop();
+    }
+
+    const notes: string[] = [];
+    if (collapsed > 0) {
+      notes.push(`collapsed ${collapsed} progress line(s)`);
+    }
+    if (filteredWarnings > 0) {
+      notes.push(`filtered ${filteredWarnings} warning(s) - use show_warnings: true to include them`);
+    }
+    const truncation = notes.length > 0 ? {
+      truncated: true,
+      reason: "filtered_noise" as const,
+      description: notes.join("; "),
+    } : undefined;
+
+    if (exitCode === 0) {
+      return { summary: lines.length > 0 ? lines.join("\n") : "Command succeeded", truncation };
+    }
+
+    // Keep the final block of package-manager errors plus the output leading up
+    // to it, which is usually the failing script's own error
+    let blockStart = lines.length;
+    while (blockStart > 0 && ERROR_LINE.test(lines[blockStart - 1])) {
+      blockStart--;
+    }
+    const contextLines = this.settings.context_lines ?? 20;
+    const summary = lines.slice(Math.max(0, blockStart - contextLines));
+
+    const failing = new Set<string>();
+    for (const line of lines) {
+      const match = line.match(NPM_WORKSPACE) ?? line.match(PNPM_FIRST_FAIL);
+      if (match) {
+        failing.add(match[1]);
+      }
+    }
+    if (failing.size > 0) {
+      summary.push(`Failing packages: ${[...failing].join(", ")}`);
+    }
+
+    return { summary: summary.length > 0 ? summary.join("\n") : "Command failed", truncation };
+  }
+}
+
+export const npmHandler: HandlerFactory = {
+  matches(cmd: string, args: string[]): boolean {
+    return cmd === "npm" || cmd === "yarn" || cmd === "pnpm";
+  },
+
+  create(cmd: string, args: string[], settings: Record<string, any>): Handler {
+    return new NpmHandler(cmd, args, settings);
+  },
+
+  settings(): SettingsSchema {
+    return {
+      quiet: {
+        type: "boolean",
+        default: true,
+        description: "Add --silent/--no-progress flags to reduce output noise",
+      },
+      show_warnings: {
+        type: "boolean",
+        default: false,
+        description: "Include npm WARN / warning lines in the summary",
+      },
+      context_lines: {
+        type: "number",
+        default: 20,
+        description: "Lines of output to keep before the final error block on failure",
+      },
+    };
+  },
+};
+
+// Loaded as yarn.ts / pnpm.ts, the runtime looks for an export named after the file
+export const yarnHandler = npmHandler;
+export const pnpmHandler = npmHandler;
@@ -0,0 +1,102 @@
+import type { HandlerFactory, Handler, PrepareResult, SummaryResult, SettingsSchema } from "./api.ts";
+
+// "==== FAILURES ====", "---- Captured stdout call ----" etc.
+const SECTION_HEADER = /^=+ (.+?) =+$/;
+// "1 failed, 4 passed in 0.12s", optionally wrapped in "=" padding without -q
+const FINAL_LINE = /^=*\s*((\d+ \w+(, )?)+ in [\d.]+s.*?|no tests ran in [\d.]+s.*?)\s*=*$/;
+const TRACEBACK_SECTIONS = new Set(["FAILURES", "ERRORS"]);
+
+class PytestHandler implements Handler {
+  private cmd: string;
+  private args: string[];
+  private settings: Record<string, any>;
+  private stdout = "";
+  private stderr = "";
+
+  constructor(cmd: string, args: string[], settings: Record<string, any>) {
+    this.cmd = cmd;
+    this.args = args;
+    this.settings = settings;
+  }
+
+  prepare(): PrepareResult {
+    const quiet = this.settings.quiet ?? true;
+    const verbose = this.args.some((a) => /^-(v+|q+)$/.test(a) || a === "--verbose" || a === "--quiet");
+
+    const modifiedArgs = [...this.args];
+    if (quiet && !verbose) {
+      modifiedArgs.unshift("-q");
+    }
+
+    return { cmd: this.cmd, args: modifiedArgs, env: {} };
+  }
+
+  summarize(stdoutChunk: string, stderrChunk: string, exitCode: number | null): SummaryResult {
+    this.stdout += stdoutChunk;
+    this.stderr += stderrChunk;
+
+    if (exitCode === null) {
+      return { summary: null };
+    }
+
+    const tracebacks: string[] = [];
+    const failures: string[] = [];
+    let finalLine: string | null = null;
+    let section: string | null = null;
+
+    for (const line of this.stdout.split("\n")) {
+      const header = line.match(SECTION_HEADER);
+      const final = line.match(FINAL_LINE);
+      if (final) {
+        finalLine = final[1];
+        section = null;
+      } else if (header) {
+        section = header[1];
+      } else if (section !== null && TRACEBACK_SECTIONS.has(section)) {
+        tracebacks.push(line);
+      } else if (/^(FAILED|ERROR) /.test(line)) {
+        failures.push(line);
+      }
+    }
+
+    // Exit code 2-4 are usage/internal errors: pytest never got to run tests
+    if (finalLine === null) {
+      const output = (this.stdout + this.stderr).trim();
+      return { summary: output || `pytest exited with code ${exitCode}` };
+    }
+
+    if (exitCode === 0 || exitCode === 5) {
+      return { summary: finalLine };
+    }
+
+    const summary = [...tracebacks, ...failures, finalLine].join("\n").trim();
+    return {
+      summary,
+      truncation: {
+        truncated: true,
+        reason: "filtered_noise" as const,
+        description: "Showing failing tests only - passing tests, warnings and collection output were removed",
+      },
+    };
+  }
+}
+
+export const pytestHandler: HandlerFactory = {
+  matches(cmd: string, args: string[]): boolean {
+    return cmd === "pytest";
+  },
+
+  create(cmd: string, args: string[], settings: Record<string, any>): Handler {
+    return new PytestHandler(cmd, args, settings);
+  },
+
+  settings(): SettingsSchema {
+    return {
+      quiet: {
+        type: "boolean",
+        default: true,
+        description: "Add -q unless a verbosity flag is already given",
+      },
+    };
+  },
+};
@@ -1,7 +1,13 @@
 use clap::{Parser, Subcommand};
+use futures::stream::{self, StreamExt};
+use shelly::process_manager::ProcessManager;
+use anyhow::Context;
+use shelly::runtime::{self, Handler};
 use shelly::{testing, handler};
 use std::collections::HashMap;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
 
 #[derive(Parser)]
 #[command(name = "shelly")]
@@ -20,6 +26,12 @@ enum Commands {
         /// Update test snapshots instead of running tests
         #[arg(long)]
         update: bool,
+        /// Print the full expected and actual summaries of failing tests, not just the diff
+        #[arg(long)]
+        verbose: bool,
+        /// How many test cases to run at once [default: number of CPUs]
+        #[arg(long, short)]
+        jobs: Option<usize>,
     },
     /// Execute a command with handler processing
     Execute {
@@ -32,6 +44,87 @@ enum Commands {
         #[arg(long)]
         working_dir: Option<PathBuf>,
     },
+    /// Run a program with handler processing, the same way the MCP `execute_cli` tool
+    /// does. Arguments are passed to the program as given, e.g.
+    /// `shelly run -- cargo build --release`.
+    Run {
+        /// Program to run
+        cmd: String,
+        /// Arguments for the program
+        #[arg(trailing_var_arg = true, allow_hyphen_values = true)]
+        args: Vec<String>,
+        /// Cancel the command if it runs for longer than this many seconds
+        #[arg(long)]
+        timeout: Option<u64>,
+        /// Execute in exact mode (no handler processing)
+        #[arg(long)]
+        exact: bool,
+        /// Set an environment variable for the command, as KEY=VALUE (repeatable)
+        #[arg(long, value_parser = parse_env_var)]
+        env: Vec<(String, String)>,
+        /// Working directory
+        #[arg(long)]
+        working_dir: Option<PathBuf>,
+        /// Print the command the handler would run, and its environment, without running it
+        #[arg(long)]
+        dry_run: bool,
+        /// Run the command through $SHELL, so pipes, `&&`, globs and variables work
+        #[arg(long)]
+        shell: bool,
+        /// Collapse repeated lines in output that no handler summarizes
+        #[arg(long)]
+        dedupe: bool,
+        /// Kill the command once it has used this many seconds of CPU time (Unix only)
+        #[arg(long)]
+        max_cpu_secs: Option<u64>,
+        /// Limit the command's address space to this many MiB (Unix only)
+        #[arg(long)]
+        max_memory_mb: Option<u64>,
+        /// Print the whole result as JSON instead of the summary, for scripts
+        #[arg(long)]
+        json: bool,
+    },
+    /// Create a skeleton handler for a command in ~/.shelly, with a starter test in
+    /// .shelly/tests
+    New {
+        /// Command the handler is for, e.g. "terraform"
+        name: String,
+        /// Replace an existing handler and test
+        #[arg(long)]
+        force: bool,
+    },
+    /// Load handlers and check their settings schema, exiting non-zero if any fails.
+    /// Takes handler names (e.g. "cargo") or paths to handler files.
+    Validate {
+        /// Handlers to check
+        #[arg(required = true)]
+        handlers: Vec<String>,
+    },
+    /// Inspect installed handlers
+    Handlers {
+        #[command(subcommand)]
+        command: HandlersCommand,
+    },
+}
+
+#[derive(Subcommand)]
+enum HandlersCommand {
+    /// List every installed handler, where it comes from and what it matches
+    List,
+    /// Show which handlers would intercept a command, e.g. `shelly handlers which cargo test`
+    Which {
+        /// Program to look up
+        cmd: String,
+        /// Arguments for the program
+        #[arg(trailing_var_arg = true, allow_hyphen_values = true)]
+        args: Vec<String>,
+    },
+}
+
+fn parse_env_var(var: &str) -> Result<(String, String), String> {
+    var.split_once('=')
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .ok_or_else(|| format!("expected KEY=VALUE, got `{var}`"))
 }
 
 #[tokio::main]
@@ -39,9 +132,22 @@ async fn main() -> anyhow::Result<()> {
     let cli = Cli::parse();
 
     match cli.command {
-        Commands::Test { handler, update } => {
+        Commands::Test {
+            handler,
+            update,
+            verbose,
+            jobs,
+        } => {
+            let options = TestOptions {
+                update,
+                verbose,
+                jobs: jobs
+                    .or_else(|| std::thread::available_parallelism().ok().map(Into::into))
+                    .unwrap_or(1)
+                    .max(1),
+            };
             if let Some(handler_name) = handler {
-                run_handler_tests(&handler_name, update).await?;
+                run_handler_tests(&handler_name, &options).await?;
             } else {
                 // Run tests for all handlers found in .shelly/tests/
                 let test_dir = PathBuf::from(".shelly/tests");
@@ -51,7 +157,7 @@ async fn main() -> anyhow::Result<()> {
                         if entry.path().is_dir() {
                             let handler_name = entry.file_name().to_string_lossy().to_string();
                             println!("Testing handler: {}", handler_name);
-                            run_handler_tests(&handler_name, update).await?;
+                            run_handler_tests(&handler_name, &options).await?;
                         }
                     }
                 } else {
@@ -72,6 +178,21 @@ async fn main() -> anyhow::Result<()> {
                 exact,
                 working_dir: working_dir.unwrap_or_else(|| std::env::current_dir().unwrap()),
                 env: std::env::vars().collect(),
+                env_policy: Default::default(),
+                kill_on_timeout: false,
+                max_runtime_ms: None,
+                idle_timeout_ms: None,
+                handler: None,
+                output_dir: None,
+                ansi: shelly::output::AnsiMode::Strip,
+                pty: false,
+                dry_run: false,
+                redact_env: None,
+                max_summary_tokens: None,
+                shell: false,
+                dedupe: false,
+                limits: Default::default(),
+                stdin: None,
             };
 
             let result = shelly::execute_command(request).await?;
@@ -81,16 +202,210 @@ async fn main() -> anyhow::Result<()> {
                 std::process::exit(result.exit_code);
             }
         }
+        Commands::Run {
+            cmd,
+            args,
+            timeout,
+            exact,
+            env,
+            working_dir,
+            dry_run,
+            shell,
+            dedupe,
+            max_cpu_secs,
+            max_memory_mb,
+            json,
+        } => {
+            let request = shelly::ExecuteRequest {
+                cmd,
+                args,
+                settings: HashMap::new(),
+                exact,
+                working_dir: working_dir.unwrap_or_else(|| std::env::current_dir().unwrap()),
+                env: env.into_iter().collect(),
+                env_policy: Default::default(),
+                // Nothing would be left to join the command once we exit
+                kill_on_timeout: true,
+                max_runtime_ms: None,
+                idle_timeout_ms: None,
+                handler: None,
+                output_dir: None,
+                ansi: shelly::output::AnsiMode::Strip,
+                pty: false,
+                dry_run,
+                redact_env: None,
+                max_summary_tokens: None,
+                shell,
+                dedupe,
+                limits: shelly::streaming_executor::ResourceLimits {
+                    cpu_secs: max_cpu_secs,
+                    memory_mb: max_memory_mb,
+                },
+                stdin: None,
+            };
+
+            let timeout = timeout.map_or(Duration::MAX, Duration::from_secs);
+            let result = shelly::execute_command_streaming(
+                request,
+                Arc::new(ProcessManager::new()),
+                timeout,
+            )
+            .await?;
+            if let Some(original) = &result.original_command {
+                eprintln!(
+                    "warning: a handler ran `{}` instead of `{original}`",
+                    result.executed_command.command()
+                );
+            }
+            if json {
+                println!("{}", serde_json::to_string_pretty(&result)?);
+                // A dry run has no exit code to pass on
+                if !dry_run && result.exit_code != 0 {
+                    std::process::exit(result.exit_code);
+                }
+                return Ok(());
+            }
+            print!("{}", result.summary);
+            if !result.summary.is_empty() && !result.summary.ends_with('\n') {
+                println!();
+            }
+            if dry_run {
+                let mut env: Vec<_> = result.executed_command.env.iter().collect();
+                env.sort();
+                for (key, value) in env {
+                    println!("  {key}={value}");
+                }
+                return Ok(());
+            }
+            println!("Output file: {}", result.output_file);
+            match &result.exit_code_meaning {
+                Some(meaning) => println!("Exit code: {} ({meaning})", result.exit_code),
+                None => println!("Exit code: {}", result.exit_code),
+            }
+
+            if result.exit_code != 0 {
+                std::process::exit(result.exit_code);
+            }
+        }
+        Commands::New { name, force } => {
+            let home_dir = dirs::home_dir()
+                .ok_or_else(|| anyhow::anyhow!("Could not find home directory"))?;
+            let written = handler::scaffold_handler(
+                &name,
+                &home_dir.join(".shelly"),
+                Path::new(".shelly/tests"),
+                force,
+            )?;
+            for path in written {
+                println!("Created {}", path.display());
+            }
+            println!("Run `shelly test {name}` to try it out");
+        }
+        Commands::Validate { handlers } => {
+            let mut failed = 0;
+            for name in handlers {
+                match validate_handler(&name).await {
+                    Ok(path) => println!("  ✓ {}", display_path(&path)),
+                    Err(e) => {
+                        println!("  ✗ {e:#}");
+                        failed += 1;
+                    }
+                }
+            }
+            if failed > 0 {
+                std::process::exit(1);
+            }
+        }
+        Commands::Handlers { command: HandlersCommand::List } => {
+            for installed in handler::list_handlers(&std::env::current_dir()?)? {
+                println!(
+                    "{} ({}): {}",
+                    installed.command,
+                    installed.source,
+                    display_path(&installed.path)
+                );
+                let mut handler = match Handler::load(&installed.path).await {
+                    Ok(handler) => handler,
+                    Err(e) => {
+                        println!("  failed to load: {e:#}");
+                        continue;
+                    }
+                };
+                // Declarative handlers say what they match; try scripts on their name
+                let (cmd, args) = match &handler {
+                    Handler::Declarative(declarative) => {
+                        (declarative.command.clone(), declarative.args.clone())
+                    }
+                    Handler::Script(_) => (installed.command.clone(), Vec::new()),
+                };
+                let command = shell_words::join(std::iter::once(&cmd).chain(&args));
+                match handler.matches(&cmd, &args).await {
+                    Ok(true) => println!("  matches `{command}`"),
+                    Ok(false) => println!("  does not match `{command}`"),
+                    Err(e) => println!("  matches() failed: {e:#}"),
+                }
+            }
+        }
+        Commands::Handlers {
+            command: HandlersCommand::Which { cmd, args },
+        } => {
+            let command = shell_words::join(std::iter::once(&cmd).chain(&args));
+            let mut intercepted = false;
+            for path in handler::find_handler(&command, &std::env::current_dir()?)? {
+                let matched = match Handler::load(&path).await {
+                    Ok(mut handler) => handler.matches(&cmd, &args).await,
+                    Err(e) => Err(e),
+                };
+                match matched {
+                    Ok(true) => {
+                        intercepted = true;
+                        println!("{} (matches)", display_path(&path));
+                    }
+                    Ok(false) => println!("{} (does not match)", display_path(&path)),
+                    Err(e) => println!("{} (failed: {e:#})", display_path(&path)),
+                }
+            }
+            if !intercepted {
+                println!("No handler intercepts `{command}`; it runs unmodified");
+            }
+        }
     }
 
     Ok(())
 }
 
-async fn run_handler_tests(handler_name: &str, update: bool) -> anyhow::Result<()> {
-    // Find handler file
-    let handler_path = if let Some(path) = handler::find_handler(handler_name)? {
+/// Load `name`, a handler file or the most specific handler for a command, and check
+/// its settings schema. Returns the file that was checked.
+async fn validate_handler(name: &str) -> anyhow::Result<PathBuf> {
+    let path = if Path::new(name).is_file() {
+        PathBuf::from(name)
+    } else if let Some(path) = handler::find_handler(name, &std::env::current_dir()?)?.pop() {
         path
     } else {
+        anyhow::bail!("{name}: handler not found");
+    };
+    let checked = async {
+        // Loading transpiles the file and resolves its `{name}Handler` export
+        let mut handler = Handler::load(&path).await?;
+        let schema = handler.settings().await.context("settings() failed")?;
+        runtime::check_settings_schema(&schema)
+    };
+    checked.await.with_context(|| display_path(&path))?;
+    Ok(path)
+}
+
+struct TestOptions {
+    update: bool,
+    verbose: bool,
+    /// Test cases run concurrently, each in its own handler runtime
+    jobs: usize,
+}
+
+async fn run_handler_tests(handler_name: &str, options: &TestOptions) -> anyhow::Result<()> {
+    // Find handler file
+    // Test the most specific handler, which is last in the chain
+    let current_dir = std::env::current_dir()?;
+    let Some(handler_path) = handler::find_handler(handler_name, &current_dir)?.pop() else {
         anyhow::bail!("Handler not found: {}", handler_name);
     };
 
@@ -106,33 +421,54 @@ async fn run_handler_tests(handler_name: &str, update: bool) -> anyhow::Result<(
     let mut passed = 0;
     let mut failed = 0;
 
-    for (name, mut test) in tests {
-        if update {
-            // Update snapshot
-            testing::update_snapshot(&handler_path, handler_name, &name, &mut test).await?;
-            println!("  ✓ Updated snapshot: {}", name);
-        } else {
-            // Run test
-            let result = testing::run_test(&handler_path, &name, &test).await?;
-            if result.passed {
+    let handler_path = &handler_path;
+    let mut outcomes: Vec<_> = stream::iter(tests.into_iter().enumerate())
+        .map(|(i, (name, mut test))| async move {
+            let outcome = if options.update {
+                testing::update_snapshot(handler_path, handler_name, &name, &mut test)
+                    .await
+                    .map(|()| None)
+            } else {
+                testing::run_test(handler_path, &name, &test).await.map(Some)
+            };
+            (i, name, outcome)
+        })
+        .buffer_unordered(options.jobs)
+        .collect()
+        .await;
+    // Report in file order, whichever test finished first
+    outcomes.sort_by_key(|(i, _, _)| *i);
+
+    for (_, name, outcome) in outcomes {
+        match outcome? {
+            None => println!("  ✓ Updated snapshot: {}", name),
+            Some(result) if result.passed => {
                 println!("  ✓ {}", name);
                 passed += 1;
-            } else {
+            }
+            Some(result) => {
                 println!("  ✗ {}", name);
-                println!("    Expected:");
-                for line in result.expected.lines() {
-                    println!("      {}", line);
+                print_diff(&result.diff);
+                if !result.output_file_diff.is_empty() {
+                    println!("    Output file:");
+                    print_diff(&result.output_file_diff);
                 }
-                println!("    Actual:");
-                for line in result.actual.lines() {
-                    println!("      {}", line);
+                if options.verbose {
+                    println!("    Expected:");
+                    for line in result.expected.lines() {
+                        println!("      {}", line);
+                    }
+                    println!("    Actual:");
+                    for line in result.actual.lines() {
+                        println!("      {}", line);
+                    }
                 }
                 failed += 1;
             }
         }
     }
 
-    if !update {
+    if !options.update {
         println!("\nResults: {} passed, {} failed", passed, failed);
         if failed > 0 {
             std::process::exit(1);
@@ -141,3 +477,23 @@ async fn run_handler_tests(handler_name: &str, update: bool) -> anyhow::Result<(
 
     Ok(())
 }
+
+/// Print the lines that differ, marked with -/+
+fn print_diff(diff: &[testing::DiffLine]) {
+    for line in diff {
+        match line {
+            testing::DiffLine::Same(_) => {}
+            testing::DiffLine::Removed(line) => println!("    - {}", line),
+            testing::DiffLine::Added(line) => println!("    + {}", line),
+        }
+    }
+}
+
+/// `path` relative to the current directory if it is inside it, for shorter output
+fn display_path(path: &Path) -> String {
+    let current_dir = std::env::current_dir().unwrap_or_default();
+    path.strip_prefix(&current_dir)
+        .unwrap_or(path)
+        .display()
+        .to_string()
+}
@@ -1,52 +1,374 @@
 use anyhow::Result;
-use std::{fs, io::Write, path::PathBuf};
+use std::{
+    fs,
+    io::Write,
+    path::{Path, PathBuf},
+};
 use tracing::info;
 
 // Embed built-in handlers at compile time
 const CARGO_HANDLER: &[u8] = include_bytes!("../handlers/cargo.ts");
+const NPM_HANDLER: &[u8] = include_bytes!("../handlers/npm.ts");
+const GIT_HANDLER: &[u8] = include_bytes!("../handlers/git.ts");
+const PYTEST_HANDLER: &[u8] = include_bytes!("../handlers/pytest.ts");
+const HANDLER_API: &str = include_str!("../handlers/api.ts");
 
-/// Find handler file for a command
-/// Searches in order: ~/.shelly, CWD/.shelly, built-in handlers
-pub fn find_handler(command: &str) -> Result<Option<PathBuf>> {
-    let cmd_name = command
-        .split_whitespace()
+/// Starting point for `scaffold_handler`. `__COMMAND__`, `__CLASS__` and `__FACTORY__`
+/// are filled in with the command name and identifiers derived from it.
+const HANDLER_TEMPLATE: &str = r#"import type {
+  Handler,
+  HandlerContext,
+  HandlerFactory,
+  PrepareResult,
+  SettingsSchema,
+  SummaryResult,
+} from "./api.ts";
+
+class __CLASS__ implements Handler {
+  private stdout = "";
+  private stderr = "";
+
+  constructor(
+    private cmd: string,
+    private args: string[],
+    private settings: Record<string, any>,
+  ) {}
+
+  prepare(): PrepareResult {
+    // Add flags or environment variables here
+    return { cmd: this.cmd, args: this.args, env: {} };
+  }
+
+  summarize(stdoutChunk: string, stderrChunk: string, exitCode: number | null): SummaryResult {
+    this.stdout += stdoutChunk;
+    this.stderr += stderrChunk;
+    if (exitCode === null) {
+      // Keep buffering until the command exits
+      return { summary: null };
+    }
+    // Filter the output down to what matters here
+    return { summary: this.stdout + this.stderr };
+  }
+}
+
+export const __FACTORY__: HandlerFactory = {
+  matches(cmd: string, args: string[]): boolean {
+    return cmd === "__COMMAND__";
+  },
+
+  create(
+    cmd: string,
+    args: string[],
+    settings: Record<string, any>,
+    context: HandlerContext,
+  ): Handler {
+    return new __CLASS__(cmd, args, settings);
+  },
+
+  settings(): SettingsSchema {
+    return {};
+  },
+};
+"#;
+
+/// Commands with a built-in handler
+const BUILTIN_COMMANDS: &[&str] = &["cargo", "npm", "yarn", "pnpm", "git", "pytest"];
+
+/// File extensions recognized as handlers; `.json`/`.yaml` are declarative filters
+const HANDLER_EXTENSIONS: &[&str] = &["ts", "json", "yaml", "yml"];
+
+/// Find every handler file for a command run in `working_dir`, in chain order:
+/// built-in, then ~/.shelly, then the project's `.shelly`. The most specific handler
+/// comes last so that, at equal `priority()`, it runs last and its `prepare()` env wins.
+///
+/// The project handler is the nearest one found walking up from `working_dir`, so a
+/// `.shelly` at a repository root applies in its subdirectories. The walk stops below
+/// the home directory, whose `.shelly` is always consulted.
+///
+/// Besides `<cmd>.ts`, a handler can be named after the command and its leading
+/// subcommands, e.g. `go-test.ts` for `go test ./...`. Such files come after the
+/// handler for the shorter prefix in the same directory. A file is only a candidate;
+/// whether it applies is up to its `matches()`.
+pub fn find_handler(command: &str, working_dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut words = command.split_whitespace();
+    let cmd_name = words
         .next()
         .ok_or_else(|| anyhow::anyhow!("Empty command"))?;
+    let names = subcommand_names(cmd_name, words);
+
+    tracing::info!("looking for {cmd_name} handlers...");
+    let mut handlers = Vec::new();
 
-    let handler_filename = format!("{}.ts", cmd_name);
-    tracing::info!("looking for a {handler_filename}...");
+    // 1. Check built-in handlers
+    handlers.extend(builtin_handler(cmd_name)?);
 
-    // 1. Check ~/.shelly
+    // 2. Check ~/.shelly
     if let Some(home_dir) = dirs::home_dir() {
-        let home_handler = home_dir.join(".shelly").join(&handler_filename);
-        tracing::info!("looking for a {home_handler:?}...");
-        if home_handler.exists() {
-            info!("found handler: {home_handler:?}");
-            return Ok(Some(home_handler));
+        let home_dir = home_dir.join(".shelly");
+        tracing::info!("looking for {cmd_name} handlers in {home_dir:?}...");
+        for name in &names {
+            for home_handler in handler_files(&home_dir, name) {
+                info!("found handler: {home_handler:?}");
+                handlers.push(home_handler);
+            }
         }
     }
 
-    // 2. Check CWD/.shelly
-    let cwd_handler = PathBuf::from(".shelly").join(&handler_filename);
-    if cwd_handler.exists() {
-        return Ok(Some(cwd_handler));
+    // 3. Check the nearest .shelly from the working directory up
+    let project_dirs = project_dirs(working_dir);
+    for name in &names {
+        let nearest = project_dirs
+            .iter()
+            .map(|dir| handler_files(dir, name))
+            .find(|files| !files.is_empty());
+        handlers.extend(nearest.unwrap_or_default());
     }
 
-    // 3. Check built-in handlers
-    let builtin_content = match cmd_name {
-        "cargo" => Some(CARGO_HANDLER),
-        _ => None,
+    Ok(handlers)
+}
+
+/// Write the built-in handler for `cmd_name`, if there is one, to a temp file that can
+/// be loaded like any other handler
+fn builtin_handler(cmd_name: &str) -> Result<Option<PathBuf>> {
+    let content = match cmd_name {
+        "cargo" => CARGO_HANDLER,
+        "npm" | "yarn" | "pnpm" => NPM_HANDLER,
+        "git" => GIT_HANDLER,
+        "pytest" => PYTEST_HANDLER,
+        _ => return Ok(None),
     };
 
-    if let Some(content) = builtin_content {
-        // Create temp file with built-in handler content
-        // Use the original handler name to ensure proper module loading
-        let temp_dir = std::env::temp_dir();
-        let temp_handler = temp_dir.join(handler_filename);
-        let mut file = fs::File::create(&temp_handler)?;
-        file.write_all(content)?;
+    // Use the original handler name to ensure proper module loading
+    let handler_filename = format!("{cmd_name}.ts");
+    let temp_dir = std::env::temp_dir();
+    let temp_handler = temp_dir.join(&handler_filename);
+    // Leave an up to date file alone, so its mtime keeps loaded copies of it cached
+    if fs::read(&temp_handler).is_ok_and(|existing| existing == content) {
         return Ok(Some(temp_handler));
     }
+    // Write then rename, so a concurrent command loading the same handler never
+    // sees a truncated file
+    let staging = temp_dir.join(format!("{handler_filename}.{}", uuid::Uuid::new_v4()));
+    let mut file = fs::File::create(&staging)?;
+    file.write_all(content)?;
+    fs::rename(&staging, &temp_handler)?;
+    Ok(Some(temp_handler))
+}
+
+/// Where an installed handler was found
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HandlerSource {
+    BuiltIn,
+    /// `~/.shelly`
+    Home,
+    /// The nearest `.shelly` in the working directory or one of its parents
+    Local,
+}
+
+impl std::fmt::Display for HandlerSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::BuiltIn => "built-in",
+            Self::Home => "~/.shelly",
+            Self::Local => ".shelly",
+        })
+    }
+}
+
+/// A handler file, and the command it is looked up for
+#[derive(Debug, Clone)]
+pub struct InstalledHandler {
+    pub command: String,
+    pub path: PathBuf,
+    pub source: HandlerSource,
+}
+
+/// Every handler installed for commands run in `working_dir`: built-in, then
+/// `~/.shelly`, then project handlers, the order `find_handler` chains them in. Project
+/// handlers shadowed by a nearer one of the same name are left out.
+pub fn list_handlers(working_dir: &Path) -> Result<Vec<InstalledHandler>> {
+    let mut handlers = Vec::new();
+    for command in BUILTIN_COMMANDS {
+        if let Some(path) = builtin_handler(command)? {
+            handlers.push(InstalledHandler {
+                command: command.to_string(),
+                path,
+                source: HandlerSource::BuiltIn,
+            });
+        }
+    }
+
+    let mut dirs = Vec::new();
+    if let Some(home_dir) = dirs::home_dir() {
+        dirs.push((home_dir.join(".shelly"), HandlerSource::Home));
+    }
+    dirs.extend(
+        project_dirs(working_dir)
+            .into_iter()
+            .map(|dir| (dir, HandlerSource::Local)),
+    );
+    // Commands that have a handler in a nearer project directory
+    let mut shadowed = std::collections::HashSet::new();
+    for (dir, source) in dirs {
+        let Ok(entries) = fs::read_dir(&dir) else {
+            continue;
+        };
+        let mut paths: Vec<PathBuf> = entries
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .filter(|path| {
+                path.is_file()
+                    && path
+                        .extension()
+                        .and_then(|ext| ext.to_str())
+                        .is_some_and(|ext| HANDLER_EXTENSIONS.contains(&ext))
+            })
+            .collect();
+        paths.sort();
+        let mut found = Vec::new();
+        for path in paths {
+            let Some(command) = path.file_stem().and_then(|stem| stem.to_str()) else {
+                continue;
+            };
+            if source == HandlerSource::Local && shadowed.contains(command) {
+                continue;
+            }
+            found.push(command.to_string());
+            handlers.push(InstalledHandler {
+                command: command.to_string(),
+                path: path.clone(),
+                source,
+            });
+        }
+        if source == HandlerSource::Local {
+            shadowed.extend(found);
+        }
+    }
+    Ok(handlers)
+}
+
+/// The handler file `name` refers to: a path to a handler file, or the command an
+/// installed handler is named after, e.g. `cargo` or `go-test`. Of several handlers with
+/// that name, the one `find_handler` would chain last (the most specific) is used.
+pub fn resolve_handler(name: &str, working_dir: &Path) -> Result<PathBuf> {
+    let path = working_dir.join(name);
+    let is_handler_file = path
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .is_some_and(|ext| HANDLER_EXTENSIONS.contains(&ext));
+    if is_handler_file && path.is_file() {
+        return Ok(path);
+    }
+    list_handlers(working_dir)?
+        .into_iter()
+        .rfind(|handler| handler.command == name)
+        .map(|handler| handler.path)
+        .ok_or_else(|| anyhow::anyhow!("No handler named {name:?}"))
+}
+
+/// Create `<handler_dir>/<name>.ts` from a skeleton handler for the `name` command, a
+/// starter test in `<test_dir>/<name>/`, and `api.ts` next to the handler if it's
+/// missing or out of date. Refuses to replace an existing handler or test unless `force` is set.
+/// Returns the files written.
+pub fn scaffold_handler(
+    name: &str,
+    handler_dir: &Path,
+    test_dir: &Path,
+    force: bool,
+) -> Result<Vec<PathBuf>> {
+    // The factory is looked up by an export named after the file
+    let factory = crate::runtime::handler_export_name(name);
+    let is_identifier = factory
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '$')
+        && !factory.starts_with(|c: char| c.is_ascii_digit());
+    if name.is_empty() || !is_identifier {
+        anyhow::bail!(
+            "Invalid handler name {name:?}: `{factory}` must be a valid JavaScript identifier"
+        );
+    }
+    let handler_path = handler_dir.join(format!("{name}.ts"));
+    let test_path = test_dir.join(name).join("basic.toml");
+    if !force {
+        for path in [&handler_path, &test_path] {
+            if path.exists() {
+                anyhow::bail!("{} already exists; pass --force to replace it", path.display());
+            }
+        }
+    }
+
+    let class = factory[..1].to_ascii_uppercase() + &factory[1..];
+    let handler = HANDLER_TEMPLATE
+        .replace("__COMMAND__", name)
+        .replace("__CLASS__", &class)
+        .replace("__FACTORY__", &factory);
+
+    let test = crate::testing::TestCase {
+        cmd: name.to_string(),
+        args: Vec::new(),
+        command: None,
+        settings: Default::default(),
+        stdout: "hello\n".to_string(),
+        stderr: String::new(),
+        exit_code: 0,
+        chunks: Vec::new(),
+        expected_summary: "hello\n".to_string(),
+        check_output_file: false,
+        dir: PathBuf::new(),
+    };
+
+    let mut written = Vec::new();
+    fs::create_dir_all(handler_dir)?;
+    let api_path = handler_dir.join("api.ts");
+    // An api.ts from an older shelly would type-check handlers against a stale API
+    if fs::read_to_string(&api_path).ok().as_deref() != Some(HANDLER_API) {
+        fs::write(&api_path, HANDLER_API)?;
+        written.push(api_path);
+    }
+    fs::write(&handler_path, handler)?;
+    written.push(handler_path);
+    fs::create_dir_all(test_dir.join(name))?;
+    fs::write(&test_path, toml::to_string_pretty(&test)?)?;
+    written.push(test_path);
+    Ok(written)
+}
+
+/// `.shelly` directories in `working_dir` and its parents, nearest first, up to but not
+/// including the home directory
+fn project_dirs(working_dir: &Path) -> Vec<PathBuf> {
+    let home_dir = dirs::home_dir();
+    let working_dir =
+        std::path::absolute(working_dir).unwrap_or_else(|_| working_dir.to_path_buf());
+    working_dir
+        .ancestors()
+        .take_while(|dir| Some(*dir) != home_dir.as_deref())
+        .map(|dir| dir.join(".shelly"))
+        .filter(|dir| dir.is_dir())
+        .collect()
+}
+
+/// Handler file names to look for: the command itself, then the command joined with
+/// each longer run of leading subcommands (`go`, `go-test`). Stops at the first
+/// argument that isn't a plain word, such as a flag or a path.
+fn subcommand_names<'a>(cmd_name: &str, args: impl Iterator<Item = &'a str>) -> Vec<String> {
+    let mut names = vec![cmd_name.to_string()];
+    let mut name = cmd_name.to_string();
+    for arg in args {
+        let plain = !arg.starts_with('-')
+            && arg.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+        if !plain {
+            break;
+        }
+        name = format!("{name}-{arg}");
+        names.push(name.clone());
+    }
+    names
+}
 
-    Ok(None)
+/// Handler files for `cmd_name` in `dir`: TypeScript first, then declarative
+fn handler_files(dir: &Path, cmd_name: &str) -> Vec<PathBuf> {
+    HANDLER_EXTENSIONS
+        .iter()
+        .map(|ext| dir.join(format!("{cmd_name}.{ext}")))
+        .filter(|path| path.exists())
+        .collect()
 }
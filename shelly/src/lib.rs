@@ -1,20 +1,56 @@
+use anyhow::Context;
+use futures::stream::{BoxStream, Stream, StreamExt};
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use tokio::sync::mpsc;
 use tokio::time::Duration;
 
-use crate::process_manager::ProcessState;
+use crate::process_manager::{floor_char_boundary, ProcessState};
+
+/// Bytes of each stream returned in an `ExecutionResult`
+pub const RAW_OUTPUT_LIMIT: usize = 16 * 1024;
+
+/// Lines, and at most bytes, of raw output previewed while a command is still running
+const PREVIEW_LINES: usize = 10;
+const PREVIEW_BYTES: usize = 1024;
+
+/// Set to anything but `0` to run every command as if it were `exact`, e.g. to rule
+/// handlers out while debugging
+pub const DISABLE_HANDLERS_ENV: &str = "SHELLY_DISABLE_HANDLERS";
+
+/// Whether `SHELLY_DISABLE_HANDLERS` turns handlers off for every command. Warns the
+/// first time it does.
+pub fn handlers_disabled() -> bool {
+    static WARNED: std::sync::Once = std::sync::Once::new();
+    let disabled = std::env::var_os(DISABLE_HANDLERS_ENV)
+        .is_some_and(|value| !value.is_empty() && value != "0");
+    if disabled {
+        WARNED.call_once(|| {
+            tracing::warn!("{DISABLE_HANDLERS_ENV} is set; every command runs without handlers")
+        });
+    }
+    disabled
+}
 
 pub mod executor;
 pub mod handler;
+pub mod metrics;
 pub mod output;
 pub mod process_manager;
+#[cfg(unix)]
+mod pty;
+#[cfg(windows)]
+#[path = "pty_windows.rs"]
+mod pty;
+pub mod redact;
+mod registry;
 pub mod runtime;
 pub mod streaming_executor;
 pub mod testing;
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct ExecuteRequest {
     pub cmd: String,
     pub args: Vec<String>,
@@ -22,6 +58,66 @@ pub struct ExecuteRequest {
     pub exact: bool,
     pub working_dir: PathBuf,
     pub env: HashMap<String, String>,
+    /// Whether the command inherits shelly's environment, and which variables it doesn't
+    #[serde(default)]
+    pub env_policy: streaming_executor::EnvPolicy,
+    /// Terminate the command instead of leaving it running when the timeout elapses
+    #[serde(default)]
+    pub kill_on_timeout: bool,
+    /// Cancel the command once it has run this long, whether or not anyone is still
+    /// waiting for it. Unlike the timeout, this also applies after it is left running.
+    #[serde(default)]
+    pub max_runtime_ms: Option<u64>,
+    /// Kill the command if it goes this long without printing anything, and report it
+    /// as failed. Catches commands stuck on a lock or a network call.
+    #[serde(default)]
+    pub idle_timeout_ms: Option<u64>,
+    /// Use this handler, by name or path, instead of the ones found for the command's
+    /// name. Its `matches` still has to accept the command. Ignored with `exact`.
+    #[serde(default)]
+    pub handler: Option<String>,
+    /// Directory for output files; defaults to `SHELLY_OUTPUT_DIR` or the temp dir.
+    /// Shelly only ever removes its own `<label>-<timestamp>.txt` files from it, so it
+    /// can be a directory that holds other files too.
+    #[serde(default)]
+    pub output_dir: Option<PathBuf>,
+    /// Whether to keep ANSI escape codes in the output file; summaries are always stripped
+    #[serde(default)]
+    pub ansi: output::AnsiMode,
+    /// Run the command under a pseudo-terminal, for tools that change their output or
+    /// refuse to run without one. stderr is merged into stdout.
+    #[serde(default)]
+    pub pty: bool,
+    /// Only load the handler and run `prepare`, reporting the command that would run
+    /// instead of running it
+    #[serde(default)]
+    pub dry_run: bool,
+    /// Environment variables whose values are masked in the output file and summaries,
+    /// `*` matching any run of characters. Defaults to `redact::default_sensitive_env()`.
+    #[serde(default)]
+    pub redact_env: Option<Vec<String>>,
+    /// Roughly how many tokens the summary may take; handlers are told, and a longer
+    /// final summary is cut. Defaults to `runtime::default_max_summary_tokens()`.
+    #[serde(default)]
+    pub max_summary_tokens: Option<usize>,
+    /// Run the command through `$SHELL -c` (`/bin/sh`, or `cmd /C` on Windows), joined
+    /// into one string, so pipes, `&&`, globs and `$VAR` work. Anything in the arguments
+    /// is then interpreted by the shell, so only set this for commands you would type
+    /// yourself.
+    #[serde(default)]
+    pub shell: bool,
+    /// Collapse runs of identical lines, and cap how often one line repeats, when the
+    /// output is passed through without a handler
+    #[serde(default)]
+    pub dedupe: bool,
+    /// CPU time and memory caps for the command, enforced on Unix only. Unlimited by
+    /// default.
+    #[serde(default)]
+    pub limits: streaming_executor::ResourceLimits,
+    /// Written to the command's stdin, which is then closed, like `cmd < file`. Input
+    /// can't be sent later with `send_input`.
+    #[serde(default)]
+    pub stdin: Option<String>,
 }
 
 impl ExecuteRequest {
@@ -54,144 +150,682 @@ impl ExecutedCommand {
     }
 }
 
-/// Execute a command with streaming support and timeout handling
-pub async fn execute_command_streaming(
-    request: ExecuteRequest,
-    process_manager: Arc<process_manager::ProcessManager>,
-    timeout_duration: Duration,
-) -> anyhow::Result<ExecutionResult> {
+/// What `start_command` did with a request
+enum Started {
+    /// Nothing was run: the handler rejected the command, or it was a dry run
+    Finished(Box<ExecutionResult>),
+    Spawned {
+        process_id: process_manager::ProcessId,
+        executed_command: ExecutedCommand,
+        output_file: PathBuf,
+        /// The requested command, if a handler changed which program runs
+        original_command: Option<String>,
+    },
+}
+
+/// Load the command's handlers, let them rewrite it, and spawn it
+async fn start_command(
+    request: &ExecuteRequest,
+    process_manager: &Arc<process_manager::ProcessManager>,
+) -> anyhow::Result<Started> {
     let command = request.command();
     let settings = &request.settings;
-    let exact = request.exact;
-
-    // Clean up old output files
-    let _ = output::cleanup_old_files();
-
-    // Create output file
-    let output_file = output::create_output_file(&command)?;
-
-    // Find and load handler (if not exact mode)
-    let (final_cmd, final_args, handler_env, rt) = if exact {
-        (
-            request.cmd.clone(),
-            request.args.clone(),
-            HashMap::new(),
-            None,
-        )
-    } else if let Some(handler_path) = handler::find_handler(&command)? {
-        tracing::info!("found custom hanlder for {command} @ {handler_path:?}");
-        let mut rt = runtime::HandlerRuntime::new()?;
-        rt.load_handler(handler_path.to_str().unwrap()).await?;
-
-        if rt.matches(&request.cmd, &request.args).await? {
-            tracing::info!("Found custom handler for {command}");
-            rt.create_handler(&request.cmd, &request.args, &settings)
-                .await?;
-            let prep = rt.prepare().await?;
+    let exact = request.exact || handlers_disabled();
+
+    // Find and load handlers (if not exact mode)
+    let handler_paths = match &request.handler {
+        _ if exact => Vec::new(),
+        Some(name) => vec![handler::resolve_handler(name, &request.working_dir)
+            .context(runtime::HandlerError)?],
+        None => handler::find_handler(&command, &request.working_dir)
+            .context(runtime::HandlerError)?,
+    };
+    let chain = if handler_paths.is_empty() {
+        None
+    } else {
+        tracing::info!("found handlers for {command} @ {handler_paths:?}");
+        let context = runtime::HandlerContext {
+            working_dir: request.working_dir.clone(),
+            env: request.env.clone(),
+            max_summary_tokens: request
+                .max_summary_tokens
+                .unwrap_or_else(runtime::default_max_summary_tokens),
+        };
+        runtime::HandlerChain::load_cached(
+            &handler_paths,
+            &request.cmd,
+            &request.args,
+            settings,
+            &context,
+            &process_manager.handler_cache,
+        )
+        .await
+        .context(runtime::HandlerError)?
+    };
+    if let (Some(name), None, false) = (&request.handler, &chain, exact) {
+        return Err(anyhow::anyhow!("handler {name:?} does not match {command}"))
+            .context(runtime::HandlerError);
+    }
+    if let Some((_, runtime::PrepareResult { reject: Some(rejection), .. })) = &chain {
+        tracing::info!("handler rejected {command}: {}", rejection.reason);
+        return Ok(Started::Finished(Box::new(ExecutionResult {
+            summary: format!("Command rejected by handler: {}", rejection.reason),
+            // Nothing ran, so nothing was written
+            output_file: String::new(),
+            output_file_relative: None,
+            // What a shell reports for a command it found but could not execute
+            exit_code: 126,
+            is_error: true,
+            exit_code_meaning: None,
+            truncated: false,
+            truncation_reason: None,
+            truncation_description: None,
+            executed_command: ExecutedCommand {
+                cmd: request.cmd.clone(),
+                args: request.args.clone(),
+                env: request.env.clone(),
+                working_dir: request.working_dir.clone(),
+            },
+            process_id: None,
+            is_running: false,
+            available_actions: vec![],
+            stdout: String::new(),
+            stderr: String::new(),
+            raw_output_truncated: false,
+            command_rewritten: false,
+            original_command: None,
+            sections: None,
+            output_preview: None,
+        })));
+    }
+    let (final_cmd, final_args, handler_env, output_label, rt) = match chain {
+        Some((chain, prep)) => {
             tracing::info!("Command has changed command to be: {prep:?}");
-            (prep.cmd, prep.args, prep.env, Some(rt))
-        } else {
+            (prep.cmd, prep.args, prep.env, prep.output_label, Some(chain))
+        }
+        None => {
             tracing::info!("no custom handler for {command}");
             (
                 request.cmd.clone(),
                 request.args.clone(),
                 HashMap::new(),
                 None,
+                None,
             )
         }
-    } else {
-        (
-            request.cmd.clone(),
-            request.args.clone(),
-            HashMap::new(),
-            None,
-        )
     };
 
     // Merge env vars: start with agent's, then handler's (handler wins)
     let mut final_env = request.env.clone();
     final_env.extend(handler_env);
 
+    // Swapping the program, rather than adding flags, changes what the command does
+    let original_command = program_changed(&request.cmd, &final_cmd).then(|| command.clone());
+    if original_command.is_some() {
+        tracing::warn!("a handler rewrote {command} to run {final_cmd} instead");
+    }
+
+    let (final_cmd, final_args) = if request.shell {
+        shell_command(&final_cmd, &final_args)
+    } else {
+        (final_cmd, final_args)
+    };
+    if request.dry_run {
+        let mut result = dry_run_result(ExecutedCommand {
+            cmd: final_cmd,
+            args: final_args,
+            env: final_env,
+            working_dir: request.working_dir.clone(),
+        });
+        result.command_rewritten = original_command.is_some();
+        result.original_command = original_command;
+        return Ok(Started::Finished(Box::new(result)));
+    }
+
+    let sensitive_env = request
+        .redact_env
+        .clone()
+        .unwrap_or_else(redact::default_sensitive_env);
+    let redactor =
+        redact::Redactor::for_command_env(&sensitive_env, &final_env, request.env_policy.inherit);
+
+    // Create output file. Stale ones are swept by the process manager.
+    let output_dir = request.output_dir.as_deref();
+    let output_file = output::create_output_file(output_dir, &command, output_label.as_deref())?;
+
     // Execute with streaming
     let streaming_config = streaming_executor::StreamingExecutorConfig {
         cmd: final_cmd.clone(),
         args: final_args.clone(),
         env: final_env.clone(),
+        env_policy: request.env_policy.clone(),
         working_dir: request.working_dir.clone(),
         update_interval: Duration::from_millis(500), // Update every 500ms
         handler: rt,
         output_file: output_file.clone(),
+        ansi: request.ansi,
+        pty: request.pty,
+        redactor,
+        dedupe: request.dedupe,
+        limits: request.limits,
+        stdin: request.stdin.clone(),
+        idle_timeout: request.idle_timeout_ms.map(Duration::from_millis),
     };
 
     let process_id = streaming_executor::spawn(streaming_config, process_manager.clone()).await?;
-    let status = process_manager
-        .join_process(&process_id, timeout_duration)
+    if let Some(max_runtime_ms) = request.max_runtime_ms {
+        tokio::spawn(enforce_max_runtime(
+            process_manager.clone(),
+            process_id.clone(),
+            Duration::from_millis(max_runtime_ms),
+        ));
+    }
+    Ok(Started::Spawned {
+        process_id,
+        executed_command: ExecutedCommand {
+            cmd: final_cmd,
+            args: final_args,
+            env: final_env,
+            working_dir: request.working_dir.clone(),
+        },
+        output_file,
+        original_command,
+    })
+}
+
+/// Execute a command with streaming support and timeout handling
+pub async fn execute_command_streaming(
+    request: ExecuteRequest,
+    process_manager: Arc<process_manager::ProcessManager>,
+    timeout_duration: Duration,
+) -> anyhow::Result<ExecutionResult> {
+    execute_command_streaming_with_progress(request, process_manager, timeout_duration, None)
+        .await
+}
+
+/// Like `execute_command_streaming`, but while waiting out the timeout each new piece
+/// of the summary is also sent to `progress` as it is produced
+pub async fn execute_command_streaming_with_progress(
+    request: ExecuteRequest,
+    process_manager: Arc<process_manager::ProcessManager>,
+    timeout_duration: Duration,
+    progress: Option<mpsc::UnboundedSender<process_manager::ProcessUpdate>>,
+) -> anyhow::Result<ExecutionResult> {
+    let command = request.command();
+    let (process_id, executed_command, output_file, original_command) =
+        match start_command(&request, &process_manager).await? {
+            Started::Finished(result) => return Ok(*result),
+            Started::Spawned {
+                process_id,
+                executed_command,
+                output_file,
+                original_command,
+            } => (process_id, executed_command, output_file, original_command),
+        };
+    let progress = progress.map(|progress| {
+        tokio::spawn(forward_progress(
+            process_manager.clone(),
+            process_id.clone(),
+            progress,
+        ))
+    });
+    let mut status = process_manager
+        .join_process(&process_id, timeout_duration, None)
         .await
         .expect("we just started it, it should be running");
-    let executed_command = ExecutedCommand {
-        cmd: final_cmd,
-        args: final_args,
-        env: final_env,
-        working_dir: request.working_dir,
+    if let Some(progress) = progress {
+        progress.abort();
+    }
+
+    let timed_out = request.kill_on_timeout && !status.status.is_finished();
+    if timed_out {
+        tracing::info!("{command} exceeded its {timeout_duration:?} timeout, cancelling");
+        process_manager.mark_timed_out(&process_id).await;
+        process_manager.cancel_process(&process_id).await;
+        let remaining = process_manager
+            .join_process(&process_id, Duration::ZERO, None)
+            .await
+            .expect("cancelled processes are still tracked");
+        status.incremental_summary.push_str(&remaining.incremental_summary);
+        status.status = remaining.status;
+    }
+    // What the handler filtered out explains the summary better than the buffer limit
+    let handler_truncation = status.truncation.take().filter(|t| t.truncated);
+    let truncated = status.output_truncated || handler_truncation.is_some();
+    let (truncation_reason, truncation_description) = match handler_truncation {
+        Some(truncation) => (
+            Some(truncation.reason.unwrap_or_else(|| "handler".to_string())),
+            truncation.description,
+        ),
+        None if status.output_truncated => (Some("output_limit".to_string()), None),
+        None => (None, None),
+    };
+    let info = process_manager
+        .get_process_status(&process_id)
+        .await
+        .expect("processes are tracked until they expire");
+    // Cancelled for running too long, either here or by the max runtime
+    let (timed_out, time_limit) = match request.max_runtime_ms {
+        Some(max_runtime_ms) if !timed_out && info.timed_out => {
+            (true, Duration::from_millis(max_runtime_ms))
+        }
+        _ => (timed_out, timeout_duration),
+    };
+    let (stdout, stdout_cut) = tail_bytes(&info.raw_stdout, RAW_OUTPUT_LIMIT);
+    let (stderr, stderr_cut) = tail_bytes(&info.raw_stderr, RAW_OUTPUT_LIMIT);
+    let (stdout, stderr) = (stdout.to_string(), stderr.to_string());
+    let raw_output_truncated = stdout_cut || stderr_cut || info.omitted_bytes > 0;
+    let custom_actions: Vec<ProcessAction> = std::mem::take(&mut status.actions)
+        .into_iter()
+        .map(ProcessAction::from)
+        .collect();
+    let (is_error, exit_code_meaning) = (status.is_error, status.exit_code_meaning);
+    let sections = status.sections.take();
+    let output_preview = if status.status == ProcessState::Running {
+        process_manager
+            .head_output(&process_id, PREVIEW_LINES)
+            .await
+            .filter(|head| !head.is_empty())
+            .map(|mut head| {
+                head.truncate(floor_char_boundary(&head, PREVIEW_BYTES));
+                head
+            })
+    } else {
+        None
     };
 
-    // If command timed out, return partial results with process info
-    Ok(match status.status {
-        ProcessState::Running => ExecutionResult {
-            summary: format!(
-                "Command is still running - use join_process to continue monitoring\n{}",
-                status.incremental_summary
+    let is_running = !status.status.is_finished();
+    let still_running_actions = [
+        ProcessAction::Join,
+        ProcessAction::Cancel,
+        ProcessAction::Status,
+    ];
+    let timeout_truncation = (Some("timeout".to_string()), truncation_description.clone());
+    // Only the summary, exit code, actions and truncation depend on how the process ended
+    let (summary, exit_code, available_actions, (truncation_reason, truncation_description)) =
+        match status.status {
+            ProcessState::Queued => (
+                "Command is queued behind other running processes - use join_process to \
+                 continue monitoring\n"
+                    .to_string(),
+                -1,
+                still_running_actions.to_vec(),
+                timeout_truncation,
             ),
-            output_file: output_file.to_string_lossy().to_string(),
-            exit_code: -1,
-            truncated: false,
-            truncation_reason: Some("timeout".to_string()),
-            executed_command,
-            process_id: Some(process_id),
-            is_running: true,
-            available_actions: vec![
-                ProcessAction::Join,
-                ProcessAction::Cancel,
-                ProcessAction::Status,
-            ],
-        },
-        ProcessState::Completed { exit_code } => ExecutionResult {
-            summary: status.incremental_summary,
-            output_file: output_file.to_string_lossy().to_string(),
-            exit_code,
-            truncated: false,
-            truncation_reason: Some("ignore".to_string()),
-            executed_command,
-            process_id: Some(process_id),
-            is_running: false,
-            available_actions: vec![],
-        },
-        ProcessState::Failed { error } => ExecutionResult {
-            summary: format!("Command failed: {}", error),
-            output_file: output_file.to_string_lossy().to_string(),
-            exit_code: 127,
-            truncated: false,
-            truncation_reason: None,
-            executed_command,
-            process_id: Some(process_id),
-            is_running: false,
-            available_actions: vec![],
-        },
-        ProcessState::Cancelled => ExecutionResult {
-            summary: "Command was cancelled".to_string(),
-            output_file: output_file.to_string_lossy().to_string(),
-            exit_code: 130,
-            truncated: false,
-            truncation_reason: None,
-            executed_command,
-            process_id: Some(process_id),
-            is_running: false,
-            available_actions: vec![],
-        },
+            // If command timed out, return partial results with process info
+            ProcessState::Running => (
+                match &output_preview {
+                    // Nothing summarized yet, so show what the command printed instead
+                    Some(preview) if status.incremental_summary.trim().is_empty() => format!(
+                        "Command is still running - use join_process to continue monitoring\n\
+                         Output so far (preview, first {PREVIEW_LINES} lines at most):\n{preview}"
+                    ),
+                    _ => format!(
+                        "Command is still running - use join_process to continue monitoring\n{}",
+                        status.incremental_summary
+                    ),
+                },
+                -1,
+                still_running_actions.into_iter().chain(custom_actions).collect(),
+                timeout_truncation,
+            ),
+            ProcessState::Completed { exit_code } => (
+                status.incremental_summary,
+                exit_code,
+                custom_actions,
+                (truncation_reason, truncation_description),
+            ),
+            ProcessState::Signaled { signal } => (
+                format!(
+                    "{}Command terminated by {}{}\n",
+                    status.incremental_summary,
+                    streaming_executor::signal_name(signal),
+                    request
+                        .limits
+                        .explain(signal)
+                        .map(|reason| format!(" ({reason})"))
+                        .unwrap_or_default()
+                ),
+                128 + signal,
+                custom_actions,
+                (truncation_reason, truncation_description),
+            ),
+            ProcessState::Failed { error } => (
+                format!("Command failed: {}", error),
+                127,
+                vec![],
+                (None, None),
+            ),
+            ProcessState::Cancelled if timed_out => (
+                format!(
+                    "Command timed out after {:?} and was cancelled\n{}",
+                    time_limit, status.incremental_summary
+                ),
+                130,
+                vec![],
+                timeout_truncation,
+            ),
+            ProcessState::Cancelled => (
+                format!("Command was cancelled\n{}", status.incremental_summary),
+                130,
+                vec![],
+                (None, None),
+            ),
+        };
+
+    let result = ExecutionResult {
+        summary,
+        output_file: output_file.to_string_lossy().to_string(),
+        output_file_relative: relative_path(&output_file, &executed_command.working_dir),
+        exit_code,
+        is_error,
+        exit_code_meaning,
+        truncated,
+        truncation_reason,
+        truncation_description,
+        executed_command,
+        process_id: Some(process_id),
+        is_running,
+        available_actions,
+        stdout,
+        stderr,
+        raw_output_truncated,
+        command_rewritten: original_command.is_some(),
+        original_command,
+        sections,
+        output_preview,
+    };
+    Ok(result)
+}
+
+/// A running command's updates, from `execute_command_stream`
+pub struct CommandStream {
+    pub process_id: process_manager::ProcessId,
+    /// Path to the file containing the full output
+    pub output_file: PathBuf,
+    /// The command after the handlers rewrote it
+    pub executed_command: ExecutedCommand,
+    updates: BoxStream<'static, process_manager::ProcessUpdate>,
+}
+
+impl Stream for CommandStream {
+    type Item = process_manager::ProcessUpdate;
+
+    fn poll_next(
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Option<Self::Item>> {
+        self.updates.poll_next_unpin(cx)
+    }
+}
+
+/// Start a command and stream its summary instead of joining it: an update with the new
+/// part of the summary each time it grows, then a last one with the final status once
+/// the command has finished. Dropping the stream leaves the command running; cancel it
+/// through `process_manager`. Requests that don't run anything (a dry run, or a command
+/// the handler rejects) are an error, since there is nothing to stream.
+pub async fn execute_command_stream(
+    request: ExecuteRequest,
+    process_manager: Arc<process_manager::ProcessManager>,
+) -> anyhow::Result<CommandStream> {
+    let (process_id, executed_command, output_file) =
+        match start_command(&request, &process_manager).await? {
+            Started::Finished(result) => anyhow::bail!("Nothing to stream: {}", result.summary),
+            Started::Spawned {
+                process_id,
+                executed_command,
+                output_file,
+                ..
+            } => (process_id, executed_command, output_file),
+        };
+    let summary_changed = process_manager
+        .summary_signal(&process_id)
+        .await
+        .expect("we just started it, it should be tracked");
+
+    let state = Some((process_manager, process_id.clone(), summary_changed, 0));
+    let updates = futures::stream::unfold(state, |state| async move {
+        let (process_manager, process_id, mut summary_changed, cursor) = state?;
+        tokio::select! {
+            _ = summary_changed.changed() => {}
+            _ = process_manager.wait_for(&process_id) => {}
+        }
+        // An explicit cursor leaves the one shared by plain joins untouched
+        let update = process_manager
+            .join_process(&process_id, Duration::ZERO, Some(cursor))
+            .await?;
+        let next = (!update.status.is_finished())
+            .then_some((process_manager, process_id, summary_changed, update.cursor));
+        Some((update, next))
+    });
+    Ok(CommandStream {
+        process_id,
+        output_file,
+        executed_command,
+        updates: updates.boxed(),
+    })
+}
+
+/// Whether `prepared` runs a different program than `requested`, ignoring the
+/// directory it was looked up in
+fn program_changed(requested: &str, prepared: &str) -> bool {
+    let program = |cmd: &str| Path::new(cmd).file_name().map(|name| name.to_owned());
+    program(requested) != program(prepared)
+}
+
+/// `path` relative to `dir`, if it is inside it
+fn relative_path(path: &Path, dir: &Path) -> Option<String> {
+    let path = path.canonicalize().ok()?;
+    let dir = dir.canonicalize().ok()?;
+    let relative = path.strip_prefix(dir).ok()?;
+    Some(relative.to_string_lossy().to_string())
+}
+
+/// Cancel the process if it is still running after `max_runtime`
+async fn enforce_max_runtime(
+    process_manager: Arc<process_manager::ProcessManager>,
+    process_id: process_manager::ProcessId,
+    max_runtime: Duration,
+) {
+    let finished = process_manager.wait_for(&process_id);
+    if tokio::time::timeout(max_runtime, finished).await.is_err() {
+        tracing::info!("{} exceeded its {max_runtime:?} max runtime, cancelling", process_id.0);
+        process_manager.mark_timed_out(&process_id).await;
+        process_manager.cancel_process(&process_id).await;
+    }
+}
+
+/// The end of `output`, at most `max_bytes` long and starting on a new line if it can.
+/// Also returns whether anything was cut.
+fn tail_bytes(output: &str, max_bytes: usize) -> (&str, bool) {
+    if output.len() <= max_bytes {
+        return (output, false);
+    }
+    let mut start = output.len() - max_bytes;
+    while !output.is_char_boundary(start) {
+        start += 1;
+    }
+    let tail = &output[start..];
+    match tail.find('\n') {
+        Some(newline) if newline + 1 < tail.len() => (&tail[newline + 1..], true),
+        _ => (tail, true),
+    }
+}
+
+/// Send each piece of the process's summary to `progress` as it is produced
+async fn forward_progress(
+    process_manager: Arc<process_manager::ProcessManager>,
+    process_id: process_manager::ProcessId,
+    progress: mpsc::UnboundedSender<process_manager::ProcessUpdate>,
+) {
+    let Some(mut summary_changed) = process_manager.summary_signal(&process_id).await else {
+        return;
+    };
+    let mut cursor = 0;
+    while summary_changed.changed().await.is_ok() {
+        // An explicit cursor leaves the one shared by plain joins untouched
+        let Some(update) = process_manager
+            .join_process(&process_id, Duration::ZERO, Some(cursor))
+            .await
+        else {
+            return;
+        };
+        cursor = update.cursor;
+        if progress.send(update).is_err() {
+            return;
+        }
+    }
+}
+
+/// What shelly would do with a command before running it
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HandlerDescription {
+    /// Matching handler files, in chain order
+    pub handlers: Vec<PathBuf>,
+    /// Settings the matching handlers accept
+    pub settings: runtime::SettingsSchema,
+    /// The command after every handler's `prepare()`
+    pub prepared: runtime::PrepareResult,
+}
+
+/// Find the handlers that would intercept `command` and preview their effect, without
+/// running anything. Returns `None` if no handler matches.
+pub async fn describe_handler(
+    command: &str,
+    settings: &HashMap<String, serde_json::Value>,
+    working_dir: &Path,
+) -> anyhow::Result<Option<HandlerDescription>> {
+    let words = shell_words::split(command)?;
+    let Some((cmd, args)) = words.split_first() else {
+        anyhow::bail!("Empty command");
+    };
+
+    let paths = handler::find_handler(command, working_dir)?;
+    let context = runtime::HandlerContext {
+        working_dir: working_dir.to_path_buf(),
+        env: HashMap::new(),
+        max_summary_tokens: runtime::default_max_summary_tokens(),
+    };
+    let Some((mut chain, prepared)) =
+        runtime::HandlerChain::load(&paths, cmd, args, settings, &context).await?
+    else {
+        return Ok(None);
+    };
+
+    Ok(Some(HandlerDescription {
+        handlers: chain.paths().to_vec(),
+        settings: chain.settings().await?,
+        prepared,
+    }))
+}
+
+/// A handler's summary of output that was already captured
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReplayResult {
+    /// The handler file that summarized the output
+    pub handler: PathBuf,
+    /// Exit code recorded in the output file; `None` if the command hadn't finished
+    pub exit_code: Option<i32>,
+    #[serde(flatten)]
+    pub result: runtime::SummaryResult,
+}
+
+/// Run the handler `handler` (a path or the name of an installed handler) over the
+/// output already captured in `output_file`, as if it had just come from `command`, or
+/// the command recorded in the file if that is `None`. Nothing is executed. A finished
+/// command's output goes through `finalize()`, a running one's through `summarize()`.
+pub async fn replay_output(
+    output_file: &Path,
+    handler: &str,
+    command: Option<&str>,
+    settings: &HashMap<String, serde_json::Value>,
+    working_dir: &Path,
+) -> anyhow::Result<ReplayResult> {
+    let path = handler::resolve_handler(handler, working_dir)?;
+    let captured = output::parse_output_file(output_file)?;
+    let command = match (command, &captured.header) {
+        (Some(command), _) => command,
+        (None, Some(header)) => &header.command,
+        (None, None) => anyhow::bail!("The output file doesn't record its command"),
+    };
+    let words = shell_words::split(command)?;
+    let Some((cmd, args)) = words.split_first() else {
+        anyhow::bail!("Empty command");
+    };
+
+    let context = runtime::HandlerContext {
+        working_dir: working_dir.to_path_buf(),
+        env: HashMap::new(),
+        max_summary_tokens: runtime::default_max_summary_tokens(),
+    };
+    let mut rt = runtime::Handler::load(&path).await?;
+    rt.create_handler(cmd, args, settings, &context).await?;
+    rt.prepare().await?;
+    let result = match captured.exit_code {
+        Some(exit_code) => {
+            rt.finalize(exit_code, &captured.stdout, &captured.stderr)
+                .await?
+        }
+        None => {
+            rt.summarize(&captured.stdout, &captured.stderr, None)
+                .await?
+        }
+    };
+
+    Ok(ReplayResult {
+        handler: path,
+        exit_code: captured.exit_code,
+        result,
     })
 }
 
-/// Simple wrapper around execute_command_streaming for non-streaming use cases
+/// Run `cmd` and `args` through the user's shell, as one string so that its operators,
+/// globs and variables work as typed
+fn shell_command(cmd: &str, args: &[String]) -> (String, Vec<String>) {
+    let script = std::iter::once(cmd)
+        .chain(args.iter().map(String::as_str))
+        .collect::<Vec<_>>()
+        .join(" ");
+    if cfg!(windows) {
+        return ("cmd".to_string(), vec!["/C".to_string(), script]);
+    }
+    let shell = std::env::var("SHELL")
+        .ok()
+        .filter(|shell| !shell.is_empty())
+        .unwrap_or_else(|| "/bin/sh".to_string());
+    (shell, vec!["-c".to_string(), script])
+}
+
+/// The result of a dry run: the command that would have been executed, without running it
+fn dry_run_result(executed_command: ExecutedCommand) -> ExecutionResult {
+    ExecutionResult {
+        summary: format!("Dry run, would execute: {}\n", executed_command.command()),
+        output_file: String::new(),
+        output_file_relative: None,
+        // Like a command that is still running, there is no exit code yet
+        exit_code: -1,
+        is_error: false,
+        exit_code_meaning: None,
+        truncated: false,
+        truncation_reason: None,
+        truncation_description: None,
+        executed_command,
+        process_id: None,
+        is_running: false,
+        available_actions: vec![],
+        stdout: String::new(),
+        stderr: String::new(),
+        raw_output_truncated: false,
+        command_rewritten: false,
+        original_command: None,
+        sections: None,
+        output_preview: None,
+    }
+}
+
+/// Simple wrapper around execute_command_streaming for non-streaming use cases. Each
+/// call gets a manager of its own, which is dropped, cleanup task and all, once the
+/// command has finished.
 pub async fn execute_command(request: ExecuteRequest) -> anyhow::Result<ExecutionResult> {
     let process_manager = Arc::new(process_manager::ProcessManager::new());
     let timeout_duration = Duration::from_secs(30);
@@ -200,16 +834,27 @@ pub async fn execute_command(request: ExecuteRequest) -> anyhow::Result<Executio
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ExecutionResult {
-    /// Summary of the command output (max ~500 tokens)
+    /// Summary of the command output, cut to about `max_summary_tokens` tokens
     pub summary: String,
     /// Path to file containing full output
     pub output_file: String,
-    /// Exit code of the command
+    /// `output_file` relative to the working directory, when the output directory is
+    /// inside the project
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub output_file_relative: Option<String>,
+    /// Exit code of the command; -1 if it hasn't finished or was a dry run
     pub exit_code: i32,
+    /// Whether the command failed. Usually the same as a non-zero exit code, but a
+    /// handler can say an exit is expected, e.g. `grep` exiting 1 when nothing matched.
+    pub is_error: bool,
+    /// The handler's explanation of the exit code, e.g. "no matches"
+    pub exit_code_meaning: Option<String>,
     /// Whether output was truncated
     pub truncated: bool,
-    /// Reason for truncation (if any)
+    /// Reason for truncation (if any): the handler's, `output_limit` or `timeout`
     pub truncation_reason: Option<String>,
+    /// The handler's description of what it left out
+    pub truncation_description: Option<String>,
     /// The actual command that was executed
     pub executed_command: ExecutedCommand,
     /// Process ID for long-running commands (if applicable)
@@ -218,6 +863,27 @@ pub struct ExecutionResult {
     pub is_running: bool,
     /// Available actions for the client
     pub available_actions: Vec<ProcessAction>,
+    /// The command's stdout so far, at most the last `RAW_OUTPUT_LIMIT` bytes
+    pub stdout: String,
+    /// The command's stderr so far, at most the last `RAW_OUTPUT_LIMIT` bytes
+    pub stderr: String,
+    /// Whether `stdout` or `stderr` is missing output; the output file has all of it
+    pub raw_output_truncated: bool,
+    /// Whether a handler changed which program runs, e.g. `echo` to `rm`, rather than
+    /// just its arguments
+    #[serde(default)]
+    pub command_rewritten: bool,
+    /// The command as requested, when `command_rewritten` is set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub original_command: Option<String>,
+    /// The handler's summary split into errors, warnings, stats and tail, if it
+    /// returned one. `summary` still has everything.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sections: Option<runtime::SummarySections>,
+    /// The first lines of raw output while the command is still running, so the caller
+    /// can tell whether it started correctly before the handler has summarized anything
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub output_preview: Option<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
@@ -225,11 +891,23 @@ pub enum ProcessAction {
     Join,   // Continue waiting with updates
     Cancel, // Cancel the running process
     Status, // Get current status
+    /// Offered by the command's handler, e.g. restarting a dev server
+    Custom { name: String, description: String },
+}
+
+impl From<runtime::HandlerAction> for ProcessAction {
+    fn from(action: runtime::HandlerAction) -> Self {
+        Self::Custom {
+            name: action.name,
+            description: action.description,
+        }
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::collections::HashSet;
 
     #[tokio::test]
     async fn test_basic_runtime() {
@@ -244,7 +922,7 @@ mod tests {
 
         // Create handler instance
         let settings = HashMap::new();
-        rt.create_handler("cargo", &["build".to_string()], &settings)
+        rt.create_handler("cargo", &["build".to_string()], &settings, &Default::default())
             .await
             .unwrap();
 
@@ -259,7 +937,7 @@ mod tests {
         rt.load_handler("handlers/cargo.ts").await.unwrap();
 
         let settings = HashMap::new();
-        rt.create_handler("cargo", &["build".to_string()], &settings)
+        rt.create_handler("cargo", &["build".to_string()], &settings, &Default::default())
             .await
             .unwrap();
         rt.prepare().await.unwrap();
@@ -278,98 +956,1856 @@ mod tests {
     }
 
     #[tokio::test]
-    async fn test_execute_command_error() {
-        let request = ExecuteRequest {
-            cmd: "ccccccargo".to_string(),
-            args: vec!["--version".to_string()],
-            settings: HashMap::new(),
-            exact: false,
-            working_dir: std::env::current_dir().unwrap(),
-            env: HashMap::new(),
-        };
-        let result = execute_command(request).await.unwrap();
-        assert_eq!(result.exit_code, 127);
-        assert!(!result.output_file.is_empty());
-        assert!(!result.summary.is_empty());
-    }
+    async fn test_finalize_falls_back_to_summarize() {
+        let dir = tempfile::tempdir().unwrap();
+        let finalizing = dir.path().join("finalizing.ts");
+        std::fs::write(
+            &finalizing,
+            r#"
+            export const finalizingHandler = {
+              matches: (cmd: string) => cmd === "tool",
+              create: (cmd: string, args: string[]) => ({
+                prepare: () => ({ cmd, args, env: {} }),
+                summarize: () => ({ summary: "delta" }),
+                finalize: (exitCode: number, stdout: string) =>
+                  ({ summary: `exit ${exitCode}: ${stdout}` }),
+              }),
+              settings: () => ({}),
+            };
+            "#,
+        )
+        .unwrap();
+        let legacy = dir.path().join("legacy.ts");
+        std::fs::write(
+            &legacy,
+            r#"
+            export const legacyHandler = {
+              matches: (cmd: string) => cmd === "tool",
+              create: (cmd: string, args: string[]) => ({
+                prepare: () => ({ cmd, args, env: {} }),
+                summarize: (stdout: string, stderr: string, exitCode: number | null) =>
+                  ({ summary: `summarize ${exitCode}: ${stdout}` }),
+              }),
+              settings: () => ({}),
+            };
+            "#,
+        )
+        .unwrap();
 
-    #[tokio::test]
-    async fn test_execute_command_with_handler() {
-        let request = ExecuteRequest {
-            cmd: "cargo".to_string(),
-            args: vec!["--version".to_string()],
-            settings: HashMap::new(),
-            exact: false,
-            working_dir: std::env::current_dir().unwrap(),
-            env: HashMap::new(),
-        };
+        for (path, expected) in [(finalizing, "exit 2: out"), (legacy, "summarize 2: out")] {
+            let mut rt = runtime::HandlerRuntime::new().unwrap();
+            rt.load_handler(path.to_str().unwrap()).await.unwrap();
+            rt.create_handler("tool", &[], &HashMap::new(), &Default::default())
+                .await
+                .unwrap();
+            rt.prepare().await.unwrap();
 
-        let result = execute_command(request).await.unwrap();
-        assert_eq!(result.exit_code, 0);
-        assert!(!result.output_file.is_empty());
-        assert!(!result.summary.is_empty());
+            let result = rt.finalize(2, "out", "").await.unwrap();
+            assert_eq!(result.summary.as_deref(), Some(expected));
+        }
     }
 
     #[tokio::test]
-    async fn test_execute_command_exact_mode() {
-        let request = ExecuteRequest {
-            cmd: "echo".to_string(),
-            args: vec!["hello".to_string()],
-            settings: HashMap::new(),
-            exact: true,
-            working_dir: std::env::current_dir().unwrap(),
-            env: HashMap::new(),
+    async fn test_handler_reads_working_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        let project = dir.path().join("project");
+        std::fs::create_dir(&project).unwrap();
+        std::fs::write(project.join("package.json"), r#"{"name": "demo"}"#).unwrap();
+        std::fs::write(dir.path().join("secret.txt"), "hunter2").unwrap();
+        let handler_path = dir.path().join("reading.ts");
+        std::fs::write(
+            &handler_path,
+            r#"
+            export const readingHandler = {
+              matches: () => true,
+              create: (cmd: string, args: string[], settings: any, context: any) => {
+                const pkg = JSON.parse(context.readFile("package.json"));
+                let escaped = "read";
+                try { context.readFile("../secret.txt"); } catch { escaped = "denied"; }
+                return {
+                  prepare: () => ({ cmd, args, env: {} }),
+                  summarize: () => ({ summary: `${pkg.name} ${context.env.MODE} ${escaped}` }),
+                };
+              },
+              settings: () => ({}),
+            };
+            "#,
+        )
+        .unwrap();
+
+        let context = runtime::HandlerContext {
+            working_dir: project,
+            env: HashMap::from([("MODE".to_string(), "ci".to_string())]),
+            ..Default::default()
         };
+        let mut rt = runtime::HandlerRuntime::new().unwrap();
+        rt.load_handler(handler_path.to_str().unwrap()).await.unwrap();
+        rt.create_handler("npm", &[], &HashMap::new(), &context)
+            .await
+            .unwrap();
 
-        let result = execute_command(request).await.unwrap();
-        assert_eq!(result.exit_code, 0);
-        assert!(result.summary.contains("hello"));
+        let result = rt.summarize("", "", None).await.unwrap();
+        assert_eq!(result.summary.as_deref(), Some("demo ci denied"));
     }
 
     #[tokio::test]
-    async fn test_output_file_creation() {
-        let request = ExecuteRequest {
-            cmd: "echo".to_string(),
-            args: vec!["test".to_string(), "output".to_string()],
-            settings: HashMap::new(),
-            exact: true,
-            working_dir: std::env::current_dir().unwrap(),
-            env: HashMap::new(),
-        };
+    async fn test_granted_capabilities_fetch_and_write() {
+        use std::io::{BufRead, Write};
 
-        let result = execute_command(request).await.unwrap();
+        // Answers a single request
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/status", listener.local_addr().unwrap());
+        std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = std::io::BufReader::new(&stream);
+            let mut line = String::new();
+            while reader.read_line(&mut line).unwrap() > 2 {
+                line.clear();
+            }
+            let response = "HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\ngreen";
+            (&stream).write_all(response.as_bytes()).unwrap();
+        });
 
-        // Check that output file was created and contains content
-        assert!(!result.output_file.is_empty());
-        let output_content = std::fs::read_to_string(&result.output_file).unwrap();
-        assert!(output_content.contains("test output"));
+        let dir = tempfile::tempdir().unwrap();
+        let project = dir.path().join("project");
+        std::fs::create_dir(&project).unwrap();
+        let handler_path = dir.path().join("granted.ts");
+        std::fs::write(
+            &handler_path,
+            r#"
+            export const grantedHandler = {
+              matches: () => true,
+              create: (cmd: string, args: string[], settings: any, context: any) => {
+                const response = context.fetch(context.env.STATUS_URL);
+                context.writeFile("status.txt", response.body);
+                let escaped = "written";
+                try { context.writeFile("../escaped.txt", "x"); } catch { escaped = "denied"; }
+                return {
+                  prepare: () => ({ cmd, args, env: {} }),
+                  summarize: () => ({ summary: `${response.status} ${response.body} ${escaped}` }),
+                };
+              },
+              settings: () => ({}),
+            };
+            "#,
+        )
+        .unwrap();
+
+        let context = runtime::HandlerContext {
+            working_dir: project.clone(),
+            env: HashMap::from([("STATUS_URL".to_string(), url)]),
+            ..Default::default()
+        };
+        let capabilities = runtime::HandlerCapabilities {
+            network: true,
+            fs_write: true,
+        };
+        let mut rt = runtime::HandlerRuntime::with_capabilities(capabilities).unwrap();
+        rt.load_handler(handler_path.to_str().unwrap()).await.unwrap();
+        rt.create_handler("make", &[], &HashMap::new(), &context)
+            .await
+            .unwrap();
+
+        let result = rt.summarize("", "", None).await.unwrap();
+        assert_eq!(result.summary.as_deref(), Some("200 green denied"));
+        assert_eq!(std::fs::read_to_string(project.join("status.txt")).unwrap(), "green");
+        assert!(!dir.path().join("escaped.txt").exists());
     }
 
     #[tokio::test]
-    async fn test_streaming_output_file_creation() {
-        let process_manager = Arc::new(process_manager::ProcessManager::new());
+    async fn test_capabilities_are_denied_by_default() {
+        let dir = tempfile::tempdir().unwrap();
+        let handler_path = dir.path().join("denied.ts");
+        std::fs::write(
+            &handler_path,
+            r#"
+            export const deniedHandler = {
+              matches: () => true,
+              create: (cmd: string, args: string[], settings: any, context: any) => {
+                const ops = Deno.core.ops;
+                const found = [
+                  context.fetch,
+                  context.writeFile,
+                  ops.op_shelly_fetch,
+                  ops.op_shelly_write_file,
+                  globalThis.fetch,
+                ].map((value) => typeof value);
+                return {
+                  prepare: () => ({ cmd, args, env: {} }),
+                  summarize: () => ({ summary: found.join(" ") }),
+                };
+              },
+              settings: () => ({}),
+            };
+            "#,
+        )
+        .unwrap();
 
-        let request = ExecuteRequest {
-            cmd: "echo".to_string(),
-            args: vec!["streaming".to_string(), "test".to_string()],
-            settings: HashMap::new(),
-            exact: true,
-            working_dir: std::env::current_dir().unwrap(),
-            env: HashMap::new(),
+        let context = runtime::HandlerContext {
+            working_dir: dir.path().to_path_buf(),
+            ..Default::default()
         };
+        let mut rt = runtime::HandlerRuntime::new().unwrap();
+        rt.load_handler(handler_path.to_str().unwrap()).await.unwrap();
+        rt.create_handler("make", &[], &HashMap::new(), &context)
+            .await
+            .unwrap();
 
-        let result = execute_command_streaming(
-            request,
-            process_manager,
-            Duration::from_secs(30), // Long timeout so it completes
+        let result = rt.summarize("", "", None).await.unwrap();
+        assert_eq!(
+            result.summary.as_deref(),
+            Some("undefined undefined undefined undefined undefined")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_throwing_handler_falls_back_to_passthrough() {
+        let dir = tempfile::tempdir().unwrap();
+        let handler_path = dir.path().join("throwing.ts");
+        std::fs::write(
+            &handler_path,
+            r#"
+            export const throwingHandler = {
+              matches: (cmd: string) => cmd === "throwing",
+              create: (cmd: string, args: string[]) => ({
+                prepare: () => ({ cmd, args, env: {} }),
+                summarize: () => { throw new Error("boom"); },
+              }),
+              settings: () => ({}),
+            };
+            "#,
         )
-        .await
         .unwrap();
 
-        dbg!(&result);
-        // Check that output file was created and contains content
-        assert!(!result.output_file.is_empty());
-        let output_content = std::fs::read_to_string(&result.output_file).unwrap();
-        assert!(output_content.contains("streaming test"));
+        let mut rt = runtime::HandlerRuntime::new().unwrap();
+        rt.load_handler(handler_path.to_str().unwrap()).await.unwrap();
+        rt.create_handler("throwing", &[], &HashMap::new(), &Default::default())
+            .await
+            .unwrap();
+        rt.prepare().await.unwrap();
+
+        let err = rt.summarize("out\n", "", None).await.unwrap_err();
+        assert!(err.to_string().contains("boom"), "{err:#}");
+
+        // `process` passes the output through instead
+        let chain = runtime::HandlerChain::from(rt);
+        let result = runtime::process("out\n", "err\n", &Some(chain), false).await;
+        assert_eq!(result.summary.as_deref(), Some("out\nerr\n"));
+    }
+
+    #[tokio::test]
+    async fn test_handler_can_reject_command() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("guarded.ts");
+        std::fs::write(
+            &path,
+            r#"
+            export const guardedHandler = {
+              matches: (cmd: string) => cmd === "rm",
+              create: (cmd: string, args: string[]) => ({
+                prepare: () => args.includes("/")
+                  ? { reject: { reason: "refusing to delete /" } }
+                  : { cmd, args, env: {} },
+                summarize: (stdout: string) => ({ summary: stdout }),
+              }),
+              settings: () => ({}),
+            };
+            "#,
+        )
+        .unwrap();
+        let paths = vec![path];
+        let context = runtime::HandlerContext::default();
+
+        let args = vec!["-rf".to_string(), "/".to_string()];
+        let (_, prep) = runtime::HandlerChain::load(&paths, "rm", &args, &HashMap::new(), &context)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            prep.reject,
+            Some(runtime::Rejection {
+                reason: "refusing to delete /".to_string()
+            })
+        );
+
+        let args = vec!["-rf".to_string(), "build".to_string()];
+        let (_, prep) = runtime::HandlerChain::load(&paths, "rm", &args, &HashMap::new(), &context)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(prep.reject, None);
+        assert_eq!(prep.args, args);
+    }
+
+    #[test]
+    fn test_summary_rendered_from_sections() {
+        let result = runtime::SummaryResult::from_json(
+            r#"{
+                "summary": null,
+                "sections": {
+                    "errors": ["error[E0308]: mismatched types\n", "error: aborting"],
+                    "stats": ["1 error"],
+                    "tail": []
+                }
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(
+            result.summary.as_deref(),
+            Some(concat!(
+                "Errors:\nerror[E0308]: mismatched types\nerror: aborting\n",
+                "\nStats:\n1 error\n",
+            ))
+        );
+        let sections = result.sections.unwrap();
+        assert_eq!(sections.errors.len(), 2);
+        assert!(sections.warnings.is_empty());
+
+        // A summary the handler wrote itself is kept, and no sections still means buffering
+        let result = runtime::SummaryResult::from_json(
+            r#"{ "summary": "2 errors", "sections": { "errors": ["a", "b"] } }"#,
+        )
+        .unwrap();
+        assert_eq!(result.summary.as_deref(), Some("2 errors"));
+        let result = runtime::SummaryResult::from_json(r#"{ "summary": null }"#).unwrap();
+        assert_eq!(result.summary, None);
+        assert_eq!(result.sections, None);
+    }
+
+    #[test]
+    fn test_prepare_result_applies_arg_changes() {
+        let original = ["build".to_string(), "-v".to_string()];
+        let changes: runtime::PrepareResult = serde_json::from_value(serde_json::json!({
+            "prependArgs": ["+nightly"],
+            "appendArgs": ["--quiet"],
+            "removeArgs": ["-v"],
+        }))
+        .unwrap();
+        let resolved = changes.clone().resolve("cargo", &original);
+        assert_eq!(resolved.cmd, "cargo");
+        assert_eq!(resolved.args, ["+nightly", "build", "--quiet"]);
+
+        // On top of a replaced command, the changes apply to the replacement
+        let replaced = runtime::PrepareResult {
+            cmd: "cargo".to_string(),
+            args: vec!["check".to_string(), "-v".to_string()],
+            ..changes
+        };
+        let resolved = replaced.resolve("cargo", &original);
+        assert_eq!(resolved.args, ["+nightly", "check", "--quiet"]);
+    }
+
+    #[tokio::test]
+    async fn test_handler_can_append_args_to_prepared_command() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(
+            dir.path().join("release.ts"),
+            r#"
+            export const releaseHandler = {
+              matches: (cmd: string) => cmd === "cargo",
+              create: (cmd: string, args: string[]) => ({
+                prepare: () => ({ cmd, args: [...args, "--release"], env: {} }),
+                summarize: (stdout: string) => ({ summary: stdout }),
+              }),
+              settings: () => ({}),
+            };
+            "#,
+        )
+        .unwrap();
+        std::fs::write(
+            dir.path().join("quiet.ts"),
+            r#"
+            export const quietHandler = {
+              matches: (cmd: string) => cmd === "cargo",
+              create: () => ({
+                prepare: () => ({ appendArgs: ["--quiet"], removeArgs: ["-v"] }),
+                summarize: (stdout: string) => ({ summary: stdout }),
+              }),
+              settings: () => ({}),
+              priority: () => 1,
+            };
+            "#,
+        )
+        .unwrap();
+        let paths = vec![dir.path().join("release.ts"), dir.path().join("quiet.ts")];
+
+        let args = vec!["build".to_string(), "-v".to_string()];
+        let context = runtime::HandlerContext::default();
+        let (_, prep) =
+            runtime::HandlerChain::load(&paths, "cargo", &args, &HashMap::new(), &context)
+                .await
+                .unwrap()
+                .unwrap();
+        assert_eq!(prep.cmd, "cargo");
+        assert_eq!(prep.args, ["build", "--release", "--quiet"]);
+    }
+
+    #[tokio::test]
+    async fn test_handler_imports_shared_module() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join("lib")).unwrap();
+        std::fs::write(
+            dir.path().join("lib/filters.ts"),
+            r#"
+            export const dropNoise = (text: string): string =>
+              text.split("\n").filter((line) => !line.startsWith("noise")).join("\n");
+            "#,
+        )
+        .unwrap();
+        std::fs::write(
+            dir.path().join("make.ts"),
+            r#"
+            import { dropNoise } from "./lib/filters.ts";
+            export const makeHandler = {
+              matches: (cmd: string) => cmd === "make",
+              create: (cmd: string, args: string[]) => ({
+                prepare: () => ({ cmd, args, env: {} }),
+                summarize: (stdout: string) => ({ summary: dropNoise(stdout) }),
+              }),
+              settings: () => ({}),
+            };
+            "#,
+        )
+        .unwrap();
+
+        let mut rt = runtime::HandlerRuntime::new().unwrap();
+        let path = dir.path().join("make.ts");
+        rt.load_handler(path.to_str().unwrap()).await.unwrap();
+        rt.create_handler("make", &[], &HashMap::new(), &Default::default())
+            .await
+            .unwrap();
+        let result = rt.summarize("noise\nsignal\n", "", None).await.unwrap();
+        assert_eq!(result.summary.as_deref(), Some("signal\n"));
+
+        // A bad import is an error naming the module rather than a panic
+        for (import, expected) in [
+            ("./lib/missing.ts", "Failed to read module"),
+            ("./lib/filters", "imports need a file extension"),
+            ("https://example.com/filters.ts", "can only import local files"),
+        ] {
+            let path = dir.path().join("broken.ts");
+            let code = format!("import {{ dropNoise }} from {import:?};\n");
+            std::fs::write(&path, code + "export const brokenHandler = {};").unwrap();
+            let mut rt = runtime::HandlerRuntime::new().unwrap();
+            let err = rt.load_handler(path.to_str().unwrap()).await.unwrap_err();
+            assert!(format!("{err:#}").contains(expected), "{err:#}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_console_output_stays_out_of_summary() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("chatty.ts");
+        std::fs::write(
+            &path,
+            r#"
+            console.log("loading");
+            export const chattyHandler = {
+              matches: (cmd: string) => cmd === "chatty",
+              create: (cmd: string, args: string[]) => ({
+                prepare: () => ({ cmd, args, env: {} }),
+                summarize: (stdout: string) => {
+                  console.log("got", stdout.length, "bytes", { stdout });
+                  console.warn(new Error("not really"));
+                  console.error("circular", globalThis);
+                  return { summary: stdout };
+                },
+              }),
+              settings: () => ({}),
+            };
+            "#,
+        )
+        .unwrap();
+
+        let mut rt = runtime::HandlerRuntime::new().unwrap();
+        rt.load_handler(path.to_str().unwrap()).await.unwrap();
+        rt.create_handler("chatty", &[], &HashMap::new(), &Default::default())
+            .await
+            .unwrap();
+        let result = rt.summarize("hello\n", "", None).await.unwrap();
+        assert_eq!(result.summary.as_deref(), Some("hello\n"));
+    }
+
+    #[tokio::test]
+    async fn test_handler_sandbox_has_no_network_or_file_writes() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("probe.ts");
+        std::fs::write(
+            &path,
+            r#"
+            const ops = Object.keys(Deno.core.ops);
+            export const probeHandler = {
+              matches: () =>
+                [globalThis.fetch, globalThis.WebSocket, globalThis.XMLHttpRequest]
+                  .every((api) => api === undefined) &&
+                !ops.some((op) => /fetch|net_|fs_|write_file|spawn/.test(op)),
+            };
+            "#,
+        )
+        .unwrap();
+        let mut rt = runtime::HandlerRuntime::new().unwrap();
+        rt.load_handler(path.to_str().unwrap()).await.unwrap();
+        assert!(rt.matches("probe", &[]).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_bad_import_leaves_runtime_usable() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("notes.txt"), "not a module").unwrap();
+        let path = dir.path().join("notes.ts");
+        std::fs::write(
+            &path,
+            r#"
+            import notes from "./notes.txt";
+            export const notesHandler = { matches: () => notes !== "" };
+            "#,
+        )
+        .unwrap();
+
+        let mut rt = runtime::HandlerRuntime::new().unwrap();
+        let err = rt.load_handler(path.to_str().unwrap()).await.unwrap_err();
+        let message = format!("{err:#}");
+        assert!(message.contains("notes.txt"), "{message}");
+        assert!(message.contains("only .ts, .js and .json modules"), "{message}");
+
+        // The runtime thread survived, so later calls get the handler's own error back
+        // rather than finding the runtime gone
+        let err = rt.matches("notes", &[]).await.unwrap_err();
+        assert!(err.downcast_ref::<tokio::sync::oneshot::error::RecvError>().is_none(), "{err:#}");
+    }
+
+    #[tokio::test]
+    async fn test_looping_handler_is_terminated() {
+        let dir = tempfile::tempdir().unwrap();
+        let handler_path = dir.path().join("looping.ts");
+        std::fs::write(
+            &handler_path,
+            r#"
+            export const loopingHandler = {
+              matches: (cmd: string) => cmd === "looping",
+              create: (cmd: string, args: string[]) => ({
+                prepare: () => ({ cmd, args, env: {} }),
+                summarize: () => { while (true) {} },
+              }),
+              settings: () => ({}),
+            };
+            "#,
+        )
+        .unwrap();
+
+        let mut rt =
+            runtime::HandlerRuntime::with_script_timeout(Duration::from_millis(200)).unwrap();
+        rt.load_handler(handler_path.to_str().unwrap()).await.unwrap();
+        rt.create_handler("looping", &[], &HashMap::new(), &Default::default())
+            .await
+            .unwrap();
+        rt.prepare().await.unwrap();
+
+        let err = rt.summarize("out\n", "", None).await.unwrap_err();
+        assert!(err.downcast_ref::<runtime::HandlerTimeout>().is_some(), "{err:#}");
+
+        // The isolate recovers from the termination
+        assert!(rt.matches("looping", &[]).await.unwrap());
+
+        let chain = runtime::HandlerChain::from(rt);
+        let result = runtime::process("out\n", "", &Some(chain), false).await;
+        assert_eq!(result.summary.as_deref(), Some("out\n"));
+        let truncation = result.truncation.unwrap();
+        assert_eq!(truncation.reason.as_deref(), Some("handler_timeout"));
+    }
+
+    #[tokio::test]
+    async fn test_handler_chain_orders_by_priority() {
+        let dir = tempfile::tempdir().unwrap();
+        let write_handler = |name: &str, priority: i32, tag: &str| {
+            let path = dir.path().join(format!("{name}.ts"));
+            std::fs::write(
+                &path,
+                format!(
+                    r#"
+                    export const {name}Handler = {{
+                      matches: (cmd: string) => cmd === "chained",
+                      priority: () => {priority},
+                      create: (cmd: string, args: string[]) => ({{
+                        prepare: () => ({{
+                          cmd,
+                          args: [...args, "--{tag}"],
+                          env: {{ SHARED: "{tag}", ONLY_{tag}: "1" }},
+                        }}),
+                        summarize: (stdout: string) => ({{
+                          summary: `{tag}(${{stdout}})`,
+                          actions: [{{ name: "{tag}-action", description: "{tag}" }}],
+                        }}),
+                      }}),
+                      settings: () => ({{}}),
+                    }};
+                    "#
+                ),
+            )
+            .unwrap();
+            path
+        };
+        // Discovery order is deliberately the reverse of priority order
+        let paths = vec![write_handler("late", 10, "late"), write_handler("early", 1, "early")];
+
+        let (chain, prep) = runtime::HandlerChain::load(
+            &paths,
+            "chained",
+            &["x".to_string()],
+            &HashMap::new(),
+            &Default::default(),
+        )
+        .await
+        .unwrap()
+        .unwrap();
+        assert_eq!(prep.args, vec!["x", "--early", "--late"]);
+        assert_eq!(prep.env["SHARED"], "late");
+        assert_eq!(prep.env["ONLY_early"], "1");
+
+        let result = chain.summarize("out", "", Some(0)).await.unwrap();
+        assert_eq!(result.summary.as_deref(), Some("late(early(out))"));
+        let actions: Vec<_> = result.actions.iter().map(|a| a.name.as_str()).collect();
+        assert_eq!(actions, vec!["early-action", "late-action"]);
+    }
+
+    #[tokio::test]
+    async fn test_handler_cache_reuses_runtime_until_file_changes() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("counted.ts");
+        let write_handler = |tag: &str| {
+            std::fs::write(
+                &path,
+                format!(
+                    r#"
+                    let created = 0;
+                    export const countedHandler = {{
+                      matches: (cmd: string) => cmd === "counted",
+                      create: (cmd: string, args: string[]) => {{
+                        created++;
+                        return {{
+                          prepare: () => ({{ cmd, args, env: {{ CREATED: `{tag}${{created}}` }} }}),
+                          summarize: (stdout: string) => ({{ summary: stdout }}),
+                        }};
+                      }},
+                      settings: () => ({{}}),
+                    }};
+                    "#
+                ),
+            )
+            .unwrap();
+        };
+        write_handler("v1-");
+        let paths = vec![path.clone()];
+        let cache = runtime::HandlerCache::new();
+        let settings = HashMap::new();
+        let context = runtime::HandlerContext::default();
+        let load = || {
+            runtime::HandlerChain::load_cached(&paths, "counted", &[], &settings, &context, &cache)
+        };
+
+        let (chain, prep) = load().await.unwrap().unwrap();
+        assert_eq!(prep.env["CREATED"], "v1-1");
+        // In use until the chain is dropped
+        assert_eq!(cache.idle_count(&path), 0);
+        drop(chain);
+        assert_eq!(cache.idle_count(&path), 1);
+
+        let (chain, prep) = load().await.unwrap().unwrap();
+        assert_eq!(prep.env["CREATED"], "v1-2");
+        drop(chain);
+
+        write_handler("v2-");
+        let modified = std::time::SystemTime::now() + Duration::from_secs(10);
+        std::fs::File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_modified(modified)
+            .unwrap();
+        let (_, prep) = load().await.unwrap().unwrap();
+        assert_eq!(prep.env["CREATED"], "v2-1");
+    }
+
+    #[tokio::test]
+    async fn test_edited_handler_is_used_by_next_command() {
+        let dir = tempfile::tempdir().unwrap();
+        let handler_path = dir.path().join(".shelly/echo.ts");
+        std::fs::create_dir(dir.path().join(".shelly")).unwrap();
+        let write_handler = |tag: &str| {
+            std::fs::write(
+                &handler_path,
+                format!(
+                    r#"
+                    export const echoHandler = {{
+                      matches: (cmd: string) => cmd === "echo",
+                      create: (cmd: string, args: string[]) => ({{
+                        prepare: () => ({{ cmd, args, env: {{}} }}),
+                        summarize: (stdout: string) => ({{ summary: `{tag}: ${{stdout}}` }}),
+                      }}),
+                      settings: () => ({{}}),
+                    }};
+                    "#
+                ),
+            )
+            .unwrap();
+        };
+        let request = || ExecuteRequest {
+            cmd: "echo".to_string(),
+            args: vec!["hi".to_string()],
+            working_dir: dir.path().to_path_buf(),
+            ..Default::default()
+        };
+        let pm = Arc::new(process_manager::ProcessManager::new());
+        let timeout = Duration::from_secs(10);
+
+        write_handler("old");
+        let result = execute_command_streaming(request(), pm.clone(), timeout)
+            .await
+            .unwrap();
+        assert_eq!(result.summary, "old: hi\n");
+        // Wait for the runtime to be handed back, so the next command could reuse it
+        let released = async {
+            while pm.handler_cache.idle_count(&handler_path) == 0 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        };
+        tokio::time::timeout(timeout, released).await.unwrap();
+
+        write_handler("edited");
+        let result = execute_command_streaming(request(), pm.clone(), timeout)
+            .await
+            .unwrap();
+        assert_eq!(result.summary, "edited: hi\n");
+    }
+
+    #[tokio::test]
+    async fn test_declarative_handler_filters_lines() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("make.json");
+        std::fs::write(
+            &path,
+            r#"{
+                "matches": { "command": "make", "args": ["test"] },
+                "drop": ["^warning:", "^\\s*$"],
+                "keep_last_n": 2
+            }"#,
+        )
+        .unwrap();
+        let paths = vec![path];
+
+        let other_args = vec!["build".to_string()];
+        let context = runtime::HandlerContext::default();
+        let loaded =
+            runtime::HandlerChain::load(&paths, "make", &other_args, &HashMap::new(), &context)
+                .await
+                .unwrap();
+        assert!(loaded.is_none());
+
+        let args = vec!["test".to_string(), "-j4".to_string()];
+        let (chain, prep) =
+            runtime::HandlerChain::load(&paths, "make", &args, &HashMap::new(), &context)
+                .await
+                .unwrap()
+                .unwrap();
+        assert_eq!(prep.cmd, "make");
+        assert_eq!(prep.args, args);
+
+        let stdout = "warning: unused\nstep 1\n\nstep 2\n";
+        let result = chain.summarize(stdout, "error: boom\n", Some(0)).await.unwrap();
+        assert_eq!(result.summary.as_deref(), Some("step 1\nstep 2\nerror: boom\n"));
+        assert!(result.truncation.is_none());
+
+        let result = chain.summarize(stdout, "error: boom\n", Some(2)).await.unwrap();
+        assert_eq!(result.summary.as_deref(), Some("step 2\nerror: boom\n"));
+        assert_eq!(result.truncation.unwrap().reason.as_deref(), Some("keep_last_n"));
+    }
+
+    #[tokio::test]
+    async fn test_execution_result_reports_handler_truncation() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join(".shelly")).unwrap();
+        std::fs::write(
+            dir.path().join(".shelly/bash.json"),
+            r#"{ "matches": { "command": "bash" }, "keep_last_n": 2 }"#,
+        )
+        .unwrap();
+        let request = |script: &str| ExecuteRequest {
+            cmd: "bash".to_string(),
+            args: vec!["-c".to_string(), script.to_string()],
+            working_dir: dir.path().to_path_buf(),
+            ..Default::default()
+        };
+        let pm = Arc::new(process_manager::ProcessManager::new());
+        let timeout = Duration::from_secs(10);
+
+        let result = execute_command_streaming(request("seq 5; exit 1"), pm.clone(), timeout)
+            .await
+            .unwrap();
+        assert_eq!(result.summary, "4\n5\n");
+        assert!(result.truncated);
+        assert_eq!(result.truncation_reason.as_deref(), Some("keep_last_n"));
+        assert_eq!(
+            result.truncation_description.as_deref(),
+            Some("Showing the last 2 of 5 lines")
+        );
+
+        let result = execute_command_streaming(request("seq 5"), pm, timeout)
+            .await
+            .unwrap();
+        assert!(!result.truncated);
+        assert_eq!(result.truncation_reason, None);
+    }
+
+    #[tokio::test]
+    async fn test_shell_mode_interprets_shell_syntax() {
+        let request = |shell| ExecuteRequest {
+            cmd: "echo".to_string(),
+            args: ["$FOO", "&&", "echo", "two"].map(String::from).to_vec(),
+            exact: true,
+            working_dir: std::env::current_dir().unwrap(),
+            env: HashMap::from([("FOO".to_string(), "one".to_string())]),
+            shell,
+            ..Default::default()
+        };
+        let pm = Arc::new(process_manager::ProcessManager::new());
+        let timeout = Duration::from_secs(10);
+
+        let result = execute_command_streaming(request(true), pm.clone(), timeout)
+            .await
+            .unwrap();
+        assert_eq!(result.stdout, "one\ntwo\n");
+        assert_eq!(result.executed_command.args, ["-c", "echo $FOO && echo two"]);
+
+        // Without a shell the arguments reach the program untouched
+        let result = execute_command_streaming(request(false), pm, timeout)
+            .await
+            .unwrap();
+        assert_eq!(result.stdout, "$FOO && echo two\n");
+    }
+
+    #[tokio::test]
+    async fn test_final_summary_is_cut_to_token_budget() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join(".shelly")).unwrap();
+        std::fs::write(
+            dir.path().join(".shelly/seq.json"),
+            r#"{ "matches": { "command": "seq" } }"#,
+        )
+        .unwrap();
+        let request = ExecuteRequest {
+            cmd: "seq".to_string(),
+            args: vec!["100".to_string()],
+            working_dir: dir.path().to_path_buf(),
+            max_summary_tokens: Some(10),
+            ..Default::default()
+        };
+        let pm = Arc::new(process_manager::ProcessManager::new());
+
+        let result = execute_command_streaming(request, pm, Duration::from_secs(10))
+            .await
+            .unwrap();
+        let note = "[summary cut from ~73 to ~10 tokens, see the output file]\n";
+        assert!(result.summary.ends_with(&format!("99\n100\n{note}")), "{}", result.summary);
+        assert!(result.truncated);
+        assert_eq!(result.truncation_reason.as_deref(), Some("token_budget"));
+        assert!(result.stdout.starts_with("1\n2\n"));
+    }
+
+    #[tokio::test]
+    async fn test_handler_explains_expected_exit_codes() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join(".shelly")).unwrap();
+        std::fs::write(
+            dir.path().join(".shelly/grep.json"),
+            r#"{
+                "matches": { "command": "grep" },
+                "exit_codes": { "1": { "label": "no matches", "is_error": false } }
+            }"#,
+        )
+        .unwrap();
+        std::fs::write(dir.path().join("haystack.txt"), "hay\n").unwrap();
+        let request = |file: &str| ExecuteRequest {
+            cmd: "grep".to_string(),
+            args: vec!["needle".to_string(), file.to_string()],
+            working_dir: dir.path().to_path_buf(),
+            ..Default::default()
+        };
+        let pm = Arc::new(process_manager::ProcessManager::new());
+        let timeout = Duration::from_secs(10);
+
+        let result = execute_command_streaming(request("haystack.txt"), pm.clone(), timeout)
+            .await
+            .unwrap();
+        assert_eq!(result.exit_code, 1);
+        assert!(!result.is_error);
+        assert_eq!(result.exit_code_meaning.as_deref(), Some("no matches"));
+
+        // Codes the handler doesn't mention are errors as usual
+        let result = execute_command_streaming(request("missing.txt"), pm, timeout)
+            .await
+            .unwrap();
+        assert_eq!(result.exit_code, 2);
+        assert!(result.is_error);
+        assert_eq!(result.exit_code_meaning, None);
+    }
+
+    #[tokio::test]
+    async fn test_cancelled_command_is_summarized_by_handler() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join(".shelly")).unwrap();
+        std::fs::write(
+            dir.path().join(".shelly/bash.json"),
+            r#"{ "matches": { "command": "bash" }, "keep_last_n": 2 }"#,
+        )
+        .unwrap();
+        let request = ExecuteRequest {
+            cmd: "bash".to_string(),
+            args: vec!["-c".to_string(), "seq 5; sleep 30".to_string()],
+            working_dir: dir.path().to_path_buf(),
+            ..Default::default()
+        };
+        let pm = Arc::new(process_manager::ProcessManager::new());
+
+        let result = execute_command_streaming(request, pm.clone(), Duration::from_secs(1))
+            .await
+            .unwrap();
+        assert!(result.is_running);
+        let process_id = result.process_id.unwrap();
+        assert!(pm.cancel_process(&process_id).await);
+
+        // The handler was finalized as though the command had failed
+        let update = pm.join_process(&process_id, Duration::ZERO, None).await.unwrap();
+        assert_eq!(update.status, ProcessState::Cancelled);
+        assert_eq!(update.incremental_summary, "4\n5\n");
+    }
+
+    #[tokio::test]
+    async fn test_command_cancelled_elsewhere_returns_handler_summary() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join(".shelly")).unwrap();
+        std::fs::write(
+            dir.path().join(".shelly/bash.json"),
+            r#"{ "matches": { "command": "bash" }, "keep_last_n": 2 }"#,
+        )
+        .unwrap();
+        let request = ExecuteRequest {
+            cmd: "bash".to_string(),
+            args: vec!["-c".to_string(), "seq 5; sleep 30".to_string()],
+            working_dir: dir.path().to_path_buf(),
+            ..Default::default()
+        };
+        let pm = Arc::new(process_manager::ProcessManager::new());
+
+        let execution = tokio::spawn(execute_command_streaming(
+            request,
+            pm.clone(),
+            Duration::from_secs(30),
+        ));
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        let process_id = pm.processes.read().await.keys().next().unwrap().clone();
+        assert!(pm.cancel_process(&process_id).await);
+
+        let result = execution.await.unwrap().unwrap();
+        assert_eq!(result.exit_code, 130);
+        assert_eq!(result.summary, "Command was cancelled\n4\n5\n");
+    }
+
+    #[tokio::test]
+    async fn test_quick_command_returns_final_handler_summary() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join(".shelly")).unwrap();
+        std::fs::write(
+            dir.path().join(".shelly/bash.json"),
+            r#"{ "matches": { "command": "bash" }, "drop": ["^noise"] }"#,
+        )
+        .unwrap();
+        let pm = Arc::new(process_manager::ProcessManager::new());
+
+        // Finishing long before the timeout must not beat the final summary
+        for _ in 0..20 {
+            let request = ExecuteRequest {
+                cmd: "bash".to_string(),
+                args: vec!["-c".to_string(), "echo noise; echo signal".to_string()],
+                working_dir: dir.path().to_path_buf(),
+                output_dir: Some(dir.path().to_path_buf()),
+                ..Default::default()
+            };
+            let result = execute_command_streaming(request, pm.clone(), Duration::from_secs(10))
+                .await
+                .unwrap();
+            assert_eq!(result.exit_code, 0);
+            assert_eq!(result.summary, "signal\n");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_request_can_pick_handler() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join(".shelly")).unwrap();
+        std::fs::write(
+            dir.path().join(".shelly/quiet.json"),
+            r#"{ "matches": { "command": "bash" }, "drop": ["^noise"] }"#,
+        )
+        .unwrap();
+        std::fs::write(
+            dir.path().join(".shelly/make.json"),
+            r#"{ "matches": { "command": "make" } }"#,
+        )
+        .unwrap();
+        let pm = Arc::new(process_manager::ProcessManager::new());
+        let request = |handler: Option<&str>| ExecuteRequest {
+            cmd: "bash".to_string(),
+            args: vec!["-c".to_string(), "echo noise; echo signal".to_string()],
+            working_dir: dir.path().to_path_buf(),
+            handler: handler.map(str::to_string),
+            output_dir: Some(dir.path().to_path_buf()),
+            ..Default::default()
+        };
+        let run = |handler| {
+            execute_command_streaming(request(handler), pm.clone(), Duration::from_secs(10))
+        };
+
+        // quiet.json isn't named after bash, so it only applies when asked for
+        assert_eq!(run(None).await.unwrap().summary, "noise\nsignal\n");
+        assert_eq!(run(Some("quiet")).await.unwrap().summary, "signal\n");
+
+        let err = run(Some("make")).await.unwrap_err();
+        assert!(err.downcast_ref::<runtime::HandlerError>().is_some());
+        assert!(format!("{err:#}").contains("does not match"), "{err:#}");
+        let err = run(Some("missing")).await.unwrap_err();
+        assert!(format!("{err:#}").contains("No handler named"), "{err:#}");
+    }
+
+    #[tokio::test]
+    async fn test_replay_output_summarizes_captured_output() {
+        use process_manager::{OutputLine, OutputStream};
+
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join(".shelly")).unwrap();
+        std::fs::write(
+            dir.path().join(".shelly/bash.json"),
+            r#"{ "matches": { "command": "bash" }, "drop": ["^noise"] }"#,
+        )
+        .unwrap();
+        let lines: Vec<OutputLine> = [
+            (OutputStream::Stdout, "noise\n"),
+            (OutputStream::Stdout, "signal\n"),
+            (OutputStream::Stderr, "noise on stderr\n"),
+            (OutputStream::Stderr, "warning\n"),
+        ]
+        .into_iter()
+        .enumerate()
+        .map(|(seq, (stream, text))| OutputLine {
+            seq: seq as u64,
+            stream,
+            text: text.to_string(),
+        })
+        .collect();
+
+        // A command that is still running has no exit code in its file yet
+        let running = dir.path().join("running.txt");
+        output::append_lines(&running, &lines).unwrap();
+        let replay = replay_output(&running, "bash", Some("bash"), &HashMap::new(), dir.path())
+            .await
+            .unwrap();
+        assert_eq!(replay.exit_code, None);
+        assert_eq!(replay.handler, dir.path().join(".shelly/bash.json"));
+        assert_eq!(replay.result.summary.as_deref(), Some("signal\nwarning\n"));
+
+        // A finished command's file records the command to replay it as
+        let finished = dir.path().join("finished.txt");
+        let header = output::OutputHeader {
+            command: "bash -c x".to_string(),
+            ..Default::default()
+        };
+        output::write_output(&finished, &lines, 3, Some(&header)).unwrap();
+        let replay = replay_output(&finished, "bash", None, &HashMap::new(), dir.path())
+            .await
+            .unwrap();
+        assert_eq!(replay.exit_code, Some(3));
+        assert!(replay.result.summary.unwrap().contains("signal\nwarning\n"));
+
+        let err = replay_output(&finished, "nope", None, &HashMap::new(), dir.path())
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("No handler named"), "{err}");
+    }
+
+    #[tokio::test]
+    async fn test_dry_run_reports_command_without_running_it() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join(".shelly")).unwrap();
+        std::fs::write(
+            dir.path().join(".shelly/bash.json"),
+            r#"{ "matches": { "command": "bash" } }"#,
+        )
+        .unwrap();
+        let request = ExecuteRequest {
+            cmd: "bash".to_string(),
+            args: vec!["-c".to_string(), "touch ran".to_string()],
+            working_dir: dir.path().to_path_buf(),
+            env: HashMap::from([("FOO".to_string(), "bar".to_string())]),
+            dry_run: true,
+            ..Default::default()
+        };
+        let pm = Arc::new(process_manager::ProcessManager::new());
+
+        let result = execute_command_streaming(request, pm, Duration::from_secs(10))
+            .await
+            .unwrap();
+        assert_eq!(result.summary, "Dry run, would execute: bash -c touch ran\n");
+        assert_eq!(result.executed_command.args, ["-c", "touch ran"]);
+        assert_eq!(result.executed_command.env["FOO"], "bar");
+        assert_eq!(result.exit_code, -1);
+        assert!(!result.is_running);
+        assert_eq!(result.process_id, None);
+        assert_eq!(result.output_file, "");
+        assert!(!dir.path().join("ran").exists());
+        assert!(!result.command_rewritten);
+        assert_eq!(result.original_command, None);
+    }
+
+    #[tokio::test]
+    async fn test_result_flags_handler_that_changes_program() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join(".shelly")).unwrap();
+        std::fs::write(
+            dir.path().join(".shelly/echo.ts"),
+            r#"
+            export const echoHandler = {
+              matches: (cmd: string) => cmd === "echo",
+              create: (cmd: string, args: string[]) => ({
+                prepare: () => ({ cmd: "printf", args: ["%s\\n", ...args], env: {} }),
+                summarize: (stdout: string) => ({ summary: stdout }),
+              }),
+              settings: () => ({}),
+            };
+            "#,
+        )
+        .unwrap();
+        let request = ExecuteRequest {
+            cmd: "echo".to_string(),
+            args: vec!["hi".to_string()],
+            working_dir: dir.path().to_path_buf(),
+            output_dir: Some(dir.path().to_path_buf()),
+            ..Default::default()
+        };
+        let pm = Arc::new(process_manager::ProcessManager::new());
+
+        let result = execute_command_streaming(request, pm, Duration::from_secs(10))
+            .await
+            .unwrap();
+        assert_eq!(result.executed_command.cmd, "printf");
+        assert!(result.command_rewritten);
+        assert_eq!(result.original_command.as_deref(), Some("echo hi"));
+    }
+
+    #[test]
+    fn test_program_changed_ignores_directory() {
+        assert!(!program_changed("cargo", "cargo"));
+        assert!(!program_changed("cargo", "/home/me/.cargo/bin/cargo"));
+        assert!(program_changed("echo", "rm"));
+    }
+
+    #[tokio::test]
+    async fn test_secrets_are_redacted_from_output() {
+        let dir = tempfile::tempdir().unwrap();
+        let github_token = format!("ghp_{}", "a1B2".repeat(9));
+        let request = ExecuteRequest {
+            cmd: "bash".to_string(),
+            args: vec![
+                "-c".to_string(),
+                format!("echo \"token=$MY_TOKEN\"; echo {github_token} >&2; echo done"),
+            ],
+            exact: true,
+            working_dir: dir.path().to_path_buf(),
+            env: HashMap::from([("MY_TOKEN".to_string(), "hunter2-s3cret".to_string())]),
+            output_dir: Some(dir.path().to_path_buf()),
+            ..Default::default()
+        };
+        let pm = Arc::new(process_manager::ProcessManager::new());
+
+        let result = execute_command_streaming(request, pm, Duration::from_secs(10))
+            .await
+            .unwrap();
+        assert_eq!(result.stdout, "token=***\ndone\n");
+        assert_eq!(result.stderr, "***\n");
+        for text in [result.summary, std::fs::read_to_string(&result.output_file).unwrap()] {
+            assert!(text.contains("token=***"), "{text}");
+            assert!(!text.contains("hunter2-s3cret"), "{text}");
+            assert!(!text.contains(&github_token), "{text}");
+        }
+    }
+
+    #[test]
+    fn test_validate_settings() {
+        let schema: runtime::SettingsSchema = serde_json::from_value(serde_json::json!({
+            "quiet": { "type": "boolean", "default": true, "description": "" },
+            "RUST_LOG": { "type": "string", "default": null, "description": "" },
+            "limit": { "type": "number", "default": 10, "description": "" },
+        }))
+        .unwrap();
+
+        let settings = HashMap::from([
+            ("limit".to_string(), serde_json::json!(3)),
+            ("other_handler_key".to_string(), serde_json::json!("kept")),
+        ]);
+        let validated = runtime::validate_settings(&schema, &settings).unwrap();
+        assert_eq!(validated["quiet"], serde_json::json!(true));
+        assert_eq!(validated["RUST_LOG"], serde_json::Value::Null);
+        assert_eq!(validated["limit"], serde_json::json!(3));
+        assert_eq!(validated["other_handler_key"], serde_json::json!("kept"));
+
+        let settings = HashMap::from([("quiet".to_string(), serde_json::json!("yes"))]);
+        let err = runtime::validate_settings(&schema, &settings).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Invalid value for setting `quiet`: expected boolean, got \"yes\""
+        );
+    }
+
+    #[tokio::test]
+    async fn test_create_handler_rejects_mistyped_setting() {
+        let mut rt = runtime::HandlerRuntime::new().unwrap();
+        rt.load_handler("handlers/cargo.ts").await.unwrap();
+
+        let schema = rt.settings().await.unwrap();
+        assert_eq!(schema["quiet"].kind, runtime::SettingType::Boolean);
+
+        let settings = HashMap::from([("quiet".to_string(), serde_json::json!("false"))]);
+        let err = rt
+            .create_handler("cargo", &["build".to_string()], &settings, &Default::default())
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("`quiet`"), "{err:#}");
+    }
+
+    #[tokio::test]
+    async fn test_describe_handler_previews_prepare() {
+        let description = describe_handler("cargo build", &HashMap::new(), Path::new("."))
+            .await
+            .unwrap()
+            .expect("cargo has a built-in handler");
+        assert!(description.handlers[0].ends_with("cargo.ts"));
+        assert_eq!(
+            description.settings["show_warnings"].kind,
+            runtime::SettingType::Boolean
+        );
+        assert_eq!(description.prepared.cmd, "cargo");
+        assert!(description.prepared.args.contains(&"--quiet".to_string()));
+
+        let quiet_off = HashMap::from([("quiet".to_string(), serde_json::json!(false))]);
+        let description = describe_handler("cargo build", &quiet_off, Path::new("."))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(description.prepared.args, vec!["build"]);
+    }
+
+    #[tokio::test]
+    async fn test_describe_handler_without_match() {
+        let command = "definitely-unhandled-tool 'a b'";
+        let description = describe_handler(command, &HashMap::new(), Path::new("."))
+            .await
+            .unwrap();
+        assert!(description.is_none());
+        assert!(describe_handler("   ", &HashMap::new(), Path::new("."))
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_slow_handler_does_not_stall_other_processes() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("slow.ts");
+        std::fs::write(
+            &path,
+            r#"
+            export const slowHandler = {
+              matches: () => true,
+              create: (cmd: string, args: string[]) => ({
+                prepare: () => ({ cmd, args, env: {} }),
+                summarize: (stdout: string) => {
+                  const until = Date.now() + 1000;
+                  while (Date.now() < until) {}
+                  return { summary: stdout };
+                },
+              }),
+              settings: () => ({}),
+            };
+            "#,
+        )
+        .unwrap();
+        let context = runtime::HandlerContext::default();
+        let (slow, _) = runtime::HandlerChain::load(&[path], "yes", &[], &HashMap::new(), &context)
+            .await
+            .unwrap()
+            .unwrap();
+
+        let process_manager = Arc::new(process_manager::ProcessManager::new());
+        let config = |cmd: &str, args: &[&str], handler| {
+            let output_file = dir.path().join(format!("{cmd}.txt"));
+            streaming_executor::StreamingExecutorConfig {
+                update_interval: Duration::from_millis(50),
+                handler,
+                ..streaming_executor::test_config(cmd, args, output_file)
+            }
+        };
+        let slow_id = streaming_executor::spawn(
+            config("bash", &["-c", "for i in 1 2 3; do echo $i; sleep 0.1; done"], Some(slow)),
+            process_manager.clone(),
+        )
+        .await
+        .unwrap();
+        tokio::time::sleep(Duration::from_millis(200)).await;
+
+        let started = std::time::Instant::now();
+        let fast_id =
+            streaming_executor::spawn(config("echo", &["fast"], None), process_manager.clone())
+                .await
+                .unwrap();
+        let update = process_manager
+            .join_process(&fast_id, Duration::from_secs(10), None)
+            .await
+            .unwrap();
+        assert_eq!(update.incremental_summary, "fast\n");
+        assert!(started.elapsed() < Duration::from_millis(500), "{:?}", started.elapsed());
+
+        process_manager.cancel_process(&slow_id).await;
+    }
+
+    #[tokio::test]
+    async fn test_execute_command_error() {
+        let request = ExecuteRequest {
+            cmd: "ccccccargo".to_string(),
+            args: vec!["--version".to_string()],
+            working_dir: std::env::current_dir().unwrap(),
+            ..Default::default()
+        };
+        let result = execute_command(request).await.unwrap();
+        assert_eq!(result.exit_code, 127);
+        assert!(!result.output_file.is_empty());
+        assert!(!result.summary.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_execute_command_streaming_reports_failure() {
+        let process_manager = Arc::new(process_manager::ProcessManager::new());
+
+        let request = ExecuteRequest {
+            cmd: "nonexistent-command-that-should-not-exist".to_string(),
+            args: vec![],
+            exact: true,
+            working_dir: std::env::current_dir().unwrap(),
+            ..Default::default()
+        };
+
+        let result =
+            execute_command_streaming(request, process_manager, Duration::from_secs(30))
+                .await
+                .unwrap();
+
+        assert_ne!(result.exit_code, 0);
+        assert!(!result.is_running);
+        assert!(result.available_actions.is_empty());
+        assert!(
+            result.summary.contains("No such file or directory"),
+            "summary should carry the spawn error: {}",
+            result.summary
+        );
+    }
+
+    #[tokio::test]
+    async fn test_execute_command_with_handler() {
+        let request = ExecuteRequest {
+            cmd: "cargo".to_string(),
+            args: vec!["--version".to_string()],
+            working_dir: std::env::current_dir().unwrap(),
+            ..Default::default()
+        };
+
+        let result = execute_command(request).await.unwrap();
+        assert_eq!(result.exit_code, 0);
+        assert!(!result.output_file.is_empty());
+        assert!(!result.summary.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_execute_command_exact_mode() {
+        let request = ExecuteRequest {
+            cmd: "echo".to_string(),
+            args: vec!["hello".to_string()],
+            exact: true,
+            working_dir: std::env::current_dir().unwrap(),
+            ..Default::default()
+        };
+
+        let result = execute_command(request).await.unwrap();
+        assert_eq!(result.exit_code, 0);
+        assert!(result.summary.contains("hello"));
+    }
+
+    #[tokio::test]
+    async fn test_exact_mode_dedupes_repeated_lines() {
+        let script = "echo start; for i in $(seq 500); do echo 'warning: deprecated'; done; \
+                      for i in $(seq 20); do echo tick; echo tock; done; echo end";
+        let request = ExecuteRequest {
+            cmd: "bash".to_string(),
+            args: vec!["-c".to_string(), script.to_string()],
+            exact: true,
+            working_dir: std::env::current_dir().unwrap(),
+            dedupe: true,
+            ..Default::default()
+        };
+
+        let result = execute_command(request).await.unwrap();
+        assert_eq!(result.exit_code, 0);
+        assert!(
+            result.summary.starts_with("start\nwarning: deprecated\n... (repeated 500 times)\n"),
+            "{}",
+            result.summary
+        );
+        assert_eq!(result.summary.matches("tick\n").count(), 10, "{}", result.summary);
+        assert!(result.summary.ends_with("end\n"), "{}", result.summary);
+        assert!(result.truncated);
+        assert_eq!(result.truncation_reason.as_deref(), Some("filtered_duplicates"));
+        let output = std::fs::read_to_string(&result.output_file).unwrap();
+        assert_eq!(output.matches("[out] warning: deprecated").count(), 500);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_execute_command_reports_signal() {
+        let request = ExecuteRequest {
+            cmd: "bash".to_string(),
+            args: vec!["-c".to_string(), "echo crashing; kill -SEGV $$".to_string()],
+            exact: true,
+            working_dir: std::env::current_dir().unwrap(),
+            ..Default::default()
+        };
+
+        let result = execute_command(request).await.unwrap();
+        assert_eq!(result.exit_code, 128 + libc::SIGSEGV);
+        assert_eq!(result.summary, "crashing\nCommand terminated by SIGSEGV\n");
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_cpu_limit_kills_runaway_command() {
+        let request = ExecuteRequest {
+            cmd: "bash".to_string(),
+            args: vec!["-c".to_string(), "echo spinning; while :; do :; done".to_string()],
+            exact: true,
+            working_dir: std::env::current_dir().unwrap(),
+            kill_on_timeout: true,
+            limits: streaming_executor::ResourceLimits {
+                cpu_secs: Some(1),
+                memory_mb: None,
+            },
+            ..Default::default()
+        };
+
+        let result = execute_command(request).await.unwrap();
+        assert_eq!(result.exit_code, 128 + libc::SIGXCPU);
+        assert_eq!(
+            result.summary,
+            "spinning\nCommand terminated by SIGXCPU (exceeded its CPU time limit of 1s)\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_execute_command_splits_streams() {
+        let request = ExecuteRequest {
+            cmd: "bash".to_string(),
+            args: vec![
+                "-c".to_string(),
+                "echo out; echo err >&2; seq 1 20000".to_string(),
+            ],
+            exact: true,
+            working_dir: std::env::current_dir().unwrap(),
+            ..Default::default()
+        };
+
+        let result = execute_command(request).await.unwrap();
+        assert_eq!(result.stderr, "err\n");
+        // Only the tail of stdout fits, starting on a whole line
+        assert!(result.raw_output_truncated);
+        assert!(result.stdout.len() <= RAW_OUTPUT_LIMIT);
+        assert!(result.stdout.starts_with(|c: char| c.is_ascii_digit()));
+        assert!(result.stdout.ends_with("\n19999\n20000\n"));
+        assert!(!result.stdout.contains("out"));
+    }
+
+    #[tokio::test]
+    async fn test_output_file_creation() {
+        let request = ExecuteRequest {
+            cmd: "echo".to_string(),
+            args: vec!["test".to_string(), "output".to_string()],
+            exact: true,
+            working_dir: std::env::current_dir().unwrap(),
+            ..Default::default()
+        };
+
+        let result = execute_command(request).await.unwrap();
+
+        // Check that output file was created and contains content
+        assert!(!result.output_file.is_empty());
+        let output_content = std::fs::read_to_string(&result.output_file).unwrap();
+        assert!(output_content.contains("test output"));
+    }
+
+    #[test]
+    fn test_output_file_named_after_handler_label() {
+        let dir = tempfile::tempdir().unwrap();
+        let name = |label| {
+            let path =
+                output::create_output_file(Some(dir.path()), "cargo build --release", label)
+                    .unwrap();
+            let name = path.file_name().unwrap().to_string_lossy().into_owned();
+            name.rsplit_once('-').unwrap().0.to_string()
+        };
+        assert_eq!(name(Some("cargo-build-release")), "cargo-build-release");
+        assert_eq!(name(Some("../cargo build/x")), "cargo-buildx");
+        // Without a usable label the name comes from the command
+        assert_eq!(name(None), "cargobuild--releas");
+        assert_eq!(name(Some("//")), "cargobuild--releas");
+    }
+
+    #[tokio::test]
+    async fn test_max_runtime_cancels_detached_command() {
+        let request = ExecuteRequest {
+            cmd: "bash".to_string(),
+            args: vec!["-c".to_string(), "echo started; sleep 30".to_string()],
+            exact: true,
+            working_dir: std::env::current_dir().unwrap(),
+            max_runtime_ms: Some(800),
+            ..Default::default()
+        };
+        let pm = Arc::new(process_manager::ProcessManager::new());
+
+        // Control comes back before the cap, with the command still running
+        let wait = Duration::from_millis(200);
+        let result = execute_command_streaming(request.clone(), pm.clone(), wait)
+            .await
+            .unwrap();
+        assert!(result.is_running);
+        let process_id = result.process_id.unwrap();
+        tokio::time::timeout(Duration::from_secs(5), pm.wait_for(&process_id))
+            .await
+            .expect("the max runtime should have cancelled the command");
+        let status = pm.get_process_status(&process_id).await.unwrap();
+        assert_eq!(status.state, ProcessState::Cancelled);
+        assert!(status.timed_out);
+
+        // Waiting past the cap reports the command as timed out
+        let result = execute_command_streaming(request, pm, Duration::from_secs(10))
+            .await
+            .unwrap();
+        assert!(!result.is_running);
+        assert_eq!(result.truncation_reason.as_deref(), Some("timeout"));
+        assert!(
+            result.summary.starts_with("Command timed out after 800ms and was cancelled\n"),
+            "{}",
+            result.summary
+        );
+    }
+
+    #[tokio::test]
+    async fn test_running_response_previews_first_output() {
+        let script = "echo started; echo listening on 8080; sleep 30";
+        let request = ExecuteRequest {
+            cmd: "bash".to_string(),
+            args: vec!["-c".to_string(), script.to_string()],
+            exact: true,
+            working_dir: std::env::current_dir().unwrap(),
+            ..Default::default()
+        };
+        let pm = Arc::new(process_manager::ProcessManager::new());
+
+        let result = execute_command_streaming(request, pm.clone(), Duration::from_millis(500))
+            .await
+            .unwrap();
+        assert!(result.is_running);
+        assert_eq!(
+            result.output_preview.as_deref(),
+            Some("started\nlistening on 8080\n")
+        );
+        pm.cancel_process(&result.process_id.unwrap()).await;
+    }
+
+    #[tokio::test]
+    async fn test_output_dir_override() {
+        let dir = tempfile::tempdir().unwrap();
+        let output_dir = dir.path().join("nested");
+        let request = ExecuteRequest {
+            cmd: "echo".to_string(),
+            args: vec!["elsewhere".to_string()],
+            exact: true,
+            working_dir: std::env::current_dir().unwrap(),
+            output_dir: Some(output_dir.clone()),
+            ..Default::default()
+        };
+
+        let result = execute_command(request).await.unwrap();
+
+        assert!(PathBuf::from(&result.output_file).starts_with(&output_dir));
+        let output_content = std::fs::read_to_string(&result.output_file).unwrap();
+        assert!(output_content.contains("elsewhere"));
+        assert_eq!(result.output_file_relative, None);
+    }
+
+    #[tokio::test]
+    async fn test_output_file_relative_to_working_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        let request = ExecuteRequest {
+            cmd: "echo".to_string(),
+            args: vec!["inside".to_string()],
+            exact: true,
+            working_dir: dir.path().to_path_buf(),
+            output_dir: Some(dir.path().join("logs")),
+            ..Default::default()
+        };
+
+        let result = execute_command(request).await.unwrap();
+
+        let relative = result.output_file_relative.unwrap();
+        assert!(relative.starts_with("logs/"), "{relative}");
+        assert_eq!(
+            std::fs::read_to_string(dir.path().join(&relative)).unwrap(),
+            std::fs::read_to_string(&result.output_file).unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_execute_command_stream_yields_updates_until_done() {
+        let dir = tempfile::tempdir().unwrap();
+        let request = ExecuteRequest {
+            cmd: "bash".to_string(),
+            args: vec!["-c".to_string(), "echo one; sleep 1; echo two".to_string()],
+            exact: true,
+            working_dir: dir.path().to_path_buf(),
+            output_dir: Some(dir.path().to_path_buf()),
+            ..Default::default()
+        };
+        let process_manager = Arc::new(process_manager::ProcessManager::new());
+
+        let stream = execute_command_stream(request.clone(), process_manager.clone())
+            .await
+            .unwrap();
+        let updates: Vec<_> = tokio::time::timeout(Duration::from_secs(10), stream.collect())
+            .await
+            .unwrap();
+
+        assert!(updates.len() >= 2, "{updates:?}");
+        assert_eq!(updates[0].incremental_summary, "one\n");
+        let last = updates.last().unwrap();
+        assert_eq!(last.status, ProcessState::Completed { exit_code: 0 });
+        let summary: String = updates.iter().map(|u| u.incremental_summary.as_str()).collect();
+        assert_eq!(summary, "one\ntwo\n");
+
+        // A dry run has nothing to stream
+        let dry_run = ExecuteRequest {
+            dry_run: true,
+            ..request
+        };
+        assert!(execute_command_stream(dry_run, process_manager).await.is_err());
+    }
+
+    #[test]
+    fn test_trim_summary_keeps_whole_lines_and_errors() {
+        use process_manager::trim_summary;
+
+        assert_eq!(trim_summary("short\n", 100), ("short\n".to_string(), 0));
+
+        let mut summary = "error: the build broke\n".to_string();
+        for i in 0..20 {
+            summary.push_str(&format!("progress line {i:02}\n"));
+        }
+        let (trimmed, omitted) = trim_summary(&summary, 60);
+        assert_eq!(
+            trimmed,
+            "error: the build broke\n[... 306 chars omitted ...]\nprogress line 18\nprogress line 19\n"
+        );
+        assert_eq!(omitted, 306);
+
+        // A line that doesn't fit at all is cut to its last characters
+        let (trimmed, omitted) = trim_summary(&"x".repeat(30), 10);
+        assert_eq!(trimmed, format!("[... 20 chars omitted ...]\n{}", "x".repeat(10)));
+        assert_eq!(omitted, 20);
+    }
+
+    #[test]
+    fn test_read_output_pages_by_bytes() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("output.txt");
+        std::fs::write(&path, "price: 5\u{20ac}\nnext\n").unwrap();
+
+        // The window ends inside the three-byte euro sign, which is held back
+        let slice = output::read_output(&path, 6, 3, output::ReadUnit::Bytes).unwrap();
+        assert_eq!(slice.content, " 5");
+        assert_eq!(slice.next_offset, 8);
+        assert_eq!(slice.total_size, 17);
+        assert!(slice.has_more);
+
+        let slice = output::read_output(&path, 8, 100, output::ReadUnit::Bytes).unwrap();
+        assert_eq!(slice.content, "\u{20ac}\nnext\n");
+        assert!(!slice.has_more);
+    }
+
+    #[test]
+    fn test_cleanup_old_files_skips_what_it_cannot_remove() {
+        let dir = tempfile::tempdir().unwrap();
+        let stale = dir.path().join("stale-1700000000000.txt");
+        let fresh = dir.path().join("fresh-1700000000000.txt");
+        std::fs::write(&stale, "old").unwrap();
+        std::fs::write(&fresh, "new").unwrap();
+        let two_days_ago = std::time::SystemTime::now() - Duration::from_secs(2 * 86400);
+        std::fs::File::options()
+            .write(true)
+            .open(&stale)
+            .unwrap()
+            .set_modified(two_days_ago)
+            .unwrap();
+        // A stale directory isn't removable with remove_file and must not stop the sweep
+        let processes = dir.path().join("processes");
+        std::fs::create_dir(&processes).unwrap();
+        std::fs::File::open(&processes)
+            .unwrap()
+            .set_modified(two_days_ago)
+            .unwrap();
+
+        output::cleanup_old_files(
+            Some(dir.path()),
+            &output::Retention::default(),
+            &HashSet::new(),
+        )
+        .unwrap();
+        assert!(!stale.exists());
+        assert!(fresh.exists());
+        assert!(processes.exists());
+    }
+
+    #[test]
+    fn test_cleanup_old_files_removes_oldest_over_budget() {
+        let dir = tempfile::tempdir().unwrap();
+        let now = std::time::SystemTime::now();
+        let files: Vec<_> = (0..4)
+            .map(|i| {
+                let path = dir.path().join(format!("output-{}.txt", 1700000000000u64 + i));
+                std::fs::write(&path, "x".repeat(100)).unwrap();
+                // output-0 is the oldest
+                let modified = now - Duration::from_secs(60 * (4 - i));
+                std::fs::File::options()
+                    .write(true)
+                    .open(&path)
+                    .unwrap()
+                    .set_modified(modified)
+                    .unwrap();
+                path
+            })
+            .collect();
+
+        let retention = output::Retention {
+            max_bytes: 250,
+            ..Default::default()
+        };
+        output::cleanup_old_files(Some(dir.path()), &retention, &HashSet::new()).unwrap();
+        let kept: Vec<bool> = files.iter().map(|path| path.exists()).collect();
+        assert_eq!(kept, [false, false, true, true]);
+    }
+
+    #[test]
+    fn test_cleanup_old_files_keeps_foreign_and_running_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let two_days_ago = std::time::SystemTime::now() - Duration::from_secs(2 * 86400);
+        let write = |name: &str, modified| {
+            let path = dir.path().join(name);
+            std::fs::write(&path, "x".repeat(100)).unwrap();
+            std::fs::File::options()
+                .write(true)
+                .open(&path)
+                .unwrap()
+                .set_modified(modified)
+                .unwrap();
+            path
+        };
+        // The directory may be one the user keeps their own files in
+        let foreign = write("notes-2024.txt", two_days_ago);
+        let running = write("cargo-build-1700000000000.txt", two_days_ago);
+        let in_use = HashSet::from([running.clone()]);
+
+        let finished = write("cargo-test-1700000000001.txt", two_days_ago);
+        output::cleanup_old_files(Some(dir.path()), &output::Retention::default(), &in_use)
+            .unwrap();
+        assert!(!finished.exists());
+        assert!(foreign.exists());
+        assert!(running.exists());
+
+        let finished = write("cargo-test-1700000000002.txt", std::time::SystemTime::now());
+        let retention = output::Retention {
+            max_bytes: 0,
+            ..Default::default()
+        };
+        output::cleanup_old_files(Some(dir.path()), &retention, &in_use).unwrap();
+        assert!(!finished.exists());
+        assert!(foreign.exists());
+        assert!(running.exists());
+    }
+
+    #[tokio::test]
+    async fn test_streaming_output_file_creation() {
+        let process_manager = Arc::new(process_manager::ProcessManager::new());
+
+        let request = ExecuteRequest {
+            cmd: "echo".to_string(),
+            args: vec!["streaming".to_string(), "test".to_string()],
+            exact: true,
+            working_dir: std::env::current_dir().unwrap(),
+            ..Default::default()
+        };
+
+        let result = execute_command_streaming(
+            request,
+            process_manager,
+            Duration::from_secs(30), // Long timeout so it completes
+        )
+        .await
+        .unwrap();
+
+        dbg!(&result);
+        // Check that output file was created and contains content
+        assert!(!result.output_file.is_empty());
+        let output_content = std::fs::read_to_string(&result.output_file).unwrap();
+        assert!(output_content.contains("streaming test"));
+    }
+
+    #[tokio::test]
+    async fn test_kill_on_timeout_cancels_process() {
+        let process_manager = Arc::new(process_manager::ProcessManager::new());
+
+        let request = ExecuteRequest {
+            cmd: "sleep".to_string(),
+            args: vec!["30".to_string()],
+            exact: true,
+            working_dir: std::env::current_dir().unwrap(),
+            kill_on_timeout: true,
+            ..Default::default()
+        };
+
+        let result = execute_command_streaming(
+            request,
+            process_manager.clone(),
+            Duration::from_millis(200),
+        )
+        .await
+        .unwrap();
+
+        assert!(!result.is_running);
+        assert_eq!(result.truncation_reason.as_deref(), Some("timeout"));
+        let status = process_manager
+            .get_process_status(&result.process_id.unwrap())
+            .await
+            .unwrap();
+        assert_eq!(status.state, ProcessState::Cancelled);
     }
 }
@@ -0,0 +1,144 @@
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::fmt::Write;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// How many of the latest summary sizes the percentile is taken over
+const SUMMARY_SAMPLES: usize = 1000;
+
+/// How a finished process turned out, for counting. Each process counts once, under
+/// the first that applies.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    /// Stopped for running too long or going quiet
+    TimedOut,
+    Cancelled,
+    /// A failing exit, a signal, or an error running it
+    Failed,
+    Succeeded,
+}
+
+/// Running totals of the processes a manager has run
+#[derive(Debug, Default)]
+pub struct Metrics {
+    counters: Mutex<Counters>,
+}
+
+#[derive(Debug, Default)]
+struct Counters {
+    started: u64,
+    succeeded: u64,
+    failed: u64,
+    cancelled: u64,
+    timed_out: u64,
+    total_duration: Duration,
+    summary_bytes: VecDeque<usize>,
+}
+
+impl Metrics {
+    pub fn record_start(&self) {
+        self.counters.lock().unwrap().started += 1;
+    }
+
+    /// Count a process that finished after `duration` with a summary of `summary_bytes`
+    pub fn record_finish(&self, outcome: Outcome, duration: Duration, summary_bytes: usize) {
+        let mut counters = self.counters.lock().unwrap();
+        *match outcome {
+            Outcome::TimedOut => &mut counters.timed_out,
+            Outcome::Cancelled => &mut counters.cancelled,
+            Outcome::Failed => &mut counters.failed,
+            Outcome::Succeeded => &mut counters.succeeded,
+        } += 1;
+        counters.total_duration += duration;
+        if counters.summary_bytes.len() == SUMMARY_SAMPLES {
+            counters.summary_bytes.pop_front();
+        }
+        counters.summary_bytes.push_back(summary_bytes);
+    }
+
+    pub fn snapshot(&self) -> MetricsSnapshot {
+        let counters = self.counters.lock().unwrap();
+        let finished =
+            counters.succeeded + counters.failed + counters.cancelled + counters.timed_out;
+        let mut sizes: Vec<usize> = counters.summary_bytes.iter().copied().collect();
+        sizes.sort_unstable();
+        // Nearest rank
+        let p95 = (sizes.len() * 95).div_ceil(100).max(1) - 1;
+        MetricsSnapshot {
+            started: counters.started,
+            finished,
+            succeeded: counters.succeeded,
+            failed: counters.failed,
+            cancelled: counters.cancelled,
+            timed_out: counters.timed_out,
+            avg_duration_ms: (finished > 0)
+                .then(|| counters.total_duration.as_millis() as u64 / finished),
+            p95_summary_bytes: sizes.get(p95).copied(),
+        }
+    }
+}
+
+/// The totals at one point in time
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct MetricsSnapshot {
+    /// Processes started, including queued and running ones
+    pub started: u64,
+    pub finished: u64,
+    pub succeeded: u64,
+    pub failed: u64,
+    pub cancelled: u64,
+    pub timed_out: u64,
+    /// Mean run time of the finished processes
+    pub avg_duration_ms: Option<u64>,
+    /// 95th percentile size of the latest 1000 summaries
+    pub p95_summary_bytes: Option<usize>,
+}
+
+impl MetricsSnapshot {
+    /// The totals in the Prometheus text exposition format
+    pub fn to_prometheus(&self) -> String {
+        let mut out = String::new();
+        let mut metric = |name: &str, kind: &str, help: &str, samples: &[(&str, u64)]| {
+            let _ = writeln!(out, "# HELP shelly_{name} {help}");
+            let _ = writeln!(out, "# TYPE shelly_{name} {kind}");
+            for (labels, value) in samples {
+                let _ = writeln!(out, "shelly_{name}{labels} {value}");
+            }
+        };
+        metric(
+            "commands_started_total",
+            "counter",
+            "Commands started.",
+            &[("", self.started)],
+        );
+        metric(
+            "commands_finished_total",
+            "counter",
+            "Commands finished, by outcome.",
+            &[
+                ("{outcome=\"succeeded\"}", self.succeeded),
+                ("{outcome=\"failed\"}", self.failed),
+                ("{outcome=\"cancelled\"}", self.cancelled),
+                ("{outcome=\"timed_out\"}", self.timed_out),
+            ],
+        );
+        if let Some(avg) = self.avg_duration_ms {
+            metric(
+                "command_duration_avg_milliseconds",
+                "gauge",
+                "Mean run time of finished commands.",
+                &[("", avg)],
+            );
+        }
+        if let Some(p95) = self.p95_summary_bytes {
+            metric(
+                "summary_bytes_p95",
+                "gauge",
+                "95th percentile summary size of the latest 1000 commands.",
+                &[("", p95 as u64)],
+            );
+        }
+        out
+    }
+}
@@ -1,55 +1,692 @@
 use anyhow::{Context, Result};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
 use std::fs;
+use std::io::{self, BufRead, Read, Seek, SeekFrom, Write};
+#[cfg(unix)]
+use std::os::unix::fs::OpenOptionsExt;
 use std::path::{Path, PathBuf};
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::sync::LazyLock;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-const MAX_AGE_SECS: u64 = 86400; // 1 day
+use crate::process_manager::{OutputLine, OutputStream};
 
-/// Get the shelly output directory, creating it if needed
-pub fn output_dir() -> Result<PathBuf> {
-    let dir = std::env::temp_dir().join("shelly");
-    fs::create_dir_all(&dir).context("Failed to create output directory")?;
+/// Environment variable that overrides how long output files are kept, in seconds
+pub const MAX_AGE_ENV: &str = "SHELLY_OUTPUT_MAX_AGE_SECS";
+
+/// Environment variable that overrides how many bytes of output files are kept
+pub const MAX_BYTES_ENV: &str = "SHELLY_MAX_OUTPUT_BYTES";
+
+/// How much of the output directory `cleanup_old_files` keeps
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Retention {
+    /// Files last written longer ago than this are removed
+    pub max_age: Duration,
+    /// Once the files left add up to more than this, the oldest are removed until they
+    /// fit
+    pub max_bytes: u64,
+}
+
+impl Default for Retention {
+    fn default() -> Self {
+        Self {
+            max_age: Duration::from_secs(86400), // 1 day
+            max_bytes: 1024 * 1024 * 1024,       // 1 GiB
+        }
+    }
+}
+
+impl Retention {
+    /// The defaults, overridden by `SHELLY_OUTPUT_MAX_AGE_SECS` and
+    /// `SHELLY_MAX_OUTPUT_BYTES` where they are set to a number
+    pub fn from_env() -> Self {
+        let env = |name| std::env::var(name).ok().and_then(|v| v.parse::<u64>().ok());
+        let default = Self::default();
+        Self {
+            max_age: env(MAX_AGE_ENV).map_or(default.max_age, Duration::from_secs),
+            max_bytes: env(MAX_BYTES_ENV).unwrap_or(default.max_bytes),
+        }
+    }
+}
+
+/// What to do with ANSI escape codes (colors, cursor movement) in command output
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum AnsiMode {
+    /// Strip escape codes everywhere
+    #[default]
+    Strip,
+    /// Keep escape codes in the output file so it can be viewed in color. Summaries
+    /// are always stripped.
+    Keep,
+}
+
+/// Remove ANSI escape sequences from `input`
+pub fn strip_ansi_codes(input: &str) -> Cow<'_, str> {
+    if !input.contains('\x1b') {
+        return Cow::Borrowed(input);
+    }
+
+    let mut result = String::with_capacity(input.len());
+    let mut chars = input.chars().peekable();
+    while let Some(ch) = chars.next() {
+        if ch != '\x1b' {
+            result.push(ch);
+            continue;
+        }
+        match chars.next() {
+            // CSI: `ESC [ params final-byte`, e.g. colors and cursor movement
+            Some('[') => {
+                for c in chars.by_ref() {
+                    if ('@'..='~').contains(&c) {
+                        break;
+                    }
+                }
+            }
+            // OSC: `ESC ] ... BEL` or `ESC ] ... ESC \`, e.g. hyperlinks and titles
+            Some(']') => {
+                while let Some(c) = chars.next() {
+                    if c == '\x07' || (c == '\x1b' && chars.next_if_eq(&'\\').is_some()) {
+                        break;
+                    }
+                }
+            }
+            // Two-character escapes like `ESC 7` (save cursor)
+            _ => {}
+        }
+    }
+    Cow::Owned(result)
+}
+
+/// Shortest run of identical lines that `dedupe_lines` collapses; a shorter one isn't
+/// worth the note
+const MIN_REPEAT_RUN: usize = 3;
+
+/// How often the same line may show up in one chunk, runs aside, before further copies
+/// are dropped
+const MAX_LINE_REPEATS: usize = 10;
+
+/// Collapse runs of identical lines in `text` into the line and `... (repeated N
+/// times)`, and drop copies of a line beyond `MAX_LINE_REPEATS`. Blank lines are left
+/// alone. Returns the text and how many lines were removed.
+pub fn dedupe_lines(text: &str) -> (String, usize) {
+    let mut result = String::with_capacity(text.len());
+    let mut seen: HashMap<&str, usize> = HashMap::new();
+    let mut removed = 0;
+    let mut lines = text.split_inclusive('\n').peekable();
+    while let Some(line) = lines.next() {
+        let content = line.trim_end_matches(['\n', '\r']);
+        if content.trim().is_empty() {
+            result.push_str(line);
+            continue;
+        }
+        let mut run = 1;
+        while lines
+            .next_if(|next| next.trim_end_matches(['\n', '\r']) == content)
+            .is_some()
+        {
+            run += 1;
+        }
+
+        let count = seen.entry(content).or_default();
+        *count += 1;
+        if *count > MAX_LINE_REPEATS {
+            removed += run;
+            continue;
+        }
+        if run < MIN_REPEAT_RUN {
+            for _ in 0..run {
+                result.push_str(line);
+            }
+            continue;
+        }
+        result.push_str(content);
+        result.push_str(&format!("\n... (repeated {run} times)\n"));
+        removed += run - 1;
+    }
+    (result, removed)
+}
+
+/// Environment variable that overrides the default output directory
+pub const OUTPUT_DIR_ENV: &str = "SHELLY_OUTPUT_DIR";
+
+/// Resolve the shelly output directory, creating it if needed.
+///
+/// An explicit `dir` wins, then `SHELLY_OUTPUT_DIR`, then `$TMPDIR/shelly`.
+pub fn output_dir(dir: Option<&Path>) -> Result<PathBuf> {
+    let dir = match dir {
+        Some(dir) => dir.to_path_buf(),
+        None => match std::env::var_os(OUTPUT_DIR_ENV) {
+            Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+            _ => std::env::temp_dir().join("shelly"),
+        },
+    };
+    fs::create_dir_all(&dir)
+        .with_context(|| format!("Failed to create output directory {}", dir.display()))?;
     Ok(dir)
 }
 
-/// Create a new output file for a command
-pub fn create_output_file(command: &str) -> Result<PathBuf> {
-    let dir = output_dir()?;
+/// Longest handler label kept in an output file name
+const MAX_LABEL_LEN: usize = 60;
+
+/// Create a new output file for a command in the resolved output directory. It is named
+/// after the handler's `label` if one was given, else the start of the command.
+pub fn create_output_file(
+    dir: Option<&Path>,
+    command: &str,
+    label: Option<&str>,
+) -> Result<PathBuf> {
+    let dir = output_dir(dir)?;
     let timestamp = SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis();
-    let safe_cmd = command
-        .chars()
-        .take(20)
-        .filter(|c| c.is_alphanumeric() || *c == '-' || *c == '_')
-        .collect::<String>();
+    let safe_label = label
+        .map(|label| {
+            label
+                .chars()
+                .map(|c| if c.is_whitespace() { '-' } else { c })
+                .filter(|c| c.is_alphanumeric() || matches!(c, '-' | '_' | '.'))
+                .take(MAX_LABEL_LEN)
+                .collect::<String>()
+        })
+        // Leading dots would hide the file or climb out of the directory
+        .map(|label| label.trim_start_matches('.').to_string())
+        .filter(|label| !label.is_empty());
+    let safe_cmd = safe_label.unwrap_or_else(|| {
+        command
+            .chars()
+            .take(20)
+            .filter(|c| c.is_alphanumeric() || *c == '-' || *c == '_')
+            .collect::<String>()
+    });
     let filename = format!("{}-{}.txt", safe_cmd, timestamp);
     Ok(dir.join(filename))
 }
 
-/// Write output to a file
-pub fn write_output(path: &Path, stdout: &str, stderr: &str, exit_code: i32) -> Result<()> {
-    let content = format!(
-        "Exit Code: {}\n\n=== STDOUT ===\n{}\n\n=== STDERR ===\n{}",
-        exit_code, stdout, stderr
-    );
-    fs::write(path, content).context("Failed to write output file")?;
+/// Names `create_output_file` gives out: a sanitized label, a millisecond timestamp
+/// and `.txt`
+static OUTPUT_FILE_NAME: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"^[\w.-]*-\d{13,}\.txt$").unwrap());
+
+/// Whether `path` is named like a file `create_output_file` made, and so is one that
+/// `cleanup_old_files` may remove
+pub fn is_output_file(path: &Path) -> bool {
+    path.file_name()
+        .and_then(|name| name.to_str())
+        .is_some_and(|name| OUTPUT_FILE_NAME.is_match(name))
+}
+
+/// Output files can hold secrets and usually live in a shared temp dir, so only their
+/// owner may read them. On Windows `%TEMP%` is already private to the user.
+#[cfg(unix)]
+const OUTPUT_FILE_MODE: u32 = 0o600;
+
+/// Open an output file for writing, creating it readable only by its owner. Process
+/// records hold the same commands and summaries, so they are written with it too.
+pub(crate) fn open_output(path: &Path, append: bool) -> io::Result<fs::File> {
+    let mut options = fs::OpenOptions::new();
+    options.create(true);
+    #[cfg(unix)]
+    options.mode(OUTPUT_FILE_MODE);
+    if append {
+        options.append(true);
+    } else {
+        options.write(true).truncate(true);
+    }
+    options.open(path)
+}
+
+/// Create an empty output file for a command that is about to start
+pub fn start_output(path: &Path) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        output_dir(Some(parent))?;
+    }
+    open_output(path, false).context("Failed to create output file")?;
     Ok(())
 }
 
-/// Clean up old output files
-pub fn cleanup_old_files() -> Result<()> {
-    let dir = output_dir()?;
-    let now = SystemTime::now();
+/// Append lines to an output file as a single stream in arrival order, each line
+/// labeled with the stream it came from
+pub fn append_lines(path: &Path, lines: &[OutputLine]) -> Result<()> {
+    if lines.is_empty() {
+        return Ok(());
+    }
+    // The directory may have been cleaned up while the command was running
+    if let Some(parent) = path.parent() {
+        output_dir(Some(parent))?;
+    }
+
+    let mut ordered: Vec<&OutputLine> = lines.iter().collect();
+    ordered.sort_by_key(|line| line.seq);
+
+    let mut content = String::new();
+    for line in ordered {
+        let label = match line.stream {
+            OutputStream::Stdout => "out",
+            OutputStream::Stderr => "err",
+        };
+        content.push_str(&format!("[{label}] {}", line.text));
+        if !line.text.ends_with('\n') {
+            content.push('\n');
+        }
+    }
+    let mut file = open_output(path, true).context("Failed to open output file")?;
+    file.write_all(content.as_bytes())
+        .context("Failed to write output file")?;
+    Ok(())
+}
+
+/// What produced an output file, written between its exit code and the output so the
+/// file can be understood, and the command rerun, without anything else
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OutputHeader {
+    /// The program and arguments that were spawned, shell-quoted
+    pub command: String,
+    pub working_dir: PathBuf,
+    /// Names of the handlers that processed the output, in chain order
+    pub handlers: Vec<String>,
+    /// Variables set on top of the inherited environment, with secret values masked
+    pub env: BTreeMap<String, String>,
+}
+
+impl OutputHeader {
+    /// One `Key: value` line per field. Newlines in values are written as `\n` so
+    /// they can't end the header early.
+    fn render(&self) -> String {
+        let escape = |value: &str| value.replace('\n', "\\n");
+        let mut header = format!(
+            "Command: {}\nWorking Dir: {}\n",
+            escape(&self.command),
+            self.working_dir.display()
+        );
+        if !self.handlers.is_empty() {
+            header.push_str(&format!("Handlers: {}\n", self.handlers.join(", ")));
+        }
+        for (name, value) in &self.env {
+            header.push_str(&format!("Env: {name}={}\n", escape(value)));
+        }
+        header
+    }
+
+    /// The header of an output file, or `None` if it has no `Command:` line
+    fn parse(header: &str) -> Option<Self> {
+        let mut parsed = Self::default();
+        let mut has_command = false;
+        for line in header.lines() {
+            let Some((key, value)) = line.split_once(": ") else {
+                continue;
+            };
+            match key {
+                "Command" => {
+                    parsed.command = value.to_string();
+                    has_command = true;
+                }
+                "Working Dir" => parsed.working_dir = PathBuf::from(value),
+                "Handlers" => parsed.handlers = value.split(", ").map(String::from).collect(),
+                "Env" => {
+                    if let Some((name, value)) = value.split_once('=') {
+                        parsed.env.insert(name.to_string(), value.to_string());
+                    }
+                }
+                _ => {}
+            }
+        }
+        has_command.then_some(parsed)
+    }
+}
+
+/// Finish an output file: append the remaining lines and put the exit code, and the
+/// header if there is one, in front of everything spilled while the command ran
+pub fn write_output(
+    path: &Path,
+    lines: &[OutputLine],
+    exit_code: i32,
+    header: Option<&OutputHeader>,
+) -> Result<()> {
+    append_lines(path, lines)?;
+
+    // Copy rather than read the body, it may be far larger than we want in memory
+    let staging = path.with_extension(format!("{}.tmp", uuid::Uuid::new_v4()));
+    let mut out = open_output(&staging, false).context("Failed to write output file")?;
+    let header = header.map(OutputHeader::render).unwrap_or_default();
+    write!(out, "Exit Code: {exit_code}\n{header}\n")?;
+    match fs::File::open(path) {
+        Ok(mut body) => {
+            io::copy(&mut body, &mut out).context("Failed to write output file")?;
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e).context("Failed to read output file"),
+    }
+    fs::rename(&staging, path).context("Failed to write output file")?;
+    Ok(())
+}
+
+/// A command's output read back from its output file
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CapturedOutput {
+    pub stdout: String,
+    pub stderr: String,
+    /// `None` if the command hadn't finished when the file was read
+    pub exit_code: Option<i32>,
+    /// `None` for a command that hadn't finished, or a file written without one
+    pub header: Option<OutputHeader>,
+}
+
+/// Split an output file written by `append_lines` and `write_output` back into the
+/// command's stdout, stderr, exit code and header
+pub fn parse_output_file(path: &Path) -> Result<CapturedOutput> {
+    let bytes = fs::read(path).context("Failed to read output file")?;
+    let content = String::from_utf8_lossy(&bytes);
+    let mut body = content.as_ref();
+    let mut captured = CapturedOutput::default();
+    if let Some((header, rest)) = body.split_once("\n\n") {
+        if let Some(header) = header.strip_prefix("Exit Code: ") {
+            let (code, header) = header.split_once('\n').unwrap_or((header, ""));
+            captured.exit_code = Some(code.trim().parse().context("Invalid exit code")?);
+            captured.header = OutputHeader::parse(header);
+            body = rest;
+        }
+    }
+    for line in body.split_inclusive('\n') {
+        // Unlabeled lines shouldn't occur, but count them as stdout rather than lose them
+        match (line.strip_prefix("[out] "), line.strip_prefix("[err] ")) {
+            (Some(text), _) => captured.stdout.push_str(text),
+            (_, Some(text)) => captured.stderr.push_str(text),
+            _ => captured.stdout.push_str(line),
+        }
+    }
+    Ok(captured)
+}
+
+/// Which of a command's streams tails and searches look at
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum StreamFilter {
+    Stdout,
+    Stderr,
+    /// Both streams in the order their lines arrived
+    #[default]
+    Both,
+}
+
+impl StreamFilter {
+    /// Whether a line of an output file is kept. With a single stream selected, only
+    /// that stream's labeled lines are, not the header.
+    fn includes_line(self, line: &[u8]) -> bool {
+        match self {
+            Self::Stdout => line.starts_with(b"[out] "),
+            Self::Stderr => line.starts_with(b"[err] "),
+            Self::Both => true,
+        }
+    }
+}
+
+/// Read the last `lines` lines of `stream` from an output file, reading backwards from
+/// the end so a large file is never loaded whole
+pub fn tail_lines(path: &Path, lines: usize, stream: StreamFilter) -> Result<String> {
+    const BLOCK_SIZE: u64 = 64 * 1024;
+
+    let mut file = fs::File::open(path).context("Failed to read output file")?;
+    let len = file.metadata()?.len();
+    let mut start = len;
+    let mut tail = Vec::new();
+    // One extra newline: the file's own trailing newline doesn't start a line
+    while start > 0 && tail.iter().filter(|&&b| b == b'\n').count() <= lines {
+        let block_start = start.saturating_sub(BLOCK_SIZE);
+        let mut block = vec![0; (start - block_start) as usize];
+        file.seek(SeekFrom::Start(block_start))?;
+        file.read_exact(&mut block)?;
+        block.extend_from_slice(&tail);
+        tail = block;
+        start = block_start;
+        if stream != StreamFilter::Both {
+            // Drop the other stream's lines as we go, except the first, which may
+            // continue in the block before
+            let first_end = tail.iter().position(|&b| b == b'\n').map_or(tail.len(), |i| i + 1);
+            let rest = tail.split_off(first_end);
+            tail.extend(
+                rest.split_inclusive(|&b| b == b'\n')
+                    .filter(|line| stream.includes_line(line))
+                    .flatten(),
+            );
+        }
+    }
+    if stream != StreamFilter::Both && !stream.includes_line(&tail) {
+        let first_end = tail.iter().position(|&b| b == b'\n').map_or(tail.len(), |i| i + 1);
+        tail.drain(..first_end);
+    }
+
+    let content = String::from_utf8_lossy(&tail);
+    Ok(crate::process_manager::last_n_lines(&content, lines).to_string())
+}
+
+/// How `read_output` measures offsets and lengths
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ReadUnit {
+    Bytes,
+    #[default]
+    Lines,
+}
+
+/// A window of an output file returned by `read_output`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OutputSlice {
+    pub content: String,
+    pub unit: ReadUnit,
+    pub offset: u64,
+    /// Offset to pass to read the following window
+    pub next_offset: u64,
+    /// Size of the whole file in bytes
+    pub total_size: u64,
+    pub has_more: bool,
+}
 
-    for entry in fs::read_dir(dir)? {
-        let entry = entry?;
-        let metadata = entry.metadata()?;
+/// Read `length` bytes or lines of an output file starting at `offset`.
+///
+/// Byte windows seek straight to `offset` and never end inside a UTF-8 character.
+/// Line windows skip ahead a line at a time without keeping what they skip.
+pub fn read_output(path: &Path, offset: u64, length: u64, unit: ReadUnit) -> Result<OutputSlice> {
+    let mut file = fs::File::open(path).context("Failed to read output file")?;
+    let total_size = file.metadata()?.len();
 
-        if let Ok(modified) = metadata.modified() {
-            if let Ok(age) = now.duration_since(modified) {
-                if age.as_secs() > MAX_AGE_SECS {
-                    fs::remove_file(entry.path())?;
+    let (content, next_offset, has_more) = match unit {
+        ReadUnit::Bytes => {
+            let start = offset.min(total_size);
+            file.seek(SeekFrom::Start(start))?;
+            let mut bytes = Vec::new();
+            file.take(length).read_to_end(&mut bytes)?;
+            // Hold back a character split by the end of the window
+            let valid = match std::str::from_utf8(&bytes) {
+                Err(e) if e.error_len().is_none() && e.valid_up_to() > 0 => e.valid_up_to(),
+                _ => bytes.len(),
+            };
+            bytes.truncate(valid);
+            let next_offset = start + valid as u64;
+            (
+                String::from_utf8_lossy(&bytes).into_owned(),
+                next_offset,
+                next_offset < total_size,
+            )
+        }
+        ReadUnit::Lines => {
+            let mut reader = io::BufReader::new(file);
+            let mut buf = Vec::new();
+            let mut line = 0;
+            while line < offset {
+                if reader.read_until(b'\n', &mut buf)? == 0 {
+                    break;
+                }
+                buf.clear();
+                line += 1;
+            }
+            let mut read = 0;
+            while read < length {
+                if reader.read_until(b'\n', &mut buf)? == 0 {
+                    break;
                 }
+                read += 1;
             }
+            let has_more = !reader.fill_buf()?.is_empty();
+            (
+                String::from_utf8_lossy(&buf).into_owned(),
+                line + read,
+                has_more,
+            )
+        }
+    };
+
+    Ok(OutputSlice {
+        content,
+        unit,
+        offset,
+        next_offset,
+        total_size,
+        has_more,
+    })
+}
+
+/// A line returned by `search_output`, either a match or context around one
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct SearchLine {
+    /// 1-based line number in the output file
+    pub line_number: usize,
+    pub text: String,
+    pub is_match: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SearchResult {
+    /// Matching lines and their context, in file order without duplicates
+    pub lines: Vec<SearchLine>,
+    /// Matches in the whole file, including any past `max_matches`
+    pub total_matches: usize,
+}
+
+/// Find lines of `stream` matching `pattern` in an output file, with up to
+/// `context_lines` lines of the same stream around each. Only the first `max_matches`
+/// matches are returned. The file is read a line at a time, so it can be much larger
+/// than memory.
+pub fn search_output(
+    path: &Path,
+    pattern: &str,
+    context_lines: usize,
+    max_matches: usize,
+    stream: StreamFilter,
+) -> Result<SearchResult> {
+    let regex = Regex::new(pattern).with_context(|| format!("Invalid pattern `{pattern}`"))?;
+    let mut reader =
+        io::BufReader::new(fs::File::open(path).context("Failed to read output file")?);
+
+    let mut lines = Vec::new();
+    let mut before: VecDeque<SearchLine> = VecDeque::with_capacity(context_lines);
+    let mut after_remaining = 0;
+    let mut total_matches = 0;
+    let mut buf = Vec::new();
+    let mut line_number = 0;
+    loop {
+        buf.clear();
+        if reader.read_until(b'\n', &mut buf)? == 0 {
+            break;
+        }
+        line_number += 1;
+        if !stream.includes_line(&buf) {
+            continue;
+        }
+        let text = String::from_utf8_lossy(&buf);
+        let text = text.trim_end_matches(['\n', '\r']);
+
+        let is_match = regex.is_match(text);
+        if is_match {
+            total_matches += 1;
+        }
+        if total_matches > max_matches {
+            // Past the limit; keep counting but stop collecting
+            continue;
+        }
+
+        let line = SearchLine {
+            line_number,
+            text: text.to_string(),
+            is_match,
+        };
+        if is_match {
+            lines.extend(before.drain(..));
+            lines.push(line);
+            after_remaining = context_lines;
+        } else if after_remaining > 0 {
+            lines.push(line);
+            after_remaining -= 1;
+        } else if context_lines > 0 {
+            if before.len() == context_lines {
+                before.pop_front();
+            }
+            before.push_back(line);
+        }
+    }
+
+    Ok(SearchResult {
+        lines,
+        total_matches,
+    })
+}
+
+/// Remove output files from the resolved output directory that `retention` doesn't
+/// keep: first those past its age, then the oldest until the rest fit its size budget.
+///
+/// Only files named like `create_output_file` names them are touched, since the
+/// directory may be one the user also keeps other files in. Files in `in_use`, which
+/// belong to processes that are still running, are neither removed nor counted against
+/// the budget. A file that can't be inspected or removed is logged and skipped, so one
+/// bad entry doesn't stop the sweep.
+pub fn cleanup_old_files(
+    dir: Option<&Path>,
+    retention: &Retention,
+    in_use: &HashSet<PathBuf>,
+) -> Result<()> {
+    let dir = output_dir(dir)?;
+    let now = SystemTime::now();
+
+    let mut files = Vec::new();
+    for entry in fs::read_dir(&dir)? {
+        let path = match entry {
+            Ok(entry) => entry.path(),
+            Err(e) => {
+                tracing::warn!("skipping an entry of {}: {e}", dir.display());
+                continue;
+            }
+        };
+        if !is_output_file(&path) || in_use.contains(&path) {
+            continue;
+        }
+        let metadata = match fs::symlink_metadata(&path) {
+            Ok(metadata) => metadata,
+            Err(e) => {
+                tracing::warn!("skipping {}: {e}", path.display());
+                continue;
+            }
+        };
+        // Leave directories, e.g. the process registry, alone
+        if !metadata.is_file() {
+            continue;
+        }
+        let modified = metadata.modified().unwrap_or(now);
+        files.push((modified, metadata.len(), path));
+    }
+
+    // Oldest first
+    files.sort();
+    let mut total: u64 = files.iter().map(|(_, len, _)| len).sum();
+    for (modified, len, path) in files {
+        let stale = now
+            .duration_since(modified)
+            .is_ok_and(|age| age > retention.max_age);
+        if !stale && total <= retention.max_bytes {
+            break;
+        }
+        match fs::remove_file(&path) {
+            Ok(()) => total -= len,
+            Err(e) => tracing::warn!("failed to remove old output file {}: {e}", path.display()),
         }
     }
 
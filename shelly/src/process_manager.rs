@@ -1,15 +1,41 @@
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::path::PathBuf;
 use std::sync::Arc;
 use std::time::SystemTime;
-use tokio::sync::{watch, RwLock};
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+use tokio::sync::{oneshot, watch, Mutex, OwnedSemaphorePermit, RwLock, Semaphore};
 use tokio::task::JoinHandle;
-use tokio::time::Duration;
+use tokio::time::{Duration, Instant};
 use uuid::Uuid;
 
-use crate::output;
-use crate::runtime::{process, HandlerRuntime};
+use crate::metrics::{Metrics, Outcome};
+use crate::output::{self, AnsiMode, StreamFilter};
+use crate::redact::Redactor;
+use crate::registry::{self, ProcessRecord};
+use crate::runtime::{
+    estimate_tokens, process, ExitCodeMeaning, HandlerAction, HandlerCache, HandlerChain,
+    SummarySections, TruncationInfo,
+};
+
+/// Default for how much of each stream is kept in memory; the output file always has
+/// everything
+pub const DEFAULT_BUFFER_LIMIT: usize = 1024 * 1024;
+
+/// Default for how many processes run at once; later ones wait in `Queued`
+pub const DEFAULT_MAX_CONCURRENT: usize = 16;
+
+/// Overrides `DEFAULT_MAX_CONCURRENT`
+pub const MAX_CONCURRENT_ENV: &str = "SHELLY_MAX_CONCURRENT";
+
+/// Characters of summary returned per update
+const SUMMARY_LIMIT: usize = 1000;
+
+/// Separates the head and tail of a buffer that went over its limit
+pub const OMITTED_MARKER: &str = "\n[... output omitted, see the output file ...]\n";
+
+/// Where input for a process is written: its stdin pipe, or its terminal in PTY mode
+pub type ProcessStdin = Box<dyn AsyncWrite + Unpin + Send>;
 
 #[derive(Debug, Clone, Serialize, Deserialize, Hash, Eq, PartialEq)]
 pub struct ProcessId(pub String);
@@ -20,23 +46,171 @@ impl ProcessId {
     }
 }
 
+impl Default for ProcessId {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
 pub enum ProcessState {
+    /// Waiting for a slot under the manager's concurrency limit
+    Queued,
     Running,
     Completed { exit_code: i32 },
+    /// Killed by a signal it didn't handle, e.g. a segfault or the OOM killer
+    Signaled { signal: i32 },
     Cancelled,
     Failed { error: String },
 }
 
+impl ProcessState {
+    /// Whether the process has stopped for good, as opposed to queued or running
+    pub fn is_finished(&self) -> bool {
+        !matches!(self, ProcessState::Queued | ProcessState::Running)
+    }
+}
+
+/// Which stream a line of output was read from
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputStream {
+    Stdout,
+    Stderr,
+}
+
+/// A line of output, numbered in the order lines arrived across both streams
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OutputLine {
+    pub seq: u64,
+    pub stream: OutputStream,
+    pub text: String,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ProcessInfo {
     pub id: ProcessId,
     pub command: String,
     pub state: ProcessState,
     pub started_at: SystemTime,
+    /// When the first output arrived on either stream
+    pub first_output_at: Option<SystemTime>,
+    /// When the process stopped, set once its state is finished
+    pub finished_at: Option<SystemTime>,
+    /// Retained stdout/stderr: everything up to the buffer limit, then its head and tail
+    /// separated by `OMITTED_MARKER`
     pub raw_stdout: String,
     pub raw_stderr: String,
+    /// Bytes dropped from the middle of `raw_stdout` and `raw_stderr`
+    pub omitted_bytes: usize,
+    /// Lines not yet written to the output file, with sequence numbers for
+    /// reconstructing interleaving
+    pub lines: Vec<OutputLine>,
+    /// Number of lines seen so far, across both streams
+    pub line_count: u64,
     pub output_file: Option<PathBuf>,
+    /// Set when the process is stopped for running too long or going idle
+    #[serde(default)]
+    pub timed_out: bool,
+}
+
+impl ProcessInfo {
+    /// How long the process ran for, or has been running so far
+    pub fn duration(&self) -> Duration {
+        let end = self.finished_at.unwrap_or_else(SystemTime::now);
+        end.duration_since(self.started_at).unwrap_or(Duration::ZERO)
+    }
+
+    /// How long after it was started the process produced its first output. Includes
+    /// time spent queued.
+    pub fn time_to_first_output(&self) -> Option<Duration> {
+        let first_output_at = self.first_output_at?;
+        Some(first_output_at.duration_since(self.started_at).unwrap_or(Duration::ZERO))
+    }
+
+    fn record_lines(&mut self, stream: OutputStream, output: &str) {
+        for text in output.split_inclusive('\n') {
+            match self.lines.last_mut() {
+                // The start of this line was forwarded before its newline arrived
+                Some(last) if last.stream == stream && !last.text.ends_with('\n') => {
+                    last.text.push_str(text);
+                    last.text = last_frame(&last.text).to_string();
+                }
+                _ => {
+                    self.lines.push(OutputLine {
+                        seq: self.line_count,
+                        stream,
+                        text: last_frame(text).to_string(),
+                    });
+                    self.line_count += 1;
+                }
+            }
+        }
+    }
+
+    /// Take the lines that can no longer change: all of them except an unterminated
+    /// last line, which may still be continued
+    fn take_finished_lines(&mut self) -> Vec<OutputLine> {
+        let keep_last = self
+            .lines
+            .last()
+            .is_some_and(|line| !line.text.ends_with('\n'));
+        let finished = self.lines.len() - usize::from(keep_last);
+        self.lines.drain(..finished).collect()
+    }
+}
+
+/// Append `output` to `buf`, keeping it under roughly `limit` bytes by dropping the
+/// middle. Returns how many bytes were dropped.
+fn push_bounded(buf: &mut String, output: &str, limit: usize) -> usize {
+    buf.push_str(output);
+    if buf.len() <= limit {
+        return 0;
+    }
+
+    let half = limit / 2;
+    let tail_start = match buf.find(OMITTED_MARKER) {
+        Some(marker) => marker + OMITTED_MARKER.len(),
+        None => {
+            // Prefer to end the head on a line boundary
+            let head_end = floor_char_boundary(buf, half);
+            let head_end = buf[..head_end].rfind('\n').map_or(head_end, |i| i + 1);
+            buf.insert_str(head_end, OMITTED_MARKER);
+            head_end + OMITTED_MARKER.len()
+        }
+    };
+    if buf.len() <= limit {
+        return 0;
+    }
+
+    // Trim the tail to a quarter of the limit, so appends only pay for the move every
+    // so often
+    let cut = floor_char_boundary(buf, buf.len() - limit / 4).max(tail_start);
+    let cut = buf[cut..].find('\n').map_or(cut, |i| cut + i + 1);
+    buf.replace_range(tail_start..cut, "");
+    cut - tail_start
+}
+
+pub(crate) fn floor_char_boundary(s: &str, mut index: usize) -> usize {
+    while !s.is_char_boundary(index) {
+        index -= 1;
+    }
+    index
+}
+
+/// The part of a line after its last `\r` redraw, so a progress bar is stored as its
+/// final frame
+fn last_frame(line: &str) -> &str {
+    let content = line
+        .strip_suffix('\n')
+        .map(|l| l.strip_suffix('\r').unwrap_or(l))
+        .unwrap_or(line);
+    // A trailing `\r` may still be followed by `\n`
+    let content = content.strip_suffix('\r').unwrap_or(content);
+    match content.rfind('\r') {
+        Some(cr) => &line[cr + 1..],
+        None => line,
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -45,92 +219,467 @@ pub struct ProcessStatus {
     pub command: String,
     pub state: ProcessState,
     pub started_at: SystemTime,
+    pub finished_at: Option<SystemTime>,
+    /// Milliseconds the process ran for, or has been running so far
+    pub duration_ms: u64,
+    /// Milliseconds from starting the process to its first output, if it has printed
+    /// anything
+    pub time_to_first_output_ms: Option<u64>,
     pub stdout_length: usize,
     pub stderr_length: usize,
+    /// Bytes of output only available in the output file
+    pub omitted_bytes: usize,
     pub output_file: Option<PathBuf>,
+    /// Whether the process was stopped for running too long or going idle
+    pub timed_out: bool,
+}
+
+impl From<&ProcessInfo> for ProcessStatus {
+    fn from(info: &ProcessInfo) -> Self {
+        ProcessStatus {
+            id: info.id.clone(),
+            command: info.command.clone(),
+            state: info.state.clone(),
+            started_at: info.started_at,
+            finished_at: info.finished_at,
+            duration_ms: info.duration().as_millis() as u64,
+            time_to_first_output_ms: info.time_to_first_output().map(|d| d.as_millis() as u64),
+            stdout_length: info.raw_stdout.len(),
+            stderr_length: info.raw_stderr.len(),
+            omitted_bytes: info.omitted_bytes,
+            output_file: info.output_file.clone(),
+            timed_out: info.timed_out,
+        }
+    }
 }
 
-#[derive(Serialize, Debug)]
+#[derive(Serialize, Deserialize, Debug)]
 pub struct ProcessUpdate {
     pub incremental_summary: String,
     pub status: ProcessState,
+    /// Whether the join returned because its timeout ran out while the process was still
+    /// running, rather than because the process finished
+    pub timed_out: bool,
+    /// Whether output went over the in-memory limit, so handlers only saw part of it
+    /// in their final call
+    pub output_truncated: bool,
+    /// Characters dropped from the summary to keep the update short
+    pub omitted_chars: usize,
+    /// Pass to the next join to get only what was added after this update
+    pub cursor: usize,
+    /// Actions the handler currently offers for this process
+    pub actions: Vec<HandlerAction>,
+    /// What the handler last said it left out of the summary
+    pub truncation: Option<TruncationInfo>,
+    /// The handler's latest summary split up by kind, if it returned one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sections: Option<SummarySections>,
+    /// Milliseconds the process ran for, or has been running so far
+    pub duration_ms: u64,
+    /// Milliseconds from starting the process to its first output, if it has printed
+    /// anything
+    pub time_to_first_output_ms: Option<u64>,
+    /// Whether the process failed. A non-zero exit only counts if the handler doesn't
+    /// say it is expected, like `grep` finding no matches.
+    pub is_error: bool,
+    /// What the handler says the exit code means
+    pub exit_code_meaning: Option<String>,
+}
+
+/// How long a finished process stays queryable
+const MAX_AGE: Duration = Duration::from_secs(3600);
+
+fn is_expired(started_at: SystemTime) -> bool {
+    SystemTime::now()
+        .duration_since(started_at)
+        .unwrap_or(Duration::ZERO)
+        > MAX_AGE
+}
+
+/// Output files of processes that haven't finished, which cleanup must leave alone
+fn unfinished_output_files(processes: &HashMap<ProcessId, ProcessTask>) -> HashSet<PathBuf> {
+    processes
+        .values()
+        .filter(|task| !task.info.state.is_finished())
+        .filter_map(|task| task.info.output_file.clone())
+        .collect()
+}
+
+/// Remove stale output files from a directory without holding up the caller. Files in
+/// `in_use` are kept.
+fn spawn_output_cleanup(dir: PathBuf, in_use: HashSet<PathBuf>) {
+    tokio::task::spawn_blocking(move || {
+        let retention = output::Retention::from_env();
+        if let Err(e) = output::cleanup_old_files(Some(&dir), &retention, &in_use) {
+            tracing::warn!("failed to clean up {}: {e:#}", dir.display());
+        }
+    });
 }
 
 // Simplified ProcessTask that just stores updates and state
 pub struct ProcessTask {
     pub info: ProcessInfo,
-    pub delta_summary: String,
+    /// Summary text produced so far. Only ever appended to, so offsets into it are
+    /// stable cursors.
+    pub summary: String,
+    /// The handler's summary of the whole run, which supersedes `summary` once set
+    pub final_summary: Option<String>,
+    /// How much of `summary` has been returned by joins that didn't pass a cursor
+    pub joined_up_to: usize,
+    /// The handler's latest non-empty set of actions
+    pub actions: Vec<HandlerAction>,
+    /// What the handler last said it left out of the summary
+    pub truncation: Option<TruncationInfo>,
+    /// The handler's latest summary sections
+    pub sections: Option<SummarySections>,
+    /// Set if the output wasn't plain UTF-8, and reported when the handler has nothing
+    /// to say about truncation
+    pub encoding_note: Option<TruncationInfo>,
+    /// What the handler says the command's exit codes mean
+    pub exit_codes: HashMap<i32, ExitCodeMeaning>,
+    /// stdout and stderr in the order they arrived, for tailing
+    pub combined_output: String,
+    /// Output appended since the handler last summarized
+    pub unsummarized_stdout: String,
+    pub unsummarized_stderr: String,
+    /// The end of an unfinished line, held back until it is known not to cut a secret
+    /// in two
+    pub held_stdout: String,
+    pub held_stderr: String,
+    /// Whether escape codes are kept in the output file
+    pub ansi: AnsiMode,
+    /// What produced the output, written at the top of the output file once it is done
+    pub header: Option<output::OutputHeader>,
+    /// Masks secrets in output before it is stored anywhere
+    pub redactor: Redactor,
     pub executor_handle: Option<JoinHandle<anyhow::Result<()>>>,
+    /// OS process id of the spawned child, once it has been spawned
+    pub pid: Option<u32>,
+    /// Write end of the child's stdin, dropped once the process finishes
+    pub stdin: Option<Arc<Mutex<ProcessStdin>>>,
+    /// Set by `cancel_process` so the executor records `Cancelled` instead of `Completed`
+    pub cancel_requested: bool,
+    /// Why the executor stopped the process itself, recorded as `Failed` instead of
+    /// how it exited
+    pub failure: Option<String>,
+    /// When output was last read from the process, for the idle timeout
+    pub last_output_at: Option<Instant>,
     pub complete_tx: watch::Sender<bool>,
     pub complete_rx: watch::Receiver<bool>,
+    /// Tells the executor to terminate the child
+    pub cancel_tx: watch::Sender<bool>,
+    /// Length of `summary`, bumped whenever it grows
+    pub summary_tx: watch::Sender<usize>,
+}
+
+impl ProcessTask {
+    /// Whether the process has failed, and the handler's label for its exit code
+    fn exit_interpretation(&self) -> (bool, Option<String>) {
+        match &self.info.state {
+            ProcessState::Queued | ProcessState::Running => (false, None),
+            ProcessState::Completed { exit_code } => match self.exit_codes.get(exit_code) {
+                Some(meaning) => (meaning.is_error, Some(meaning.label.clone())),
+                None => (*exit_code != 0, None),
+            },
+            _ => (true, None),
+        }
+    }
+
+    fn new(info: ProcessInfo, ansi: AnsiMode, redactor: Redactor) -> Self {
+        let finished = info.state.is_finished();
+        let (complete_tx, complete_rx) = watch::channel(finished);
+        let (cancel_tx, _) = watch::channel(false);
+        let (summary_tx, _) = watch::channel(0);
+        Self {
+            info,
+            executor_handle: None,
+            pid: None,
+            stdin: None,
+            cancel_requested: false,
+            failure: None,
+            last_output_at: None,
+            summary: String::new(),
+            final_summary: None,
+            joined_up_to: 0,
+            actions: Vec::new(),
+            truncation: None,
+            sections: None,
+            encoding_note: None,
+            exit_codes: HashMap::new(),
+            combined_output: String::new(),
+            unsummarized_stdout: String::new(),
+            unsummarized_stderr: String::new(),
+            held_stdout: String::new(),
+            held_stderr: String::new(),
+            ansi,
+            header: None,
+            redactor,
+            complete_tx,
+            complete_rx,
+            cancel_tx,
+            summary_tx,
+        }
+    }
+
+    /// A process recorded by an earlier manager. Its output is only in the output file.
+    fn restore(record: ProcessRecord) -> Self {
+        let state = match record.state {
+            // Nothing is reading its output or will reap it anymore
+            state if !state.is_finished() => ProcessState::Failed {
+                error: "shelly restarted while the process was running".to_string(),
+            },
+            state => state,
+        };
+        let info = ProcessInfo {
+            id: record.id,
+            command: record.command,
+            state,
+            started_at: record.started_at,
+            first_output_at: None,
+            // A process that was still running stopped being tracked now
+            finished_at: record.finished_at.or_else(|| Some(SystemTime::now())),
+            raw_stdout: String::new(),
+            raw_stderr: String::new(),
+            omitted_bytes: 0,
+            lines: Vec::new(),
+            line_count: 0,
+            output_file: record.output_file,
+            timed_out: false,
+        };
+        let mut task = Self::new(info, AnsiMode::default(), Redactor::default());
+        task.final_summary = record.summary;
+        task
+    }
 }
 
 pub struct ProcessManager {
     pub processes: Arc<RwLock<HashMap<ProcessId, ProcessTask>>>,
+    /// Bytes of each stream kept in memory per process
+    pub buffer_limit: usize,
+    /// Where a record of each process is kept so it outlives this manager
+    pub registry_dir: Option<PathBuf>,
+    /// How many processes may run at once
+    pub max_concurrent: usize,
+    slots: Arc<Semaphore>,
+    /// Directories output files have been written to, swept for stale files by the
+    /// cleanup task
+    output_dirs: Arc<std::sync::Mutex<HashSet<PathBuf>>>,
+    /// Handlers kept loaded for the next command that needs them
+    pub handler_cache: HandlerCache,
+    /// Counts of how the processes started by this manager turned out
+    pub metrics: Metrics,
+    /// Dropped along with the manager, which stops the cleanup task
+    _shutdown: oneshot::Sender<()>,
+}
+
+impl Default for ProcessManager {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl ProcessManager {
     pub fn new() -> Self {
-        let processes: Arc<RwLock<HashMap<ProcessId, ProcessTask>>> =
-            Arc::new(RwLock::new(HashMap::new()));
+        Self::with_buffer_limit(DEFAULT_BUFFER_LIMIT)
+    }
+
+    /// A manager that keeps at most about `buffer_limit` bytes of each stream in memory
+    pub fn with_buffer_limit(buffer_limit: usize) -> Self {
+        Self::build(buffer_limit, None, HashMap::new())
+    }
+
+    /// A manager that records every process in `registry_dir` and picks up the records
+    /// left there by earlier managers, so process ids stay valid across restarts.
+    /// Restored processes can be queried but not reattached to: one that was still
+    /// running is reported as failed.
+    pub fn with_registry(registry_dir: PathBuf) -> anyhow::Result<Self> {
+        std::fs::create_dir_all(&registry_dir)?;
+        let mut processes = HashMap::new();
+        for record in registry::load(&registry_dir)? {
+            if is_expired(record.started_at) {
+                registry::remove(&registry_dir, &record.id);
+                continue;
+            }
+            processes.insert(record.id.clone(), ProcessTask::restore(record));
+        }
+        Ok(Self::build(DEFAULT_BUFFER_LIMIT, Some(registry_dir), processes))
+    }
+
+    fn build(
+        buffer_limit: usize,
+        registry_dir: Option<PathBuf>,
+        processes: HashMap<ProcessId, ProcessTask>,
+    ) -> Self {
+        let processes = Arc::new(RwLock::new(processes));
+        let output_dirs = Arc::new(std::sync::Mutex::new(HashSet::new()));
 
-        // Spawn cleanup task
+        // Spawn cleanup task, which runs until the manager is dropped
         let processes_cleanup = processes.clone();
+        let registry_cleanup = registry_dir.clone();
+        let output_dirs_cleanup = output_dirs.clone();
+        let (shutdown, mut shutdown_rx) = oneshot::channel::<()>();
         tokio::spawn(async move {
             let mut interval = tokio::time::interval(Duration::from_secs(300));
             loop {
-                interval.tick().await;
-                let max_age = Duration::from_secs(3600);
+                tokio::select! {
+                    _ = interval.tick() => {}
+                    _ = &mut shutdown_rx => break,
+                }
 
                 let mut processes = processes_cleanup.write().await;
-                let now = SystemTime::now();
+                let dirs: Vec<PathBuf> =
+                    output_dirs_cleanup.lock().unwrap().iter().cloned().collect();
+                let in_use = unfinished_output_files(&processes);
+                for dir in dirs {
+                    spawn_output_cleanup(dir, in_use.clone());
+                }
 
                 let to_remove: Vec<_> = processes
                     .iter()
                     .filter(|(_, task)| {
-                        matches!(
-                            task.info.state,
-                            ProcessState::Completed { .. }
-                                | ProcessState::Cancelled
-                                | ProcessState::Failed { .. }
-                        ) && now
-                            .duration_since(task.info.started_at)
-                            .unwrap_or(Duration::ZERO)
-                            > max_age
+                        task.info.state.is_finished() && is_expired(task.info.started_at)
                     })
                     .map(|(id, _)| id.clone())
                     .collect();
 
                 for id in to_remove {
                     processes.remove(&id);
+                    if let Some(dir) = &registry_cleanup {
+                        registry::remove(dir, &id);
+                    }
                 }
             }
         });
 
-        Self { processes }
+        let max_concurrent = std::env::var(MAX_CONCURRENT_ENV)
+            .ok()
+            .and_then(|limit| limit.parse().ok())
+            .filter(|&limit| limit > 0)
+            .unwrap_or(DEFAULT_MAX_CONCURRENT);
+        Self {
+            processes,
+            buffer_limit,
+            registry_dir,
+            max_concurrent,
+            slots: Arc::new(Semaphore::new(max_concurrent)),
+            output_dirs,
+            handler_cache: HandlerCache::new(),
+            metrics: Metrics::default(),
+            _shutdown: shutdown,
+        }
+    }
+
+    /// Let at most `max_concurrent` processes run at once, instead of the default from
+    /// `SHELLY_MAX_CONCURRENT` or `DEFAULT_MAX_CONCURRENT`
+    pub fn with_max_concurrent(self, max_concurrent: usize) -> Self {
+        let max_concurrent = max_concurrent.max(1);
+        Self {
+            max_concurrent,
+            slots: Arc::new(Semaphore::new(max_concurrent)),
+            ..self
+        }
     }
 
-    pub async fn start_process(&self, command: String, output_file: PathBuf) -> ProcessId {
+    /// Wait until the process may run, then mark it running. It keeps its slot until
+    /// the returned permit is dropped.
+    pub async fn acquire_slot(&self, process_id: &ProcessId) -> OwnedSemaphorePermit {
+        let permit = self
+            .slots
+            .clone()
+            .acquire_owned()
+            .await
+            .expect("the semaphore is never closed");
+        let mut processes = self.processes.write().await;
+        if let Some(task) = processes.get_mut(process_id) {
+            if task.info.state == ProcessState::Queued {
+                task.info.state = ProcessState::Running;
+                self.persist(task);
+            }
+        }
+        permit
+    }
+
+    /// How many processes are running right now, not counting queued ones
+    pub fn running_count(&self) -> usize {
+        self.max_concurrent - self.slots.available_permits()
+    }
+
+    /// Record the process's current state in the registry, if there is one
+    fn persist(&self, task: &ProcessTask) {
+        let Some(dir) = &self.registry_dir else {
+            return;
+        };
+        let record = ProcessRecord {
+            id: task.info.id.clone(),
+            command: task.info.command.clone(),
+            state: task.info.state.clone(),
+            started_at: task.info.started_at,
+            finished_at: task.info.finished_at,
+            output_file: task.info.output_file.clone(),
+            summary: task
+                .info
+                .state
+                .is_finished()
+                .then(|| task.final_summary.clone().unwrap_or_else(|| task.summary.clone())),
+        };
+        if let Err(e) = registry::save(dir, &record) {
+            tracing::warn!("{e:#}");
+        }
+    }
+
+    /// Track a command that is about to be spawned. `header` describes it at the top of
+    /// its output file; its command and env values are masked like the output.
+    pub async fn start_process(
+        &self,
+        command: String,
+        output_file: PathBuf,
+        ansi: AnsiMode,
+        redactor: Redactor,
+        mut header: output::OutputHeader,
+    ) -> ProcessId {
         let process_id = ProcessId::new();
         let info = ProcessInfo {
             id: process_id.clone(),
-            command,
-            state: ProcessState::Running,
+            // Tokens passed as arguments would otherwise end up in the registry
+            command: redactor.redact(&command).into_owned(),
+            state: ProcessState::Queued,
             started_at: SystemTime::now(),
+            first_output_at: None,
+            finished_at: None,
             raw_stdout: String::new(),
             raw_stderr: String::new(),
-            output_file: Some(output_file),
+            omitted_bytes: 0,
+            lines: Vec::new(),
+            line_count: 0,
+            output_file: Some(output_file.clone()),
+            timed_out: false,
         };
 
-        let (tx, rx) = watch::channel(false);
-        let process_task = ProcessTask {
-            info,
-            executor_handle: None,
-            delta_summary: String::new(),
-            complete_tx: tx,
-            complete_rx: rx,
-        };
+        // Lines are appended as they arrive, so the file can be inspected while the
+        // command runs and keeps what was captured if it never finishes
+        if let Err(e) = output::start_output(&output_file) {
+            tracing::warn!("{e:#}");
+        }
+        if let Some(dir) = output_file.parent() {
+            // Sweep a directory straight away the first time it's used, e.g. by a
+            // one-off `shelly` invocation that won't live to see the cleanup task run.
+            // The caller may have picked a directory with other files in it, which
+            // `cleanup_old_files` leaves alone.
+            if self.output_dirs.lock().unwrap().insert(dir.to_path_buf()) {
+                let mut in_use = unfinished_output_files(&*self.processes.read().await);
+                in_use.insert(output_file.clone());
+                spawn_output_cleanup(dir.to_path_buf(), in_use);
+            }
+        }
 
+        header.command = redactor.redact(&header.command).into_owned();
+        for value in header.env.values_mut() {
+            *value = redactor.redact(value).into_owned();
+        }
+        let mut process_task = ProcessTask::new(info, ansi, redactor);
+        process_task.header = Some(header);
+        self.persist(&process_task);
+        self.metrics.record_start();
         let mut processes = self.processes.write().await;
         processes.insert(process_id.clone(), process_task);
 
@@ -148,82 +697,447 @@ impl ProcessManager {
         }
     }
 
-    pub async fn update_process_output(
+    /// Receiver that flips to `true` when the process should be terminated
+    pub async fn cancel_signal(&self, process_id: &ProcessId) -> Option<watch::Receiver<bool>> {
+        let processes = self.processes.read().await;
+        processes
+            .get(process_id)
+            .map(|task| task.cancel_tx.subscribe())
+    }
+
+    pub async fn register_pid(&self, process_id: &ProcessId, pid: u32) {
+        let mut processes = self.processes.write().await;
+        if let Some(task) = processes.get_mut(process_id) {
+            task.pid = Some(pid);
+        }
+    }
+
+    pub async fn register_stdin(&self, process_id: &ProcessId, stdin: ProcessStdin) {
+        let mut processes = self.processes.write().await;
+        if let Some(task) = processes.get_mut(process_id) {
+            task.stdin = Some(Arc::new(Mutex::new(stdin)));
+        }
+    }
+
+    /// Write `data` to a running process's stdin, optionally followed by a newline
+    pub async fn send_input(
         &self,
         process_id: &ProcessId,
-        stdout: String,
-        stderr: String,
-        handler: &Option<HandlerRuntime>,
-    ) {
+        data: &str,
+        newline: bool,
+    ) -> anyhow::Result<()> {
+        let stdin = {
+            let processes = self.processes.read().await;
+            let task = processes
+                .get(process_id)
+                .ok_or_else(|| anyhow::anyhow!("Process not found"))?;
+            match task.info.state {
+                ProcessState::Running => {}
+                ProcessState::Queued => anyhow::bail!("Process is queued and hasn't started yet"),
+                _ => anyhow::bail!("Process has already exited"),
+            }
+            task.stdin
+                .clone()
+                .ok_or_else(|| anyhow::anyhow!("Process stdin is not available"))?
+        };
+
+        // Don't hold the map lock while the child may be slow to drain its pipe
+        let mut stdin = stdin.lock().await;
+        stdin.write_all(data.as_bytes()).await?;
+        if newline {
+            stdin.write_all(b"\n").await?;
+        }
+        stdin.flush().await?;
+        Ok(())
+    }
+
+    /// Record output as it is read. It is handed to the handler by the next
+    /// `summarize_pending` call.
+    pub async fn append_output(&self, process_id: &ProcessId, stream: OutputStream, output: &str) {
         let mut processes = self.processes.write().await;
         let task = processes.get_mut(process_id).unwrap();
-        task.info.raw_stdout.push_str(&stdout);
-        task.info.raw_stderr.push_str(&stderr);
-        let summary = process(&stdout, &stderr, &handler).await.unwrap();
-        task.delta_summary
-            .push_str(&summary.summary.unwrap_or_default());
+        task.info.first_output_at.get_or_insert_with(SystemTime::now);
+        task.last_output_at = Some(Instant::now());
+        let held = match stream {
+            OutputStream::Stdout => &mut task.held_stdout,
+            OutputStream::Stderr => &mut task.held_stderr,
+        };
+        // Nothing downstream, the output file included, ever sees a secret, even one
+        // that arrives split across two reads
+        held.push_str(output);
+        let mut redacted = task.redactor.redact(held).into_owned();
+        *held = redacted.split_off(task.redactor.ready_len(&redacted));
+        self.record_output(task, stream, &redacted);
     }
 
-    pub async fn final_process_summary(
+    /// Record whatever output was held back, once the process has stopped producing any
+    pub async fn release_held_output(&self, process_id: &ProcessId) {
+        let mut processes = self.processes.write().await;
+        let Some(task) = processes.get_mut(process_id) else {
+            return;
+        };
+        for stream in [OutputStream::Stdout, OutputStream::Stderr] {
+            let held = match stream {
+                OutputStream::Stdout => std::mem::take(&mut task.held_stdout),
+                OutputStream::Stderr => std::mem::take(&mut task.held_stderr),
+            };
+            self.record_output(task, stream, &held);
+        }
+    }
+
+    /// Store redacted output in the buffers, the output file and what the handler sees next
+    fn record_output(&self, task: &mut ProcessTask, stream: OutputStream, output: &str) {
+        if output.is_empty() {
+            return;
+        }
+        let (raw, unsummarized) = match stream {
+            OutputStream::Stdout => (&mut task.info.raw_stdout, &mut task.unsummarized_stdout),
+            OutputStream::Stderr => (&mut task.info.raw_stderr, &mut task.unsummarized_stderr),
+        };
+        // Handlers and tails only ever see plain text
+        let stripped = output::strip_ansi_codes(output);
+        task.info.omitted_bytes += push_bounded(raw, &stripped, self.buffer_limit);
+        unsummarized.push_str(&stripped);
+        push_bounded(&mut task.combined_output, &stripped, self.buffer_limit);
+        match task.ansi {
+            AnsiMode::Strip => task.info.record_lines(stream, &stripped),
+            AnsiMode::Keep => task.info.record_lines(stream, output),
+        }
+
+        // Spill to the output file as we go, so memory stays bounded
+        let finished = task.info.take_finished_lines();
+        if let Some(output_file) = &task.info.output_file {
+            if let Err(e) = output::append_lines(output_file, &finished) {
+                tracing::warn!("{e:#}");
+            }
+        }
+    }
+
+    /// Record that `stream` wasn't plain text: `replaced` invalid UTF-8 sequences were
+    /// replaced, or, if `binary_bytes` is set, it looked like binary data and only its
+    /// size is recorded as output
+    pub async fn note_non_text(
         &self,
         process_id: &ProcessId,
-        exit_code: i32,
-        handler: &HandlerRuntime,
+        stream: OutputStream,
+        replaced: usize,
+        binary_bytes: Option<usize>,
     ) {
+        let name = match stream {
+            OutputStream::Stdout => "stdout",
+            OutputStream::Stderr => "stderr",
+        };
+        let note = match binary_bytes {
+            Some(bytes) => {
+                let summary = format!("binary output, {bytes} bytes\n");
+                self.append_output(process_id, stream, &summary).await;
+                TruncationInfo {
+                    truncated: true,
+                    reason: Some("binary_output".to_string()),
+                    description: Some(format!(
+                        "{name} looked like binary data; its {bytes} bytes were left out"
+                    )),
+                }
+            }
+            None if replaced > 0 => TruncationInfo {
+                truncated: false,
+                reason: Some("non_utf8".to_string()),
+                description: Some(format!(
+                    "{replaced} invalid UTF-8 sequences in {name} were replaced with U+FFFD"
+                )),
+            },
+            None => return,
+        };
         let mut processes = self.processes.write().await;
-        let task = processes.get_mut(process_id).unwrap();
+        if let Some(task) = processes.get_mut(process_id) {
+            // Binary output is the more useful thing to know about
+            if !task.encoding_note.as_ref().is_some_and(|n| n.truncated) {
+                task.encoding_note = Some(note);
+            }
+        }
+    }
 
-        // Call handler with final exit code
-        let summary = handler
-            .summarize(
-                &task.info.raw_stdout,
-                &task.info.raw_stderr,
-                Some(exit_code),
+    /// Run the handler once over everything appended since the last call. `dedupe`
+    /// collapses repeated lines when the output is passed through without a handler.
+    pub async fn summarize_pending(
+        &self,
+        process_id: &ProcessId,
+        handler: &Option<HandlerChain>,
+        dedupe: bool,
+    ) {
+        let (stdout, stderr) = {
+            let mut processes = self.processes.write().await;
+            let task = processes.get_mut(process_id).unwrap();
+            (
+                std::mem::take(&mut task.unsummarized_stdout),
+                std::mem::take(&mut task.unsummarized_stderr),
             )
-            .await
-            .unwrap();
+        };
+        if stdout.is_empty() && stderr.is_empty() {
+            return;
+        }
+
+        // The handler can take a while; don't block every other process on it
+        let summary = process(&stdout, &stderr, handler, dedupe).await;
+
+        let mut processes = self.processes.write().await;
+        if let Some(task) = processes.get_mut(process_id) {
+            // A handler can piece a secret back together from output it buffered
+            let text = summary.summary.unwrap_or_default();
+            task.summary.push_str(&task.redactor.redact(&text));
+            if !summary.actions.is_empty() {
+                task.actions = summary.actions;
+            }
+            if summary.truncation.is_some() {
+                task.truncation = summary.truncation;
+            }
+            if summary.sections.is_some() {
+                task.sections = summary.sections;
+            }
+            task.exit_codes.extend(summary.exit_codes);
+            task.summary_tx.send_if_modified(|len| {
+                let changed = *len != task.summary.len();
+                *len = task.summary.len();
+                changed
+            });
+        }
+    }
+
+    /// Receiver that is notified whenever the process's summary grows
+    pub async fn summary_signal(&self, process_id: &ProcessId) -> Option<watch::Receiver<usize>> {
+        let processes = self.processes.read().await;
+        processes
+            .get(process_id)
+            .map(|task| task.summary_tx.subscribe())
+    }
 
-        if let Some(final_summary) = summary.summary {
-            task.delta_summary = final_summary;
+    pub async fn final_process_summary(
+        &self,
+        process_id: &ProcessId,
+        exit_code: i32,
+        handler: &HandlerChain,
+    ) {
+        let (stdout, stderr) = {
+            let processes = self.processes.read().await;
+            let task = processes.get(process_id).unwrap();
+            (task.info.raw_stdout.clone(), task.info.raw_stderr.clone())
+        };
+
+        // Call handler with final exit code, without holding the lock
+        let summary = handler.finalize(exit_code, &stdout, &stderr).await;
+
+        let mut processes = self.processes.write().await;
+        let Some(task) = processes.get_mut(process_id) else {
+            return;
+        };
+        match summary {
+            Ok(summary) => {
+                if let Some(final_summary) = summary.summary {
+                    let final_summary = task.redactor.redact(&final_summary).into_owned();
+                    // The final summary replaces the earlier ones, and so does its account
+                    // of what it left out
+                    task.truncation = summary.truncation;
+                    let budget = handler.max_summary_tokens();
+                    task.final_summary = Some(match fit_token_budget(&final_summary, budget) {
+                        Some(cut) => {
+                            if !task.truncation.as_ref().is_some_and(|t| t.truncated) {
+                                task.truncation = Some(TruncationInfo {
+                                    truncated: true,
+                                    reason: Some("token_budget".to_string()),
+                                    description: Some(format!(
+                                        "Summary cut to about {budget} tokens"
+                                    )),
+                                });
+                            }
+                            cut
+                        }
+                        None => final_summary,
+                    });
+                }
+                if !summary.actions.is_empty() {
+                    task.actions = summary.actions;
+                }
+                if summary.sections.is_some() {
+                    task.sections = summary.sections;
+                }
+                task.exit_codes.extend(summary.exit_codes);
+            }
+            Err(e) => {
+                tracing::warn!("{e:#}; using unfiltered output as the summary");
+                task.final_summary = Some(task.combined_output.clone());
+                task.truncation = None;
+            }
         }
     }
 
-    pub async fn complete_process(&self, process_id: &ProcessId, exit_code: i32) {
+    /// Record that the process exited. `signal` is set if it was killed by one, in
+    /// which case `exit_code` is what a shell would report for it. Joins return as soon
+    /// as this is called, so the final summary must already be in place.
+    pub async fn complete_process(
+        &self,
+        process_id: &ProcessId,
+        exit_code: i32,
+        signal: Option<i32>,
+    ) {
         let mut processes = self.processes.write().await;
         let task = processes.get_mut(process_id).unwrap();
-        task.info.state = ProcessState::Completed { exit_code };
+        task.info.state = match signal {
+            _ if task.cancel_requested => ProcessState::Cancelled,
+            _ if task.failure.is_some() => ProcessState::Failed {
+                error: task.failure.take().unwrap_or_default(),
+            },
+            Some(signal) => ProcessState::Signaled { signal },
+            None => ProcessState::Completed { exit_code },
+        };
+        task.info.finished_at = Some(SystemTime::now());
+        task.stdin = None;
 
         // Write output to file if path is set
         if let Some(output_file) = &task.info.output_file {
-            let _ = output::write_output(
-                output_file,
-                &task.info.raw_stdout,
-                &task.info.raw_stderr,
-                exit_code,
-            );
+            let header = task.header.as_ref();
+            let _ = output::write_output(output_file, &task.info.lines, exit_code, header);
         }
 
+        self.record_finish(task);
+        self.persist(task);
         let _ = task.complete_tx.send(true);
     }
 
+    /// Count a process that has just finished in the metrics
+    fn record_finish(&self, task: &ProcessTask) {
+        let outcome = match &task.info.state {
+            _ if task.info.timed_out => Outcome::TimedOut,
+            ProcessState::Cancelled => Outcome::Cancelled,
+            _ if task.exit_interpretation().0 => Outcome::Failed,
+            _ => Outcome::Succeeded,
+        };
+        let summary = task.final_summary.as_ref().unwrap_or(&task.summary);
+        self.metrics
+            .record_finish(outcome, task.info.duration(), summary.len());
+    }
+
+    /// Record that the process was stopped for taking too long, so it counts as timed
+    /// out rather than cancelled or failed
+    pub async fn mark_timed_out(&self, process_id: &ProcessId) {
+        if let Some(task) = self.processes.write().await.get_mut(process_id) {
+            task.info.timed_out = true;
+        }
+    }
+
+    /// Record why the executor is stopping a process, so `complete_process` marks it
+    /// `Failed` with `error` rather than reporting how it exited
+    pub async fn record_failure(&self, process_id: &ProcessId, error: String) {
+        if let Some(task) = self.processes.write().await.get_mut(process_id) {
+            task.failure = Some(error);
+        }
+    }
+
+    /// Note that the process just produced output, even if none of it is recorded yet,
+    /// like a partial line or binary data
+    pub async fn record_read(&self, process_id: &ProcessId) {
+        if let Some(task) = self.processes.write().await.get_mut(process_id) {
+            task.last_output_at = Some(Instant::now());
+        }
+    }
+
+    /// When output was last read from the process, if it has printed anything
+    pub async fn last_output_at(&self, process_id: &ProcessId) -> Option<Instant> {
+        let processes = self.processes.read().await;
+        processes.get(process_id)?.last_output_at
+    }
+
     pub async fn fail_process(&self, process_id: &ProcessId, error: String) {
         let mut processes = self.processes.write().await;
         if let Some(task) = processes.get_mut(process_id) {
-            task.info.state = ProcessState::Failed { error };
+            task.info.state = if task.cancel_requested {
+                ProcessState::Cancelled
+            } else {
+                ProcessState::Failed { error }
+            };
+            task.info.finished_at = Some(SystemTime::now());
+            task.stdin = None;
+
+            // The exit codes `execute_command` reports for a process that never ran to
+            // completion, so the file reads like every other output file
+            let exit_code = if task.cancel_requested { 130 } else { 127 };
+            let remaining = std::mem::take(&mut task.info.lines);
+            if let Some(output_file) = &task.info.output_file {
+                let header = task.header.as_ref();
+                let _ = output::write_output(output_file, &remaining, exit_code, header);
+            }
+            self.record_finish(task);
+            self.persist(task);
             let _ = task.complete_tx.send(true);
         }
     }
 
+    /// Ask the executor to terminate a running process and wait until the child has
+    /// been reaped
     pub async fn cancel_process(&self, process_id: &ProcessId) -> bool {
-        let mut processes = self.processes.write().await;
-        if let Some(task) = processes.get_mut(process_id) {
-            if let Some(handle) = &task.executor_handle {
-                handle.abort();
-                task.info.state = ProcessState::Cancelled;
-                return true;
+        let mut waiter = {
+            let mut processes = self.processes.write().await;
+            let Some(task) = processes.get_mut(process_id) else {
+                return false;
+            };
+            if task.info.state.is_finished() {
+                return false;
             }
+            task.cancel_requested = true;
+            // Stored even before the executor has subscribed, which `send` would drop
+            task.cancel_tx.send_replace(true);
+            task.complete_rx.clone()
+        };
+
+        // The executor marks the task complete only after `child.wait()` has returned
+        let _ = waiter.wait_for(|t| *t).await;
+        true
+    }
+
+    /// Cancel every running process whose command contains `pattern`, or matches it as
+    /// a regex if `regex` is set, and return the ones cancelled. Refuses to cancel more
+    /// than one process unless `confirm` is set.
+    pub async fn cancel_matching(
+        &self,
+        pattern: &str,
+        regex: bool,
+        confirm: bool,
+    ) -> anyhow::Result<Vec<ProcessId>> {
+        let pattern = if regex {
+            pattern.to_string()
+        } else {
+            regex::escape(pattern)
+        };
+        let re = regex::Regex::new(&pattern)
+            .map_err(|e| anyhow::anyhow!("Invalid pattern {pattern:?}: {e}"))?;
+        let matching: Vec<(ProcessId, String)> = self
+            .list_processes(true)
+            .await
+            .into_iter()
+            .filter(|status| re.is_match(&status.command))
+            .map(|status| (status.id, status.command))
+            .collect();
+        if matching.len() > 1 && !confirm {
+            let commands: Vec<String> = matching
+                .iter()
+                .map(|(id, command)| format!("{}: {command}", id.0))
+                .collect();
+            anyhow::bail!(
+                "{} running processes match; set confirm to cancel all of them:\n{}",
+                matching.len(),
+                commands.join("\n")
+            );
         }
-        false
+
+        let cancelled = futures::future::join_all(
+            matching.iter().map(|(id, _)| self.cancel_process(id)),
+        )
+        .await;
+        // A process may have exited on its own in the meantime
+        Ok(matching
+            .into_iter()
+            .zip(cancelled)
+            .filter_map(|((id, _), cancelled)| cancelled.then_some(id))
+            .collect())
     }
 
     pub async fn get_process_status(&self, process_id: &ProcessId) -> Option<ProcessInfo> {
@@ -236,18 +1150,66 @@ impl ProcessManager {
         process_id: &ProcessId,
     ) -> Option<ProcessStatus> {
         let processes = self.processes.read().await;
-        processes.get(process_id).map(|task| {
-            let info = &task.info;
-            ProcessStatus {
-                id: info.id.clone(),
-                command: info.command.clone(),
-                state: info.state.clone(),
-                started_at: info.started_at,
-                stdout_length: info.raw_stdout.len(),
-                stderr_length: info.raw_stderr.len(),
-                output_file: info.output_file.clone(),
+        processes
+            .get(process_id)
+            .map(|task| ProcessStatus::from(&task.info))
+    }
+
+    /// Last `lines` lines of a process's stdout, stderr or both combined. Falls back to
+    /// the output file for finished processes whose in-memory output is gone, and for
+    /// tails that reach back past what is kept in memory.
+    pub async fn tail_output(
+        &self,
+        process_id: &ProcessId,
+        lines: usize,
+        stream: StreamFilter,
+    ) -> Option<String> {
+        let processes = self.processes.read().await;
+        let task = processes.get(process_id)?;
+
+        let buffer = match stream {
+            StreamFilter::Stdout => &task.info.raw_stdout,
+            StreamFilter::Stderr => &task.info.raw_stderr,
+            StreamFilter::Both => &task.combined_output,
+        };
+        let in_memory = last_n_lines(buffer, lines);
+        if (buffer.is_empty() && task.info.state.is_finished())
+            || in_memory.contains(OMITTED_MARKER)
+        {
+            if let Some(tail) = task
+                .info
+                .output_file
+                .as_deref()
+                .and_then(|path| output::tail_lines(path, lines, stream).ok())
+            {
+                return Some(tail);
             }
-        })
+        }
+        Some(in_memory.to_string())
+    }
+
+    /// The first `lines` lines of the process's output, stdout and stderr in the order
+    /// they arrived. The start of the output always stays in memory.
+    pub async fn head_output(&self, process_id: &ProcessId, lines: usize) -> Option<String> {
+        let processes = self.processes.read().await;
+        let output = &processes.get(process_id)?.combined_output;
+        let end = output
+            .match_indices('\n')
+            .nth(lines.saturating_sub(1))
+            .map_or(output.len(), |(i, _)| i + 1);
+        Some(output[..end].to_string())
+    }
+
+    /// Snapshot of every tracked process, oldest first
+    pub async fn list_processes(&self, running_only: bool) -> Vec<ProcessStatus> {
+        let processes = self.processes.read().await;
+        let mut statuses: Vec<_> = processes
+            .values()
+            .filter(|task| !running_only || !task.info.state.is_finished())
+            .map(|task| ProcessStatus::from(&task.info))
+            .collect();
+        statuses.sort_by_key(|status| status.started_at);
+        statuses
     }
 
     pub async fn wait_for(&self, process_id: &ProcessId) {
@@ -258,24 +1220,195 @@ impl ProcessManager {
         let _ = waiter.wait_for(|t| *t).await;
     }
 
-    // New method: get incremental updates since last join
+    /// Wait up to `timeout` for any of `process_ids` to finish and return the first one
+    /// with its update, like `join_process` without a cursor. A process that has
+    /// already finished is returned straight away. `None` if none finish in time.
+    pub async fn join_any(
+        &self,
+        process_ids: &[ProcessId],
+        timeout: Duration,
+    ) -> anyhow::Result<Option<(ProcessId, ProcessUpdate)>> {
+        if process_ids.is_empty() {
+            anyhow::bail!("No process IDs given");
+        }
+        let mut waiters = tokio::task::JoinSet::new();
+        {
+            let processes = self.processes.read().await;
+            for id in process_ids {
+                let task = processes
+                    .get(id)
+                    .ok_or_else(|| anyhow::anyhow!("Process not found: {}", id.0))?;
+                let mut complete_rx = task.complete_rx.clone();
+                let id = id.clone();
+                waiters.spawn(async move {
+                    let _ = complete_rx.wait_for(|t| *t).await;
+                    id
+                });
+            }
+        }
+
+        // Dropping the set cancels the waiters that are still pending
+        let finished = match tokio::time::timeout(timeout, waiters.join_next()).await {
+            Ok(Some(joined)) => joined?,
+            Ok(None) | Err(_) => return Ok(None),
+        };
+        Ok(self
+            .join_process(&finished, Duration::ZERO, None)
+            .await
+            .map(|update| (finished, update)))
+    }
+
+    /// Wait up to `timeout` for the process to finish, then return the summary added
+    /// since `cursor`.
+    ///
+    /// Pass the `cursor` from the previous update to get at-least-once delivery: a
+    /// retried join with the same cursor returns the same text, and nothing is dropped
+    /// if a response is lost. Without a cursor, joins share one server-side cursor that
+    /// each join advances, so every piece of the summary is returned once across them.
+    /// Once the handler has summarized the whole run, every join returns that final
+    /// summary.
     pub async fn join_process(
         &self,
         process_id: &ProcessId,
         timeout: Duration,
+        cursor: Option<usize>,
     ) -> Option<ProcessUpdate> {
-        let _ = tokio::time::timeout(timeout, self.wait_for(process_id)).await;
+        let waited = tokio::time::timeout(timeout, self.wait_for(process_id)).await;
         let mut processes = self.processes.write().await;
 
         let task = processes.get_mut(process_id)?;
-        let summary = std::mem::take(&mut task.delta_summary);
+        // It may have finished between the timeout and taking the lock
+        let timed_out = waited.is_err() && !task.info.state.is_finished();
+        let end = task.summary.len();
+        let start = cursor.unwrap_or(task.joined_up_to).min(end);
+        let start = floor_char_boundary(&task.summary, start);
+        if cursor.is_none() {
+            task.joined_up_to = end;
+        }
+        let summary = match &task.final_summary {
+            Some(final_summary) => final_summary,
+            None => &task.summary[start..],
+        };
+        let (incremental_summary, omitted_chars) = trim_summary(summary, SUMMARY_LIMIT);
+        let (is_error, exit_code_meaning) = task.exit_interpretation();
         Some(ProcessUpdate {
-            incremental_summary: last_n_chars(&summary, 1000).to_string(),
+            incremental_summary,
             status: task.info.state.clone(),
+            timed_out,
+            output_truncated: task.info.omitted_bytes > 0,
+            omitted_chars,
+            cursor: end,
+            actions: task.actions.clone(),
+            truncation: task.truncation.clone().or_else(|| task.encoding_note.clone()),
+            sections: task.sections.clone(),
+            duration_ms: task.info.duration().as_millis() as u64,
+            time_to_first_output_ms: task
+                .info
+                .time_to_first_output()
+                .map(|d| d.as_millis() as u64),
+            is_error,
+            exit_code_meaning,
         })
     }
 }
 
+pub(crate) fn last_n_lines(s: &str, n: usize) -> &str {
+    if n == 0 {
+        return "";
+    }
+
+    let without_trailing_newline = s.strip_suffix('\n').unwrap_or(s);
+    without_trailing_newline
+        .rmatch_indices('\n')
+        .nth(n - 1)
+        .map(|(i, _)| &s[i + 1..])
+        .unwrap_or(s)
+}
+
+/// Backstop for handlers that write past their budget: `summary` trimmed to roughly
+/// `max_tokens` tokens, with a note saying so. `None` if it already fits.
+fn fit_token_budget(summary: &str, max_tokens: usize) -> Option<String> {
+    let tokens = estimate_tokens(summary);
+    if tokens <= max_tokens {
+        return None;
+    }
+    let (mut trimmed, _) = trim_summary(summary, max_tokens * 4);
+    if !trimmed.ends_with('\n') {
+        trimmed.push('\n');
+    }
+    trimmed.push_str(&format!(
+        "[summary cut from ~{tokens} to ~{max_tokens} tokens, see the output file]\n"
+    ));
+    Some(trimmed)
+}
+
+/// Trim `summary` to whole lines totalling at most `max_chars` characters, with a marker
+/// where lines were dropped. Up to half the budget goes to the earliest lines that look
+/// like errors, the rest to the most recent lines. Returns the trimmed summary and how
+/// many characters were dropped.
+pub(crate) fn trim_summary(summary: &str, max_chars: usize) -> (String, usize) {
+    let total = summary.chars().count();
+    if total <= max_chars {
+        return (summary.to_string(), 0);
+    }
+
+    let lines: Vec<&str> = summary.split_inclusive('\n').collect();
+    let mut keep = vec![false; lines.len()];
+    let mut budget = max_chars;
+
+    let mut error_budget = max_chars / 2;
+    for (i, line) in lines.iter().enumerate() {
+        let len = line.chars().count();
+        if len <= error_budget && looks_like_error(line) {
+            keep[i] = true;
+            error_budget -= len;
+            budget -= len;
+        }
+    }
+    for (i, line) in lines.iter().enumerate().rev() {
+        let len = line.chars().count();
+        if keep[i] {
+            continue;
+        }
+        if len > budget {
+            break;
+        }
+        keep[i] = true;
+        budget -= len;
+    }
+
+    // A single line longer than the budget: fall back to its last characters
+    if !keep[lines.len() - 1] {
+        let tail = last_n_chars(summary, max_chars);
+        let omitted = total - tail.chars().count();
+        return (format!("[... {omitted} chars omitted ...]\n{tail}"), omitted);
+    }
+
+    let mut trimmed = String::new();
+    let mut omitted = 0;
+    let mut gap = 0;
+    for (line, keep) in lines.iter().zip(keep) {
+        if !keep {
+            gap += line.chars().count();
+            continue;
+        }
+        if gap > 0 {
+            trimmed.push_str(&format!("[... {gap} chars omitted ...]\n"));
+            omitted += gap;
+            gap = 0;
+        }
+        trimmed.push_str(line);
+    }
+    (trimmed, omitted)
+}
+
+fn looks_like_error(line: &str) -> bool {
+    let line = line.to_lowercase();
+    ["error", "failed", "failure", "panicked", "exception", "fatal"]
+        .iter()
+        .any(|word| line.contains(word))
+}
+
 fn last_n_chars(s: &str, n: usize) -> &str {
     let char_count = s.chars().count();
     if char_count <= n {
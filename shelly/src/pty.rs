@@ -0,0 +1,91 @@
+use anyhow::{Context, Result};
+use std::fs::File;
+use std::io;
+use std::os::fd::{FromRawFd, OwnedFd};
+use std::process::Stdio;
+
+/// A pseudo-terminal pair for running a command that only behaves when attached to a
+/// terminal (colors, progress output, interactive prompts)
+pub struct Pty {
+    /// Our end: reads everything the command writes, writes become its input
+    pub master: File,
+    /// The command's end, handed to it as stdin, stdout and stderr
+    slave: OwnedFd,
+}
+
+impl Pty {
+    pub fn open() -> Result<Self> {
+        let mut master = -1;
+        let mut slave = -1;
+        let size = libc::winsize {
+            ws_row: 24,
+            ws_col: 80,
+            ws_xpixel: 0,
+            ws_ypixel: 0,
+        };
+        // SAFETY: both out pointers are valid, and a null name/termios is allowed
+        let ret = unsafe {
+            libc::openpty(
+                &mut master,
+                &mut slave,
+                std::ptr::null_mut(),
+                std::ptr::null(),
+                &size,
+            )
+        };
+        if ret != 0 {
+            return Err(io::Error::last_os_error()).context("Failed to open a pseudo-terminal");
+        }
+        // SAFETY: openpty succeeded, so both descriptors are open and owned by us
+        let (master, slave) = unsafe { (File::from_raw_fd(master), OwnedFd::from_raw_fd(slave)) };
+        disable_output_translation(&slave)?;
+        Ok(Self { master, slave })
+    }
+
+    /// A handle to the command's end of the terminal, for one of its standard streams
+    pub fn slave_stdio(&self) -> Result<Stdio> {
+        Ok(self.slave.try_clone()?.into())
+    }
+}
+
+/// Stop the terminal from turning `\n` into `\r\n`, so the output reads the same as it
+/// would through a pipe
+fn disable_output_translation(slave: &OwnedFd) -> Result<()> {
+    use std::os::fd::AsRawFd;
+    // SAFETY: termios is plain data and is filled in by tcgetattr before use
+    unsafe {
+        let mut termios: libc::termios = std::mem::zeroed();
+        if libc::tcgetattr(slave.as_raw_fd(), &mut termios) != 0 {
+            return Err(io::Error::last_os_error()).context("Failed to read terminal settings");
+        }
+        termios.c_oflag &= !libc::ONLCR;
+        if libc::tcsetattr(slave.as_raw_fd(), libc::TCSANOW, &termios) != 0 {
+            return Err(io::Error::last_os_error()).context("Failed to set terminal settings");
+        }
+    }
+    Ok(())
+}
+
+/// Make the child the leader of a new session with the terminal on its stdin as the
+/// controlling terminal. Runs in the forked child before exec.
+pub fn attach_controlling_terminal() -> io::Result<()> {
+    // SAFETY: setsid and ioctl are async-signal-safe
+    unsafe {
+        if libc::setsid() == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        if libc::ioctl(0, libc::TIOCSCTTY as _, 0) == -1 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+/// Reading the master fails with EIO once every handle to the command's end is closed,
+/// which is the terminal's way of saying end of file
+pub fn eof_on_eio(result: io::Result<usize>) -> io::Result<usize> {
+    match result {
+        Err(e) if e.raw_os_error() == Some(libc::EIO) => Ok(0),
+        result => result,
+    }
+}
@@ -0,0 +1,28 @@
+//! Windows has no pseudo-terminals in the Unix sense, so PTY mode is refused up front.
+//! `Pty` can't be constructed; it only exists so the executor compiles unchanged.
+
+use anyhow::Result;
+use std::convert::Infallible;
+use std::fs::File;
+use std::io;
+use std::process::Stdio;
+
+pub struct Pty {
+    pub master: File,
+    never: Infallible,
+}
+
+impl Pty {
+    pub fn open() -> Result<Self> {
+        anyhow::bail!("PTY mode is only supported on Unix")
+    }
+
+    pub fn slave_stdio(&self) -> Result<Stdio> {
+        match self.never {}
+    }
+}
+
+/// Pipes report end of file as a zero-length read, there's nothing to translate
+pub fn eof_on_eio(result: io::Result<usize>) -> io::Result<usize> {
+    result
+}
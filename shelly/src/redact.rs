@@ -0,0 +1,130 @@
+use regex::Regex;
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::sync::LazyLock;
+
+/// What secrets are replaced with
+pub const MASK: &str = "***";
+
+/// Most of an unfinished line held back waiting for a secret in it to end, so a line
+/// that never ends can't hold back output indefinitely
+const MAX_HELD_BYTES: usize = 4096;
+
+/// Values shorter than this are too likely to show up by accident (`true`, `1`, a
+/// username) to be worth masking
+const MIN_SECRET_LEN: usize = 6;
+
+/// Tokens that are recognizable on sight: JWTs, GitHub tokens and AWS access key ids
+static TOKEN_PATTERN: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(concat!(
+        r"eyJ[A-Za-z0-9_-]+\.eyJ[A-Za-z0-9_-]+\.[A-Za-z0-9_-]+",
+        r"|\bgh[pousr]_[A-Za-z0-9]{36,}\b",
+        r"|\bgithub_pat_[A-Za-z0-9_]{22,}\b",
+        r"|\b(?:AKIA|ASIA)[0-9A-Z]{16}\b",
+    ))
+    .unwrap()
+});
+
+/// Names of the environment variables whose values are masked when a request doesn't
+/// say. `*` matches any run of characters.
+pub fn default_sensitive_env() -> Vec<String> {
+    ["*TOKEN*", "*SECRET*", "*PASSWORD*", "*API_KEY*", "*ACCESS_KEY*"]
+        .map(String::from)
+        .to_vec()
+}
+
+/// Whether the variable `name` matches `pattern`, where `*` matches anything
+fn name_matches(pattern: &str, name: &str) -> bool {
+    let parts: Vec<&str> = pattern.split('*').collect();
+    let (first, rest) = parts.split_first().expect("split yields at least one part");
+    let Some(mut remaining) = name.strip_prefix(first) else {
+        return false;
+    };
+    let Some((last, middle)) = rest.split_last() else {
+        return remaining.is_empty();
+    };
+    for part in middle {
+        match remaining.find(part) {
+            Some(i) => remaining = &remaining[i + part.len()..],
+            None => return false,
+        }
+    }
+    remaining.ends_with(last)
+}
+
+/// Masks secrets in command output before it is stored or summarized
+#[derive(Debug, Clone, Default)]
+pub struct Redactor {
+    /// Longest first, so a secret containing another is masked whole
+    secrets: Vec<String>,
+}
+
+impl Redactor {
+    /// Mask the values of the variables in `env` whose names match one of `names`
+    pub fn for_env<'a>(
+        names: &[String],
+        env: impl IntoIterator<Item = (&'a String, &'a String)>,
+    ) -> Self {
+        let mut secrets: Vec<String> = env
+            .into_iter()
+            .filter(|(name, value)| {
+                value.len() >= MIN_SECRET_LEN
+                    && names.iter().any(|pattern| name_matches(pattern, name))
+            })
+            .map(|(_, value)| value.clone())
+            .collect();
+        secrets.sort_by(|a, b| b.len().cmp(&a.len()).then_with(|| a.cmp(b)));
+        secrets.dedup();
+        Self { secrets }
+    }
+
+    /// Like `for_env`, for the environment a command would run with: `env` on top of
+    /// shelly's own when `inherit` is set
+    pub fn for_command_env(
+        names: &[String],
+        env: &HashMap<String, String>,
+        inherit: bool,
+    ) -> Self {
+        let inherited: HashMap<String, String> = if inherit {
+            std::env::vars().collect()
+        } else {
+            HashMap::new()
+        };
+        Self::for_env(names, inherited.iter().chain(env))
+    }
+
+    /// `text` with every secret replaced by `MASK`
+    pub fn redact<'a>(&self, text: &'a str) -> Cow<'a, str> {
+        let mut text = Cow::Borrowed(text);
+        for secret in &self.secrets {
+            if text.contains(secret.as_str()) {
+                text = Cow::Owned(text.replace(secret.as_str(), MASK));
+            }
+        }
+        match TOKEN_PATTERN.replace_all(&text, MASK) {
+            Cow::Borrowed(_) => text,
+            Cow::Owned(masked) => Cow::Owned(masked),
+        }
+    }
+
+    /// How much of `redacted`, masked output whose last line may be unfinished, can be
+    /// passed on now. The end of an unfinished line could be the start of a secret that
+    /// is only masked once the rest of it arrives, so that part is held back.
+    pub fn ready_len(&self, redacted: &str) -> usize {
+        let line_start = redacted.rfind('\n').map_or(0, |i| i + 1);
+        let partial = &redacted[line_start..];
+        // A token is one unbroken run of these, so it can only be cut off inside one
+        let token_start = partial
+            .trim_end_matches(|c: char| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.'))
+            .len();
+        let longest = self.secrets.first().map_or(0, String::len);
+        let secret_start = partial.len().saturating_sub(longest.saturating_sub(1));
+        let mut held_from = token_start
+            .min(secret_start)
+            .max(partial.len().saturating_sub(MAX_HELD_BYTES));
+        while !partial.is_char_boundary(held_from) {
+            held_from -= 1;
+        }
+        line_start + held_from
+    }
+}
@@ -0,0 +1,63 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use crate::process_manager::{ProcessId, ProcessState};
+
+/// What is kept on disk about a process, so its id still resolves after a restart
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ProcessRecord {
+    pub id: ProcessId,
+    pub command: String,
+    pub state: ProcessState,
+    pub started_at: SystemTime,
+    #[serde(default)]
+    pub finished_at: Option<SystemTime>,
+    pub output_file: Option<PathBuf>,
+    /// Summary of the finished run
+    #[serde(default)]
+    pub summary: Option<String>,
+}
+
+fn record_path(dir: &Path, id: &ProcessId) -> PathBuf {
+    dir.join(format!("{}.json", id.0))
+}
+
+/// Write the record for one process. Each process has its own file, so several
+/// servers sharing a directory never overwrite each other's records.
+pub fn save(dir: &Path, record: &ProcessRecord) -> Result<()> {
+    let path = record_path(dir, &record.id);
+    // Write then rename, so a crash never leaves a half-written record
+    let staging = dir.join(format!(".{}.json.tmp", record.id.0));
+    crate::output::open_output(&staging, false)
+        .and_then(|mut file| file.write_all(&serde_json::to_vec(record)?))
+        .with_context(|| format!("Failed to write {}", staging.display()))?;
+    fs::rename(&staging, &path)?;
+    Ok(())
+}
+
+pub fn remove(dir: &Path, id: &ProcessId) {
+    let _ = fs::remove_file(record_path(dir, id));
+}
+
+/// Every readable record in `dir`. Unreadable ones are skipped with a warning.
+pub fn load(dir: &Path) -> Result<Vec<ProcessRecord>> {
+    let mut records = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().is_none_or(|ext| ext != "json") {
+            continue;
+        }
+        match fs::read(&path)
+            .map_err(anyhow::Error::from)
+            .and_then(|data| Ok(serde_json::from_slice(&data)?))
+        {
+            Ok(record) => records.push(record),
+            Err(e) => tracing::warn!("skipping process record {}: {e:#}", path.display()),
+        }
+    }
+    Ok(records)
+}
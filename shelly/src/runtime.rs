@@ -1,14 +1,24 @@
-use anyhow::Result;
+use anyhow::{Context, Result};
 use deno_ast::{MediaType, ParseParams};
 use deno_core::{
-    JsRuntime, ModuleLoadResponse, ModuleLoader, ModuleSource, ModuleSourceCode, ModuleSpecifier,
-    ModuleType, ResolutionKind, RuntimeOptions,
+    error::JsError, op2, v8, JsRuntime, ModuleLoadResponse, ModuleLoader, ModuleSource, ModuleSourceCode, ModuleSpecifier,
+    ModuleType, OpState, ResolutionKind, RuntimeOptions,
 };
+use regex::Regex;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
+use std::path::{Path, PathBuf};
 use std::rc::Rc;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant, SystemTime};
 use tokio::sync::{mpsc, oneshot};
 
+/// Default time budget for a single call into a handler
+pub const DEFAULT_SCRIPT_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Largest file a handler may read through `context.readFile()`
+const MAX_READ_SIZE: u64 = 1024 * 1024;
+
 struct TsModuleLoader;
 
 impl ModuleLoader for TsModuleLoader {
@@ -18,19 +28,31 @@ impl ModuleLoader for TsModuleLoader {
         referrer: &str,
         _kind: ResolutionKind,
     ) -> Result<ModuleSpecifier, deno_core::error::AnyError> {
-        deno_core::resolve_import(specifier, referrer).map_err(|e| e.into())
+        let resolved = deno_core::resolve_import(specifier, referrer)?;
+        // Handlers have no network access, and nothing could load any other scheme
+        if resolved.scheme() != "file" {
+            anyhow::bail!(
+                "Cannot import {specifier:?} from {referrer}: handlers can only import local files"
+            );
+        }
+        Ok(resolved)
     }
 
     fn load(
         &self,
         module_specifier: &ModuleSpecifier,
-        _maybe_referrer: Option<&ModuleSpecifier>,
+        maybe_referrer: Option<&ModuleSpecifier>,
         _is_dyn_import: bool,
         _requested_module_type: deno_core::RequestedModuleType,
     ) -> ModuleLoadResponse {
         let module_specifier = module_specifier.clone();
+        let imported_from = maybe_referrer
+            .map(|referrer| format!(" (imported from {referrer})"))
+            .unwrap_or_default();
         let module_load = move || {
-            let path = module_specifier.to_file_path().unwrap();
+            let path = module_specifier
+                .to_file_path()
+                .map_err(|()| anyhow::anyhow!("Cannot load {module_specifier}{imported_from}"))?;
             let media_type = MediaType::from_path(&path);
             let (module_type, should_transpile) = match media_type {
                 MediaType::JavaScript | MediaType::Mjs | MediaType::Cjs => {
@@ -41,10 +63,22 @@ impl ModuleLoader for TsModuleLoader {
                     (ModuleType::JavaScript, true)
                 }
                 MediaType::Json => (ModuleType::Json, false),
-                _ => panic!("Unknown extension {:?}", path.extension()),
+                // Resolution doesn't guess extensions, the way Deno doesn't
+                _ if path.extension().is_none() => anyhow::bail!(
+                    "Cannot import {}{imported_from}: imports need a file extension, \
+                     e.g. \"./utils.ts\"",
+                    path.display()
+                ),
+                _ => anyhow::bail!(
+                    "Cannot import {}{imported_from}: only .ts, .js and .json modules are \
+                     supported",
+                    path.display()
+                ),
             };
 
-            let code = std::fs::read_to_string(&path)?;
+            let code = std::fs::read_to_string(&path).with_context(|| {
+                format!("Failed to read module {}{imported_from}", path.display())
+            })?;
             let code = if should_transpile {
                 let parsed = deno_ast::parse_module(ParseParams {
                     specifier: module_specifier.clone(),
@@ -79,17 +113,363 @@ impl ModuleLoader for TsModuleLoader {
     }
 }
 
+/// The export a handler file must provide, named after the file: `cargo.ts` exports
+/// `cargoHandler`, `brazil-build.ts` exports `brazilBuildHandler`
+pub fn handler_export_name(file_stem: &str) -> String {
+    let handler_name = file_stem
+        .split('-')
+        .enumerate()
+        .map(|(i, part)| {
+            let mut chars = part.chars();
+            match chars.next() {
+                Some(first) if i > 0 => first.to_uppercase().chain(chars).collect(),
+                _ => part.to_string(),
+            }
+        })
+        .collect::<String>();
+    format!("{handler_name}Handler")
+}
+
+/// How many tokens a command's summary should stay within unless the request says
+pub const DEFAULT_MAX_SUMMARY_TOKENS: usize = 500;
+
+/// Overrides `DEFAULT_MAX_SUMMARY_TOKENS`
+pub const MAX_SUMMARY_TOKENS_ENV: &str = "SHELLY_MAX_SUMMARY_TOKENS";
+
+/// The summary token budget for requests that don't set one: `SHELLY_MAX_SUMMARY_TOKENS`
+/// or `DEFAULT_MAX_SUMMARY_TOKENS`
+pub fn default_max_summary_tokens() -> usize {
+    std::env::var(MAX_SUMMARY_TOKENS_ENV)
+        .ok()
+        .and_then(|budget| budget.parse().ok())
+        .filter(|&budget| budget > 0)
+        .unwrap_or(DEFAULT_MAX_SUMMARY_TOKENS)
+}
+
+/// Rough number of tokens in `text`, at about four characters each
+pub fn estimate_tokens(text: &str) -> usize {
+    text.chars().count().div_ceil(4)
+}
+
+/// What a handler can see of the command's surroundings, passed to the factory's
+/// `create()`. Handlers get no other access to the machine.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HandlerContext {
+    /// Directory the command runs in. `readFile()` is confined to it.
+    pub working_dir: PathBuf,
+    /// Variables the request sets for the command
+    pub env: HashMap<String, String>,
+    /// Roughly how many tokens the summary should take. Longer final summaries are cut.
+    pub max_summary_tokens: usize,
+}
+
+impl Default for HandlerContext {
+    fn default() -> Self {
+        Self {
+            working_dir: PathBuf::new(),
+            env: HashMap::new(),
+            max_summary_tokens: DEFAULT_MAX_SUMMARY_TOKENS,
+        }
+    }
+}
+
+/// Canonical working directory of the handler's command, the only place it may read or,
+/// if granted `fs_write`, write
+struct FsRoot(PathBuf);
+
+/// Read a UTF-8 file below the working directory. Relative paths are resolved against
+/// it, and symlinks are followed before checking that the file is still inside.
+#[op2]
+#[string]
+fn op_shelly_read_file(
+    state: &mut OpState,
+    #[string] path: String,
+) -> Result<String, deno_core::error::AnyError> {
+    let root = &state
+        .try_borrow::<FsRoot>()
+        .ok_or_else(|| anyhow::anyhow!("readFile: the working directory does not exist"))?
+        .0;
+    let resolved = root
+        .join(&path)
+        .canonicalize()
+        .map_err(|e| anyhow::anyhow!("readFile: cannot read {path}: {e}"))?;
+    if !resolved.starts_with(root) {
+        anyhow::bail!("readFile: {path} is outside the working directory");
+    }
+    let len = std::fs::metadata(&resolved)?.len();
+    if len > MAX_READ_SIZE {
+        anyhow::bail!("readFile: {path} is larger than {MAX_READ_SIZE} bytes");
+    }
+    Ok(std::fs::read_to_string(&resolved)?)
+}
+
+/// Write a UTF-8 file below the working directory, replacing it if it exists. The
+/// directory it goes in must already exist, and is resolved the way `readFile` resolves
+/// paths before checking that it is still inside.
+#[op2(fast)]
+fn op_shelly_write_file(
+    state: &mut OpState,
+    #[string] path: String,
+    #[string] contents: String,
+) -> Result<(), deno_core::error::AnyError> {
+    let root = &state
+        .try_borrow::<FsRoot>()
+        .ok_or_else(|| anyhow::anyhow!("writeFile: the working directory does not exist"))?
+        .0;
+    let target = root.join(&path);
+    let (Some(parent), Some(file_name)) = (target.parent(), target.file_name()) else {
+        anyhow::bail!("writeFile: {path} is not a file path");
+    };
+    let resolved = parent
+        .canonicalize()
+        .map_err(|e| anyhow::anyhow!("writeFile: cannot write {path}: {e}"))?
+        .join(file_name);
+    // A symlink could point anywhere, so one is never written through
+    let is_symlink = std::fs::symlink_metadata(&resolved).is_ok_and(|m| m.is_symlink());
+    if !resolved.starts_with(root) || is_symlink {
+        anyhow::bail!("writeFile: {path} is outside the working directory");
+    }
+    std::fs::write(&resolved, contents)?;
+    Ok(())
+}
+
+/// What a handler passes to `fetch`
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct FetchOptions {
+    method: Option<String>,
+    headers: HashMap<String, String>,
+    body: Option<String>,
+}
+
+/// What `fetch` returns to a handler
+#[derive(Debug, Serialize)]
+struct FetchResponse {
+    status: u16,
+    body: String,
+}
+
+/// Make an HTTP request and wait for the response. An error status is returned rather
+/// than thrown, as with the web's `fetch`; only a request that gets no response throws.
+#[op2]
+#[serde]
+fn op_shelly_fetch(
+    state: &mut OpState,
+    #[string] url: String,
+    #[serde] options: FetchOptions,
+) -> Result<FetchResponse, deno_core::error::AnyError> {
+    let agent = state.borrow::<ureq::Agent>();
+    let mut request = agent.request(options.method.as_deref().unwrap_or("GET"), &url);
+    for (name, value) in &options.headers {
+        request = request.set(name, value);
+    }
+    let response = match options.body {
+        Some(body) => request.send_string(&body),
+        None => request.call(),
+    };
+    let response = match response {
+        Ok(response) | Err(ureq::Error::Status(_, response)) => response,
+        Err(e) => anyhow::bail!("fetch: {url}: {e}"),
+    };
+    Ok(FetchResponse {
+        status: response.status(),
+        body: response.into_string()?,
+    })
+}
+
+/// Name of the loaded handler, attached to what it logs
+struct HandlerName(String);
+
+/// Forward a `console` call to the log, never to the command's output or summary
+#[op2(fast)]
+fn op_shelly_log(state: &mut OpState, #[string] level: String, #[string] message: String) {
+    let handler = state
+        .try_borrow::<HandlerName>()
+        .map_or("<loading>", |name| name.0.as_str());
+    match level.as_str() {
+        "error" => tracing::error!(handler, "{message}"),
+        "warn" => tracing::warn!(handler, "{message}"),
+        "debug" => tracing::debug!(handler, "{message}"),
+        _ => tracing::info!(handler, "{message}"),
+    }
+}
+
+/// `console` for handlers, which the bare runtime lacks. Arguments are joined the way
+/// a browser prints them, with objects as JSON.
+const CONSOLE_SHIM: &str = r#"
+((ops) => {
+  const format = (args) =>
+    args
+      .map((arg) => {
+        if (typeof arg === "string") return arg;
+        if (arg instanceof Error) return arg.stack ?? String(arg);
+        try {
+          return JSON.stringify(arg) ?? String(arg);
+        } catch {
+          return String(arg);
+        }
+      })
+      .join(" ");
+  const log = (level) => (...args) => ops.op_shelly_log(level, format(args));
+  globalThis.console = {
+    log: log("info"),
+    info: log("info"),
+    debug: log("debug"),
+    warn: log("warn"),
+    error: log("error"),
+  };
+})(Deno.core.ops);
+"#;
+
+deno_core::extension!(shelly_handler_ops, ops = [op_shelly_read_file, op_shelly_log]);
+deno_core::extension!(shelly_network_ops, ops = [op_shelly_fetch]);
+deno_core::extension!(shelly_fs_write_ops, ops = [op_shelly_write_file]);
+
+/// The command as a handler wants it run. The command fields may be left out when
+/// `reject` is set, or when the handler only changes arguments with `prepend_args`,
+/// `append_args` and `remove_args`.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct PrepareResult {
+    #[serde(default)]
     pub cmd: String,
+    #[serde(default)]
     pub args: Vec<String>,
+    #[serde(default)]
     pub env: HashMap<String, String>,
+    /// Set when the handler refuses to run the command at all
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reject: Option<Rejection>,
+    /// Arguments to add before the original ones
+    #[serde(default, rename = "prependArgs", skip_serializing_if = "Option::is_none")]
+    pub prepend_args: Option<Vec<String>>,
+    /// Arguments to add after the original ones
+    #[serde(default, rename = "appendArgs", skip_serializing_if = "Option::is_none")]
+    pub append_args: Option<Vec<String>>,
+    /// Arguments to drop wherever they appear
+    #[serde(default, rename = "removeArgs", skip_serializing_if = "Option::is_none")]
+    pub remove_args: Option<Vec<String>>,
+    /// Names the output file in place of the start of the command, e.g. `cargo-build-release`
+    #[serde(default, rename = "outputLabel", skip_serializing_if = "Option::is_none")]
+    pub output_label: Option<String>,
+}
+
+impl PrepareResult {
+    /// The command a handler created with `cmd` and `args` asked for. Argument changes
+    /// apply to the original command unless the handler also replaced it; arguments
+    /// are removed first, then prepended and appended.
+    pub fn resolve(mut self, cmd: &str, args: &[String]) -> Self {
+        let remove = self.remove_args.take();
+        let prepend = self.prepend_args.take();
+        let append = self.append_args.take();
+        if self.reject.is_some() || (remove.is_none() && prepend.is_none() && append.is_none()) {
+            return self;
+        }
+        if self.cmd.is_empty() {
+            self.cmd = cmd.to_string();
+            self.args = args.to_vec();
+        }
+        let remove = remove.unwrap_or_default();
+        self.args.retain(|arg| !remove.contains(arg));
+        self.args.splice(0..0, prepend.unwrap_or_default());
+        self.args.extend(append.unwrap_or_default());
+        self
+    }
+}
+
+/// Why a handler refused to run a command
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Rejection {
+    pub reason: String,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct SummaryResult {
     pub summary: Option<String>,
     pub truncation: Option<TruncationInfo>,
+    /// Extra things the agent can do with the command, e.g. restart a dev server.
+    /// Replaces the actions from earlier summaries when non-empty.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub actions: Vec<HandlerAction>,
+    /// What the command's exit codes mean, for commands whose non-zero exits aren't
+    /// always errors. Added to the meanings from earlier summaries.
+    #[serde(default, rename = "exitCodes", skip_serializing_if = "HashMap::is_empty")]
+    pub exit_codes: HashMap<i32, ExitCodeMeaning>,
+    /// The summary split up by kind, so clients can go straight to the errors
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sections: Option<SummarySections>,
+}
+
+impl SummaryResult {
+    /// Parse what a script handler returned. A handler that only returns `sections`
+    /// gets them rendered as its summary.
+    pub fn from_json(json: &str) -> Result<Self> {
+        let mut result: Self = serde_json::from_str(json)?;
+        if result.summary.is_none() {
+            result.summary = result.sections.as_ref().map(SummarySections::render);
+        }
+        Ok(result)
+    }
+}
+
+/// Parts of a summary a handler can return separately instead of one string
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct SummarySections {
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub errors: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub warnings: Vec<String>,
+    /// e.g. "42 passed, 1 failed" or "finished in 3.2s"
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub stats: Vec<String>,
+    /// The last lines of output
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tail: Vec<String>,
+}
+
+impl SummarySections {
+    /// The sections as one string under a heading each, for clients that only read
+    /// the summary. Empty sections are left out.
+    pub fn render(&self) -> String {
+        let sections = [
+            ("Errors", &self.errors),
+            ("Warnings", &self.warnings),
+            ("Stats", &self.stats),
+            ("Tail", &self.tail),
+        ];
+        let mut rendered = String::new();
+        for (heading, items) in sections {
+            if items.is_empty() {
+                continue;
+            }
+            if !rendered.is_empty() {
+                rendered.push('\n');
+            }
+            rendered.push_str(heading);
+            rendered.push_str(":\n");
+            for item in items {
+                rendered.push_str(item.trim_end_matches('\n'));
+                rendered.push('\n');
+            }
+        }
+        rendered
+    }
+}
+
+/// What an exit code means for a particular command, e.g. `grep` exiting 1 for "no
+/// matches"
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct ExitCodeMeaning {
+    pub label: String,
+    #[serde(rename = "isError", alias = "is_error")]
+    pub is_error: bool,
+}
+
+/// A named action a handler offers the agent on top of join/cancel/status
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct HandlerAction {
+    pub name: String,
+    pub description: String,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -99,6 +479,90 @@ pub struct TruncationInfo {
     pub description: Option<String>,
 }
 
+/// Value type of a handler setting, as declared by `HandlerFactory.settings()`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SettingType {
+    Boolean,
+    String,
+    Number,
+}
+
+impl SettingType {
+    fn accepts(self, value: &serde_json::Value) -> bool {
+        match self {
+            SettingType::Boolean => value.is_boolean(),
+            SettingType::String => value.is_string(),
+            SettingType::Number => value.is_number(),
+        }
+    }
+}
+
+impl std::fmt::Display for SettingType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            SettingType::Boolean => "boolean",
+            SettingType::String => "string",
+            SettingType::Number => "number",
+        })
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SettingDefinition {
+    #[serde(rename = "type")]
+    pub kind: SettingType,
+    #[serde(default)]
+    pub default: serde_json::Value,
+    #[serde(default)]
+    pub description: String,
+}
+
+pub type SettingsSchema = HashMap<String, SettingDefinition>;
+
+/// Type-check `settings` against `schema` and fill in defaults for missing keys. Keys
+/// the schema doesn't declare are passed through untouched, since every handler in a
+/// chain receives the same settings.
+pub fn validate_settings(
+    schema: &SettingsSchema,
+    settings: &HashMap<String, serde_json::Value>,
+) -> Result<HashMap<String, serde_json::Value>> {
+    let mut validated = settings.clone();
+    for (key, definition) in schema {
+        match settings.get(key) {
+            Some(value) if !value.is_null() && !definition.kind.accepts(value) => {
+                anyhow::bail!(
+                    "Invalid value for setting `{key}`: expected {}, got {value}",
+                    definition.kind
+                );
+            }
+            Some(_) => {}
+            None => {
+                validated.insert(key.clone(), definition.default.clone());
+            }
+        }
+    }
+    Ok(validated)
+}
+
+/// Check that every default in `schema` has the type its setting declares, so a
+/// broken schema is caught before any command relies on it
+pub fn check_settings_schema(schema: &SettingsSchema) -> Result<()> {
+    let mut keys: Vec<_> = schema.keys().collect();
+    keys.sort();
+    for key in keys {
+        let definition = &schema[key];
+        if !definition.default.is_null() && !definition.kind.accepts(&definition.default) {
+            anyhow::bail!(
+                "Setting `{key}` is declared as {} but defaults to {}",
+                definition.kind,
+                definition.default
+            );
+        }
+    }
+    Ok(())
+}
+
 enum RuntimeRequest {
     LoadHandler {
         path: String,
@@ -109,10 +573,17 @@ enum RuntimeRequest {
         args: Vec<String>,
         response: oneshot::Sender<Result<bool>>,
     },
+    Priority {
+        response: oneshot::Sender<Result<f64>>,
+    },
+    Settings {
+        response: oneshot::Sender<Result<SettingsSchema>>,
+    },
     CreateHandler {
         cmd: String,
         args: Vec<String>,
         settings: HashMap<String, serde_json::Value>,
+        context: HandlerContext,
         response: oneshot::Sender<Result<()>>,
     },
     Prepare {
@@ -124,19 +595,187 @@ enum RuntimeRequest {
         exit_code: Option<i32>,
         response: oneshot::Sender<Result<SummaryResult>>,
     },
+    Finalize {
+        exit_code: i32,
+        stdout: String,
+        stderr: String,
+        response: oneshot::Sender<Result<SummaryResult>>,
+    },
+}
+
+/// A handler call ran past its time budget and was terminated
+#[derive(Debug)]
+pub struct HandlerTimeout {
+    pub hook: &'static str,
+    pub timeout: Duration,
+}
+
+impl std::fmt::Display for HandlerTimeout {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "handler {}() exceeded its {:?} time budget",
+            self.hook, self.timeout
+        )
+    }
+}
+
+impl std::error::Error for HandlerTimeout {}
+
+/// Context for errors from finding, loading or preparing a command's handlers, so they
+/// can be told apart from failures to run the command itself
+#[derive(Debug)]
+pub struct HandlerError;
+
+impl std::fmt::Display for HandlerError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("handler failed")
+    }
+}
+
+#[derive(Default)]
+struct WatchdogState {
+    deadline: Option<Instant>,
+    fired: bool,
+    shutdown: bool,
+}
+
+/// Terminates the isolate when a handler call runs past its deadline. Termination
+/// happens under the state lock, so once `disarm` returns no stale termination can
+/// hit the next call.
+struct Watchdog {
+    state: Arc<(Mutex<WatchdogState>, Condvar)>,
+}
+
+impl Watchdog {
+    fn new(isolate: v8::IsolateHandle) -> Self {
+        let state = Arc::new((Mutex::new(WatchdogState::default()), Condvar::new()));
+        let thread_state = state.clone();
+        std::thread::spawn(move || {
+            let (lock, cvar) = &*thread_state;
+            let mut guard = lock.lock().unwrap();
+            while !guard.shutdown {
+                match guard.deadline {
+                    None => guard = cvar.wait(guard).unwrap(),
+                    Some(deadline) => {
+                        let now = Instant::now();
+                        if now >= deadline {
+                            guard.deadline = None;
+                            guard.fired = true;
+                            isolate.terminate_execution();
+                        } else {
+                            guard = cvar.wait_timeout(guard, deadline - now).unwrap().0;
+                        }
+                    }
+                }
+            }
+        });
+        Self { state }
+    }
+
+    fn arm(&self, timeout: Duration) {
+        let (lock, cvar) = &*self.state;
+        let mut guard = lock.lock().unwrap();
+        guard.deadline = Some(Instant::now() + timeout);
+        guard.fired = false;
+        cvar.notify_one();
+    }
+
+    /// Returns whether the deadline was hit
+    fn disarm(&self) -> bool {
+        let (lock, cvar) = &*self.state;
+        let mut guard = lock.lock().unwrap();
+        guard.deadline = None;
+        cvar.notify_one();
+        std::mem::take(&mut guard.fired)
+    }
+}
+
+impl Drop for Watchdog {
+    fn drop(&mut self) {
+        let (lock, cvar) = &*self.state;
+        lock.lock().unwrap().shutdown = true;
+        cvar.notify_one();
+    }
+}
+
+/// Attach the handler's JS message and stack to a failed `execute_script` call
+fn handler_error(hook: &str, err: anyhow::Error) -> anyhow::Error {
+    match err.downcast_ref::<JsError>() {
+        Some(js_error) => anyhow::anyhow!(
+            "handler {hook}() threw: {}",
+            js_error
+                .stack
+                .as_deref()
+                .unwrap_or(&js_error.exception_message)
+        ),
+        None => err.context(format!("handler {hook}() failed")),
+    }
 }
 
 struct HandlerRuntimeInner {
     js_runtime: JsRuntime,
+    watchdog: Watchdog,
+    script_timeout: Duration,
+    capabilities: HandlerCapabilities,
 }
 
 impl HandlerRuntimeInner {
-    fn new() -> Self {
-        let js_runtime = JsRuntime::new(RuntimeOptions {
+    fn new(script_timeout: Duration, capabilities: HandlerCapabilities) -> Self {
+        // An op that isn't registered doesn't exist in the isolate at all
+        let mut extensions = vec![shelly_handler_ops::init_ops()];
+        if capabilities.network {
+            extensions.push(shelly_network_ops::init_ops());
+        }
+        if capabilities.fs_write {
+            extensions.push(shelly_fs_write_ops::init_ops());
+        }
+        let mut js_runtime = JsRuntime::new(RuntimeOptions {
             module_loader: Some(Rc::new(TsModuleLoader)),
+            extensions,
             ..Default::default()
         });
-        Self { js_runtime }
+        if capabilities.network {
+            // The watchdog can't interrupt a request in progress, so it gets the same limit
+            let agent = ureq::AgentBuilder::new().timeout(script_timeout).build();
+            js_runtime.op_state().borrow_mut().put(agent);
+        }
+        js_runtime
+            .execute_script("<console>", CONSOLE_SHIM.to_string())
+            .expect("the console shim is valid JavaScript");
+        let watchdog = Watchdog::new(js_runtime.v8_isolate().thread_safe_handle());
+        Self {
+            js_runtime,
+            watchdog,
+            script_timeout,
+            capabilities,
+        }
+    }
+
+    /// Run one call into the handler under the watchdog
+    fn execute_script(
+        &mut self,
+        hook: &'static str,
+        name: &'static str,
+        code: String,
+    ) -> Result<v8::Global<v8::Value>> {
+        self.watchdog.arm(self.script_timeout);
+        let result = self.js_runtime.execute_script(name, code);
+        self.check_timeout(hook)?;
+        result.map_err(|e| handler_error(hook, e))
+    }
+
+    fn check_timeout(&mut self, hook: &'static str) -> Result<()> {
+        if self.watchdog.disarm() {
+            // A terminated isolate refuses to run anything until this is cleared
+            self.js_runtime.v8_isolate().cancel_terminate_execution();
+            return Err(HandlerTimeout {
+                hook,
+                timeout: self.script_timeout,
+            }
+            .into());
+        }
+        Ok(())
     }
 
     async fn load_handler(&mut self, path: &str) -> Result<()> {
@@ -144,37 +783,23 @@ impl HandlerRuntimeInner {
         let specifier = ModuleSpecifier::from_file_path(&resolved)
             .map_err(|_| anyhow::anyhow!("Invalid path"))?;
 
-        // Extract handler name from file path (e.g., "cargo.ts" -> "cargo", "brazil-build.ts" -> "brazilBuild")
         let file_name = resolved
             .file_stem()
             .and_then(|s| s.to_str())
             .ok_or_else(|| anyhow::anyhow!("Invalid file name"))?;
+        let handler_export = handler_export_name(file_name);
+        self.js_runtime
+            .op_state()
+            .borrow_mut()
+            .put(HandlerName(file_name.to_string()));
 
-        // Convert kebab-case to camelCase for handler name
-        let handler_name = file_name
-            .split('-')
-            .enumerate()
-            .map(|(i, part)| {
-                if i == 0 {
-                    part.to_string()
-                } else {
-                    format!(
-                        "{}{}",
-                        part.chars().next().unwrap().to_uppercase(),
-                        &part[1..]
-                    )
-                }
-            })
-            .collect::<String>();
-
-        let handler_export = format!("{}Handler", handler_name);
-
+        // Quoted as JSON, so a path with quotes or backslashes in it stays one string
         let wrapper_code = format!(
             r#"
-            import {{ {handler_export} }} from "{}";
+            import {{ {handler_export} }} from {};
             globalThis.handler = {handler_export};
             "#,
-            specifier,
+            serde_json::to_string(specifier.as_str())?,
             handler_export = handler_export
         );
 
@@ -183,10 +808,18 @@ impl HandlerRuntimeInner {
             .js_runtime
             .load_side_es_module_from_code(&wrapper_spec, wrapper_code)
             .await?;
+
+        // Top-level handler code runs during evaluation, so it gets the same budget
+        self.watchdog.arm(self.script_timeout);
+        let result = self.evaluate(module_id).await;
+        self.check_timeout("load")?;
+        result
+    }
+
+    async fn evaluate(&mut self, module_id: deno_core::ModuleId) -> Result<()> {
         let result = self.js_runtime.mod_evaluate(module_id);
         self.js_runtime.run_event_loop(Default::default()).await?;
         result.await?;
-
         Ok(())
     }
 
@@ -195,31 +828,82 @@ impl HandlerRuntimeInner {
             serde_json::to_string(cmd)?,
             serde_json::to_string(args)?
         );
-        let result = self.js_runtime.execute_script("<matches>", code)?;
+        let result = self.execute_script("matches", "<matches>", code)?;
         let scope = &mut self.js_runtime.handle_scope();
         let local = deno_core::v8::Local::new(scope, result);
         Ok(local.is_true())
     }
 
+    fn priority(&mut self) -> Result<f64> {
+        let code = "JSON.stringify(typeof handler.priority === 'function' ? handler.priority() : 0)"
+            .to_string();
+        let result = self.execute_script("priority", "<priority>", code)?;
+        let scope = &mut self.js_runtime.handle_scope();
+        let local = deno_core::v8::Local::new(scope, result);
+        let json_str = local.to_rust_string_lossy(scope);
+        Ok(serde_json::from_str(&json_str)?)
+    }
+
+    fn settings(&mut self) -> Result<SettingsSchema> {
+        let code = "JSON.stringify(typeof handler.settings === 'function' ? handler.settings() : {})"
+            .to_string();
+        let result = self.execute_script("settings", "<settings>", code)?;
+        let scope = &mut self.js_runtime.handle_scope();
+        let local = deno_core::v8::Local::new(scope, result);
+        let json_str = local.to_rust_string_lossy(scope);
+        Ok(serde_json::from_str(&json_str)?)
+    }
+
     fn create_handler(
         &mut self,
         cmd: &str,
         args: &[String],
         settings: &HashMap<String, serde_json::Value>,
+        context: &HandlerContext,
     ) -> Result<()> {
+        let schema = self.settings()?;
+        let settings = validate_settings(&schema, settings)?;
+        // A missing working directory fails the command later with a clearer error.
+        // Either way a reused runtime must not keep the previous command's root.
+        {
+            let op_state = self.js_runtime.op_state();
+            let mut op_state = op_state.borrow_mut();
+            match context.working_dir.canonicalize() {
+                Ok(root) => op_state.put(FsRoot(root)),
+                Err(_) => {
+                    op_state.try_take::<FsRoot>();
+                }
+            }
+        }
+        let mut granted = String::new();
+        if self.capabilities.network {
+            granted.push_str(
+                "fetch: (url, options) => Deno.core.ops.op_shelly_fetch(url, options ?? {}), ",
+            );
+        }
+        if self.capabilities.fs_write {
+            granted.push_str(
+                "writeFile: (path, text) => Deno.core.ops.op_shelly_write_file(path, text), ",
+            );
+        }
         let code = format!(
-            "globalThis.__handler = handler.create({}, {}, {})",
+            "globalThis.__handler = handler.create({}, {}, {}, Object.freeze({{ \
+                ...{}, \
+                readFile: (path) => Deno.core.ops.op_shelly_read_file(path), \
+                {granted}\
+            }}))",
             serde_json::to_string(cmd)?,
             serde_json::to_string(args)?,
-            serde_json::to_string(settings)?
+            serde_json::to_string(&settings)?,
+            serde_json::to_string(context)?
         );
-        self.js_runtime.execute_script("<create>", code)?;
+        self.execute_script("create", "<create>", code)?;
         Ok(())
     }
 
     fn prepare(&mut self) -> Result<PrepareResult> {
-        let code = "JSON.stringify(globalThis.__handler.prepare())";
-        let result = self.js_runtime.execute_script("<prepare>", code)?;
+        let code = "JSON.stringify(globalThis.__handler.prepare())".to_string();
+        let result = self.execute_script("prepare", "<prepare>", code)?;
         let scope = &mut self.js_runtime.handle_scope();
         let local = deno_core::v8::Local::new(scope, result);
         let json_str = local.to_rust_string_lossy(scope);
@@ -240,11 +924,27 @@ impl HandlerRuntimeInner {
                 .map(|c| c.to_string())
                 .unwrap_or_else(|| "null".to_string())
         );
-        let result = self.js_runtime.execute_script("<summarize>", code)?;
+        let result = self.execute_script("summarize", "<summarize>", code)?;
         let scope = &mut self.js_runtime.handle_scope();
         let local = deno_core::v8::Local::new(scope, result);
         let json_str = local.to_rust_string_lossy(scope);
-        Ok(serde_json::from_str(&json_str)?)
+        SummaryResult::from_json(&json_str)
+    }
+
+    /// Handlers without a `finalize()` get a final `summarize()` call with the exit code
+    fn finalize(&mut self, exit_code: i32, stdout: &str, stderr: &str) -> Result<SummaryResult> {
+        let stdout = serde_json::to_string(stdout)?;
+        let stderr = serde_json::to_string(stderr)?;
+        let code = format!(
+            "JSON.stringify(typeof globalThis.__handler.finalize === 'function' \
+                ? globalThis.__handler.finalize({exit_code}, {stdout}, {stderr}) \
+                : globalThis.__handler.summarize({stdout}, {stderr}, {exit_code}))"
+        );
+        let result = self.execute_script("finalize", "<finalize>", code)?;
+        let scope = &mut self.js_runtime.handle_scope();
+        let local = deno_core::v8::Local::new(scope, result);
+        let json_str = local.to_rust_string_lossy(scope);
+        SummaryResult::from_json(&json_str)
     }
 
     async fn run(mut self, mut rx: mpsc::UnboundedReceiver<RuntimeRequest>) {
@@ -258,13 +958,22 @@ impl HandlerRuntimeInner {
                     let result = self.matches(&cmd, &args);
                     let _ = response.send(result);
                 }
+                RuntimeRequest::Priority { response } => {
+                    let result = self.priority();
+                    let _ = response.send(result);
+                }
+                RuntimeRequest::Settings { response } => {
+                    let result = self.settings();
+                    let _ = response.send(result);
+                }
                 RuntimeRequest::CreateHandler {
                     cmd,
                     args,
                     settings,
+                    context,
                     response,
                 } => {
-                    let result = self.create_handler(&cmd, &args, &settings);
+                    let result = self.create_handler(&cmd, &args, &settings, &context);
                     let _ = response.send(result);
                 }
                 RuntimeRequest::Prepare { response } => {
@@ -280,37 +989,667 @@ impl HandlerRuntimeInner {
                     let result = self.summarize(&stdout, &stderr, exit_code);
                     let _ = response.send(result);
                 }
+                RuntimeRequest::Finalize {
+                    exit_code,
+                    stdout,
+                    stderr,
+                    response,
+                } => {
+                    let result = self.finalize(exit_code, &stdout, &stderr);
+                    let _ = response.send(result);
+                }
             }
         }
     }
 }
 
+/// A handle to a JS runtime on its own thread. A runtime serves one command at a time,
+/// so concurrent commands never share one and a slow handler only delays the command it
+/// belongs to. With a `HandlerCache` the runtime is reused by later commands once the
+/// one using it is done, so top-level state in the handler's module carries over from
+/// one command to the next.
 pub struct HandlerRuntime {
     tx: mpsc::UnboundedSender<RuntimeRequest>,
 }
 
+/// What a handler may do besides turning output into a summary. Everything is off by
+/// default, which is the sandbox described in WRITING_HANDLERS.md. Granting a
+/// capability registers the ops behind it and adds its function to the handler's
+/// context; without it those ops don't exist in the runtime at all.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct HandlerCapabilities {
+    /// `context.fetch`: make HTTP requests, e.g. to enrich a summary from an API
+    pub network: bool,
+    /// `context.writeFile`: create or replace files inside the working directory
+    pub fs_write: bool,
+}
+
+/// On-disk shape of a declarative handler (`<name>.json` or `<name>.yaml`)
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct DeclarativeSpec {
+    matches: DeclarativeMatch,
+    #[serde(default)]
+    drop: Vec<String>,
+    #[serde(default)]
+    keep_last_n: Option<usize>,
+    #[serde(default)]
+    exit_codes: HashMap<i32, ExitCodeMeaning>,
+    #[serde(default)]
+    priority: f64,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct DeclarativeMatch {
+    command: String,
+    #[serde(default)]
+    args: Vec<String>,
+}
+
+/// A handler that filters output with regexes instead of running TypeScript
+#[derive(Debug)]
+pub struct DeclarativeHandler {
+    /// Command name this handler applies to
+    pub command: String,
+    /// Leading arguments the invocation must start with
+    pub args: Vec<String>,
+    /// Output lines matching any of these are dropped
+    pub drop: Vec<Regex>,
+    /// When the command fails, only keep this many trailing lines
+    pub keep_last_n: Option<usize>,
+    /// What the command's exit codes mean; non-error codes don't count as failures
+    pub exit_codes: HashMap<i32, ExitCodeMeaning>,
+    pub priority: f64,
+    invocation: Option<(String, Vec<String>)>,
+}
+
+impl DeclarativeHandler {
+    pub fn load(path: &Path) -> Result<Self> {
+        let content = std::fs::read_to_string(path)?;
+        let spec: DeclarativeSpec = match path.extension().and_then(|e| e.to_str()) {
+            Some("yaml" | "yml") => serde_yaml::from_str(&content)?,
+            _ => serde_json::from_str(&content)?,
+        };
+        let drop = spec
+            .drop
+            .iter()
+            .map(|pattern| {
+                Regex::new(pattern)
+                    .map_err(|e| anyhow::anyhow!("Invalid drop pattern {pattern:?}: {e}"))
+            })
+            .collect::<Result<_>>()?;
+        Ok(Self {
+            command: spec.matches.command,
+            args: spec.matches.args,
+            drop,
+            keep_last_n: spec.keep_last_n,
+            exit_codes: spec.exit_codes,
+            priority: spec.priority,
+            invocation: None,
+        })
+    }
+
+    pub fn matches(&self, cmd: &str, args: &[String]) -> bool {
+        cmd == self.command && args.starts_with(&self.args)
+    }
+
+    pub fn create_handler(&mut self, cmd: &str, args: &[String]) {
+        self.invocation = Some((cmd.to_string(), args.to_vec()));
+    }
+
+    /// Declarative handlers never rewrite the command
+    pub fn prepare(&self) -> Result<PrepareResult> {
+        let (cmd, args) = self
+            .invocation
+            .clone()
+            .ok_or_else(|| anyhow::anyhow!("prepare() called before create_handler()"))?;
+        Ok(PrepareResult {
+            cmd,
+            args,
+            env: HashMap::new(),
+            reject: None,
+            prepend_args: None,
+            append_args: None,
+            remove_args: None,
+            output_label: None,
+        })
+    }
+
+    pub fn summarize(&self, stdout: &str, stderr: &str, exit_code: Option<i32>) -> SummaryResult {
+        let kept: Vec<&str> = stdout
+            .split_inclusive('\n')
+            .chain(stderr.split_inclusive('\n'))
+            .filter(|line| {
+                let line = line.trim_end_matches(['\r', '\n']);
+                !self.drop.iter().any(|re| re.is_match(line))
+            })
+            .collect();
+
+        let failed = exit_code.is_some_and(|code| match self.exit_codes.get(&code) {
+            Some(meaning) => meaning.is_error,
+            None => code != 0,
+        });
+        match self.keep_last_n {
+            Some(n) if failed && kept.len() > n => SummaryResult {
+                summary: Some(kept[kept.len() - n..].concat()),
+                truncation: Some(TruncationInfo {
+                    truncated: true,
+                    reason: Some("keep_last_n".to_string()),
+                    description: Some(format!("Showing the last {n} of {} lines", kept.len())),
+                }),
+                actions: Vec::new(),
+                exit_codes: self.exit_codes.clone(),
+                sections: None,
+            },
+            _ => SummaryResult {
+                summary: Some(kept.concat()),
+                truncation: None,
+                actions: Vec::new(),
+                exit_codes: self.exit_codes.clone(),
+                sections: None,
+            },
+        }
+    }
+}
+
+/// A handler loaded from disk: a TypeScript module, or a declarative filter
+pub enum Handler {
+    Script(HandlerRuntime),
+    Declarative(DeclarativeHandler),
+}
+
+impl Handler {
+    /// Load a `.json`/`.yaml`/`.yml` file as a declarative handler, anything else as TypeScript
+    pub async fn load(path: &Path) -> Result<Self> {
+        match path.extension().and_then(|e| e.to_str()) {
+            Some("json" | "yaml" | "yml") => {
+                Ok(Self::Declarative(DeclarativeHandler::load(path)?))
+            }
+            _ => {
+                let path_str = path
+                    .to_str()
+                    .ok_or_else(|| anyhow::anyhow!("Handler path {path:?} is not valid UTF-8"))?;
+                let mut rt = HandlerRuntime::new()?;
+                rt.load_handler(path_str).await?;
+                Ok(Self::Script(rt))
+            }
+        }
+    }
+
+    pub async fn matches(&mut self, cmd: &str, args: &[String]) -> Result<bool> {
+        match self {
+            Self::Script(rt) => rt.matches(cmd, args).await,
+            Self::Declarative(handler) => Ok(handler.matches(cmd, args)),
+        }
+    }
+
+    pub async fn priority(&mut self) -> Result<f64> {
+        match self {
+            Self::Script(rt) => rt.priority().await,
+            Self::Declarative(handler) => Ok(handler.priority),
+        }
+    }
+
+    /// Declarative handlers take no settings
+    pub async fn settings(&mut self) -> Result<SettingsSchema> {
+        match self {
+            Self::Script(rt) => rt.settings().await,
+            Self::Declarative(_) => Ok(SettingsSchema::new()),
+        }
+    }
+
+    pub async fn create_handler(
+        &mut self,
+        cmd: &str,
+        args: &[String],
+        settings: &HashMap<String, serde_json::Value>,
+        context: &HandlerContext,
+    ) -> Result<()> {
+        match self {
+            Self::Script(rt) => rt.create_handler(cmd, args, settings, context).await,
+            Self::Declarative(handler) => {
+                handler.create_handler(cmd, args);
+                Ok(())
+            }
+        }
+    }
+
+    pub async fn prepare(&mut self) -> Result<PrepareResult> {
+        match self {
+            Self::Script(rt) => rt.prepare().await,
+            Self::Declarative(handler) => handler.prepare(),
+        }
+    }
+
+    pub async fn summarize(
+        &self,
+        stdout: &str,
+        stderr: &str,
+        exit_code: Option<i32>,
+    ) -> Result<SummaryResult> {
+        match self {
+            Self::Script(rt) => rt.summarize(stdout, stderr, exit_code).await,
+            Self::Declarative(handler) => Ok(handler.summarize(stdout, stderr, exit_code)),
+        }
+    }
+
+    /// Produce the summary of the whole run once the command has exited
+    pub async fn finalize(
+        &self,
+        exit_code: i32,
+        stdout: &str,
+        stderr: &str,
+    ) -> Result<SummaryResult> {
+        match self {
+            Self::Script(rt) => rt.finalize(exit_code, stdout, stderr).await,
+            Self::Declarative(handler) => Ok(handler.summarize(stdout, stderr, Some(exit_code))),
+        }
+    }
+}
+
+/// Most idle runtimes `HandlerCache` keeps for one handler file
+const MAX_IDLE_PER_HANDLER: usize = 4;
+
+/// Script handlers kept loaded between commands, so a command doesn't pay for a new
+/// runtime and a fresh transpile of every handler it looks at. A runtime is only handed
+/// out again once the command using it is done, and is dropped once its file changes.
+/// Top-level state in a handler module survives between commands; per-command state
+/// belongs in the object `create()` returns.
+#[derive(Clone, Default)]
+pub struct HandlerCache {
+    idle: Arc<Mutex<HashMap<PathBuf, IdleRuntimes>>>,
+}
+
+/// Idle runtimes for one handler file
+struct IdleRuntimes {
+    /// Version of the file they loaded
+    version: FileVersion,
+    runtimes: Vec<HandlerRuntime>,
+}
+
+/// Tells versions of a handler file apart. The size catches edits made within the
+/// filesystem's timestamp resolution.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FileVersion {
+    modified: SystemTime,
+    len: u64,
+}
+
+impl FileVersion {
+    fn of(path: &Path) -> Option<Self> {
+        let metadata = std::fs::metadata(path).ok()?;
+        Some(Self {
+            modified: metadata.modified().ok()?,
+            len: metadata.len(),
+        })
+    }
+}
+
+impl HandlerCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Reuse an idle runtime for `path` if its file hasn't changed, or load it afresh.
+    /// Also returns the file version to `release` the runtime under, if it's cacheable.
+    pub async fn load(&self, path: &Path) -> Result<(Handler, Option<FileVersion>)> {
+        let version = FileVersion::of(path);
+        if let Some(version) = version {
+            let mut idle = self.idle.lock().unwrap();
+            if let Some(entry) = idle.get_mut(path) {
+                if entry.version == version {
+                    if let Some(rt) = entry.runtimes.pop() {
+                        tracing::debug!("reusing loaded handler {path:?}");
+                        return Ok((Handler::Script(rt), Some(version)));
+                    }
+                } else {
+                    tracing::info!("handler {path:?} changed, reloading it");
+                    idle.remove(path);
+                }
+            }
+        }
+        let handler = Handler::load(path).await?;
+        let version = match handler {
+            Handler::Script(_) => version,
+            Handler::Declarative(_) => None,
+        };
+        Ok((handler, version))
+    }
+
+    /// Keep `rt`, loaded from `version` of `path`, for a later command
+    pub fn release(&self, path: &Path, version: FileVersion, rt: HandlerRuntime) {
+        let mut idle = self.idle.lock().unwrap();
+        let entry = idle
+            .entry(path.to_path_buf())
+            .or_insert_with(|| IdleRuntimes {
+                version,
+                runtimes: Vec::new(),
+            });
+        if entry.version != version {
+            // Runtimes for a newer version of the file are already kept
+            if entry.version.modified > version.modified {
+                return;
+            }
+            entry.version = version;
+            entry.runtimes.clear();
+        }
+        if entry.runtimes.len() < MAX_IDLE_PER_HANDLER {
+            entry.runtimes.push(rt);
+        }
+    }
+
+    /// Number of idle runtimes held for `path`
+    pub fn idle_count(&self, path: &Path) -> usize {
+        let idle = self.idle.lock().unwrap();
+        idle.get(path).map_or(0, |entry| entry.runtimes.len())
+    }
+}
+
+/// The handlers that matched a command, ordered by ascending `priority()`. Each
+/// handler's summary is fed to the next one as its stdout.
+pub struct HandlerChain {
+    handlers: Vec<Handler>,
+    paths: Vec<PathBuf>,
+    /// Where the handlers go back to when the chain is dropped, with the file version
+    /// each was loaded from
+    cache: Option<HandlerCache>,
+    versions: Vec<Option<FileVersion>>,
+    /// The token budget from the context the handlers were created with
+    max_summary_tokens: usize,
+}
+
+impl From<Handler> for HandlerChain {
+    fn from(handler: Handler) -> Self {
+        Self {
+            handlers: vec![handler],
+            paths: Vec::new(),
+            cache: None,
+            versions: Vec::new(),
+            max_summary_tokens: DEFAULT_MAX_SUMMARY_TOKENS,
+        }
+    }
+}
+
+impl Drop for HandlerChain {
+    fn drop(&mut self) {
+        let Some(cache) = &self.cache else {
+            return;
+        };
+        let handlers = self.handlers.drain(..).zip(&self.paths).zip(&self.versions);
+        for ((handler, path), version) in handlers {
+            if let (Handler::Script(rt), Some(version)) = (handler, version) {
+                cache.release(path, *version, rt);
+            }
+        }
+    }
+}
+
+impl From<HandlerRuntime> for HandlerChain {
+    fn from(handler: HandlerRuntime) -> Self {
+        Handler::Script(handler).into()
+    }
+}
+
+impl HandlerChain {
+    /// Roughly how many tokens the handlers' summary should take
+    pub fn max_summary_tokens(&self) -> usize {
+        self.max_summary_tokens
+    }
+
+    /// Load the handlers at `paths`, keep the ones that match the command, and run
+    /// their `prepare()` in chain order. Each handler is created with the command the
+    /// previous one prepared; env maps are merged with later handlers winning. The
+    /// first handler to reject the command ends the chain.
+    /// Returns `None` if no handler matches.
+    pub async fn load(
+        paths: &[PathBuf],
+        cmd: &str,
+        args: &[String],
+        settings: &HashMap<String, serde_json::Value>,
+        context: &HandlerContext,
+    ) -> Result<Option<(Self, PrepareResult)>> {
+        Self::load_from(paths, cmd, args, settings, context, None).await
+    }
+
+    /// `load`, taking script handlers from `cache` and returning them to it once the
+    /// chain is dropped
+    pub async fn load_cached(
+        paths: &[PathBuf],
+        cmd: &str,
+        args: &[String],
+        settings: &HashMap<String, serde_json::Value>,
+        context: &HandlerContext,
+        cache: &HandlerCache,
+    ) -> Result<Option<(Self, PrepareResult)>> {
+        Self::load_from(paths, cmd, args, settings, context, Some(cache)).await
+    }
+
+    async fn load_from(
+        paths: &[PathBuf],
+        cmd: &str,
+        args: &[String],
+        settings: &HashMap<String, serde_json::Value>,
+        context: &HandlerContext,
+        cache: Option<&HandlerCache>,
+    ) -> Result<Option<(Self, PrepareResult)>> {
+        let mut matched = Vec::new();
+        for path in paths {
+            let (mut rt, version) = match cache {
+                Some(cache) => cache.load(path).await?,
+                None => (Handler::load(path).await?, None),
+            };
+            if rt.matches(cmd, args).await? {
+                let priority = rt.priority().await?;
+                tracing::info!("handler {path:?} matches with priority {priority}");
+                matched.push((priority, path.clone(), rt, version));
+            } else if let (Some(cache), Handler::Script(rt), Some(version)) = (cache, rt, version)
+            {
+                cache.release(path, version, rt);
+            }
+        }
+        if matched.is_empty() {
+            return Ok(None);
+        }
+        // Stable, so equal priorities keep discovery order
+        matched.sort_by(|(a, ..), (b, ..)| a.total_cmp(b));
+
+        let mut prepared = PrepareResult {
+            cmd: cmd.to_string(),
+            args: args.to_vec(),
+            env: HashMap::new(),
+            reject: None,
+            prepend_args: None,
+            append_args: None,
+            remove_args: None,
+            output_label: None,
+        };
+        let mut chain = Self {
+            handlers: Vec::with_capacity(matched.len()),
+            paths: Vec::with_capacity(matched.len()),
+            cache: cache.cloned(),
+            versions: Vec::with_capacity(matched.len()),
+            max_summary_tokens: context.max_summary_tokens,
+        };
+        for (_, path, mut rt, version) in matched {
+            rt.create_handler(&prepared.cmd, &prepared.args, settings, context)
+                .await?;
+            let prep = rt.prepare().await?.resolve(&prepared.cmd, &prepared.args);
+            if prep.reject.is_none() && prep.cmd.is_empty() {
+                anyhow::bail!("handler {path:?} prepare() returned no cmd");
+            }
+            chain.handlers.push(rt);
+            chain.paths.push(path);
+            chain.versions.push(version);
+            if prep.reject.is_some() {
+                prepared.reject = prep.reject;
+                break;
+            }
+            prepared.cmd = prep.cmd;
+            prepared.args = prep.args;
+            prepared.env.extend(prep.env);
+            // Later handlers see the command last, so their label wins
+            if prep.output_label.is_some() {
+                prepared.output_label = prep.output_label;
+            }
+        }
+
+        Ok(Some((chain, prepared)))
+    }
+
+    /// Files the handlers were loaded from, in chain order
+    pub fn paths(&self) -> &[PathBuf] {
+        &self.paths
+    }
+
+    /// Settings accepted by any handler in the chain
+    pub async fn settings(&mut self) -> Result<SettingsSchema> {
+        let mut schema = SettingsSchema::new();
+        for handler in &mut self.handlers {
+            schema.extend(handler.settings().await?);
+        }
+        Ok(schema)
+    }
+
+    /// Pipe output through every handler. Stops early if a handler is still buffering.
+    pub async fn summarize(
+        &self,
+        stdout: &str,
+        stderr: &str,
+        exit_code: Option<i32>,
+    ) -> Result<SummaryResult> {
+        let (first, rest) = self
+            .handlers
+            .split_first()
+            .expect("a handler chain is never empty");
+        let mut result = first.summarize(stdout, stderr, exit_code).await?;
+        for handler in rest {
+            let Some(summary) = result.summary.take() else {
+                break;
+            };
+            let truncation = result.truncation.take();
+            let sections = result.sections.take();
+            let mut actions = std::mem::take(&mut result.actions);
+            let mut exit_codes = std::mem::take(&mut result.exit_codes);
+            result = handler.summarize(&summary, "", exit_code).await?;
+            result.truncation = result.truncation.or(truncation);
+            result.sections = result.sections.or(sections);
+            actions.append(&mut result.actions);
+            result.actions = actions;
+            exit_codes.extend(result.exit_codes.drain());
+            result.exit_codes = exit_codes;
+        }
+        Ok(result)
+    }
+
+    /// Pipe the complete output through every handler's `finalize()`
+    pub async fn finalize(
+        &self,
+        exit_code: i32,
+        stdout: &str,
+        stderr: &str,
+    ) -> Result<SummaryResult> {
+        let (first, rest) = self
+            .handlers
+            .split_first()
+            .expect("a handler chain is never empty");
+        let mut result = first.finalize(exit_code, stdout, stderr).await?;
+        for handler in rest {
+            let Some(summary) = result.summary.take() else {
+                break;
+            };
+            let truncation = result.truncation.take();
+            let sections = result.sections.take();
+            let mut actions = std::mem::take(&mut result.actions);
+            let mut exit_codes = std::mem::take(&mut result.exit_codes);
+            result = handler.finalize(exit_code, &summary, "").await?;
+            result.truncation = result.truncation.or(truncation);
+            result.sections = result.sections.or(sections);
+            actions.append(&mut result.actions);
+            result.actions = actions;
+            exit_codes.extend(result.exit_codes.drain());
+            result.exit_codes = exit_codes;
+        }
+        Ok(result)
+    }
+}
+
+/// Summarize an incremental chunk of output. Without a handler, or if the handler
+/// throws, the chunk is passed through, with repeated lines collapsed if `dedupe` is set.
 pub async fn process(
     stdout: &str,
     stderr: &str,
-    handler: &Option<HandlerRuntime>,
-) -> Result<SummaryResult> {
+    handler: &Option<HandlerChain>,
+    dedupe: bool,
+) -> SummaryResult {
     if let Some(handler) = handler {
-        handler.summarize(stdout, stderr, None).await
-    } else {
-        Ok(SummaryResult {
-            summary: Some(format!("{stdout}{stderr}")),
-            truncation: None,
-        })
+        match handler.summarize(stdout, stderr, None).await {
+            Ok(result) => return result,
+            Err(e) => {
+                tracing::warn!("{e:#}; passing output through unfiltered");
+                let mut result = passthrough(stdout, stderr, dedupe);
+                if let Some(timeout) = e.downcast_ref::<HandlerTimeout>() {
+                    result.truncation = Some(TruncationInfo {
+                        truncated: false,
+                        reason: Some("handler_timeout".to_string()),
+                        description: Some(format!("{timeout}; output is unfiltered")),
+                    });
+                }
+                return result;
+            }
+        }
+    }
+    passthrough(stdout, stderr, dedupe)
+}
+
+fn passthrough(stdout: &str, stderr: &str, dedupe: bool) -> SummaryResult {
+    let mut summary = format!("{stdout}{stderr}");
+    let mut truncation = None;
+    if dedupe {
+        let (deduped, removed) = crate::output::dedupe_lines(&summary);
+        if removed > 0 {
+            summary = deduped;
+            truncation = Some(TruncationInfo {
+                truncated: true,
+                reason: Some("filtered_duplicates".to_string()),
+                description: Some(format!(
+                    "{removed} repeated lines left out; the output file has all of them"
+                )),
+            });
+        }
+    }
+    SummaryResult {
+        summary: Some(summary),
+        truncation,
+        actions: Vec::new(),
+        exit_codes: HashMap::new(),
+        sections: None,
     }
 }
 
 impl HandlerRuntime {
     pub fn new() -> Result<Self> {
+        Self::with_script_timeout(DEFAULT_SCRIPT_TIMEOUT)
+    }
+
+    /// Create a runtime whose handler calls are terminated after `script_timeout`
+    pub fn with_script_timeout(script_timeout: Duration) -> Result<Self> {
+        Self::with_options(script_timeout, HandlerCapabilities::default())
+    }
+
+    /// Create a runtime whose handlers may also do what `capabilities` grants
+    pub fn with_capabilities(capabilities: HandlerCapabilities) -> Result<Self> {
+        Self::with_options(DEFAULT_SCRIPT_TIMEOUT, capabilities)
+    }
+
+    fn with_options(script_timeout: Duration, capabilities: HandlerCapabilities) -> Result<Self> {
+        // Only the ops registered in `HandlerRuntimeInner::new` exist in the isolate, and
+        // only those `capabilities` grants reach the network or write files
         let (tx, rx) = mpsc::unbounded_channel();
 
         std::thread::spawn(move || {
             let rt = tokio::runtime::Runtime::new().unwrap();
-            let inner = HandlerRuntimeInner::new();
+            let inner = HandlerRuntimeInner::new(script_timeout, capabilities);
             rt.block_on(inner.run(rx));
         });
 
@@ -336,17 +1675,35 @@ impl HandlerRuntime {
         rx.await?
     }
 
+    /// The factory's `priority()`, or 0 if it doesn't declare one
+    pub async fn priority(&mut self) -> Result<f64> {
+        let (tx, rx) = oneshot::channel();
+        self.tx.send(RuntimeRequest::Priority { response: tx })?;
+        rx.await?
+    }
+
+    /// The factory's declared settings schema
+    pub async fn settings(&mut self) -> Result<SettingsSchema> {
+        let (tx, rx) = oneshot::channel();
+        self.tx.send(RuntimeRequest::Settings { response: tx })?;
+        rx.await?
+    }
+
+    /// Create a handler instance. `settings` are checked against the factory's schema
+    /// and missing keys are filled in with their defaults.
     pub async fn create_handler(
         &mut self,
         cmd: &str,
         args: &[String],
         settings: &HashMap<String, serde_json::Value>,
+        context: &HandlerContext,
     ) -> Result<()> {
         let (tx, rx) = oneshot::channel();
         self.tx.send(RuntimeRequest::CreateHandler {
             cmd: cmd.to_string(),
             args: args.to_vec(),
             settings: settings.clone(),
+            context: context.clone(),
             response: tx,
         })?;
         rx.await?
@@ -373,4 +1730,21 @@ impl HandlerRuntime {
         })?;
         rx.await?
     }
+
+    /// The handler's `finalize()`, falling back to `summarize()` with the exit code
+    pub async fn finalize(
+        &self,
+        exit_code: i32,
+        stdout: &str,
+        stderr: &str,
+    ) -> Result<SummaryResult> {
+        let (tx, rx) = oneshot::channel();
+        self.tx.send(RuntimeRequest::Finalize {
+            exit_code,
+            stdout: stdout.to_string(),
+            stderr: stderr.to_string(),
+            response: tx,
+        })?;
+        rx.await?
+    }
 }
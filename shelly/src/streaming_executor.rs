@@ -1,23 +1,171 @@
 use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::path::PathBuf;
-use std::process::Stdio;
+#[cfg(unix)]
+use std::os::unix::process::ExitStatusExt;
+use std::process::{ExitStatus, Stdio};
 use std::sync::Arc;
-use tokio::io::{AsyncBufReadExt, BufReader};
-use tokio::process::Command;
-use tokio::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+use tokio::process::{Child, Command};
+use tokio::sync::watch;
+use tokio::time::{Duration, Instant, MissedTickBehavior};
 
-use crate::process_manager::{ProcessId, ProcessManager};
-use crate::runtime::HandlerRuntime;
+use crate::output::{AnsiMode, OutputHeader};
+use crate::process_manager::{OutputStream, ProcessId, ProcessManager};
+use crate::pty::{self, Pty};
+use crate::redact::Redactor;
+use crate::runtime::HandlerChain;
+
+/// Maximum number of bytes taken from a pipe per read
+const READ_CHUNK_SIZE: usize = 8192;
+
+/// Like git, a stream with a NUL byte this early on is taken to be binary
+const BINARY_SNIFF_LEN: usize = 8000;
+
+/// How long a cancelled process gets to exit after SIGTERM before it is sent SIGKILL
+#[cfg(unix)]
+const CANCEL_GRACE_PERIOD: Duration = Duration::from_secs(5);
+
+/// `CREATE_NEW_PROCESS_GROUP`, so the command doesn't share shelly's console signals
+#[cfg(windows)]
+const CREATE_NEW_PROCESS_GROUP: u32 = 0x0000_0200;
+
+/// The exit code handlers are finalized with when a command is cancelled: what a shell
+/// reports for a command interrupted with Ctrl-C
+pub const CANCELLED_EXIT_CODE: i32 = 130;
+
+/// How long the handler gets to summarize a cancelled command before the cancel goes
+/// ahead without it
+const CANCEL_SUMMARY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Which of shelly's own environment variables a command sees, before `env` is applied
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(default)]
+pub struct EnvPolicy {
+    /// Start from shelly's environment. When false the command only gets the variables
+    /// it is given explicitly.
+    pub inherit: bool,
+    /// Variables dropped from the inherited environment
+    pub remove: Vec<String>,
+}
+
+impl Default for EnvPolicy {
+    fn default() -> Self {
+        Self {
+            inherit: true,
+            // Shelly's own log level shouldn't change what the command prints
+            remove: vec!["RUST_LOG".to_string()],
+        }
+    }
+}
+
+/// Caps on what a command may use. Only enforced on Unix, where the process is killed
+/// when it goes over; unset means unlimited.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ResourceLimits {
+    /// CPU time in seconds, the command and anything it runs each counted separately
+    pub cpu_secs: Option<u64>,
+    /// Address space in MiB, per process
+    pub memory_mb: Option<u64>,
+}
+
+/// Type of the `resource` argument to `setrlimit`, which glibc declares as an enum
+#[cfg(all(target_os = "linux", target_env = "gnu"))]
+type RlimitResource = libc::__rlimit_resource_t;
+#[cfg(all(unix, not(all(target_os = "linux", target_env = "gnu"))))]
+type RlimitResource = libc::c_int;
+
+impl ResourceLimits {
+    pub fn is_unlimited(&self) -> bool {
+        self.cpu_secs.is_none() && self.memory_mb.is_none()
+    }
+
+    /// Lower the current process's limits. Runs in the child between fork and exec, so
+    /// it only makes async-signal-safe calls.
+    #[cfg(unix)]
+    fn apply(&self) -> std::io::Result<()> {
+        if let Some(secs) = self.cpu_secs {
+            // SIGXCPU at the soft limit, then SIGKILL a second later for commands that
+            // ignore it
+            set_rlimit(libc::RLIMIT_CPU, secs, secs.saturating_add(1))?;
+        }
+        if let Some(mb) = self.memory_mb {
+            let bytes = mb.saturating_mul(1024 * 1024);
+            set_rlimit(libc::RLIMIT_AS, bytes, bytes)?;
+        }
+        Ok(())
+    }
+
+    /// Which limit, if any, probably killed a command that died from `signal`
+    #[cfg(unix)]
+    pub fn explain(&self, signal: i32) -> Option<String> {
+        match signal {
+            libc::SIGXCPU => self
+                .cpu_secs
+                .map(|secs| format!("exceeded its CPU time limit of {secs}s")),
+            libc::SIGKILL if self.cpu_secs.is_some() || self.memory_mb.is_some() => {
+                Some("probably exceeded its resource limits".to_string())
+            }
+            libc::SIGSEGV | libc::SIGBUS | libc::SIGABRT => self
+                .memory_mb
+                .map(|mb| format!("possibly out of memory under its {mb} MiB limit")),
+            _ => None,
+        }
+    }
+
+    /// Windows processes aren't killed by signals, and limits aren't supported there
+    #[cfg(windows)]
+    pub fn explain(&self, _signal: i32) -> Option<String> {
+        None
+    }
+}
+
+/// Set the soft and hard limit on `resource`, never above the current hard limit, which
+/// an unprivileged process couldn't raise anyway
+#[cfg(unix)]
+fn set_rlimit(resource: RlimitResource, soft: u64, hard: u64) -> std::io::Result<()> {
+    let mut limit = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    // SAFETY: getrlimit and setrlimit only access `limit`
+    unsafe {
+        if libc::getrlimit(resource, &mut limit) != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        limit.rlim_max = limit.rlim_max.min(hard as libc::rlim_t);
+        limit.rlim_cur = limit.rlim_max.min(soft as libc::rlim_t);
+        if libc::setrlimit(resource, &limit) != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
 
 pub struct StreamingExecutorConfig {
     pub cmd: String,
     pub args: Vec<String>,
     pub env: HashMap<String, String>,
+    pub env_policy: EnvPolicy,
     pub working_dir: PathBuf,
     pub update_interval: Duration,
-    pub handler: Option<HandlerRuntime>,
+    pub handler: Option<HandlerChain>,
     pub output_file: PathBuf,
+    pub ansi: AnsiMode,
+    /// Run the command under a pseudo-terminal instead of pipes. Its stdout and stderr
+    /// then arrive merged as stdout.
+    pub pty: bool,
+    /// Masks secrets in the output
+    pub redactor: Redactor,
+    /// Collapse repeated lines when there's no handler to summarize the output
+    pub dedupe: bool,
+    pub limits: ResourceLimits,
+    /// Written to the command's stdin, which is then closed. Not supported with `pty`.
+    pub stdin: Option<String>,
+    /// Kill the command once it has gone this long without any output
+    pub idle_timeout: Option<Duration>,
 }
 
 pub struct StreamingExecutorResult {
@@ -44,8 +192,26 @@ pub async fn spawn(
     } else {
         format!("{} {}", config.cmd, config.args.join(" "))
     };
+    let header = OutputHeader {
+        command: shell_words::join(std::iter::once(&config.cmd).chain(&config.args)),
+        working_dir: config.working_dir.clone(),
+        handlers: config
+            .handler
+            .iter()
+            .flat_map(|chain| chain.paths())
+            .filter_map(|path| path.file_stem())
+            .map(|stem| stem.to_string_lossy().into_owned())
+            .collect(),
+        env: config.env.clone().into_iter().collect(),
+    };
     let process_id = process_manager
-        .start_process(command_display, config.output_file.clone())
+        .start_process(
+            command_display,
+            config.output_file.clone(),
+            config.ansi,
+            config.redactor.clone(),
+            header,
+        )
         .await;
 
     // Spawn the actual execution task
@@ -74,7 +240,7 @@ async fn execute_streaming_internal(
     if let Err(e) = &result {
         // Any error should mark the process as failed
         process_manager
-            .fail_process(&process_id, e.to_string())
+            .fail_process(&process_id, format!("{e:#}"))
             .await;
     }
 
@@ -87,93 +253,539 @@ async fn execute_streaming_inner(
     process_manager: &ProcessManager,
     process_id: &ProcessId,
 ) -> Result<()> {
+    let mut cancel_rx = process_manager
+        .cancel_signal(process_id)
+        .await
+        .context("Process is no longer tracked")?;
+
+    // Otherwise the spawn fails with an OS error that doesn't say which path was wrong
+    if !config.working_dir.exists() {
+        anyhow::bail!(
+            "working directory does not exist: {}",
+            config.working_dir.display()
+        );
+    }
+    if !config.working_dir.is_dir() {
+        anyhow::bail!(
+            "working directory is not a directory: {}",
+            config.working_dir.display()
+        );
+    }
+
+    // Held until the command has exited, so it counts towards the concurrency limit
+    let _slot = tokio::select! {
+        slot = process_manager.acquire_slot(process_id) => slot,
+        _ = cancelled(&mut cancel_rx) => anyhow::bail!("Cancelled while queued"),
+    };
+
     let mut cmd = Command::new(&config.cmd);
+    if !config.env_policy.inherit {
+        cmd.env_clear();
+    }
+    for key in &config.env_policy.remove {
+        cmd.env_remove(key);
+    }
     cmd.args(&config.args)
         .current_dir(&config.working_dir)
-        .env_remove("RUST_LOG")
         .envs(&config.env)
-        .stdout(Stdio::piped())
-        .stderr(Stdio::piped());
+        .kill_on_drop(true);
+    if !config.limits.is_unlimited() {
+        #[cfg(unix)]
+        {
+            let limits = config.limits;
+            // SAFETY: apply only makes async-signal-safe calls
+            unsafe {
+                cmd.pre_exec(move || limits.apply());
+            }
+        }
+        #[cfg(windows)]
+        anyhow::bail!("Resource limits are only supported on Unix");
+    }
 
-    let mut child = cmd.spawn().context("Failed to spawn command")?;
+    let pty = if config.pty {
+        // Closing a terminal would hang up on the command, so there'd be no end of input
+        if config.stdin.is_some() {
+            anyhow::bail!("stdin content can't be combined with PTY mode");
+        }
+        let pty = Pty::open()?;
+        cmd.stdin(pty.slave_stdio()?)
+            .stdout(pty.slave_stdio()?)
+            .stderr(pty.slave_stdio()?);
+        // A new session is also a new process group, so cancellation still reaches
+        // everything the command forks
+        // SAFETY: attach_controlling_terminal only makes async-signal-safe calls
+        #[cfg(unix)]
+        unsafe {
+            cmd.pre_exec(pty::attach_controlling_terminal);
+        }
+        Some(pty)
+    } else {
+        cmd.stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+        // Own process group so cancellation can signal everything the command forks
+        #[cfg(unix)]
+        cmd.process_group(0);
+        #[cfg(windows)]
+        cmd.creation_flags(CREATE_NEW_PROCESS_GROUP);
+        None
+    };
 
-    let stdout = child.stdout.take().context("Failed to capture stdout")?;
-    let stderr = child.stderr.take().context("Failed to capture stderr")?;
+    let mut child = cmd.spawn().context("Failed to spawn command")?;
+    // The command holds the only handles to its end of the terminal now, so reading
+    // the master ends once it and anything it forked have exited
+    drop(cmd);
+    if let Some(pid) = child.id() {
+        process_manager.register_pid(process_id, pid).await;
+    }
 
-    let mut stdout_reader = BufReader::new(stdout).lines();
-    let mut stderr_reader = BufReader::new(stderr).lines();
+    let (mut stdout, mut stderr): (Reader, Reader) = match pty {
+        Some(pty) => {
+            let input = tokio::fs::File::from_std(pty.master.try_clone()?);
+            process_manager
+                .register_stdin(process_id, Box::new(input))
+                .await;
+            let output = tokio::fs::File::from_std(pty.master);
+            (Box::new(output), Box::new(tokio::io::empty()))
+        }
+        None => {
+            match (child.stdin.take(), &config.stdin) {
+                // Written from its own task, so a command that prints while it reads
+                // can't block on output we haven't started reading yet
+                (Some(mut stdin), Some(input)) => {
+                    let input = input.clone();
+                    tokio::spawn(async move {
+                        // A command may exit without reading all of it, which is fine
+                        if let Err(e) = stdin.write_all(input.as_bytes()).await {
+                            tracing::debug!("stopped writing stdin: {e}");
+                        }
+                        // Dropping stdin closes it, so the command sees end of input
+                    });
+                }
+                (Some(stdin), None) => {
+                    process_manager
+                        .register_stdin(process_id, Box::new(stdin))
+                        .await;
+                }
+                (None, _) => {}
+            }
+            (
+                Box::new(child.stdout.take().context("Failed to capture stdout")?),
+                Box::new(child.stderr.take().context("Failed to capture stderr")?),
+            )
+        }
+    };
 
     let handler = &config.handler;
+    let mut pending_stdout = PendingOutput::default();
+    let mut pending_stderr = PendingOutput::default();
+    let mut stdout_buf = vec![0u8; READ_CHUNK_SIZE];
+    let mut stderr_buf = vec![0u8; READ_CHUNK_SIZE];
 
-    // Read output line by line with periodic updates
-    loop {
+    // Output is recorded as soon as a line is complete, but the handler only sees it
+    // once per tick. An unfinished line (e.g. a progress bar redrawn with `\r`) is
+    // recorded on the tick as well so it still shows up.
+    let mut ticker = tokio::time::interval(config.update_interval);
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let idle = idle_timeout(process_manager, process_id, config.idle_timeout);
+    tokio::pin!(idle);
+    let mut idle_timed_out = false;
+    while !(pending_stdout.closed && pending_stderr.closed) {
         tokio::select! {
-            line = stdout_reader.next_line() => {
-                match line? {
-                    Some(l) => {
-                            process_manager.update_process_output(
-                                process_id,
-                                format!("{}\n", l),
-                                String::new(),
-                                handler,
-                            ).await;
-                    }
-                    None => break,
+            n = stdout.read(&mut stdout_buf), if !pending_stdout.closed => {
+                let n = pty::eof_on_eio(n)?;
+                if n > 0 {
+                    process_manager.record_read(process_id).await;
+                }
+                pending_stdout.push(&stdout_buf[..n]);
+                let lines = pending_stdout.take_lines();
+                if !lines.is_empty() {
+                    process_manager
+                        .append_output(process_id, OutputStream::Stdout, &lines)
+                        .await;
+                }
+            }
+            n = stderr.read(&mut stderr_buf), if !pending_stderr.closed => {
+                let n = n?;
+                if n > 0 {
+                    process_manager.record_read(process_id).await;
+                }
+                pending_stderr.push(&stderr_buf[..n]);
+                let lines = pending_stderr.take_lines();
+                if !lines.is_empty() {
+                    process_manager
+                        .append_output(process_id, OutputStream::Stderr, &lines)
+                        .await;
                 }
             }
-            line = stderr_reader.next_line() => {
-                if let Some(l) = line? {
-                    process_manager.update_process_output(
-                        process_id,
-                        String::new(),
-                        format!("{}\n", l),
-                        handler,
-                    ).await;
+            _ = ticker.tick() => {
+                flush_pending(process_manager, process_id, &mut pending_stdout, &mut pending_stderr).await;
+                // A slow handler mustn't hold up a cancel
+                tokio::select! {
+                    _ = process_manager.summarize_pending(process_id, handler, config.dedupe) => {}
+                    _ = cancelled(&mut cancel_rx) => break,
                 }
             }
+            _ = cancelled(&mut cancel_rx) => break,
+            _ = &mut idle => {
+                idle_timed_out = true;
+                break;
+            }
         }
     }
+    flush_pending(process_manager, process_id, &mut pending_stdout, &mut pending_stderr).await;
+    process_manager.release_held_output(process_id).await;
+    for (stream, pending) in [
+        (OutputStream::Stdout, &pending_stdout),
+        (OutputStream::Stderr, &pending_stderr),
+    ] {
+        let binary_bytes = pending.binary.then_some(pending.received);
+        process_manager
+            .note_non_text(process_id, stream, pending.replaced, binary_bytes)
+            .await;
+    }
+    let summarize = process_manager.summarize_pending(process_id, handler, config.dedupe);
+    bounded_if_cancelled(&cancel_rx, summarize).await;
 
-    let status = child.wait().await?;
-    let exit_code = status.code().unwrap_or(-1);
-
-    // Final handler call with exit code
-    if let Some(ref handler) = handler {
+    // Output can close before the process exits, so cancellation and the idle timeout
+    // have to be able to interrupt the wait as well
+    let status = if idle_timed_out {
+        terminate(&mut child).await?
+    } else {
+        tokio::select! {
+            status = child.wait() => status?,
+            _ = cancelled(&mut cancel_rx) => terminate(&mut child).await?,
+            _ = &mut idle => {
+                idle_timed_out = true;
+                terminate(&mut child).await?
+            }
+        }
+    };
+    if idle_timed_out {
+        tracing::info!("{} went {:?} without output", process_id.0, config.idle_timeout);
+        process_manager.mark_timed_out(process_id).await;
         process_manager
-            .final_process_summary(process_id, exit_code, handler)
+            .record_failure(process_id, "idle timeout".to_string())
             .await;
     }
+    // Report a signal the way a shell does, so handlers still see a failing exit code
+    #[cfg(unix)]
+    let signal = status.signal();
+    #[cfg(windows)]
+    let signal = None;
+    let exit_code = status
+        .code()
+        .or(signal.map(|signal| 128 + signal))
+        .unwrap_or(-1);
+
+    // Final handler call with exit code. A cancelled command still gets a summary of
+    // what it did before it was stopped. It has to be stored before `complete_process`
+    // wakes up joiners, or a quick command's join could return before its summary.
+    if let Some(ref handler) = handler {
+        let handler_exit_code = if *cancel_rx.borrow() {
+            CANCELLED_EXIT_CODE
+        } else {
+            exit_code
+        };
+        let finalize =
+            process_manager.final_process_summary(process_id, handler_exit_code, handler);
+        bounded_if_cancelled(&cancel_rx, finalize).await;
+    }
 
     process_manager
-        .complete_process(process_id, exit_code)
+        .complete_process(process_id, exit_code, signal)
         .await;
     Ok(())
 }
 
+/// Resolves once the process has gone `timeout` without output, counting from when this
+/// is first polled. Never resolves without a timeout.
+async fn idle_timeout(
+    process_manager: &ProcessManager,
+    process_id: &ProcessId,
+    timeout: Option<Duration>,
+) {
+    let Some(timeout) = timeout else {
+        return std::future::pending().await;
+    };
+    let started = Instant::now();
+    loop {
+        let last_output = process_manager.last_output_at(process_id).await;
+        let deadline = last_output.map_or(started, |last| last.max(started)) + timeout;
+        if Instant::now() >= deadline {
+            return;
+        }
+        tokio::time::sleep_until(deadline).await;
+    }
+}
+
+/// The command's stdout or stderr, either a pipe or the master side of its terminal
+type Reader = Box<dyn AsyncRead + Unpin + Send>;
+
+/// Output read from one of the child's pipes that hasn't been forwarded yet
+#[derive(Default)]
+struct PendingOutput {
+    bytes: Vec<u8>,
+    closed: bool,
+    /// Bytes read from the stream so far
+    received: usize,
+    /// Invalid UTF-8 sequences replaced with U+FFFD so far
+    replaced: usize,
+    /// Whether the stream looks like binary data, which is counted but not forwarded
+    binary: bool,
+}
+
+impl PendingOutput {
+    /// Append a chunk from `read`; an empty chunk means the pipe was closed
+    fn push(&mut self, chunk: &[u8]) {
+        if chunk.is_empty() {
+            self.closed = true;
+            return;
+        }
+        let sniffed = BINARY_SNIFF_LEN.saturating_sub(self.received).min(chunk.len());
+        self.received += chunk.len();
+        self.binary |= chunk[..sniffed].contains(&0);
+        if self.binary {
+            self.bytes.clear();
+            return;
+        }
+        self.bytes.extend_from_slice(chunk);
+        // Only the latest frame of an unfinished progress line is worth keeping
+        let line_start = self
+            .bytes
+            .iter()
+            .rposition(|&b| b == b'\n')
+            .map_or(0, |i| i + 1);
+        let collapsed = collapse_carriage_returns(&self.bytes[line_start..]).len();
+        let redrawn = self.bytes.len() - line_start - collapsed;
+        self.bytes.drain(line_start..line_start + redrawn);
+    }
+
+    /// Every complete line read so far
+    fn take_lines(&mut self) -> String {
+        match self.bytes.iter().rposition(|&b| b == b'\n') {
+            Some(end) => {
+                let lines: Vec<u8> = self.bytes.drain(..=end).collect();
+                let mut output = String::with_capacity(lines.len());
+                for line in lines.split_inclusive(|&b| b == b'\n') {
+                    self.decode(collapse_carriage_returns(line), &mut output);
+                }
+                output
+            }
+            None => String::new(),
+        }
+    }
+
+    /// Everything read so far, including an unfinished last line. A multi-byte character
+    /// split across reads is held back until the rest of it arrives.
+    fn take_all(&mut self) -> String {
+        let mut output = self.take_lines();
+        let valid = match std::str::from_utf8(&self.bytes) {
+            Ok(_) => self.bytes.len(),
+            Err(e) if e.error_len().is_none() && !self.closed => e.valid_up_to(),
+            Err(_) => self.bytes.len(),
+        };
+        let partial: Vec<u8> = self.bytes.drain(..valid).collect();
+        self.decode(&partial, &mut output);
+        output
+    }
+
+    /// Append `bytes` to `output` the way `String::from_utf8_lossy` would, counting
+    /// the replacements
+    fn decode(&mut self, bytes: &[u8], output: &mut String) {
+        for chunk in bytes.utf8_chunks() {
+            output.push_str(chunk.valid());
+            if !chunk.invalid().is_empty() {
+                output.push(char::REPLACEMENT_CHARACTER);
+                self.replaced += 1;
+            }
+        }
+    }
+}
+
+/// Record whatever is buffered, including unfinished lines
+async fn flush_pending(
+    process_manager: &ProcessManager,
+    process_id: &ProcessId,
+    stdout: &mut PendingOutput,
+    stderr: &mut PendingOutput,
+) {
+    for (stream, pending) in [(OutputStream::Stdout, stdout), (OutputStream::Stderr, stderr)] {
+        let output = pending.take_all();
+        if !output.is_empty() {
+            process_manager
+                .append_output(process_id, stream, &output)
+                .await;
+        }
+    }
+}
+
+/// Drop the frames of a line that were overwritten with `\r`, keeping the last `\r` so
+/// that a line whose start was already forwarded is still marked as redrawn. A `\r` at
+/// the very end may be half of a `\r\n` and is left alone.
+fn collapse_carriage_returns(line: &[u8]) -> &[u8] {
+    let content_end = line.len() - line.ends_with(b"\n") as usize;
+    let search_end = content_end.saturating_sub(1);
+    match line[..search_end].iter().rposition(|&b| b == b'\r') {
+        Some(cr) => &line[cr..],
+        None => line,
+    }
+}
+
+/// Resolves once cancellation has been requested. Never resolves if the process is no
+/// longer tracked, since nobody can cancel it anymore.
+async fn cancelled(cancel_rx: &mut watch::Receiver<bool>) {
+    if cancel_rx.wait_for(|cancelled| *cancelled).await.is_err() {
+        std::future::pending::<()>().await;
+    }
+}
+
+/// Run a handler call to completion, or for at most `CANCEL_SUMMARY_TIMEOUT` once the
+/// command has been cancelled
+async fn bounded_if_cancelled(
+    cancel_rx: &watch::Receiver<bool>,
+    call: impl std::future::Future<Output = ()>,
+) {
+    let cancelled = *cancel_rx.borrow();
+    if !cancelled {
+        return call.await;
+    }
+    if tokio::time::timeout(CANCEL_SUMMARY_TIMEOUT, call).await.is_err() {
+        tracing::warn!(
+            "handler didn't summarize the cancelled command within {CANCEL_SUMMARY_TIMEOUT:?}"
+        );
+    }
+}
+
+/// SIGTERM the child's process group, escalating to SIGKILL if it is still alive
+/// after `CANCEL_GRACE_PERIOD`
+#[cfg(unix)]
+async fn terminate(child: &mut Child) -> Result<ExitStatus> {
+    if let Some(pid) = child.id() {
+        signal_process_group(pid, libc::SIGTERM);
+        if let Ok(status) = tokio::time::timeout(CANCEL_GRACE_PERIOD, child.wait()).await {
+            return Ok(status?);
+        }
+        tracing::warn!("process {pid} ignored SIGTERM, sending SIGKILL");
+        signal_process_group(pid, libc::SIGKILL);
+    }
+    Ok(child.wait().await?)
+}
+
+/// Kill the child and every process it started. Windows has no SIGTERM to ask nicely
+/// with, so this is immediate.
+#[cfg(windows)]
+async fn terminate(child: &mut Child) -> Result<ExitStatus> {
+    if let Some(pid) = child.id() {
+        let killed = Command::new("taskkill")
+            .args(["/T", "/F", "/PID", &pid.to_string()])
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status()
+            .await;
+        if !killed.is_ok_and(|status| status.success()) {
+            tracing::warn!("taskkill failed for process {pid}, terminating only the command");
+            child.start_kill()?;
+        }
+    }
+    Ok(child.wait().await?)
+}
+
+/// Send `signal` to the process group led by `pid`. Children are spawned as group
+/// leaders, so this also reaches anything they forked.
+#[cfg(unix)]
+fn signal_process_group(pid: u32, signal: libc::c_int) {
+    // SAFETY: kill(2) has no memory-safety preconditions
+    unsafe {
+        libc::kill(-(pid as libc::pid_t), signal);
+    }
+}
+
+/// Conventional name of a signal, e.g. `SIGSEGV`, or its number if it isn't a common one
+#[cfg(unix)]
+pub fn signal_name(signal: i32) -> String {
+    let name = match signal {
+        libc::SIGHUP => "SIGHUP",
+        libc::SIGINT => "SIGINT",
+        libc::SIGQUIT => "SIGQUIT",
+        libc::SIGILL => "SIGILL",
+        libc::SIGTRAP => "SIGTRAP",
+        libc::SIGABRT => "SIGABRT",
+        libc::SIGBUS => "SIGBUS",
+        libc::SIGFPE => "SIGFPE",
+        libc::SIGKILL => "SIGKILL",
+        libc::SIGUSR1 => "SIGUSR1",
+        libc::SIGSEGV => "SIGSEGV",
+        libc::SIGUSR2 => "SIGUSR2",
+        libc::SIGPIPE => "SIGPIPE",
+        libc::SIGALRM => "SIGALRM",
+        libc::SIGTERM => "SIGTERM",
+        libc::SIGXCPU => "SIGXCPU",
+        libc::SIGXFSZ => "SIGXFSZ",
+        _ => return format!("signal {signal}"),
+    };
+    name.to_string()
+}
+
+/// Windows processes aren't killed by signals, so there are no names to give
+#[cfg(windows)]
+pub fn signal_name(signal: i32) -> String {
+    format!("signal {signal}")
+}
+
+/// A config that runs `cmd` with `args` in the current directory and writes
+/// `output_file`, with every option at its default, for tests to override
+#[cfg(test)]
+pub(crate) fn test_config(
+    cmd: &str,
+    args: &[&str],
+    output_file: PathBuf,
+) -> StreamingExecutorConfig {
+    StreamingExecutorConfig {
+        cmd: cmd.to_string(),
+        args: args.iter().map(|arg| arg.to_string()).collect(),
+        env: HashMap::new(),
+        env_policy: EnvPolicy::default(),
+        working_dir: std::env::current_dir().unwrap(),
+        update_interval: Duration::from_millis(100),
+        handler: None,
+        output_file,
+        ansi: AnsiMode::Strip,
+        pty: false,
+        redactor: Redactor::default(),
+        dedupe: false,
+        limits: ResourceLimits::default(),
+        stdin: None,
+        idle_timeout: None,
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::output::StreamFilter;
     use std::env;
     use tempfile::tempdir;
 
+    /// An output file's lines, after its exit code and header
+    fn output_body(content: &str) -> &str {
+        content.split_once("\n\n").unwrap().1
+    }
+
     #[tokio::test]
     async fn test_nonexistent_command_fails_properly() {
         let process_manager = Arc::new(ProcessManager::new());
         let temp_dir = tempdir().unwrap();
 
-        let config = StreamingExecutorConfig {
-            cmd: "nonexistent-command-that-should-not-exist".to_string(),
-            args: vec![],
-            env: HashMap::new(),
-            working_dir: env::current_dir().unwrap(),
-            update_interval: Duration::from_millis(100),
-            handler: None,
-            output_file: temp_dir.path().join("output.txt"),
-        };
+        let config = test_config(
+            "nonexistent-command-that-should-not-exist",
+            &[],
+            temp_dir.path().join("output.txt"),
+        );
 
         let process_id = spawn(config, process_manager.clone()).await.unwrap();
         let _resp = process_manager
-            .join_process(&process_id, Duration::from_millis(10))
+            .join_process(&process_id, Duration::from_millis(10), None)
             .await;
 
         // Wait a bit for the process to fail
@@ -191,6 +803,15 @@ mod tests {
             }
             other => panic!("Expected Failed state, got: {:?}", other),
         }
+
+        // Written with the same exit code and header as any other output file
+        let captured =
+            crate::output::parse_output_file(&temp_dir.path().join("output.txt")).unwrap();
+        assert_eq!(captured.exit_code, Some(127));
+        assert_eq!(
+            captured.header.unwrap().command,
+            "nonexistent-command-that-should-not-exist"
+        );
     }
 
     #[tokio::test]
@@ -199,13 +820,8 @@ mod tests {
         let temp_dir = tempdir().unwrap();
 
         let config = StreamingExecutorConfig {
-            cmd: "echo".to_string(),
-            args: vec!["hello".into()],
-            env: HashMap::new(),
             working_dir: PathBuf::from("/nonexistent/directory/that/should/not/exist"),
-            update_interval: Duration::from_millis(100),
-            handler: None,
-            output_file: temp_dir.path().join("output.txt"),
+            ..test_config("echo", &["hello"], temp_dir.path().join("output.txt"))
         };
 
         let process_id = spawn(config, process_manager.clone()).await.unwrap();
@@ -220,10 +836,907 @@ mod tests {
 
         // The process should be in Failed state, not Running
         match status.state {
-            crate::process_manager::ProcessState::Failed { .. } => {
-                // This is what we expect
+            crate::process_manager::ProcessState::Failed { error } => {
+                assert_eq!(
+                    error,
+                    "working directory does not exist: \
+                     /nonexistent/directory/that/should/not/exist"
+                );
             }
             other => panic!("Expected Failed state, got: {:?}", other),
         }
     }
+
+    #[tokio::test]
+    async fn test_output_file_preserves_interleaving() {
+        let process_manager = Arc::new(ProcessManager::new());
+        let temp_dir = tempdir().unwrap();
+        let output_file = temp_dir.path().join("output.txt");
+
+        let config = test_config(
+            "bash",
+            &[
+                "-c",
+                "echo one; sleep 0.1; echo two >&2; sleep 0.1; echo three",
+            ],
+            output_file.clone(),
+        );
+
+        let process_id = spawn(config, process_manager.clone()).await.unwrap();
+        process_manager.wait_for(&process_id).await;
+
+        let status = process_manager
+            .get_process_status(&process_id)
+            .await
+            .unwrap();
+        assert_eq!(status.raw_stdout, "one\nthree\n");
+        assert_eq!(status.raw_stderr, "two\n");
+
+        let content = std::fs::read_to_string(&output_file).unwrap();
+        assert_eq!(
+            content,
+            format!(
+                "Exit Code: 0\n\
+                 Command: bash -c 'echo one; sleep 0.1; echo two >&2; sleep 0.1; echo three'\n\
+                 Working Dir: {}\n\
+                 \n\
+                 [out] one\n[err] two\n[out] three\n",
+                env::current_dir().unwrap().display()
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn test_output_file_header_masks_secret_env() {
+        let process_manager = Arc::new(ProcessManager::new());
+        let temp_dir = tempdir().unwrap();
+        let output_file = temp_dir.path().join("output.txt");
+        let env = HashMap::from([
+            ("API_TOKEN".to_string(), "hunter2-secret".to_string()),
+            ("RUST_LOG".to_string(), "debug".to_string()),
+        ]);
+
+        let config = StreamingExecutorConfig {
+            redactor: Redactor::for_command_env(&["*TOKEN*".to_string()], &env, false),
+            env,
+            working_dir: temp_dir.path().to_path_buf(),
+            ..test_config("true", &[], output_file.clone())
+        };
+        let process_id = spawn(config, process_manager.clone()).await.unwrap();
+        process_manager.wait_for(&process_id).await;
+
+        let captured = crate::output::parse_output_file(&output_file).unwrap();
+        let header = captured.header.unwrap();
+        assert_eq!(header.command, "true");
+        assert_eq!(header.working_dir, temp_dir.path());
+        assert!(header.handlers.is_empty());
+        assert_eq!(header.env["RUST_LOG"], "debug");
+        assert_ne!(header.env["API_TOKEN"], "hunter2-secret");
+        assert!(!std::fs::read_to_string(&output_file)
+            .unwrap()
+            .contains("hunter2-secret"));
+    }
+
+    #[tokio::test]
+    async fn test_secret_split_across_reads_is_masked() {
+        let process_manager = Arc::new(ProcessManager::new());
+        let temp_dir = tempdir().unwrap();
+        let output_file = temp_dir.path().join("output.txt");
+        let env = HashMap::from([("API_TOKEN".to_string(), "hunter2-secret".to_string())]);
+
+        // The first half is read, and would be flushed, well before the second
+        let config = StreamingExecutorConfig {
+            redactor: Redactor::for_command_env(&["*TOKEN*".to_string()], &env, false),
+            ..test_config(
+                "bash",
+                &["-c", "printf 'key=hunter2'; sleep 0.5; printf -- '-secret\\n'"],
+                output_file.clone(),
+            )
+        };
+        let process_id = spawn(config, process_manager.clone()).await.unwrap();
+        process_manager.wait_for(&process_id).await;
+
+        let status = process_manager
+            .get_process_status(&process_id)
+            .await
+            .unwrap();
+        assert_eq!(status.raw_stdout, "key=***\n");
+        let content = std::fs::read_to_string(&output_file).unwrap();
+        assert_eq!(output_body(&content), "[out] key=***\n");
+    }
+
+    #[tokio::test]
+    async fn test_ansi_codes_are_stripped() {
+        for (ansi, expected_file) in [
+            (AnsiMode::Strip, "[out] error: bad\n"),
+            (AnsiMode::Keep, "[out] \x1b[1;31merror\x1b[0m: bad\n"),
+        ] {
+            let process_manager = Arc::new(ProcessManager::new());
+            let temp_dir = tempdir().unwrap();
+            let output_file = temp_dir.path().join("output.txt");
+
+            let config = StreamingExecutorConfig {
+                ansi,
+                ..test_config(
+                    "printf",
+                    &["\\033[1;31merror\\033[0m: bad\\n"],
+                    output_file.clone(),
+                )
+            };
+
+            let process_id = spawn(config, process_manager.clone()).await.unwrap();
+            let update = process_manager
+                .join_process(&process_id, Duration::from_secs(5), None)
+                .await
+                .unwrap();
+            assert_eq!(update.incremental_summary, "error: bad\n", "{ansi:?}");
+
+            let content = std::fs::read_to_string(&output_file).unwrap();
+            assert_eq!(output_body(&content), expected_file, "{ansi:?}");
+        }
+    }
+
+    #[test]
+    fn test_strip_ansi_codes() {
+        use crate::output::strip_ansi_codes;
+
+        assert_eq!(strip_ansi_codes("\x1b[31mred\x1b[0m"), "red");
+        assert_eq!(strip_ansi_codes("\x1b[2K\x1b[1Gdone"), "done");
+        assert_eq!(
+            strip_ansi_codes("\x1b]8;;https://example.com\x07link\x1b]8;;\x1b\\"),
+            "link"
+        );
+        assert_eq!(strip_ansi_codes("plain [text]"), "plain [text]");
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_pty_mode() {
+        for (pty, expected) in [(true, "tty\nerr\n"), (false, "pipe\n")] {
+            let process_manager = Arc::new(ProcessManager::new());
+            let temp_dir = tempdir().unwrap();
+
+            let config = StreamingExecutorConfig {
+                pty,
+                ..test_config(
+                    "bash",
+                    &["-c", "[ -t 1 ] && echo tty || echo pipe; echo err >&2"],
+                    temp_dir.path().join("output.txt"),
+                )
+            };
+
+            let process_id = spawn(config, process_manager.clone()).await.unwrap();
+            process_manager.wait_for(&process_id).await;
+            let status = process_manager
+                .get_process_status(&process_id)
+                .await
+                .unwrap();
+            assert_eq!(
+                status.state,
+                crate::process_manager::ProcessState::Completed { exit_code: 0 },
+                "pty: {pty}"
+            );
+            assert_eq!(status.raw_stdout, expected, "pty: {pty}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_stdin_is_piped_and_closed() {
+        // Bigger than a pipe buffer, so writing it must not wait for cat to finish
+        let large = "line\n".repeat(50_000);
+        for input in ["hello\nworld\n".to_string(), large] {
+            let process_manager = Arc::new(ProcessManager::new());
+            let temp_dir = tempdir().unwrap();
+
+            let config = StreamingExecutorConfig {
+                stdin: Some(input.clone()),
+                ..test_config("cat", &[], temp_dir.path().join("output.txt"))
+            };
+
+            let process_id = spawn(config, process_manager.clone()).await.unwrap();
+            tokio::time::timeout(Duration::from_secs(10), process_manager.wait_for(&process_id))
+                .await
+                .expect("cat should exit once its stdin is closed");
+            let status = process_manager
+                .get_process_status(&process_id)
+                .await
+                .unwrap();
+            assert_eq!(
+                status.state,
+                crate::process_manager::ProcessState::Completed { exit_code: 0 }
+            );
+            assert_eq!(status.raw_stdout.len(), input.len());
+            assert!(process_manager.send_input(&process_id, "more", true).await.is_err());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_non_utf8_output_is_replaced_or_summarized() {
+        let cases = [
+            ("printf 'caf\\xe9\\n'", "caf\u{FFFD}\n", "non_utf8"),
+            ("printf 'PK\\x03\\x04\\0\\0binary'", "binary output, 12 bytes\n", "binary_output"),
+        ];
+        for (script, summary, reason) in cases {
+            let process_manager = Arc::new(ProcessManager::new());
+            let temp_dir = tempdir().unwrap();
+
+            let config = test_config("bash", &["-c", script], temp_dir.path().join("output.txt"));
+
+            let process_id = spawn(config, process_manager.clone()).await.unwrap();
+            let update = process_manager
+                .join_process(&process_id, Duration::from_secs(10), None)
+                .await
+                .unwrap();
+            assert_eq!(
+                update.status,
+                crate::process_manager::ProcessState::Completed { exit_code: 0 }
+            );
+            assert_eq!(update.incremental_summary, summary);
+            let truncation = update.truncation.unwrap();
+            assert_eq!(truncation.reason.as_deref(), Some(reason));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_partial_lines_are_streamed() {
+        let process_manager = Arc::new(ProcessManager::new());
+        let temp_dir = tempdir().unwrap();
+        let output_file = temp_dir.path().join("output.txt");
+
+        let config = StreamingExecutorConfig {
+            args: vec![
+                "-c".into(),
+                "printf 'Downloading 10%%'; sleep 0.5; printf '\\rDownloading 50%%'; sleep 0.5; \
+                 printf '\\rDownloading 100%%\\n'; echo done"
+                    .into(),
+            ],
+            ..test_config("bash", &[], output_file.clone())
+        };
+
+        let process_id = spawn(config, process_manager.clone()).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        let status = process_manager
+            .get_process_status(&process_id)
+            .await
+            .unwrap();
+        assert_eq!(status.raw_stdout, "Downloading 10%");
+
+        process_manager.wait_for(&process_id).await;
+        let content = std::fs::read_to_string(&output_file).unwrap();
+        assert_eq!(output_body(&content), "[out] Downloading 100%\n[out] done\n");
+    }
+
+    #[tokio::test]
+    async fn test_summaries_are_batched_per_interval() {
+        let process_manager = Arc::new(ProcessManager::new());
+        let temp_dir = tempdir().unwrap();
+
+        let config = StreamingExecutorConfig {
+            update_interval: Duration::from_secs(60),
+            ..test_config(
+                "bash",
+                &["-c", "sleep 0.1; seq 1 1000; sleep 0.5"],
+                temp_dir.path().join("output.txt"),
+            )
+        };
+
+        let process_id = spawn(config, process_manager.clone()).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(300)).await;
+
+        // Output is recorded as it arrives, but nothing is summarized until the next tick
+        let status = process_manager
+            .get_process_status(&process_id)
+            .await
+            .unwrap();
+        assert!(status.raw_stdout.ends_with("1000\n"));
+        let update = process_manager
+            .join_process(&process_id, Duration::ZERO, None)
+            .await
+            .unwrap();
+        assert_eq!(update.incremental_summary, "");
+
+        // The remaining output is summarized in one go when the process exits
+        let update = process_manager
+            .join_process(&process_id, Duration::from_secs(5), None)
+            .await
+            .unwrap();
+        assert!(update.incremental_summary.ends_with("999\n1000\n"));
+    }
+
+    #[tokio::test]
+    async fn test_join_with_cursor_is_repeatable() {
+        let process_manager = Arc::new(ProcessManager::new());
+        let temp_dir = tempdir().unwrap();
+
+        let config = StreamingExecutorConfig {
+            update_interval: Duration::from_millis(50),
+            ..test_config(
+                "bash",
+                &["-c", "echo one; sleep 0.5; echo two"],
+                temp_dir.path().join("output.txt"),
+            )
+        };
+
+        let process_id = spawn(config, process_manager.clone()).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(250)).await;
+
+        let first = process_manager
+            .join_process(&process_id, Duration::ZERO, None)
+            .await
+            .unwrap();
+        assert_eq!(first.incremental_summary, "one\n");
+        assert!(first.timed_out);
+
+        // A retry from the start gets the same output again
+        let retry = process_manager
+            .join_process(&process_id, Duration::ZERO, Some(0))
+            .await
+            .unwrap();
+        assert_eq!(retry.incremental_summary, "one\n");
+        assert_eq!(retry.cursor, first.cursor);
+
+        let second = process_manager
+            .join_process(&process_id, Duration::from_secs(5), Some(first.cursor))
+            .await
+            .unwrap();
+        assert_eq!(second.incremental_summary, "two\n");
+        assert!(!second.timed_out);
+
+        // Joins without a cursor only see what no cursorless join has returned yet
+        let shared = process_manager
+            .join_process(&process_id, Duration::ZERO, None)
+            .await
+            .unwrap();
+        assert_eq!(shared.incremental_summary, "two\n");
+        let shared = process_manager
+            .join_process(&process_id, Duration::ZERO, None)
+            .await
+            .unwrap();
+        assert_eq!(shared.incremental_summary, "");
+        assert!(!shared.timed_out);
+    }
+
+    #[tokio::test]
+    async fn test_join_any_returns_first_to_finish() {
+        let process_manager = Arc::new(ProcessManager::new());
+        let temp_dir = tempdir().unwrap();
+
+        let mut ids = Vec::new();
+        for (name, script) in [("slow", "sleep 5"), ("quick", "sleep 0.2; echo quick")] {
+            let config = StreamingExecutorConfig {
+                update_interval: Duration::from_millis(50),
+                ..test_config(
+                    "bash",
+                    &["-c", script],
+                    temp_dir.path().join(format!("{name}.txt")),
+                )
+            };
+            ids.push(spawn(config, process_manager.clone()).await.unwrap());
+        }
+
+        let (finished, update) = process_manager
+            .join_any(&ids, Duration::from_secs(5))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(finished, ids[1]);
+        assert_eq!(update.incremental_summary, "quick\n");
+
+        process_manager.cancel_process(&ids[0]).await;
+    }
+
+    #[tokio::test]
+    async fn test_env_policy() {
+        let temp_dir = tempdir().unwrap();
+        let run = |env_policy| {
+            let process_manager = Arc::new(ProcessManager::new());
+            let config = StreamingExecutorConfig {
+                env: HashMap::from([("ONLY".to_string(), "1".to_string())]),
+                env_policy,
+                update_interval: Duration::from_millis(50),
+                ..test_config("env", &[], temp_dir.path().join("output.txt"))
+            };
+            async move {
+                let process_id = spawn(config, process_manager.clone()).await.unwrap();
+                process_manager.wait_for(&process_id).await;
+                let status = process_manager.get_process_status(&process_id).await;
+                status.unwrap().raw_stdout
+            }
+        };
+
+        let clean = run(EnvPolicy {
+            inherit: false,
+            remove: vec![],
+        })
+        .await;
+        assert_eq!(clean, "ONLY=1\n");
+
+        let inherited = run(EnvPolicy::default()).await;
+        assert!(inherited.contains("PATH="));
+        assert!(inherited.contains("ONLY=1\n"));
+
+        let dropped = run(EnvPolicy {
+            inherit: true,
+            remove: vec!["PATH".to_string()],
+        })
+        .await;
+        assert!(!dropped.lines().any(|line| line.starts_with("PATH=")));
+    }
+
+    #[test]
+    fn test_pending_output_collapses_redraws() {
+        let mut pending = PendingOutput::default();
+        pending.push(b"10%\r20%\r30%\ndone\n");
+        assert_eq!(pending.take_lines(), "\r30%\ndone\n");
+
+        pending.push(b"a\rb\rc");
+        assert_eq!(pending.bytes, b"\rc");
+        pending.push(b"\r");
+        pending.push(b"\n");
+        assert_eq!(pending.take_lines(), "\rc\r\n");
+
+        // The first two bytes of a three-byte character are held back
+        pending.push("ok \u{20ac}".as_bytes().split_last().unwrap().1);
+        assert_eq!(pending.take_all(), "ok ");
+        pending.push(&[0xac]);
+        assert_eq!(pending.take_all(), "\u{20ac}");
+    }
+
+    #[tokio::test]
+    async fn test_output_buffers_are_bounded() {
+        let process_manager = Arc::new(ProcessManager::with_buffer_limit(1000));
+        let temp_dir = tempdir().unwrap();
+        let output_file = temp_dir.path().join("output.txt");
+
+        let config = test_config("seq", &["1", "10000"], output_file.clone());
+
+        let process_id = spawn(config, process_manager.clone()).await.unwrap();
+        let update = process_manager
+            .join_process(&process_id, Duration::from_secs(5), None)
+            .await
+            .unwrap();
+        assert!(update.output_truncated);
+
+        let status = process_manager
+            .get_process_status(&process_id)
+            .await
+            .unwrap();
+        assert!(status.raw_stdout.len() <= 1000, "{}", status.raw_stdout.len());
+        assert!(status.raw_stdout.starts_with("1\n2\n"));
+        assert!(status
+            .raw_stdout
+            .contains(crate::process_manager::OMITTED_MARKER));
+        assert!(status.raw_stdout.ends_with("\n9999\n10000\n"));
+        assert!(status.omitted_bytes > 40_000);
+
+        // Nothing is lost from the file
+        let content = std::fs::read_to_string(&output_file).unwrap();
+        let expected: String = (1..=10000).map(|i| format!("[out] {i}\n")).collect();
+        assert!(content.starts_with("Exit Code: 0\n"));
+        assert_eq!(output_body(&content), expected);
+
+        // Tails that reach past the in-memory window come from the file
+        let tail = process_manager
+            .tail_output(&process_id, 500, StreamFilter::Both)
+            .await
+            .unwrap();
+        assert!(tail.starts_with("[out] 9501\n"));
+        assert!(tail.ends_with("[out] 10000\n"));
+    }
+
+    #[tokio::test]
+    async fn test_tail_and_search_one_stream() {
+        let process_manager = Arc::new(ProcessManager::with_buffer_limit(1000));
+        let temp_dir = tempdir().unwrap();
+        let output_file = temp_dir.path().join("output.txt");
+
+        let config = test_config(
+            "bash",
+            &[
+                "-c",
+                "for i in $(seq 3000); do echo out$i; echo err$i >&2; done",
+            ],
+            output_file.clone(),
+        );
+        let process_id = spawn(config, process_manager.clone()).await.unwrap();
+        process_manager.wait_for(&process_id).await;
+
+        let tail = |lines, stream| process_manager.tail_output(&process_id, lines, stream);
+        // Short tails come from memory, long ones from the file
+        assert_eq!(tail(2, StreamFilter::Stderr).await.unwrap(), "err2999\nerr3000\n");
+        assert_eq!(tail(1, StreamFilter::Stdout).await.unwrap(), "out3000\n");
+        let stderr = tail(2000, StreamFilter::Stderr).await.unwrap();
+        assert_eq!(stderr.lines().count(), 2000);
+        assert!(stderr.starts_with("[err] err1001\n"), "{}", &stderr[..50]);
+        assert!(stderr.lines().all(|line| line.starts_with("[err] ")));
+
+        // Context comes from the same stream
+        let result =
+            crate::output::search_output(&output_file, "1$", 1, 10, StreamFilter::Stdout)
+                .unwrap();
+        assert!(result.lines.iter().all(|line| line.text.starts_with("[out] ")));
+        assert_eq!(result.lines[0].text, "[out] out1");
+        assert_eq!(result.lines[1].text, "[out] out2");
+    }
+
+    #[tokio::test]
+    async fn test_output_file_is_written_while_running() {
+        let process_manager = Arc::new(ProcessManager::new());
+        let temp_dir = tempdir().unwrap();
+        let output_file = temp_dir.path().join("output.txt");
+
+        let config = test_config(
+            "bash",
+            &["-c", "echo started; sleep 0.1; echo oops >&2; sleep 30"],
+            output_file.clone(),
+        );
+
+        let process_id = spawn(config, process_manager.clone()).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(500)).await;
+
+        let content = std::fs::read_to_string(&output_file).unwrap();
+        assert_eq!(content, "[out] started\n[err] oops\n");
+
+        process_manager.cancel_process(&process_id).await;
+        let content = std::fs::read_to_string(&output_file).unwrap();
+        assert!(content.starts_with("Exit Code: "), "{content}");
+        assert!(content.ends_with("\n\n[out] started\n[err] oops\n"), "{content}");
+    }
+
+    #[tokio::test]
+    async fn test_process_timings_are_recorded() {
+        let process_manager = Arc::new(ProcessManager::new());
+        let temp_dir = tempdir().unwrap();
+        let config = test_config(
+            "bash",
+            &["-c", "sleep 0.3; echo hi; sleep 0.2"],
+            temp_dir.path().join("output.txt"),
+        );
+
+        let process_id = spawn(config, process_manager.clone()).await.unwrap();
+        let status = process_manager
+            .get_process_status_summary(&process_id)
+            .await
+            .unwrap();
+        assert_eq!(status.finished_at, None);
+        assert_eq!(status.time_to_first_output_ms, None);
+
+        process_manager.wait_for(&process_id).await;
+        let status = process_manager
+            .get_process_status_summary(&process_id)
+            .await
+            .unwrap();
+        assert!(status.finished_at.is_some());
+        let first_output = status.time_to_first_output_ms.unwrap();
+        assert!(first_output >= 300, "{first_output}");
+        assert!(status.duration_ms >= first_output + 200, "{}", status.duration_ms);
+        let update = process_manager
+            .join_process(&process_id, Duration::ZERO, None)
+            .await
+            .unwrap();
+        assert_eq!(update.duration_ms, status.duration_ms);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_output_file_is_private() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let process_manager = Arc::new(ProcessManager::new());
+        let temp_dir = tempdir().unwrap();
+        let output_file = temp_dir.path().join("output.txt");
+        let mode = || std::fs::metadata(&output_file).unwrap().permissions().mode() & 0o777;
+
+        let config = test_config(
+            "bash",
+            &["-c", "echo started; sleep 30"],
+            output_file.clone(),
+        );
+
+        let process_id = spawn(config, process_manager.clone()).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        assert_eq!(std::fs::read_to_string(&output_file).unwrap(), "[out] started\n");
+        assert_eq!(mode(), 0o600);
+
+        process_manager.cancel_process(&process_id).await;
+        assert!(std::fs::read_to_string(&output_file).unwrap().starts_with("Exit Code: "));
+        assert_eq!(mode(), 0o600);
+    }
+
+    #[tokio::test]
+    async fn test_processes_beyond_limit_are_queued() {
+        use crate::process_manager::ProcessState;
+
+        let process_manager = Arc::new(ProcessManager::new().with_max_concurrent(1));
+        let temp_dir = tempdir().unwrap();
+        let config = |cmd: &str, args: &[&str], name: &str| {
+            test_config(cmd, args, temp_dir.path().join(name))
+        };
+
+        let sleeper = spawn(config("sleep", &["1"], "sleep.txt"), process_manager.clone())
+            .await
+            .unwrap();
+        let queued = spawn(config("echo", &["hi"], "echo.txt"), process_manager.clone())
+            .await
+            .unwrap();
+        let cancelled = spawn(config("echo", &["no"], "no.txt"), process_manager.clone())
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(200)).await;
+
+        assert_eq!(process_manager.running_count(), 1);
+        for id in [&queued, &cancelled] {
+            let status = process_manager.get_process_status(id).await.unwrap();
+            assert_eq!(status.state, ProcessState::Queued);
+        }
+
+        // A queued process can be cancelled without ever starting
+        assert!(process_manager.cancel_process(&cancelled).await);
+        let status = process_manager.get_process_status(&cancelled).await.unwrap();
+        assert_eq!(status.state, ProcessState::Cancelled);
+        let captured = crate::output::parse_output_file(&temp_dir.path().join("no.txt")).unwrap();
+        assert_eq!(captured.exit_code, Some(130));
+        assert_eq!(captured.header.unwrap().command, "echo no");
+
+        process_manager.wait_for(&sleeper).await;
+        process_manager.wait_for(&queued).await;
+        let status = process_manager.get_process_status(&queued).await.unwrap();
+        assert_eq!(status.state, ProcessState::Completed { exit_code: 0 });
+        assert_eq!(status.raw_stdout, "hi\n");
+        assert_eq!(process_manager.running_count(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_cleanup_task_stops_with_manager() {
+        let process_manager = ProcessManager::new();
+        let processes = Arc::downgrade(&process_manager.processes);
+        drop(process_manager);
+
+        // The cleanup task holds the only other reference to the process table, so the
+        // table goes away once the task has exited
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while processes.upgrade().is_some() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("the cleanup task should exit when its manager is dropped");
+    }
+
+    #[tokio::test]
+    async fn test_registry_survives_restart() {
+        let temp_dir = tempdir().unwrap();
+        let registry_dir = temp_dir.path().join("processes");
+        let process_manager =
+            Arc::new(ProcessManager::with_registry(registry_dir.clone()).unwrap());
+
+        let config = test_config("echo", &["hello"], temp_dir.path().join("output.txt"));
+        let process_id = spawn(config, process_manager.clone()).await.unwrap();
+        process_manager.wait_for(&process_id).await;
+        drop(process_manager);
+        // Records hold commands and summaries, so they are as private as output files
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let record = registry_dir.join(format!("{}.json", process_id.0));
+            let mode = std::fs::metadata(record).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+
+        let restarted = ProcessManager::with_registry(registry_dir).unwrap();
+        let update = restarted
+            .join_process(&process_id, Duration::ZERO, None)
+            .await
+            .unwrap();
+        assert_eq!(
+            update.status,
+            crate::process_manager::ProcessState::Completed { exit_code: 0 }
+        );
+        assert_eq!(update.incremental_summary, "hello\n");
+        let tail = restarted
+            .tail_output(&process_id, 10, StreamFilter::Both)
+            .await
+            .unwrap();
+        assert!(tail.contains("hello"), "{tail}");
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_cancel_kills_process_group() {
+        let process_manager = Arc::new(ProcessManager::new());
+        let temp_dir = tempdir().unwrap();
+
+        let config = test_config(
+            "bash",
+            &["-c", "sleep 30 & echo $!; sleep 30"],
+            temp_dir.path().join("output.txt"),
+        );
+
+        let process_id = spawn(config, process_manager.clone()).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        let background_pid = process_manager
+            .get_process_status(&process_id)
+            .await
+            .unwrap()
+            .raw_stdout
+            .trim()
+            .to_string();
+
+        let started = std::time::Instant::now();
+        assert!(process_manager.cancel_process(&process_id).await);
+        assert!(started.elapsed() < Duration::from_secs(5));
+
+        let status = process_manager
+            .get_process_status(&process_id)
+            .await
+            .unwrap();
+        assert_eq!(status.state, crate::process_manager::ProcessState::Cancelled);
+
+        // The backgrounded sleep is in the same process group and should be gone
+        // too (or a zombie waiting on init to reap it)
+        let ps = std::process::Command::new("ps")
+            .args(["-o", "stat=", "-p", &background_pid])
+            .output()
+            .unwrap();
+        let stat = String::from_utf8_lossy(&ps.stdout);
+        assert!(
+            stat.trim().is_empty() || stat.trim().starts_with('Z'),
+            "background process {background_pid} is still running: {stat}"
+        );
+
+        // Cancelling a finished process is a no-op
+        assert!(!process_manager.cancel_process(&process_id).await);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_cancel_matching_requires_confirm_for_several() {
+        let process_manager = Arc::new(ProcessManager::new());
+        let temp_dir = tempdir().unwrap();
+
+        let mut ids = Vec::new();
+        for seconds in ["30", "31"] {
+            let config = test_config(
+                "sleep",
+                &[seconds],
+                temp_dir.path().join(format!("{seconds}.txt")),
+            );
+            ids.push(spawn(config, process_manager.clone()).await.unwrap());
+        }
+
+        let err = process_manager
+            .cancel_matching("sleep", false, false)
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("2 running processes match"), "{err}");
+        assert_eq!(process_manager.list_processes(true).await.len(), 2);
+
+        let cancelled = process_manager
+            .cancel_matching("1$", true, false)
+            .await
+            .unwrap();
+        assert_eq!(cancelled, vec![ids[1].clone()]);
+
+        // Only one is left, so no confirmation is needed
+        let cancelled = process_manager
+            .cancel_matching("sleep 3", false, false)
+            .await
+            .unwrap();
+        assert_eq!(cancelled, vec![ids[0].clone()]);
+        assert!(process_manager
+            .cancel_matching("sleep", false, false)
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_idle_timeout_fails_silent_process() {
+        let process_manager = Arc::new(ProcessManager::new());
+        let temp_dir = tempdir().unwrap();
+
+        let config = |script: &str, name: &str| StreamingExecutorConfig {
+            idle_timeout: Some(Duration::from_millis(500)),
+            ..test_config("bash", &["-c", script], temp_dir.path().join(name))
+        };
+
+        let stuck = config("echo started; sleep 30", "stuck.txt");
+        let process_id = spawn(stuck, process_manager.clone()).await.unwrap();
+        let waited = tokio::time::timeout(
+            Duration::from_secs(10),
+            process_manager.wait_for(&process_id),
+        )
+        .await;
+        assert!(waited.is_ok(), "the idle process was never killed");
+        let status = process_manager.get_process_status(&process_id).await.unwrap();
+        assert_eq!(
+            status.state,
+            crate::process_manager::ProcessState::Failed {
+                error: "idle timeout".to_string()
+            }
+        );
+        let output = std::fs::read_to_string(temp_dir.path().join("stuck.txt")).unwrap();
+        assert!(output.contains("[out] started"), "{output}");
+
+        // Output that keeps coming resets the clock, even past the timeout in total
+        let busy = config("for i in 1 2 3 4 5 6; do echo $i; sleep 0.2; done", "busy.txt");
+        let process_id = spawn(busy, process_manager.clone()).await.unwrap();
+        process_manager.wait_for(&process_id).await;
+        let status = process_manager.get_process_status(&process_id).await.unwrap();
+        assert_eq!(
+            status.state,
+            crate::process_manager::ProcessState::Completed { exit_code: 0 }
+        );
+
+        // So does binary output, which is never recorded as lines
+        let binary = config(
+            "for i in 1 2 3 4 5 6; do printf 'x\\0y'; sleep 0.2; done",
+            "binary.txt",
+        );
+        let process_id = spawn(binary, process_manager.clone()).await.unwrap();
+        process_manager.wait_for(&process_id).await;
+        let status = process_manager.get_process_status(&process_id).await.unwrap();
+        assert_eq!(
+            status.state,
+            crate::process_manager::ProcessState::Completed { exit_code: 0 }
+        );
+    }
+
+    #[cfg(windows)]
+    fn cmd_config(script: &str, output_file: PathBuf) -> StreamingExecutorConfig {
+        test_config("cmd", &["/C", script], output_file)
+    }
+
+    #[cfg(windows)]
+    #[tokio::test]
+    async fn test_windows_echo() {
+        let process_manager = Arc::new(ProcessManager::new());
+        let temp_dir = tempdir().unwrap();
+
+        let config = cmd_config("echo hello", temp_dir.path().join("output.txt"));
+        let process_id = spawn(config, process_manager.clone()).await.unwrap();
+        process_manager.wait_for(&process_id).await;
+
+        let status = process_manager
+            .get_process_status(&process_id)
+            .await
+            .unwrap();
+        assert_eq!(
+            status.state,
+            crate::process_manager::ProcessState::Completed { exit_code: 0 }
+        );
+        assert_eq!(status.raw_stdout.trim_end(), "hello");
+    }
+
+    #[cfg(windows)]
+    #[tokio::test]
+    async fn test_windows_cancel_kills_process_tree() {
+        let process_manager = Arc::new(ProcessManager::new());
+        let temp_dir = tempdir().unwrap();
+
+        let config = cmd_config("ping -n 30 127.0.0.1", temp_dir.path().join("output.txt"));
+        let process_id = spawn(config, process_manager.clone()).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(300)).await;
+
+        let started = std::time::Instant::now();
+        assert!(process_manager.cancel_process(&process_id).await);
+        assert!(started.elapsed() < Duration::from_secs(5));
+        let status = process_manager
+            .get_process_status(&process_id)
+            .await
+            .unwrap();
+        assert_eq!(status.state, crate::process_manager::ProcessState::Cancelled);
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn test_windows_output_dir_is_under_temp() {
+        if env::var_os(crate::output::OUTPUT_DIR_ENV).is_some() {
+            return;
+        }
+        let temp = PathBuf::from(env::var_os("TEMP").unwrap());
+        let dir = crate::output::output_dir(None).unwrap();
+        assert!(dir.starts_with(&temp), "{} is not under {}", dir.display(), temp.display());
+    }
 }
@@ -1,8 +1,10 @@
-use anyhow::Result;
+use anyhow::{Context, Result};
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::path::{Path, PathBuf};
 
+use crate::process_manager::{OutputLine, OutputStream};
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct TestCase {
     #[serde(default)]
@@ -12,10 +14,35 @@ pub struct TestCase {
     #[serde(default, skip_serializing_if = "Option::is_none")]
     pub command: Option<String>, // For backward compatibility
     pub settings: HashMap<String, serde_json::Value>,
+    #[serde(default)]
     pub stdout: String,
+    #[serde(default)]
     pub stderr: String,
     pub exit_code: i32,
+    /// Output as it arrives, in place of `stdout`/`stderr`. Each chunk is fed to
+    /// `summarize()` in turn, the way the streaming executor drives handlers.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub chunks: Vec<OutputChunk>,
     pub expected_summary: String,
+    /// Also compare the output file the run would write against `<name>.output` next
+    /// to the test
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub check_output_file: bool,
+    /// Directory the test was loaded from
+    #[serde(skip)]
+    pub dir: PathBuf,
+}
+
+/// Output passed to one `summarize()` call
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct OutputChunk {
+    #[serde(default)]
+    pub stdout: String,
+    #[serde(default)]
+    pub stderr: String,
+    /// Marks the run as complete; no further chunks are fed and `finalize()` isn't called
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub exit_code: Option<i32>,
 }
 
 impl TestCase {
@@ -63,36 +90,121 @@ fn load_tests_from_dir(test_dir: &Path, tests: &mut Vec<(String, TestCase)>) ->
             let name = path.file_stem().unwrap().to_string_lossy().to_string();
             let mut test: TestCase = toml::from_str(&std::fs::read_to_string(&path)?)?;
             test.normalize(); // Handle backward compatibility
+            test.dir = test_dir.to_path_buf();
             tests.push((name, test));
         }
     }
     Ok(())
 }
 
-/// Run a single test case
-pub async fn run_test(handler_path: &Path, name: &str, test: &TestCase) -> Result<TestResult> {
-    let mut rt = crate::runtime::HandlerRuntime::new()?;
-    rt.load_handler(handler_path.to_str().unwrap()).await?;
+/// Run the handler over the test's output and return the summary it produces
+async fn summarize_test(handler_path: &Path, test: &TestCase) -> Result<String> {
+    let has_output = !test.stdout.is_empty() || !test.stderr.is_empty();
+    if !test.chunks.is_empty() && has_output {
+        anyhow::bail!("A test sets either `stdout`/`stderr` or `chunks`, not both");
+    }
+    let mut rt = crate::runtime::Handler::load(handler_path).await?;
 
-    rt.create_handler(&test.cmd, &test.args, &test.settings).await?;
+    rt.create_handler(&test.cmd, &test.args, &test.settings, &Default::default())
+        .await?;
     rt.prepare().await?;
 
-    let result = rt
-        .summarize(&test.stdout, &test.stderr, Some(test.exit_code))
-        .await?;
+    if test.chunks.is_empty() {
+        let result = rt
+            .finalize(test.exit_code, &test.stdout, &test.stderr)
+            .await?;
+        return Ok(result.summary.unwrap_or_default());
+    }
+
+    let mut summary = String::new();
+    let mut stdout = String::new();
+    let mut stderr = String::new();
+    for chunk in &test.chunks {
+        let result = rt
+            .summarize(&chunk.stdout, &chunk.stderr, chunk.exit_code)
+            .await?;
+        summary.push_str(&result.summary.unwrap_or_default());
+        if chunk.exit_code.is_some() {
+            return Ok(summary);
+        }
+        stdout.push_str(&chunk.stdout);
+        stderr.push_str(&chunk.stderr);
+    }
+    // As in the executor, a final summary replaces what the chunks emitted
+    let result = rt.finalize(test.exit_code, &stdout, &stderr).await?;
+    Ok(result.summary.unwrap_or(summary))
+}
+
+/// Where the golden output file of test `name` in `dir` lives
+pub fn golden_output_path(dir: &Path, name: &str) -> PathBuf {
+    dir.join(format!("{name}.output"))
+}
 
-    let actual_summary = result.summary.unwrap_or_default();
+/// The output file `output::write_output` produces for the test's output. Chunks are
+/// written in order, each chunk's stdout before its stderr.
+pub fn render_output_file(test: &TestCase) -> Result<String> {
+    let chunks = if test.chunks.is_empty() {
+        vec![OutputChunk {
+            stdout: test.stdout.clone(),
+            stderr: test.stderr.clone(),
+            exit_code: None,
+        }]
+    } else {
+        test.chunks.clone()
+    };
+    let mut lines = Vec::new();
+    for chunk in &chunks {
+        for (stream, text) in [
+            (OutputStream::Stdout, &chunk.stdout),
+            (OutputStream::Stderr, &chunk.stderr),
+        ] {
+            for line in text.split_inclusive('\n') {
+                lines.push(OutputLine {
+                    seq: lines.len() as u64,
+                    stream,
+                    text: line.to_string(),
+                });
+            }
+        }
+    }
+
+    let path = std::env::temp_dir().join(format!("shelly-test-{}.txt", uuid::Uuid::new_v4()));
+    crate::output::write_output(&path, &lines, test.exit_code, None)?;
+    let rendered = std::fs::read_to_string(&path);
+    let _ = std::fs::remove_file(&path);
+    Ok(rendered?)
+}
+
+/// Run a single test case
+pub async fn run_test(handler_path: &Path, name: &str, test: &TestCase) -> Result<TestResult> {
+    let actual_summary = summarize_test(handler_path, test).await?;
 
     // Trim leading/trailing whitespace for comparison
     let expected_trimmed = test.expected_summary.trim();
     let actual_trimmed = actual_summary.trim();
-    let passed = actual_trimmed == expected_trimmed;
+    let mut passed = actual_trimmed == expected_trimmed;
+    let diff = diff_lines(expected_trimmed, actual_trimmed);
+
+    let mut output_file_diff = Vec::new();
+    if test.check_output_file {
+        let golden_path = golden_output_path(&test.dir, name);
+        let golden = std::fs::read_to_string(&golden_path).with_context(|| {
+            format!("Reading {}; run with --update to create it", golden_path.display())
+        })?;
+        let rendered = render_output_file(test)?;
+        if rendered != golden {
+            passed = false;
+            output_file_diff = diff_lines(&golden, &rendered);
+        }
+    }
 
     Ok(TestResult {
         name: name.to_string(),
         passed,
         expected: test.expected_summary.clone(),
         actual: actual_summary,
+        diff,
+        output_file_diff,
     })
 }
 
@@ -102,6 +214,55 @@ pub struct TestResult {
     pub passed: bool,
     pub expected: String,
     pub actual: String,
+    /// Line diff from the expected to the actual summary, ignoring surrounding whitespace
+    pub diff: Vec<DiffLine>,
+    /// Line diff from the golden to the actual output file; empty if they match
+    pub output_file_diff: Vec<DiffLine>,
+}
+
+/// A line of a diff between two summaries
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DiffLine {
+    Same(String),
+    /// Only in the expected summary
+    Removed(String),
+    /// Only in the actual summary
+    Added(String),
+}
+
+/// Diff `expected` against `actual` line by line, using their longest common subsequence
+pub fn diff_lines(expected: &str, actual: &str) -> Vec<DiffLine> {
+    let expected: Vec<&str> = expected.lines().collect();
+    let actual: Vec<&str> = actual.lines().collect();
+
+    // lcs[i][j] is the length of the LCS of expected[i..] and actual[j..]
+    let mut lcs = vec![vec![0usize; actual.len() + 1]; expected.len() + 1];
+    for i in (0..expected.len()).rev() {
+        for j in (0..actual.len()).rev() {
+            lcs[i][j] = if expected[i] == actual[j] {
+                lcs[i + 1][j + 1] + 1
+            } else {
+                lcs[i + 1][j].max(lcs[i][j + 1])
+            };
+        }
+    }
+
+    let mut diff = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    while i < expected.len() || j < actual.len() {
+        if i < expected.len() && j < actual.len() && expected[i] == actual[j] {
+            diff.push(DiffLine::Same(expected[i].to_string()));
+            i += 1;
+            j += 1;
+        } else if j == actual.len() || (i < expected.len() && lcs[i + 1][j] >= lcs[i][j + 1]) {
+            diff.push(DiffLine::Removed(expected[i].to_string()));
+            i += 1;
+        } else {
+            diff.push(DiffLine::Added(actual[j].to_string()));
+            j += 1;
+        }
+    }
+    diff
 }
 
 /// Update test snapshot
@@ -111,17 +272,7 @@ pub async fn update_snapshot(
     name: &str,
     test: &mut TestCase,
 ) -> Result<()> {
-    let mut rt = crate::runtime::HandlerRuntime::new()?;
-    rt.load_handler(handler_path.to_str().unwrap()).await?;
-
-    rt.create_handler(&test.cmd, &test.args, &test.settings).await?;
-    rt.prepare().await?;
-
-    let result = rt
-        .summarize(&test.stdout, &test.stderr, Some(test.exit_code))
-        .await?;
-
-    test.expected_summary = result.summary.unwrap_or_default();
+    test.expected_summary = summarize_test(handler_path, test).await?;
 
     let test_path = PathBuf::from(".shelly/tests")
         .join(handler_name)
@@ -132,7 +283,11 @@ pub async fn update_snapshot(
         std::fs::create_dir_all(parent)?;
     }
 
-    std::fs::write(test_path, toml::to_string_pretty(&test)?)?;
+    std::fs::write(&test_path, toml::to_string_pretty(&test)?)?;
+    if test.check_output_file {
+        let golden_path = golden_output_path(test_path.parent().unwrap(), name);
+        std::fs::write(golden_path, render_output_file(test)?)?;
+    }
 
     Ok(())
 }
@@ -1,5 +1,4 @@
 use shelly::{execute_command, ExecuteRequest};
-use std::collections::HashMap;
 
 #[tokio::test]
 async fn test_bash_command() {
@@ -12,8 +11,7 @@ async fn test_bash_command() {
         ],
         working_dir: "/tmp".into(),
         exact: true, // No handlers
-        settings: HashMap::new(),
-        env: HashMap::new(),
+        ..Default::default()
     })
     .await
     .unwrap();
@@ -0,0 +1,424 @@
+use std::process::Command;
+
+#[test]
+fn test_run_passes_arguments_through() {
+    let output = Command::new(env!("CARGO_BIN_EXE_shelly"))
+        .args(["run", "--exact", "--env", "GREETING=hi there", "--"])
+        .args(["bash", "-c", "echo \"$GREETING:  $1\"; exit 3", "bash", "two  spaces"])
+        .output()
+        .unwrap();
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.starts_with("hi there:  two  spaces\n"), "{stdout}");
+    assert!(stdout.contains("\nOutput file: "), "{stdout}");
+    assert!(stdout.ends_with("Exit code: 3\n"), "{stdout}");
+    assert_eq!(output.status.code(), Some(3));
+}
+
+#[test]
+fn test_run_json_prints_whole_result() {
+    let output = Command::new(env!("CARGO_BIN_EXE_shelly"))
+        .args(["run", "--exact", "--json", "bash", "-c", "echo hi; exit 2"])
+        .output()
+        .unwrap();
+
+    let result: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(result["summary"], "hi\n");
+    assert_eq!(result["exit_code"], 2);
+    assert_eq!(result["is_error"], true);
+    assert!(result["output_file"].as_str().unwrap().ends_with(".txt"), "{result}");
+    assert!(result["process_id"].is_string(), "{result}");
+    assert_eq!(output.status.code(), Some(2));
+}
+
+#[test]
+fn test_run_timeout_cancels_command() {
+    let output = Command::new(env!("CARGO_BIN_EXE_shelly"))
+        .args(["run", "--exact", "--timeout", "1", "sleep", "30"])
+        .output()
+        .unwrap();
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.starts_with("Command timed out"), "{stdout}");
+    assert_eq!(output.status.code(), Some(130));
+}
+
+#[test]
+fn test_disable_handlers_env_runs_commands_exactly() {
+    let dir = tempfile::tempdir().unwrap();
+    std::fs::create_dir(dir.path().join(".shelly")).unwrap();
+    std::fs::write(
+        dir.path().join(".shelly/bash.json"),
+        r#"{ "matches": { "command": "bash" }, "drop": ["^noise"] }"#,
+    )
+    .unwrap();
+    let run = |disable: &str| {
+        let output = Command::new(env!("CARGO_BIN_EXE_shelly"))
+            .args(["run", "bash", "-c", "echo noise; echo signal"])
+            .current_dir(dir.path())
+            .env("HOME", dir.path().join("home"))
+            .env("SHELLY_DISABLE_HANDLERS", disable)
+            .output()
+            .unwrap();
+        assert!(output.status.success(), "{output:?}");
+        String::from_utf8(output.stdout).unwrap()
+    };
+
+    let filtered = run("0");
+    assert!(filtered.starts_with("signal\n"), "{filtered}");
+    let exact = run("1");
+    assert!(exact.starts_with("noise\nsignal\n"), "{exact}");
+}
+
+#[test]
+fn test_handlers_which_and_list() {
+    let dir = tempfile::tempdir().unwrap();
+    std::fs::create_dir(dir.path().join(".shelly")).unwrap();
+    std::fs::write(
+        dir.path().join(".shelly/make.json"),
+        r#"{ "matches": { "command": "make", "args": ["test"] }, "drop": [] }"#,
+    )
+    .unwrap();
+    let shelly = |args: &[&str]| {
+        let output = Command::new(env!("CARGO_BIN_EXE_shelly"))
+            .args(args)
+            .current_dir(dir.path())
+            .env("HOME", dir.path().join("home"))
+            .output()
+            .unwrap();
+        assert!(output.status.success(), "{output:?}");
+        String::from_utf8(output.stdout).unwrap()
+    };
+
+    let which = shelly(&["handlers", "which", "make", "test", "-j4"]);
+    assert_eq!(which, ".shelly/make.json (matches)\n");
+    let which = shelly(&["handlers", "which", "make", "build"]);
+    assert!(which.starts_with(".shelly/make.json (does not match)\n"), "{which}");
+    assert!(which.ends_with("No handler intercepts `make build`; it runs unmodified\n"));
+
+    let list = shelly(&["handlers", "list"]);
+    assert!(list.contains("cargo (built-in): "), "{list}");
+    assert!(
+        list.ends_with("make (.shelly): .shelly/make.json\n  matches `make test`\n"),
+        "{list}"
+    );
+}
+
+#[test]
+fn test_new_scaffolds_handler_and_test() {
+    let dir = tempfile::tempdir().unwrap();
+    let home = dir.path().join("home");
+    let shelly = |args: &[&str]| {
+        Command::new(env!("CARGO_BIN_EXE_shelly"))
+            .args(args)
+            .current_dir(dir.path())
+            .env("HOME", &home)
+            .output()
+            .unwrap()
+    };
+
+    let output = shelly(&["new", "my-tool"]);
+    assert!(output.status.success(), "{output:?}");
+    let handler = std::fs::read_to_string(home.join(".shelly/my-tool.ts")).unwrap();
+    assert!(handler.contains("class MyToolHandler implements Handler"), "{handler}");
+    assert!(handler.contains("export const myToolHandler: HandlerFactory"), "{handler}");
+    assert!(handler.contains(r#"return cmd === "my-tool";"#), "{handler}");
+    assert!(home.join(".shelly/api.ts").exists());
+    let test = std::fs::read_to_string(dir.path().join(".shelly/tests/my-tool/basic.toml"));
+    assert!(test.unwrap().contains(r#"cmd = "my-tool""#));
+
+    // An existing handler is only replaced with --force
+    let output = shelly(&["new", "my-tool"]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("--force"));
+    assert!(shelly(&["new", "my-tool", "--force"]).status.success());
+
+    // An api.ts left behind by an older shelly is brought up to date
+    let api_path = home.join(".shelly/api.ts");
+    let api = std::fs::read_to_string(&api_path).unwrap();
+    std::fs::write(&api_path, "export interface Handler {}\n").unwrap();
+    assert!(shelly(&["new", "other-tool"]).status.success());
+    assert_eq!(std::fs::read_to_string(&api_path).unwrap(), api);
+
+    // The export has to be found under the name derived from the file name
+    assert!(!shelly(&["new", "2fa"]).status.success());
+    assert!(shelly(&["new", "my_tool"]).status.success());
+    let handler = std::fs::read_to_string(home.join(".shelly/my_tool.ts")).unwrap();
+    assert!(handler.contains("export const my_toolHandler: HandlerFactory"), "{handler}");
+}
+
+#[test]
+fn test_validate_reports_broken_handlers() {
+    let dir = tempfile::tempdir().unwrap();
+    std::fs::create_dir(dir.path().join(".shelly")).unwrap();
+    std::fs::write(
+        dir.path().join(".shelly/make.json"),
+        r#"{ "matches": { "command": "make" }, "drop": ["^ok$"] }"#,
+    )
+    .unwrap();
+    std::fs::write(dir.path().join(".shelly/ninja.json"), "{ \"matches\": ").unwrap();
+    let validate = |args: &[&str]| {
+        Command::new(env!("CARGO_BIN_EXE_shelly"))
+            .arg("validate")
+            .args(args)
+            .current_dir(dir.path())
+            .env("HOME", dir.path())
+            .output()
+            .unwrap()
+    };
+
+    let output = validate(&["make"]);
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "  ✓ .shelly/make.json\n");
+    assert!(output.status.success());
+
+    let output = validate(&["make", ".shelly/ninja.json", "nonexistent"]);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("  ✗ .shelly/ninja.json: "), "{stdout}");
+    assert!(stdout.ends_with("  ✗ nonexistent: handler not found\n"), "{stdout}");
+    assert_eq!(output.status.code(), Some(1));
+}
+
+#[test]
+fn test_handler_tests_feed_chunks_in_order() {
+    let dir = tempfile::tempdir().unwrap();
+    std::fs::create_dir_all(dir.path().join(".shelly/tests/make")).unwrap();
+    std::fs::write(
+        dir.path().join(".shelly/make.json"),
+        r#"{ "matches": { "command": "make" }, "drop": ["^noise$"], "keep_last_n": 1 }"#,
+    )
+    .unwrap();
+    let chunks = r#"
+cmd = "make"
+settings = {}
+exit_code = 1
+
+[[chunks]]
+stdout = "one\nnoise\n"
+
+[[chunks]]
+stderr = "two\n"
+"#;
+    // Without an exit code on the last chunk, finalize() sees the whole failed run and
+    // keeps only its last line
+    std::fs::write(
+        dir.path().join(".shelly/tests/make/finalized.toml"),
+        format!("expected_summary = \"two\\n\"\n{chunks}"),
+    )
+    .unwrap();
+    std::fs::write(
+        dir.path().join(".shelly/tests/make/streamed.toml"),
+        format!("expected_summary = \"one\\ntwo\\n\"\n{chunks}exit_code = 1\n"),
+    )
+    .unwrap();
+    let output = Command::new(env!("CARGO_BIN_EXE_shelly"))
+        .args(["test", "make"])
+        .current_dir(dir.path())
+        .env("HOME", dir.path())
+        .output()
+        .unwrap();
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("Results: 2 passed, 0 failed"), "{stdout}");
+}
+
+#[test]
+fn test_failing_handler_test_prints_diff() {
+    let dir = tempfile::tempdir().unwrap();
+    std::fs::create_dir_all(dir.path().join(".shelly/tests/make")).unwrap();
+    std::fs::write(
+        dir.path().join(".shelly/make.json"),
+        r#"{ "matches": { "command": "make" }, "drop": ["^noise$"] }"#,
+    )
+    .unwrap();
+    std::fs::write(
+        dir.path().join(".shelly/tests/make/basic.toml"),
+        r#"
+cmd = "make"
+settings = {}
+stdout = "one\nnoise\ntwo\nfour\n"
+exit_code = 0
+expected_summary = "one\ntwo\nthree\n"
+"#,
+    )
+    .unwrap();
+    let shelly = |args: &[&str]| {
+        let output = Command::new(env!("CARGO_BIN_EXE_shelly"))
+            .args(args)
+            .current_dir(dir.path())
+            .env("HOME", dir.path())
+            .output()
+            .unwrap();
+        assert_eq!(output.status.code(), Some(1));
+        String::from_utf8_lossy(&output.stdout).into_owned()
+    };
+
+    let stdout = shelly(&["test", "make"]);
+    assert!(stdout.contains("  ✗ basic\n    - three\n    + four\n\nResults"), "{stdout}");
+
+    let stdout = shelly(&["test", "make", "--verbose"]);
+    assert!(stdout.contains("    + four\n    Expected:\n      one\n"), "{stdout}");
+}
+
+#[test]
+fn test_handler_tests_run_in_parallel_in_stable_order() {
+    let dir = tempfile::tempdir().unwrap();
+    let test_dir = dir.path().join(".shelly/tests/make");
+    std::fs::create_dir_all(&test_dir).unwrap();
+    std::fs::write(
+        dir.path().join(".shelly/make.json"),
+        r#"{ "matches": { "command": "make" } }"#,
+    )
+    .unwrap();
+    for i in 0..6 {
+        // Every third test expects the wrong summary
+        let expected = if i % 3 == 0 { "wrong" } else { "ok" };
+        std::fs::write(
+            test_dir.join(format!("case{i}.toml")),
+            format!(
+                "cmd = \"make\"\nsettings = {{}}\nstdout = \"ok\\n\"\nexit_code = 0\n\
+                 expected_summary = \"{expected}\"\n"
+            ),
+        )
+        .unwrap();
+    }
+    let output = Command::new(env!("CARGO_BIN_EXE_shelly"))
+        .args(["test", "make", "--jobs", "3"])
+        .current_dir(dir.path())
+        .env("HOME", dir.path())
+        .output()
+        .unwrap();
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let names: Vec<_> = stdout
+        .lines()
+        .filter_map(|line| line.strip_prefix("  ✓ ").or(line.strip_prefix("  ✗ ")))
+        .collect();
+    assert_eq!(names, ["case0", "case1", "case2", "case3", "case4", "case5"]);
+    assert!(stdout.ends_with("Results: 4 passed, 2 failed\n"), "{stdout}");
+    assert_eq!(output.status.code(), Some(1));
+}
+
+#[test]
+fn test_handler_tests_check_golden_output_file() {
+    let dir = tempfile::tempdir().unwrap();
+    let test_dir = dir.path().join(".shelly/tests/make");
+    std::fs::create_dir_all(&test_dir).unwrap();
+    std::fs::write(
+        dir.path().join(".shelly/make.json"),
+        r#"{ "matches": { "command": "make" } }"#,
+    )
+    .unwrap();
+    std::fs::write(
+        test_dir.join("basic.toml"),
+        r#"
+cmd = "make"
+settings = {}
+exit_code = 2
+expected_summary = ""
+check_output_file = true
+
+[[chunks]]
+stdout = "one\n"
+stderr = "two\n"
+
+[[chunks]]
+stdout = "three"
+"#,
+    )
+    .unwrap();
+    let shelly = |args: &[&str]| {
+        Command::new(env!("CARGO_BIN_EXE_shelly"))
+            .args(args)
+            .current_dir(dir.path())
+            .env("HOME", dir.path())
+            .output()
+            .unwrap()
+    };
+
+    // The golden file has to be created before the test can pass
+    assert!(!shelly(&["test", "make"]).status.success());
+    assert!(shelly(&["test", "make", "--update"]).status.success());
+    let golden = std::fs::read_to_string(test_dir.join("basic.output")).unwrap();
+    assert_eq!(golden, "Exit Code: 2\n\n[out] one\n[err] two\n[out] three\n");
+    assert!(shelly(&["test", "make"]).status.success());
+
+    std::fs::write(test_dir.join("basic.output"), golden.replace("[err]", "[out]")).unwrap();
+    let output = shelly(&["test", "make"]);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("    Output file:\n    - [out] two\n    + [err] two\n"), "{stdout}");
+    assert_eq!(output.status.code(), Some(1));
+}
+
+#[test]
+fn test_handlers_are_found_by_subcommand() {
+    let dir = tempfile::tempdir().unwrap();
+    std::fs::create_dir(dir.path().join(".shelly")).unwrap();
+    std::fs::write(
+        dir.path().join(".shelly/go.json"),
+        r#"{ "matches": { "command": "go" } }"#,
+    )
+    .unwrap();
+    std::fs::write(
+        dir.path().join(".shelly/go-test.json"),
+        r#"{ "matches": { "command": "go", "args": ["test"] } }"#,
+    )
+    .unwrap();
+    let which = |args: &[&str]| {
+        let output = Command::new(env!("CARGO_BIN_EXE_shelly"))
+            .args(["handlers", "which"])
+            .args(args)
+            .current_dir(dir.path())
+            .env("HOME", dir.path().join("home"))
+            .output()
+            .unwrap();
+        String::from_utf8(output.stdout).unwrap()
+    };
+
+    assert_eq!(
+        which(&["go", "test", "./..."]),
+        ".shelly/go.json (matches)\n.shelly/go-test.json (matches)\n"
+    );
+    assert_eq!(which(&["go", "build"]), ".shelly/go.json (matches)\n");
+    // Flags end the subcommand prefix
+    assert_eq!(which(&["go", "-C", "test"]), ".shelly/go.json (matches)\n");
+}
+
+#[test]
+fn test_project_handlers_are_found_from_subdirectories() {
+    let dir = tempfile::tempdir().unwrap();
+    let nested = dir.path().join("crates/app");
+    std::fs::create_dir_all(&nested).unwrap();
+    std::fs::create_dir_all(dir.path().join("crates/.shelly")).unwrap();
+    std::fs::create_dir(dir.path().join(".shelly")).unwrap();
+    for (path, command) in [(".shelly/make.json", "make"), (".shelly/ninja.json", "ninja")] {
+        std::fs::write(
+            dir.path().join(path),
+            format!(r#"{{ "matches": {{ "command": "{command}" }} }}"#),
+        )
+        .unwrap();
+    }
+    // Shadows the repository root's make handler
+    std::fs::write(
+        dir.path().join("crates/.shelly/make.json"),
+        r#"{ "matches": { "command": "make" } }"#,
+    )
+    .unwrap();
+    let shelly = |args: &[&str]| {
+        let output = Command::new(env!("CARGO_BIN_EXE_shelly"))
+            .args(args)
+            .current_dir(&nested)
+            .env("HOME", dir.path().join("home"))
+            .output()
+            .unwrap();
+        String::from_utf8(output.stdout).unwrap()
+    };
+
+    let root = dir.path().canonicalize().unwrap();
+    let which = shelly(&["handlers", "which", "ninja"]);
+    assert_eq!(which, format!("{}/.shelly/ninja.json (matches)\n", root.display()));
+    let which = shelly(&["handlers", "which", "make"]);
+    assert_eq!(which, format!("{}/crates/.shelly/make.json (matches)\n", root.display()));
+
+    let list = shelly(&["handlers", "list"]);
+    assert!(!list.contains(&format!("{}/.shelly/make.json", root.display())), "{list}");
+}